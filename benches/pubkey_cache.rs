@@ -0,0 +1,34 @@
+//! Benchmarks of deriving the wallet's keys, with and without cached keys.
+//!
+//! Run with `cargo +nightly bench --features unstable`.
+
+#![cfg_attr(feature = "unstable", feature(test))]
+
+#[cfg(feature = "unstable")]
+mod benches {
+	extern crate bitcoin;
+	extern crate bitcoin_wallet;
+	extern crate test;
+
+	use self::bitcoin::Network;
+	use self::bitcoin_wallet::wallet::Wallet;
+	use self::test::Bencher;
+
+	#[bench]
+	fn bench_peek_address_cached(b: &mut Bencher) {
+		let wallet = Wallet::new_for_testing(&[42; 32], Network::Regtest);
+		wallet.peek_address(0);
+		b.iter(|| test::black_box(wallet.peek_address(0)));
+	}
+
+	/// Only the key itself is derived, the key of the keychain is cached.
+	#[bench]
+	fn bench_peek_address_uncached(b: &mut Bencher) {
+		let wallet = Wallet::new_for_testing(&[42; 32], Network::Regtest);
+		let mut offset = 0;
+		b.iter(|| {
+			offset += 1;
+			test::black_box(wallet.peek_address(offset))
+		});
+	}
+}
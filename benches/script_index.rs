@@ -0,0 +1,120 @@
+//! Benchmarks of the wallet's script index.
+//!
+//! The wallet keys its script index by the SHA-256 hashes of the scripts. The
+//! `index_*` benchmarks compare it with the baseline of an index keyed by the
+//! scripts themselves, which needs a heap allocation for every key. Cloning
+//! the index, like [Wallet::begin_batch] does, shows the difference. Lookups
+//! pay for hashing the script instead.
+//!
+//! Run with `cargo +nightly bench --features unstable`.
+
+#![cfg_attr(feature = "unstable", feature(test))]
+
+#[cfg(feature = "unstable")]
+mod benches {
+	extern crate bitcoin;
+	extern crate bitcoin_hashes;
+	extern crate bitcoin_wallet;
+	extern crate test;
+
+	use std::collections::HashMap;
+
+	use self::bitcoin::{Address, Network, OutPoint, Script, Transaction, TxIn, TxOut};
+	use self::bitcoin_hashes::{sha256, Hash};
+	use self::bitcoin_wallet::wallet::{ScriptInfo, Wallet};
+	use self::test::Bencher;
+
+	/// The number of addresses handed out, like with a large lookahead window.
+	const NB_ADDRESSES: usize = 10_000;
+
+	fn wallet_with_addresses() -> (Wallet, Vec<Address>) {
+		let mut wallet = Wallet::new_for_testing(&[42; 32], Network::Regtest);
+		let addresses = (0..NB_ADDRESSES).map(|_| wallet.new_receive_address().unwrap()).collect();
+		(wallet, addresses)
+	}
+
+	fn tx_paying(addresses: &[&Address]) -> Transaction {
+		Transaction {
+			version: 1,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: OutPoint::null(),
+				script_sig: Script::new(),
+				sequence: 0xFFFFFFFF,
+				witness: vec![],
+			}],
+			output: addresses
+				.iter()
+				.map(|a| TxOut {
+					value: 1_000,
+					script_pubkey: a.script_pubkey(),
+				})
+				.collect(),
+		}
+	}
+
+	#[bench]
+	fn bench_is_relevant_tx_mine(b: &mut Bencher) {
+		let (wallet, addresses) = wallet_with_addresses();
+		let tx = tx_paying(&[&addresses[NB_ADDRESSES / 2]]);
+		b.iter(|| test::black_box(wallet.is_relevant_tx(&tx)));
+	}
+
+	#[bench]
+	fn bench_is_relevant_tx_not_mine(b: &mut Bencher) {
+		let (wallet, _) = wallet_with_addresses();
+		let other = Wallet::new_for_testing(&[43; 32], Network::Regtest).peek_address(0);
+		let tx = tx_paying(&[&other, &other]);
+		b.iter(|| test::black_box(wallet.is_relevant_tx(&tx)));
+	}
+
+	/// The index of the wallet and the baseline index keyed by script.
+	fn indices() -> (HashMap<sha256::Hash, ScriptInfo>, HashMap<Script, ScriptInfo>) {
+		let (wallet, _) = wallet_with_addresses();
+		let scripts = wallet.relevant_scripts().unwrap();
+		let info = |s: &Script| wallet.get_script_info(s).unwrap();
+		let by_hash = scripts.iter().map(|s| (sha256::Hash::hash(s.as_bytes()), info(s))).collect();
+		let by_script = scripts.iter().map(|s| (s.clone(), info(s))).collect();
+		(by_hash, by_script)
+	}
+
+	#[bench]
+	fn bench_index_clone_by_script_hash(b: &mut Bencher) {
+		let (by_hash, _) = indices();
+		b.iter(|| test::black_box(by_hash.clone()));
+	}
+
+	/// The baseline of [bench_index_clone_by_script_hash].
+	#[bench]
+	fn bench_index_clone_by_script(b: &mut Bencher) {
+		let (_, by_script) = indices();
+		b.iter(|| test::black_box(by_script.clone()));
+	}
+
+	#[bench]
+	fn bench_index_lookup_by_script_hash(b: &mut Bencher) {
+		let (by_hash, by_script) = indices();
+		let mine = by_script.keys().next().unwrap().clone();
+		b.iter(|| test::black_box(by_hash.get(&sha256::Hash::hash(mine.as_bytes()))));
+	}
+
+	/// The baseline of [bench_index_lookup_by_script_hash].
+	#[bench]
+	fn bench_index_lookup_by_script(b: &mut Bencher) {
+		let (_, by_script) = indices();
+		let mine = by_script.keys().next().unwrap().clone();
+		b.iter(|| test::black_box(by_script.get(&mine)));
+	}
+
+	#[bench]
+	fn bench_begin_batch(b: &mut Bencher) {
+		let (wallet, _) = wallet_with_addresses();
+		b.iter(|| test::black_box(wallet.begin_batch()));
+	}
+
+	#[bench]
+	fn bench_new_receive_address(b: &mut Bencher) {
+		let mut wallet = Wallet::new_for_testing(&[42; 32], Network::Regtest);
+		b.iter(|| test::black_box(wallet.new_receive_address().unwrap()));
+	}
+}
@@ -0,0 +1,266 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Accounting of the wallet's history, independent of exchange rates.
+//!
+//! Every history entry has an [AccountingTag] saying whether it acquires or
+//! disposes of coins or only moves them between the user's own wallets.
+//! Using these tags, the history is grouped into accounting lots: every lot
+//! starts with the coins received in an entry and follows them through the
+//! history until they leave the wallet. Coins are spent first-in-first-out.
+//!
+//! The history can also be exported as CSV, one row per entry, see
+//! [Wallet::export_history_csv].
+
+use std::collections::HashMap;
+
+use bitcoin::{OutPoint, Script};
+use bitcoin_hashes::sha256d;
+use serde::{Deserialize, Serialize};
+
+use wallet::{TxEntry, TxFilter};
+
+/// The kind of an entry for accounting purposes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccountingKind {
+	/// Coins are received from someone else.
+	Acquisition,
+	/// Coins are sent to someone else.
+	Disposal,
+	/// Coins are moved within the wallet or between wallets of the same owner.
+	SelfTransfer,
+}
+
+/// The accounting tag of a history entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountingTag {
+	pub kind: AccountingKind,
+	/// The ids of linked entries, f.e. the other side of a self-transfer
+	/// in another wallet of the owner. Entries are identified by their txid.
+	#[serde(default)]
+	pub linked: Vec<sha256d::Hash>,
+}
+
+impl AccountingTag {
+	pub fn new(kind: AccountingKind) -> AccountingTag {
+		AccountingTag {
+			kind: kind,
+			linked: Vec::new(),
+		}
+	}
+}
+
+/// Options for [Wallet::export_history_csv].
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+	/// The entries to export.
+	pub filter: TxFilter,
+	pub delimiter: char,
+	/// Start with a row of column names.
+	pub header: bool,
+	/// Write amounts in BTC instead of in satoshis.
+	pub btc_amounts: bool,
+}
+
+impl Default for CsvOptions {
+	fn default() -> CsvOptions {
+		CsvOptions {
+			filter: TxFilter::default(),
+			delimiter: ',',
+			header: true,
+			btc_amounts: false,
+		}
+	}
+}
+
+/// The columns of the CSV export.
+pub const CSV_COLUMNS: &'static [&'static str] =
+	&["txid", "height", "timestamp", "direction", "amount", "fee", "label"];
+
+/// Quote the field if it contains the delimiter, quotes or line breaks.
+pub fn csv_field(field: &str, delimiter: char) -> String {
+	if field.contains(|c: char| c == delimiter || c == '"' || c == '\n' || c == '\r') {
+		format!("\"{}\"", field.replace('"', "\"\""))
+	} else {
+		field.to_owned()
+	}
+}
+
+/// Format the amount for the CSV export.
+pub fn csv_amount(amount: u64, btc: bool) -> String {
+	if btc {
+		format!("{}.{:08}", amount / 100_000_000, amount % 100_000_000)
+	} else {
+		amount.to_string()
+	}
+}
+
+/// The tag for an entry that was not tagged explicitly.
+/// Entries that don't spend our coins are acquisitions, entries that only pay
+/// to our own scripts are self-transfers and all others are disposals.
+pub fn default_tag<M>(entry: &TxEntry, is_mine: M) -> AccountingTag
+where
+	M: Fn(&Script) -> bool,
+{
+	let kind = if entry.sent.as_sat() == 0 {
+		AccountingKind::Acquisition
+	} else if entry.tx.output.iter().all(|o| is_mine(&o.script_pubkey)) {
+		AccountingKind::SelfTransfer
+	} else {
+		AccountingKind::Disposal
+	};
+	AccountingTag::new(kind)
+}
+
+/// An entry moving coins of a lot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LotMovement {
+	pub txid: sha256d::Hash,
+	pub kind: AccountingKind,
+	pub height: u32,
+	pub timestamp: u32,
+	/// The amount of the lot that left the wallet, excluding the fee.
+	pub amount: u64,
+	/// The part of the fee paid by the lot.
+	pub fee: u64,
+}
+
+/// Coins received in a single entry and what happened to them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountingLot {
+	/// The entry in which the coins were received.
+	pub txid: sha256d::Hash,
+	pub kind: AccountingKind,
+	/// The linked entries of the receiving entry.
+	pub linked: Vec<sha256d::Hash>,
+	pub height: u32,
+	pub timestamp: u32,
+	/// The amount received.
+	pub value: u64,
+	/// The amount still in the wallet.
+	pub remaining: u64,
+	/// All later entries that spent coins of the lot, in order.
+	pub movements: Vec<LotMovement>,
+}
+
+/// Take the amount from the front of the lot shares.
+fn take_shares(pool: &mut Vec<(usize, u64)>, mut amount: u64) -> Vec<(usize, u64)> {
+	let mut taken = Vec::new();
+	while amount > 0 && !pool.is_empty() {
+		let take = amount.min(pool[0].1);
+		taken.push((pool[0].0, take));
+		amount -= take;
+		pool[0].1 -= take;
+		if pool[0].1 == 0 {
+			pool.remove(0);
+		}
+	}
+	taken
+}
+
+/// Group the history entries, ordered by height, into lots. [tag] should give
+/// the accounting tag of each entry.
+pub fn lots<'a, H, M, T>(history: H, is_mine: M, tag: T) -> Vec<AccountingLot>
+where
+	H: IntoIterator<Item = &'a TxEntry>,
+	M: Fn(&Script) -> bool,
+	T: Fn(&TxEntry) -> AccountingTag,
+{
+	let mut lots: Vec<AccountingLot> = Vec::new();
+	// The lot shares of each of our unspent outputs, oldest lot first.
+	let mut shares: HashMap<OutPoint, Vec<(usize, u64)>> = HashMap::new();
+
+	for entry in history {
+		let txid = entry.txid();
+		let tag = tag(entry);
+
+		let mut pool: Vec<(usize, u64)> = Vec::new();
+		for input in &entry.tx.input {
+			if let Some(s) = shares.remove(&input.previous_output) {
+				pool.extend(s);
+			}
+		}
+		pool.sort_by_key(|s| s.0);
+		pool.dedup_by(|b, a| {
+			if a.0 == b.0 {
+				a.1 += b.1;
+			}
+			a.0 == b.0
+		});
+
+		// The coins that left the wallet are taken from the oldest lots first.
+		let pool_total: u64 = pool.iter().map(|s| s.1).sum();
+		let ours: Vec<(u32, u64)> = entry
+			.tx
+			.output
+			.iter()
+			.enumerate()
+			.filter(|(_, o)| is_mine(&o.script_pubkey))
+			.map(|(i, o)| (i as u32, o.value))
+			.collect();
+		let ours_total: u64 = ours.iter().map(|o| o.1).sum();
+		let out_of_pool = pool_total.saturating_sub(ours_total);
+		let fee = entry.fee.map(|f| f.as_sat()).unwrap_or(0).min(out_of_pool);
+		let mut movements: Vec<(usize, u64, u64)> = pool.iter().map(|s| (s.0, 0, 0)).collect();
+		for (lot, amount) in take_shares(&mut pool, out_of_pool - fee) {
+			movements.iter_mut().find(|m| m.0 == lot).unwrap().1 += amount;
+		}
+		for (lot, amount) in take_shares(&mut pool, fee) {
+			movements.iter_mut().find(|m| m.0 == lot).unwrap().2 += amount;
+		}
+		for (lot, amount, fee) in movements {
+			lots[lot].remaining -= amount + fee;
+			lots[lot].movements.push(LotMovement {
+				txid: txid,
+				kind: tag.kind,
+				height: entry.height,
+				timestamp: entry.timestamp,
+				amount: amount,
+				fee: fee,
+			});
+		}
+
+		// Our outputs get the remaining shares, what's not covered by them
+		// was received and forms a new lot.
+		let mut new_lot = None;
+		for (vout, value) in ours {
+			let mut output_shares = take_shares(&mut pool, value);
+			let covered: u64 = output_shares.iter().map(|s| s.1).sum();
+			if covered < value {
+				let lot = *new_lot.get_or_insert_with(|| {
+					lots.push(AccountingLot {
+						txid: txid,
+						kind: tag.kind,
+						linked: tag.linked.clone(),
+						height: entry.height,
+						timestamp: entry.timestamp,
+						value: 0,
+						remaining: 0,
+						movements: Vec::new(),
+					});
+					lots.len() - 1
+				});
+				lots[lot].value += value - covered;
+				lots[lot].remaining += value - covered;
+				output_shares.push((lot, value - covered));
+			}
+			let outpoint = OutPoint {
+				txid: txid,
+				vout: vout,
+			};
+			shares.insert(outpoint, output_shares);
+		}
+	}
+	lots
+}
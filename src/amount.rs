@@ -0,0 +1,90 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Amounts and feerates.
+//!
+//! The public API uses [Amount] for values and [FeeRate] for feerates, so
+//! that satoshis, bitcoins and feerates can't be confused. Amounts are stored
+//! as plain satoshis, see [as_sat], so stored wallets stay compatible.
+//!
+//! Some values stay plain satoshis: signed values like the net amount of a
+//! tx, the limits in the wallet config, the weight and fee helpers in [fee]
+//! and values in export formats like the ones of [audit] and [accounting].
+
+use std::fmt;
+
+pub use bitcoin::Amount;
+use serde::{Deserialize, Serialize};
+
+use fee::weight_to_vsize;
+
+/// A feerate in satoshis per virtual byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+	pub fn from_sat_per_vb(sat_per_vb: u64) -> FeeRate {
+		FeeRate(sat_per_vb)
+	}
+
+	/// Convert a feerate in BTC per 1000 virtual bytes, like the ones of
+	/// Bitcoin Core, rounding up so that the feerate is not undershot.
+	pub fn from_btc_per_kvb(btc_per_kvb: f64) -> FeeRate {
+		FeeRate((btc_per_kvb * 100_000_000.0 / 1000.0).ceil() as u64)
+	}
+
+	pub fn as_sat_per_vb(&self) -> u64 {
+		self.0
+	}
+
+	/// The fee for a tx with the given weight.
+	pub fn fee_for_weight(&self, weight: u64) -> Amount {
+		Amount::from_sat(self.0 * weight_to_vsize(weight))
+	}
+}
+
+impl fmt::Display for FeeRate {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} sat/vB", self.0)
+	}
+}
+
+/// Serde helpers to store an [Amount] as a number of satoshis, use with
+/// `#[serde(with = "amount::as_sat")]`.
+pub mod as_sat {
+	use bitcoin::Amount;
+	use serde::{Deserialize, Deserializer, Serializer};
+
+	pub fn serialize<S: Serializer>(amount: &Amount, s: S) -> Result<S::Ok, S::Error> {
+		s.serialize_u64(amount.as_sat())
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Amount, D::Error> {
+		Ok(Amount::from_sat(u64::deserialize(d)?))
+	}
+}
+
+/// Like [as_sat], for optional amounts.
+pub mod as_sat_opt {
+	use bitcoin::Amount;
+	use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+	pub fn serialize<S: Serializer>(amount: &Option<Amount>, s: S) -> Result<S::Ok, S::Error> {
+		amount.map(|a| a.as_sat()).serialize(s)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Amount>, D::Error> {
+		Ok(Option::<u64>::deserialize(d)?.map(Amount::from_sat))
+	}
+}
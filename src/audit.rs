@@ -0,0 +1,157 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Export of the wallet's coins for auditors.
+//!
+//! An [AuditExport] lists the UTXOs of the wallet with the derivation path and
+//! public key of the key controlling each of them. The wallet's xpub is not
+//! included, so the auditor can't derive any addresses beyond the listed ones.
+//!
+//! Every entry is signed with BIP-322 by its own key, so that the export proves
+//! control of all listed UTXOs: for every entry, create a PSBT with
+//! [Wallet::message_signing_psbt] for the [AuditEntry::address] and the
+//! [AuditExport::message], sign it and set the result of
+//! [bip322::signature_from_psbt] as the entry's signature. Only segwit keys
+//! can sign, so P2PKH UTXOs are not listed.
+//! The auditor checks the export against the UTXO set using [verify], which
+//! rejects exports with unsigned entries.
+
+use bitcoin::util::bip32;
+use bitcoin::{Address, Network, OutPoint, PublicKey, Script, TxOut};
+use bitcoin_hashes::hex::ToHex;
+use serde::{Deserialize, Serialize};
+
+use bip322;
+use error::{Error, Result};
+use wallet::AddressType;
+
+/// The first line of the signed message.
+pub const MESSAGE_PREFIX: &'static str = "Wallet audit export";
+
+/// A UTXO listed in an audit export.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+	pub outpoint: OutPoint,
+	pub value: u64,
+	/// The derivation path of the key, relative to the wallet's xpub.
+	pub path: bip32::DerivationPath,
+	#[serde(with = "serde_pubkey")]
+	pub pubkey: PublicKey,
+	pub address_type: AddressType,
+	/// The base64-encoded BIP-322 signature of the [AuditExport::message] by
+	/// the key. An entry without signature is not proven to be controlled.
+	pub signature: Option<String>,
+}
+
+impl AuditEntry {
+	/// The address the UTXO pays to.
+	pub fn address(&self, network: Network) -> Address {
+		match self.address_type {
+			AddressType::P2wpkh => Address::p2wpkh(&self.pubkey, network),
+			AddressType::P2shP2wpkh => Address::p2shwpkh(&self.pubkey, network),
+			AddressType::P2pkh => Address::p2pkh(&self.pubkey, network),
+		}
+	}
+
+	pub fn script_pubkey(&self, network: Network) -> Script {
+		self.address(network).script_pubkey()
+	}
+}
+
+/// The UTXOs of a wallet, each signed by its key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditExport {
+	pub network: Network,
+	/// The listed UTXOs, ordered by outpoint.
+	pub entries: Vec<AuditEntry>,
+}
+
+impl AuditExport {
+	/// The message that is signed by every entry's key, a line for every entry
+	/// after the prefix. The signatures are not part of it.
+	pub fn message(&self) -> Vec<u8> {
+		let mut message = format!("{} ({})\n", MESSAGE_PREFIX, self.network);
+		for entry in &self.entries {
+			message.push_str(&format!(
+				"{}:{} {} {} {}\n",
+				entry.outpoint.txid,
+				entry.outpoint.vout,
+				entry.value,
+				entry.path,
+				entry.pubkey.to_bytes().to_hex(),
+			));
+		}
+		message.into_bytes()
+	}
+
+	/// The outpoints of the entries that are not signed yet.
+	pub fn unsigned_entries(&self) -> Vec<OutPoint> {
+		self.entries.iter().filter(|e| e.signature.is_none()).map(|e| e.outpoint).collect()
+	}
+
+	/// The total value of the listed UTXOs.
+	pub fn total_value(&self) -> u64 {
+		self.entries.iter().map(|e| e.value).sum()
+	}
+}
+
+/// Verify the signed export and return the total value of the listed UTXOs.
+/// Every entry must be signed by its key.
+///
+/// [prevout] should return the outputs listed in the export, but only if they
+/// are still unspent.
+pub fn verify<F>(export: &AuditExport, prevout: F) -> Result<u64>
+where
+	F: Fn(&OutPoint) -> Option<TxOut>,
+{
+	let message = export.message();
+	for (idx, entry) in export.entries.iter().enumerate() {
+		if export.entries[..idx].iter().any(|e| e.outpoint == entry.outpoint) {
+			return Err(Error::InvalidAuditExport("UTXO listed more than once"));
+		}
+		let txout = match prevout(&entry.outpoint) {
+			Some(txout) => txout,
+			None => return Err(Error::InvalidAuditExport("UTXO not found")),
+		};
+		if txout.value != entry.value {
+			return Err(Error::InvalidAuditExport("wrong UTXO value"));
+		}
+		if txout.script_pubkey != entry.script_pubkey(export.network) {
+			return Err(Error::InvalidAuditExport("UTXO not controlled by the key"));
+		}
+		let signature = match entry.signature {
+			Some(ref signature) => signature,
+			None => return Err(Error::InvalidAuditExport("UTXO not signed")),
+		};
+		bip322::verify(&entry.address(export.network), &message, signature)?;
+	}
+	Ok(export.total_value())
+}
+
+/// Serialize public keys as hex.
+mod serde_pubkey {
+	use bitcoin::PublicKey;
+	use bitcoin_hashes::hex::{FromHex, ToHex};
+	use serde::de::Error;
+	use serde::{Deserialize, Deserializer, Serializer};
+
+	pub fn serialize<S: Serializer>(pubkey: &PublicKey, s: S) -> Result<S::Ok, S::Error> {
+		s.serialize_str(&pubkey.to_bytes().to_hex())
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<PublicKey, D::Error> {
+		let bytes = Vec::<u8>::from_hex(&String::deserialize(d)?).map_err(D::Error::custom)?;
+		PublicKey::from_slice(&bytes).map_err(D::Error::custom)
+	}
+}
@@ -0,0 +1,208 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! BIP-322 generic message signing.
+//!
+//! Signing happens through a PSBT like for any other tx: create one with
+//! [Wallet::message_signing_psbt], sign it and turn it into a signature with
+//! [signature_from_psbt].
+//!
+//! Without the `bitcoinconsensus` feature, only signatures for P2WPKH and
+//! P2SH-P2WPKH addresses can be verified.
+
+use base64;
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::util::psbt;
+use bitcoin::{Address, OutPoint, Script, Transaction, TxIn, TxOut};
+use bitcoin_hashes::{sha256, sha256d, Hash, HashEngine};
+
+use error::{Error, Result};
+
+const TAG: &'static [u8] = b"BIP0322-signed-message";
+
+/// The tagged hash of the message.
+pub fn message_hash(message: &[u8]) -> sha256::Hash {
+	let tag_hash = sha256::Hash::hash(TAG);
+	let mut engine = sha256::Hash::engine();
+	engine.input(&tag_hash[..]);
+	engine.input(&tag_hash[..]);
+	engine.input(message);
+	sha256::Hash::from_engine(engine)
+}
+
+/// The virtual tx that creates the output to spend in the signature.
+pub fn to_spend(script_pubkey: &Script, message: &[u8]) -> Transaction {
+	Transaction {
+		version: 0,
+		lock_time: 0,
+		input: vec![TxIn {
+			previous_output: OutPoint {
+				txid: sha256d::Hash::default(),
+				vout: 0xFFFFFFFF,
+			},
+			script_sig: Builder::new()
+				.push_int(0)
+				.push_slice(&message_hash(message)[..])
+				.into_script(),
+			sequence: 0,
+			witness: vec![],
+		}],
+		output: vec![TxOut {
+			value: 0,
+			script_pubkey: script_pubkey.clone(),
+		}],
+	}
+}
+
+/// The unsigned virtual tx that spends the output of [to_spend].
+pub fn to_sign(to_spend: &Transaction) -> Transaction {
+	Transaction {
+		version: 0,
+		lock_time: 0,
+		input: vec![TxIn {
+			previous_output: OutPoint {
+				txid: to_spend.txid(),
+				vout: 0,
+			},
+			script_sig: Script::new(),
+			sequence: 0,
+			witness: vec![],
+		}],
+		output: vec![TxOut {
+			value: 0,
+			script_pubkey: Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script(),
+		}],
+	}
+}
+
+/// Turn a PSBT created by [Wallet::message_signing_psbt] and signed by the key
+/// into a base64-encoded signature. The simple format is used when possible,
+/// the full format otherwise.
+pub fn signature_from_psbt(psbt: &psbt::PartiallySignedTransaction) -> Result<String> {
+	let input = match psbt.inputs.first() {
+		Some(input) => input,
+		None => return Err(Error::InvalidMessageSignature("PSBT has no inputs")),
+	};
+	let (pubkey, sig) = match input.partial_sigs.iter().next() {
+		Some(s) => s,
+		None => return Err(Error::InvalidMessageSignature("PSBT is not signed")),
+	};
+	let witness = vec![sig.clone(), pubkey.to_bytes()];
+
+	match input.redeem_script {
+		Some(ref redeem_script) => {
+			let mut tx = psbt.global.unsigned_tx.clone();
+			let script_sig = Builder::new().push_slice(redeem_script.as_bytes()).into_script();
+			tx.input[0].script_sig = script_sig;
+			tx.input[0].witness = witness;
+			Ok(base64::encode(&serialize(&tx)))
+		}
+		None => Ok(base64::encode(&serialize(&witness))),
+	}
+}
+
+/// Verify the base64-encoded signature of the message for the address.
+pub fn verify(address: &Address, message: &[u8], signature: &str) -> Result<()> {
+	let data = match base64::decode(signature) {
+		Ok(data) => data,
+		Err(_) => return Err(Error::InvalidMessageSignature("invalid base64")),
+	};
+
+	let expected = to_sign(&to_spend(&address.script_pubkey(), message));
+	let tx = match deserialize::<Vec<Vec<u8>>>(&data) {
+		Ok(witness) => {
+			let mut tx = expected.clone();
+			tx.input[0].witness = witness;
+			tx
+		}
+		Err(_) => match deserialize::<Transaction>(&data) {
+			Ok(tx) => tx,
+			Err(_) => return Err(Error::InvalidMessageSignature("invalid encoding")),
+		},
+	};
+
+	// Everything but the scriptSig and witness is fixed.
+	if tx.version != expected.version
+		|| tx.lock_time != expected.lock_time
+		|| tx.input.len() != 1
+		|| tx.input[0].previous_output != expected.input[0].previous_output
+		|| tx.input[0].sequence != expected.input[0].sequence
+		|| tx.output != expected.output
+	{
+		return Err(Error::InvalidMessageSignature("wrong message or address"));
+	}
+
+	verify_input(address, &tx)
+}
+
+#[cfg(feature = "bitcoinconsensus")]
+fn verify_input(address: &Address, to_sign: &Transaction) -> Result<()> {
+	use bitcoinconsensus;
+
+	let script = address.script_pubkey();
+	match bitcoinconsensus::verify(script.as_bytes(), 0, &serialize(to_sign), 0) {
+		Ok(()) => Ok(()),
+		Err(_) => Err(Error::InvalidMessageSignature("invalid signature")),
+	}
+}
+
+#[cfg(not(feature = "bitcoinconsensus"))]
+fn verify_input(address: &Address, to_sign: &Transaction) -> Result<()> {
+	use bitcoin::util::bip143::SighashComponents;
+	use bitcoin::PublicKey;
+	use secp256k1;
+
+	let input = &to_sign.input[0];
+	if input.witness.len() != 2 {
+		return Err(Error::InvalidMessageSignature("unsupported address type"));
+	}
+	let pubkey = match PublicKey::from_slice(&input.witness[1]) {
+		Ok(pk) => pk,
+		Err(_) => return Err(Error::InvalidMessageSignature("invalid public key")),
+	};
+
+	// Only P2WPKH and P2SH-P2WPKH are supported.
+	let script_pubkey = address.script_pubkey();
+	let p2wpkh = Address::p2wpkh(&pubkey, address.network);
+	if input.script_sig.is_empty() {
+		if p2wpkh.script_pubkey() != script_pubkey {
+			return Err(Error::InvalidMessageSignature("wrong public key"));
+		}
+	} else {
+		let script_sig = Builder::new().push_slice(p2wpkh.script_pubkey().as_bytes()).into_script();
+		let p2sh = Address::p2shwpkh(&pubkey, address.network);
+		if input.script_sig != script_sig || p2sh.script_pubkey() != script_pubkey {
+			return Err(Error::InvalidMessageSignature("wrong public key"));
+		}
+	}
+
+	// The last byte of the signature is the sighash type, only SIGHASH_ALL is allowed.
+	let sig = &input.witness[0];
+	if sig.last() != Some(&0x01) {
+		return Err(Error::InvalidMessageSignature("unsupported sighash type"));
+	}
+	let sig = match secp256k1::Signature::from_der(&sig[..sig.len() - 1]) {
+		Ok(sig) => sig,
+		Err(_) => return Err(Error::InvalidMessageSignature("invalid signature")),
+	};
+	let script_code = Address::p2pkh(&pubkey, address.network).script_pubkey();
+	let sighash = SighashComponents::new(to_sign).sighash_all(input, &script_code, 0);
+	let msg = secp256k1::Message::from_slice(&sighash[..]).expect("32 bytes");
+	match ::SECP.verify(&msg, &sig, &pubkey.key) {
+		Ok(()) => Ok(()),
+		Err(_) => Err(Error::InvalidMessageSignature("invalid signature")),
+	}
+}
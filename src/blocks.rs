@@ -0,0 +1,111 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! The hashes of the blocks processed by the wallet.
+
+use std::collections::{BTreeMap, HashMap};
+
+use bitcoin_hashes::sha256d;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The hashes of the processed blocks by height, with an index to look up
+/// the height of a block by its hash.
+///
+/// It is serialized as the map of hashes by height.
+#[derive(Debug, Clone, Default)]
+pub struct BlockHashes {
+	hashes: BTreeMap<u32, sha256d::Hash>,
+	/// The heights of the blocks by their hash.
+	heights: HashMap<sha256d::Hash, u32>,
+}
+
+impl BlockHashes {
+	pub fn new() -> BlockHashes {
+		BlockHashes::default()
+	}
+
+	pub fn len(&self) -> usize {
+		self.hashes.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.hashes.is_empty()
+	}
+
+	pub fn get(&self, height: u32) -> Option<&sha256d::Hash> {
+		self.hashes.get(&height)
+	}
+
+	/// The height of the block with the given hash.
+	pub fn height(&self, hash: &sha256d::Hash) -> Option<u32> {
+		self.heights.get(hash).cloned()
+	}
+
+	pub fn contains_hash(&self, hash: &sha256d::Hash) -> bool {
+		self.heights.contains_key(hash)
+	}
+
+	/// The lowest height of which the hash is known.
+	pub fn first_height(&self) -> Option<u32> {
+		self.hashes.keys().next().cloned()
+	}
+
+	/// Set the hash of the block at the height, replacing the known one.
+	pub fn insert(&mut self, height: u32, hash: sha256d::Hash) {
+		if let Some(old) = self.hashes.insert(height, hash) {
+			self.heights.remove(&old);
+		}
+		self.heights.insert(hash, height);
+	}
+
+	pub fn remove(&mut self, height: u32) -> Option<sha256d::Hash> {
+		let hash = self.hashes.remove(&height)?;
+		self.heights.remove(&hash);
+		Some(hash)
+	}
+
+	/// Forget the hashes of the blocks below the height.
+	pub fn prune_below(&mut self, height: u32) {
+		let kept = self.hashes.split_off(&height);
+		for hash in self.hashes.values() {
+			self.heights.remove(hash);
+		}
+		self.hashes = kept;
+	}
+
+	/// Iterate over the heights and hashes of the blocks with at least the
+	/// given height, ordered by height.
+	pub fn iter_from_height<'a>(
+		&'a self,
+		height: u32,
+	) -> impl Iterator<Item = (u32, sha256d::Hash)> + 'a {
+		self.hashes.range(height..).map(|(h, hash)| (*h, *hash))
+	}
+}
+
+impl Serialize for BlockHashes {
+	fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+		self.hashes.serialize(s)
+	}
+}
+
+impl<'de> Deserialize<'de> for BlockHashes {
+	fn deserialize<D: Deserializer<'de>>(d: D) -> Result<BlockHashes, D::Error> {
+		let mut blocks = BlockHashes::new();
+		for (height, hash) in BTreeMap::<u32, sha256d::Hash>::deserialize(d)? {
+			blocks.insert(height, hash);
+		}
+		Ok(blocks)
+	}
+}
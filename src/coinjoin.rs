@@ -0,0 +1,165 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Taking part in equal-output coinjoins.
+//!
+//! The flow is as follows:
+//! - create our contribution using [create_contribution],
+//! - send the inputs and outputs of [CoinjoinContribution::psbt] to the
+//!   coordinator,
+//! - check the coordinator's tx using [CoinjoinContribution::verify],
+//! - sign the returned PSBT and send the signatures to the coordinator.
+//!
+//! Our inputs are held by a placeholder tx that is committed to the wallet
+//! while the coinjoin is in progress. It's replaced by the coinjoin tx when
+//! it's verified, or dropped with [CoinjoinContribution::abort]. The placeholder
+//! tx should never be broadcast.
+//! The wallet doesn't do any networking itself.
+
+use bitcoin::util::psbt;
+use bitcoin::TxOut;
+
+use amount::{Amount, FeeRate};
+use error::{Error, Result};
+use wallet::Wallet;
+
+/// Our part of a coinjoin: inputs of the wallet, an output of the
+/// denomination to a new receive address and change.
+#[derive(Debug, Clone)]
+pub struct CoinjoinContribution {
+	/// The placeholder tx with our inputs and outputs.
+	psbt: psbt::PartiallySignedTransaction,
+	/// The indices of the change outputs in the placeholder tx.
+	change_indices: Vec<usize>,
+	/// The total value of our inputs.
+	total_in: u64,
+	max_fee: u64,
+}
+
+/// Create our contribution to a coinjoin with outputs of the [denomination].
+/// Inputs are selected to pay for the denomination and for our inputs and
+/// outputs at the feerate.
+///
+/// The coordinator may take more from our change, like for a coordinator
+/// fee, but all in all we pay at most [max_fee].
+pub fn create_contribution(
+	wallet: &mut Wallet,
+	denomination: Amount,
+	fee_rate: FeeRate,
+	max_fee: Amount,
+) -> Result<CoinjoinContribution> {
+	let max_fee = max_fee.as_sat();
+	let output = TxOut {
+		value: denomination.as_sat(),
+		script_pubkey: wallet.new_receive_address()?.script_pubkey(),
+	};
+	let created = wallet.create_transaction_with_feerate(vec![output], vec![], fee_rate)?;
+	let fee = created.fee.as_sat();
+	if fee > max_fee {
+		wallet.drop_pending_transaction(created.txid());
+		return Err(Error::FeeTooHigh(fee));
+	}
+	let total_out: u64 = created.psbt.global.unsigned_tx.output.iter().map(|o| o.value).sum();
+	Ok(CoinjoinContribution {
+		psbt: created.psbt,
+		change_indices: created.change.map(|c| c.output_indices).unwrap_or_default(),
+		total_in: total_out + fee,
+		max_fee: max_fee,
+	})
+}
+
+impl CoinjoinContribution {
+	/// The placeholder tx with our inputs and outputs and their PSBT data.
+	pub fn psbt(&self) -> &psbt::PartiallySignedTransaction {
+		&self.psbt
+	}
+
+	/// Check the coinjoin tx of the coordinator and replace the placeholder tx
+	/// in the wallet with it. Returns the PSBT of the coinjoin with the data
+	/// of our inputs and outputs, ready to sign.
+	///
+	/// The coinjoin must spend all our inputs, have our output of the
+	/// denomination and at least one more of it. Our change outputs may
+	/// have a lower value, as long as we don't pay more than the maximum fee.
+	pub fn verify(
+		&self,
+		wallet: &mut Wallet,
+		coinjoin: &psbt::PartiallySignedTransaction,
+	) -> Result<psbt::PartiallySignedTransaction> {
+		let invalid = |reason| Error::InvalidCoinjoin(reason);
+		let placeholder = &self.psbt.global.unsigned_tx;
+		let tx = &coinjoin.global.unsigned_tx;
+		if coinjoin.inputs.len() != tx.input.len() || coinjoin.outputs.len() != tx.output.len() {
+			return Err(invalid("invalid PSBT"));
+		}
+		let mut psbt = coinjoin.clone();
+
+		// Restore the data of our inputs.
+		for (idx, our_input) in placeholder.input.iter().enumerate() {
+			let mut positions = tx.input.iter().enumerate().filter(|(_, i)| {
+				i.previous_output == our_input.previous_output
+			});
+			match (positions.next(), positions.next()) {
+				(Some((pos, _)), None) => psbt.inputs[pos] = self.psbt.inputs[idx].clone(),
+				(None, _) => return Err(invalid("our inputs were removed")),
+				(Some(_), Some(_)) => return Err(invalid("an input is spent twice")),
+			}
+		}
+
+		// Find our outputs, every output can only be matched once.
+		let mut matched = vec![false; tx.output.len()];
+		let mut total_out = 0;
+		let mut denomination = None;
+		for (idx, our_output) in placeholder.output.iter().enumerate() {
+			let is_change = self.change_indices.contains(&idx);
+			if !is_change {
+				denomination = Some(our_output.value);
+			}
+			let found = (0..tx.output.len()).find(|pos| {
+				let output = &tx.output[*pos];
+				!matched[*pos]
+					&& output.script_pubkey == our_output.script_pubkey
+					&& (output.value == our_output.value
+						|| is_change && output.value < our_output.value)
+			});
+			match found {
+				Some(pos) => {
+					matched[pos] = true;
+					total_out += tx.output[pos].value;
+					psbt.outputs[pos] = self.psbt.outputs[idx].clone();
+				}
+				None if is_change => return Err(invalid("our change output is missing")),
+				None => return Err(invalid("our output is missing")),
+			}
+		}
+		let denomination = denomination.expect("contribution has an output");
+		if tx.output.iter().filter(|o| o.value == denomination).count() < 2 {
+			return Err(invalid("no other outputs of the denomination"));
+		}
+
+		let fee = self.total_in - total_out;
+		if fee > self.max_fee {
+			return Err(Error::FeeTooHigh(fee));
+		}
+
+		wallet.drop_pending_transaction(placeholder.txid());
+		wallet.commit_transaction(tx.clone());
+		Ok(psbt)
+	}
+
+	/// Drop the placeholder tx, so that our inputs can be used again.
+	pub fn abort(self, wallet: &mut Wallet) {
+		wallet.drop_pending_transaction(self.psbt.global.unsigned_tx.txid());
+	}
+}
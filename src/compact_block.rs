@@ -0,0 +1,238 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Reconstruction of BIP-152 compact blocks.
+//!
+//! A compact block is reconstructed from the txs that are already known,
+//! like the ones in the mempool. When txs are missing, their indices can be
+//! requested from the peer using a `getblocktxn` message, after which the
+//! reconstruction is tried again with the received txs added.
+
+use std::collections::HashMap;
+
+use bitcoin::consensus::encode::serialize;
+use bitcoin::util::hash::bitcoin_merkle_root;
+use bitcoin::{BlockHeader, Transaction};
+use bitcoin_hashes::{sha256, sha256d, Hash};
+use byteorder::{ByteOrder, LittleEndian};
+
+use error::{Error, Result};
+
+/// The contents of a `cmpctblock` message.
+#[derive(Debug, Clone)]
+pub struct CompactBlock {
+	pub header: BlockHeader,
+	pub nonce: u64,
+	/// The 6-byte short ids of the txs that are not prefilled.
+	pub short_ids: Vec<u64>,
+	/// The prefilled txs with their absolute index in the block, so not
+	/// differentially encoded like in the message.
+	pub prefilled: Vec<(usize, Transaction)>,
+	/// Whether the short ids are derived from the wtxids as in compact block
+	/// version 2, rather than from the txids.
+	pub use_wtxid: bool,
+}
+
+/// The result of reconstructing a compact block.
+#[derive(Debug, Clone)]
+pub enum Reconstruction {
+	/// All txs of the block, in order.
+	Complete(Vec<Transaction>),
+	/// The indices of the txs that are missing.
+	Missing(Vec<usize>),
+}
+
+/// SipHash-2-4 of the data with the given key.
+pub fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+	let mut v = [
+		k0 ^ 0x736f6d6570736575,
+		k1 ^ 0x646f72616e646f6d,
+		k0 ^ 0x6c7967656e657261,
+		k1 ^ 0x7465646279746573,
+	];
+	fn round(v: &mut [u64; 4]) {
+		v[0] = v[0].wrapping_add(v[1]);
+		v[1] = v[1].rotate_left(13) ^ v[0];
+		v[0] = v[0].rotate_left(32);
+		v[2] = v[2].wrapping_add(v[3]);
+		v[3] = v[3].rotate_left(16) ^ v[2];
+		v[0] = v[0].wrapping_add(v[3]);
+		v[3] = v[3].rotate_left(21) ^ v[0];
+		v[2] = v[2].wrapping_add(v[1]);
+		v[1] = v[1].rotate_left(17) ^ v[2];
+		v[2] = v[2].rotate_left(32);
+	}
+
+	let mut last = (data.len() as u64) << 56;
+	for chunk in data.chunks(8) {
+		if chunk.len() < 8 {
+			for (i, b) in chunk.iter().enumerate() {
+				last |= (*b as u64) << (8 * i);
+			}
+			break;
+		}
+		let m = LittleEndian::read_u64(chunk);
+		v[3] ^= m;
+		round(&mut v);
+		round(&mut v);
+		v[0] ^= m;
+	}
+	v[3] ^= last;
+	round(&mut v);
+	round(&mut v);
+	v[0] ^= last;
+	v[2] ^= 0xff;
+	for _ in 0..4 {
+		round(&mut v);
+	}
+	v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+impl CompactBlock {
+	/// The SipHash key for the short ids of this block.
+	fn short_id_key(&self) -> (u64, u64) {
+		let mut data = serialize(&self.header);
+		let mut nonce = [0; 8];
+		LittleEndian::write_u64(&mut nonce, self.nonce);
+		data.extend_from_slice(&nonce);
+		let hash = sha256::Hash::hash(&data);
+		(LittleEndian::read_u64(&hash[0..8]), LittleEndian::read_u64(&hash[8..16]))
+	}
+
+	/// The short id of the tx in this block.
+	pub fn short_id(&self, tx: &Transaction) -> u64 {
+		let (k0, k1) = self.short_id_key();
+		self.short_id_with_key(k0, k1, tx)
+	}
+
+	fn short_id_with_key(&self, k0: u64, k1: u64, tx: &Transaction) -> u64 {
+		let id = match self.use_wtxid {
+			true => sha256d::Hash::hash(&serialize(tx)),
+			false => tx.txid(),
+		};
+		siphash24(k0, k1, &id[..]) & 0xffff_ffff_ffff
+	}
+
+	/// The number of txs in the block.
+	pub fn nb_txs(&self) -> usize {
+		self.short_ids.len() + self.prefilled.len()
+	}
+
+	/// Reconstruct the txs of the block from the prefilled txs and the given
+	/// known txs.
+	pub fn reconstruct<'a, I>(&self, known: I) -> Result<Reconstruction>
+	where
+		I: IntoIterator<Item = &'a Transaction>,
+	{
+		let invalid = |reason| Error::InvalidCompactBlock(reason);
+		let (k0, k1) = self.short_id_key();
+		let mut by_short_id = HashMap::new();
+		for tx in known {
+			by_short_id.insert(self.short_id_with_key(k0, k1, tx), tx);
+		}
+
+		let mut txs: Vec<Option<Transaction>> = vec![None; self.nb_txs()];
+		for (idx, tx) in &self.prefilled {
+			match txs.get_mut(*idx) {
+				Some(slot) if slot.is_none() => *slot = Some(tx.clone()),
+				_ => return Err(invalid("invalid prefilled tx index")),
+			}
+		}
+		let mut short_ids = self.short_ids.iter();
+		let mut missing = Vec::new();
+		for (idx, slot) in txs.iter_mut().enumerate().filter(|(_, s)| s.is_none()) {
+			let short_id = short_ids.next().expect("nb of empty slots equals nb of short ids");
+			match by_short_id.get(short_id) {
+				Some(tx) => *slot = Some((*tx).clone()),
+				None => missing.push(idx),
+			}
+		}
+		if !missing.is_empty() {
+			return Ok(Reconstruction::Missing(missing));
+		}
+
+		let txs: Vec<Transaction> = txs.into_iter().map(|t| t.unwrap()).collect();
+		let merkle_root = bitcoin_merkle_root(txs.iter().map(|t| t.txid()).collect());
+		if merkle_root != self.header.merkle_root {
+			// Most likely a short id collision, the full block is needed.
+			return Err(invalid("merkle root mismatch"));
+		}
+		Ok(Reconstruction::Complete(txs))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::Network;
+
+	/// The SipHash-2-4 of the bytes 0, 1, ..., n-1 for n up to 63 with the
+	/// key 0, 1, ..., 15, from the appendix of the SipHash paper.
+	const SIPHASH_VECTORS: [u64; 64] = [
+		0x726fdb47dd0e0e31, 0x74f839c593dc67fd, 0x0d6c8009d9a94f5a, 0x85676696d7fb7e2d,
+		0xcf2794e0277187b7, 0x18765564cd99a68d, 0xcbc9466e58fee3ce, 0xab0200f58b01d137,
+		0x93f5f5799a932462, 0x9e0082df0ba9e4b0, 0x7a5dbbc594ddb9f3, 0xf4b32f46226bada7,
+		0x751e8fbc860ee5fb, 0x14ea5627c0843d90, 0xf723ca908e7af2ee, 0xa129ca6149be45e5,
+		0x3f2acc7f57c29bdb, 0x699ae9f52cbe4794, 0x4bc1b3f0968dd39c, 0xbb6dc91da77961bd,
+		0xbed65cf21aa2ee98, 0xd0f2cbb02e3b67c7, 0x93536795e3a33e88, 0xa80c038ccd5ccec8,
+		0xb8ad50c6f649af94, 0xbce192de8a85b8ea, 0x17d835b85bbb15f3, 0x2f2e6163076bcfad,
+		0xde4daaaca71dc9a5, 0xa6a2506687956571, 0xad87a3535c49ef28, 0x32d892fad841c342,
+		0x7127512f72f27cce, 0xa7f32346f95978e3, 0x12e0b01abb051238, 0x15e034d40fa197ae,
+		0x314dffbe0815a3b4, 0x027990f029623981, 0xcadcd4e59ef40c4d, 0x9abfd8766a33735c,
+		0x0e3ea96b5304a7d0, 0xad0c42d6fc585992, 0x187306c89bc215a9, 0xd4a60abcf3792b95,
+		0xf935451de4f21df2, 0xa9538f0419755787, 0xdb9acddff56ca510, 0xd06c98cd5c0975eb,
+		0xe612a3cb9ecba951, 0xc766e62cfcadaf96, 0xee64435a9752fe72, 0xa192d576b245165a,
+		0x0a8787bf8ecb74b2, 0x81b3e73d20b49b6f, 0x7fa8220ba3b2ecea, 0x245731c13ca42499,
+		0xb78dbfaf3a8d83bd, 0xea1ad565322a1a0b, 0x60e61c23a3795013, 0x6606d7e446282b93,
+		0x6ca4ecb15c5f91e1, 0x9f626da15c9625f3, 0xe51b38608ef25f57, 0x958a324ceb064572,
+	];
+
+	#[test]
+	fn siphash_vectors() {
+		let (k0, k1) = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+		let data: Vec<u8> = (0..64).collect();
+		for (len, expected) in SIPHASH_VECTORS.iter().enumerate() {
+			assert_eq!(siphash24(k0, k1, &data[..len]), *expected);
+		}
+	}
+
+	#[test]
+	fn short_id_vector() {
+		let genesis = genesis_block(Network::Bitcoin);
+		let coinbase = genesis.txdata[0].clone();
+		let mut block = CompactBlock {
+			header: genesis.header,
+			nonce: 0x0102030405060708,
+			short_ids: vec![0xd3dad4322e88],
+			prefilled: Vec::new(),
+			use_wtxid: false,
+		};
+		assert_eq!(block.short_id_key(), (0x9604c091d994cfca, 0x82da4839b1005eb7));
+		assert_eq!(block.short_id(&coinbase), 0xd3dad4322e88);
+		match block.reconstruct(&[coinbase.clone()]).unwrap() {
+			Reconstruction::Complete(txs) => assert_eq!(txs, vec![coinbase.clone()]),
+			Reconstruction::Missing(_) => panic!("coinbase is known"),
+		}
+		match block.reconstruct(::std::iter::empty()).unwrap() {
+			Reconstruction::Complete(_) => panic!("coinbase is unknown"),
+			Reconstruction::Missing(missing) => assert_eq!(missing, vec![0]),
+		}
+
+		// The coinbase has no witness, so its wtxid is its txid.
+		block.use_wtxid = true;
+		assert_eq!(block.short_id(&coinbase), 0xd3dad4322e88);
+	}
+}
@@ -1,8 +1,341 @@
+use std::cmp;
 
-use bitcoin::Network;
+use bitcoin::{Network, Script};
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use error::{Error, Result};
+use fee;
+
+/// The number of blocks after which coinbase outputs can be spent, on all chains.
+pub const COINBASE_MATURITY: u32 = 100;
+
+/// The chain the wallet is used on. Signet has no [Network] variant, it uses
+/// the address and key formats of testnet, so its network is [Network::Testnet].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Chain {
+	Bitcoin,
+	Testnet,
+	Signet,
+	Regtest,
+}
+
+impl Chain {
+	/// The network of the addresses and keys of the chain.
+	pub fn network(self) -> Network {
+		match self {
+			Chain::Bitcoin => Network::Bitcoin,
+			Chain::Testnet | Chain::Signet => Network::Testnet,
+			Chain::Regtest => Network::Regtest,
+		}
+	}
+
+	/// The magic bytes of P2P messages. Custom signets with another challenge
+	/// have another magic, use the one of the node for those.
+	pub fn magic(self) -> u32 {
+		match self {
+			Chain::Signet => 0x40CF030A,
+			_ => self.network().magic(),
+		}
+	}
+}
+
+impl From<Network> for Chain {
+	fn from(network: Network) -> Chain {
+		match network {
+			Network::Bitcoin => Chain::Bitcoin,
+			Network::Testnet => Chain::Testnet,
+			Network::Regtest => Chain::Regtest,
+		}
+	}
+}
+
+/// Policy to split the change of a transaction over multiple outputs
+/// with sizes resembling those of the payments, so that the change
+/// is harder to identify.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeSplitPolicy {
+	/// The maximum number of change outputs to create.
+	pub max_outputs: usize,
+	/// No change output will be created with a value below this value.
+	pub min_output_value: u64,
+	/// Also split the change of txs without payments to resemble, like
+	/// consolidations, into outputs with round values that look like
+	/// payments to self.
+	#[serde(default)]
+	pub round_decoys: bool,
+}
+
+/// Policy to round change amounts down to a random number of trailing zeros,
+/// so that the change is not identifiable by being more precise than the
+/// payments. The rounded off amount is added to the fee.
+///
+/// Only applies to txs with a single change output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeRoundingPolicy {
+	/// The maximum amount that is added to the fee by rounding.
+	pub max_extra_fee: u64,
+}
+
+/// Policy for choosing the script type of change outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeTypePolicy {
+	/// Always use the wallet's default address type.
+	WalletDefault,
+	/// Use the script type of the payment outputs if they all have the same
+	/// type and the wallet can derive it, the default type otherwise. P2SH
+	/// payments only match when they pay to our own P2SH-P2WPKH scripts, as
+	/// others can wrap any script.
+	MatchPayments,
+}
+
+impl Default for ChangeTypePolicy {
+	fn default() -> ChangeTypePolicy {
+		ChangeTypePolicy::WalletDefault
+	}
+}
+
+/// What to do when a batch payment pays the same address more than once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DuplicateRecipientPolicy {
+	/// Fail to create the tx.
+	Reject,
+	/// Create a single output with the sum of the amounts.
+	Merge,
+}
+
+impl Default for DuplicateRecipientPolicy {
+	fn default() -> DuplicateRecipientPolicy {
+		DuplicateRecipientPolicy::Reject
+	}
+}
+
+/// Caps on the number of receive addresses the wallet hands out.
+/// Change addresses are never capped, but without a separate change keychain
+/// they count towards the caps of the receive keychain.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct AddressCaps {
+	/// The maximum number of receive addresses, not counting branches.
+	pub max_external: Option<u32>,
+	/// The maximum number of receive addresses including those of all branches.
+	pub max_total: Option<u32>,
+	/// An event is emitted when this many addresses or fewer remain under a cap.
+	pub warn_remaining: u32,
+}
+
+/// When to give up on pending txs that don't confirm.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct PendingExpiryPolicy {
+	/// Drop pending txs that didn't confirm within this many blocks.
+	pub max_blocks: Option<u32>,
+	/// Drop pending txs that didn't confirm within this many seconds.
+	pub max_seconds: Option<u64>,
+}
+
+/// Guards against pathological block data from untrusted backends.
+/// Blocks violating them are rejected before any of their txs are processed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct BlockLimits {
+	/// The maximum number of txs in a block. Valid blocks can't have more
+	/// than about 16,700 txs.
+	pub max_txs: Option<usize>,
+	/// The maximum size in bytes of the scripts in the txs of a block.
+	pub max_script_size: Option<usize>,
+	/// Reject blocks that contain the same tx more than once.
+	pub reject_duplicate_txids: bool,
+}
+
+impl Default for BlockLimits {
+	fn default() -> BlockLimits {
+		BlockLimits {
+			max_txs: Some(25_000),
+			max_script_size: None,
+			reject_duplicate_txids: true,
+		}
+	}
+}
+
+/// The default dust relay fee in satoshis per 1000 virtual bytes, as in Bitcoin Core.
+pub const DEFAULT_DUST_RELAY_FEE: u64 = 3000;
+
+fn default_dust_relay_fee() -> u64 {
+	DEFAULT_DUST_RELAY_FEE
+}
+
+/// The default maximum fee of created txs, 0.1 BTC as in Bitcoin Core.
+pub const DEFAULT_MAX_ABSOLUTE_FEE: u64 = 10_000_000;
+
+fn default_max_absolute_fee() -> Option<u64> {
+	Some(DEFAULT_MAX_ABSOLUTE_FEE)
+}
+
+/// The default maximum feerate of created txs in satoshis per virtual byte,
+/// 0.1 BTC per 1000 virtual bytes as in Bitcoin Core.
+pub const DEFAULT_MAX_FEE_RATE: u64 = 10_000;
+
+fn default_max_fee_rate() -> Option<u64> {
+	Some(DEFAULT_MAX_FEE_RATE)
+}
+
+fn default_min_spend_confirmations() -> u32 {
+	1
+}
+
+/// The default number of blocks after which a block is considered safe from
+/// reorgs, about a week of blocks.
+pub const DEFAULT_REORG_SAFETY_DEPTH: u32 = 1008;
+
+fn default_reorg_safety_depth() -> u32 {
+	DEFAULT_REORG_SAFETY_DEPTH
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WalletConfig {
+	/// The network of the addresses and keys, [Network::Testnet] on signet.
 	pub network: Network,
+	/// The wallet is used on signet, see [Chain].
+	#[serde(default)]
+	pub signet: bool,
+	/// When set, change is split over multiple outputs.
+	#[serde(default)]
+	pub change_split: Option<ChangeSplitPolicy>,
+	/// When set, change amounts are rounded.
+	#[serde(default)]
+	pub change_rounding: Option<ChangeRoundingPolicy>,
+	/// How to pick the script type of change outputs.
+	#[serde(default)]
+	pub change_type: ChangeTypePolicy,
+	/// The dust relay fee in satoshis per 1000 virtual bytes, used to determine
+	/// which outputs are dust.
+	#[serde(default = "default_dust_relay_fee")]
+	pub dust_relay_fee: u64,
+	/// No outputs with a value below this value are created, even when not dust.
+	#[serde(default)]
+	pub min_output_value: u64,
+	/// The maximum weight of created txs. Divide by 4 for the virtual size.
+	#[serde(default)]
+	pub max_tx_weight: Option<u64>,
+	/// Balance thresholds in satoshis. An event is emitted every time the
+	/// spendable balance crosses one of them.
+	#[serde(default)]
+	pub balance_thresholds: Vec<u64>,
+	/// How to handle batch payments paying the same address more than once.
+	#[serde(default)]
+	pub duplicate_recipients: DuplicateRecipientPolicy,
+	/// Caps on the number of addresses handed out.
+	#[serde(default)]
+	pub address_caps: AddressCaps,
+	/// When to drop pending txs that don't confirm.
+	#[serde(default)]
+	pub pending_expiry: PendingExpiryPolicy,
+	/// Guards against pathological block data.
+	#[serde(default)]
+	pub block_limits: BlockLimits,
+	/// When selecting coins, spend all UTXOs paying the same script together,
+	/// so that reused addresses don't link multiple txs.
+	#[serde(default)]
+	pub avoid_partial_spends: bool,
+	/// The feerate in satoshis per virtual byte expected to be paid in the long
+	/// term. When set, coin selection at a feerate prefers the input set with
+	/// the least waste: more inputs below this feerate, fewer above it.
+	#[serde(default)]
+	pub long_term_fee_rate: Option<u64>,
+	/// Creating a tx paying a higher fee in satoshis fails.
+	#[serde(default = "default_max_absolute_fee")]
+	pub max_absolute_fee: Option<u64>,
+	/// Creating a tx paying a higher feerate in satoshis per virtual byte fails.
+	#[serde(default = "default_max_fee_rate")]
+	pub max_fee_rate: Option<u64>,
+	/// Coin selection doesn't pick UTXOs with fewer confirmations, unless they
+	/// are the change of our own txs. Given inputs are always spent.
+	#[serde(default = "default_min_spend_confirmations")]
+	pub min_spend_confirmations: u32,
+	/// Let coin selection pick the change of our own pending txs, which is
+	/// trusted. Unconfirmed payments from others are never spent.
+	#[serde(default)]
+	pub spend_unconfirmed: bool,
+	/// Blocks buried this deep are assumed never to be reorganized. Only the
+	/// hashes of the blocks above this depth are kept, so deeper blocks can't
+	/// be disconnected.
+	#[serde(default = "default_reorg_safety_depth")]
+	pub reorg_safety_depth: u32,
+	/// The number of addresses after the last handed out one that are watched
+	/// in each keychain, to find payments to addresses handed out by another
+	/// instance of the wallet, like a restored backup. A payment to one of
+	/// them marks it and all before it as handed out.
+	#[serde(default)]
+	pub gap_limit: u32,
+}
+
+impl WalletConfig {
+	/// Create a config for the given network with all policies set to their defaults.
+	pub fn new(network: Network) -> WalletConfig {
+		WalletConfig::for_chain(Chain::from(network))
+	}
+
+	/// Create a config for the given chain with all policies set to their defaults.
+	pub fn for_chain(chain: Chain) -> WalletConfig {
+		let network = chain.network();
+		WalletConfig {
+			network: network,
+			signet: chain == Chain::Signet,
+			change_split: None,
+			change_rounding: None,
+			change_type: ChangeTypePolicy::WalletDefault,
+			dust_relay_fee: DEFAULT_DUST_RELAY_FEE,
+			min_output_value: match chain {
+				// On mainnet, avoid outputs that are uneconomical to spend.
+				Chain::Bitcoin => 546,
+				// Test coins are worthless, only the dust limit applies.
+				Chain::Testnet | Chain::Signet | Chain::Regtest => 0,
+			},
+			max_tx_weight: None,
+			balance_thresholds: Vec::new(),
+			duplicate_recipients: DuplicateRecipientPolicy::Reject,
+			address_caps: AddressCaps::default(),
+			pending_expiry: PendingExpiryPolicy::default(),
+			block_limits: BlockLimits::default(),
+			avoid_partial_spends: false,
+			long_term_fee_rate: None,
+			max_absolute_fee: Some(DEFAULT_MAX_ABSOLUTE_FEE),
+			max_fee_rate: Some(DEFAULT_MAX_FEE_RATE),
+			min_spend_confirmations: 1,
+			spend_unconfirmed: false,
+			reorg_safety_depth: DEFAULT_REORG_SAFETY_DEPTH,
+			gap_limit: 0,
+		}
+	}
+
+	/// The chain the wallet is used on.
+	pub fn chain(&self) -> Chain {
+		match self.network {
+			Network::Testnet if self.signet => Chain::Signet,
+			network => Chain::from(network),
+		}
+	}
+
+	/// Check that the policies are sensible.
+	pub fn validate(&self) -> Result<()> {
+		if self.signet && self.network != Network::Testnet {
+			return Err(Error::InvalidConfig("signet uses the testnet network"));
+		}
+		if let Some(ref policy) = self.change_split {
+			if policy.max_outputs == 0 {
+				return Err(Error::InvalidConfig("change split needs at least one output"));
+			}
+		}
+		if let Some(max_weight) = self.max_tx_weight {
+			// Any tx needs room for at least one input and one P2WSH-sized output.
+			let min_weight = fee::TX_BASE_WEIGHT + fee::P2WPKH_INPUT_WEIGHT + 43 * 4;
+			if max_weight < min_weight {
+				return Err(Error::InvalidConfig("maximum tx weight is too low"));
+			}
+		}
+		Ok(())
+	}
+
+	/// The minimum value of an output with the given script, taking into account
+	/// both the dust threshold and the minimum output value.
+	pub fn min_value_for_script(&self, script_pubkey: &Script) -> u64 {
+		cmp::max(fee::dust_threshold(script_pubkey, self.dust_relay_fee), self.min_output_value)
+	}
 }
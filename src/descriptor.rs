@@ -0,0 +1,118 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Output descriptors and exports to Bitcoin Core.
+//!
+//! Only the small subset of descriptors the wallet uses itself is supported.
+
+use serde::{Serialize, Serializer};
+
+/// The number of indices after the last used one that are included in exports.
+/// This equals the default keypool size of Bitcoin Core.
+pub const CORE_IMPORT_LOOKAHEAD: u32 = 1000;
+
+const INPUT_CHARSET: &'static str = concat!(
+	"0123456789()[],'/*abcdefgh@:$%{}",
+	"IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~",
+	"ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ",
+);
+const CHECKSUM_CHARSET: &'static [u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn polymod(c: u64, val: u64) -> u64 {
+	let c0 = c >> 35;
+	let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+	if c0 & 1 != 0 {
+		c ^= 0xf5dee51989;
+	}
+	if c0 & 2 != 0 {
+		c ^= 0xa9fdca3312;
+	}
+	if c0 & 4 != 0 {
+		c ^= 0x1bab10e32d;
+	}
+	if c0 & 8 != 0 {
+		c ^= 0x3706b1677a;
+	}
+	if c0 & 16 != 0 {
+		c ^= 0x644d626ffd;
+	}
+	c
+}
+
+/// Calculate the BIP-380 checksum of the descriptor.
+/// Returns [None] if the descriptor contains invalid characters.
+pub fn checksum(desc: &str) -> Option<String> {
+	let mut c = 1;
+	let mut cls = 0;
+	let mut cls_count = 0;
+	for ch in desc.chars() {
+		let pos = INPUT_CHARSET.find(ch)? as u64;
+		c = polymod(c, pos & 31);
+		cls = cls * 3 + (pos >> 5);
+		cls_count += 1;
+		if cls_count == 3 {
+			c = polymod(c, cls);
+			cls = 0;
+			cls_count = 0;
+		}
+	}
+	if cls_count > 0 {
+		c = polymod(c, cls);
+	}
+	for _ in 0..8 {
+		c = polymod(c, 0);
+	}
+	c ^= 1;
+
+	let mut ret = String::with_capacity(8);
+	for j in 0..8 {
+		ret.push(CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char);
+	}
+	Some(ret)
+}
+
+/// Append the checksum to the descriptor.
+/// Returns [None] if the descriptor contains invalid characters.
+pub fn with_checksum(desc: &str) -> Option<String> {
+	checksum(desc).map(|c| format!("{}#{}", desc, c))
+}
+
+/// The timestamp from which Bitcoin Core should rescan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoreTimestamp {
+	/// Don't rescan.
+	Now,
+	/// A UNIX timestamp.
+	Time(u32),
+}
+
+impl Serialize for CoreTimestamp {
+	fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+		match *self {
+			CoreTimestamp::Now => s.serialize_str("now"),
+			CoreTimestamp::Time(t) => s.serialize_u32(t),
+		}
+	}
+}
+
+/// A request for the importdescriptors and importmulti RPCs of Bitcoin Core.
+#[derive(Debug, Clone, Serialize)]
+pub struct CoreImportRequest {
+	pub desc: String,
+	/// The first and last index to import, both inclusive.
+	pub range: (u32, u32),
+	pub timestamp: CoreTimestamp,
+	/// Whether the descriptor is used for change.
+	pub internal: bool,
+}
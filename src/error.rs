@@ -2,6 +2,7 @@
 use std::{fmt, error, result};
 
 use bitcoin::util::bip32;
+use bitcoin_hashes::sha256d;
 use secp256k1;
 
 
@@ -14,6 +15,86 @@ pub enum Error {
 	DuplicateUtxo,
 	InsufficientFunds,
 	WalletNotFullyInitialized,
+	TxLimitsExceeded,
+	InvalidLabelRecord(usize),
+	DustOutput,
+	WalletModified,
+	/// The tx would exceed the maximum weight.
+	/// Contains the amount that could be sent within the maximum weight.
+	TxTooLarge(u64),
+	ImportScriptMismatch,
+	/// Contains the reason the proof is invalid.
+	InvalidReserveProof(&'static str),
+	ScriptNotInWallet,
+	UnsupportedAddressType,
+	/// Contains the reason the signature is invalid.
+	InvalidMessageSignature(&'static str),
+	/// Contains the reason the URI is invalid.
+	InvalidUri(&'static str),
+	WrongNetwork,
+	DuplicateRecipient,
+	NoRecipients,
+	AddressCapReached,
+	/// Contains the reason the config is invalid.
+	InvalidConfig(&'static str),
+	/// Contains the reason the proposal was rejected.
+	InvalidPayjoinProposal(&'static str),
+	TxNotInHistory,
+	TxNotPending,
+	BlockTooManyTxs,
+	ScriptTooLarge,
+	/// Contains the txid that occurs more than once.
+	DuplicateTxid(sha256d::Hash),
+	InvalidMerkleProof,
+	UnknownBlock,
+	UnknownBranch,
+	/// Contains the reason the compact block can't be reconstructed.
+	InvalidCompactBlock(&'static str),
+	/// Contains the reason the header is invalid.
+	InvalidHeader(&'static str),
+	/// Contains the txid of the tx with an invalid input script.
+	ScriptVerification(sha256d::Hash),
+	/// Contains the reason the export is invalid.
+	InvalidAuditExport(&'static str),
+	/// Contains the error message of the backend.
+	Backend(String),
+	PsbtNotSigned,
+	WrongSighashType,
+	/// Contains the reason the options are invalid.
+	InvalidTxOptions(&'static str),
+	/// Contains the fee of the tx.
+	FeeTooHigh(u64),
+	/// Contains the amount of the change that would be needed.
+	ChangeNotAllowed(u64),
+	/// Contains the reason the silent payment data is invalid.
+	InvalidSilentPayment(&'static str),
+	/// Contains the feerate of the tx in satoshis per virtual byte.
+	FeeRateTooHigh(u64),
+	/// Contains the error message of the storage.
+	Storage(String),
+	MissingUtxoData,
+	UtxoTimelocked,
+	/// Contains the reason the MuSig2 data is invalid.
+	InvalidMusig(&'static str),
+	/// Contains the reason the coinjoin tx is rejected.
+	InvalidCoinjoin(&'static str),
+	/// Contains the reason the message of the peer is invalid.
+	InvalidPeerMessage(&'static str),
+	/// Contains the reason the delta can't be merged.
+	InvalidWalletDelta(&'static str),
+	/// Contains the error message of the writer.
+	Io(String),
+	/// Contains the reason the QR code data is invalid.
+	InvalidQrCode(&'static str),
+	WrongSeed,
+	/// There is no system time, a clock must be set with [Wallet::set_clock].
+	NoClock,
+	/// There is no OS randomness, a source must be set with
+	/// [Wallet::set_entropy_source].
+	NoEntropySource,
+	/// The input kind of an imported UTXO can't be derived from its script,
+	/// it must be set with [Wallet::set_utxo_input_kind].
+	UnknownInputKind,
 }
 
 impl fmt::Display for Error {
@@ -22,6 +103,31 @@ impl fmt::Display for Error {
         match *self {
 			Error::Bip32(ref e) => write!(f, "{}: {}", desc(self), e),
 			Error::Secp256k1(ref e) => write!(f, "{}: {}", desc(self), e),
+			Error::InvalidLabelRecord(line) => write!(f, "{} on line {}", desc(self), line),
+			Error::TxTooLarge(max) => write!(f, "{}; at most {} sat fits", desc(self), max),
+			Error::InvalidReserveProof(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::InvalidMessageSignature(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::InvalidUri(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::InvalidConfig(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::InvalidPayjoinProposal(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::InvalidCompactBlock(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::InvalidHeader(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::DuplicateTxid(txid) => write!(f, "{}: {}", desc(self), txid),
+			Error::ScriptVerification(txid) => write!(f, "{}: {}", desc(self), txid),
+			Error::InvalidAuditExport(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::Backend(ref msg) => write!(f, "{}: {}", desc(self), msg),
+			Error::Storage(ref msg) => write!(f, "{}: {}", desc(self), msg),
+			Error::InvalidTxOptions(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::FeeTooHigh(fee) => write!(f, "{}: {} sat", desc(self), fee),
+			Error::ChangeNotAllowed(change) => write!(f, "{}: {} sat", desc(self), change),
+			Error::InvalidSilentPayment(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::FeeRateTooHigh(rate) => write!(f, "{}: {} sat/vB", desc(self), rate),
+			Error::InvalidMusig(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::InvalidCoinjoin(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::InvalidPeerMessage(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::InvalidWalletDelta(reason) => write!(f, "{}: {}", desc(self), reason),
+			Error::Io(ref msg) => write!(f, "{}: {}", desc(self), msg),
+			Error::InvalidQrCode(reason) => write!(f, "{}: {}", desc(self), reason),
 			_ => f.write_str(desc(self)),
         }
     }
@@ -45,6 +151,56 @@ impl error::Error for Error {
 			Error::DuplicateUtxo => "a UTXO has been provided more than once",
 			Error::InsufficientFunds => "not enough funds to fund the given transaction",
 			Error::WalletNotFullyInitialized => "the wallet is not fully initialized yet",
+			Error::TxLimitsExceeded => "the tx can't be created within the given limits",
+			Error::InvalidLabelRecord(_) => "invalid BIP-329 label record",
+			Error::DustOutput => "an output has a value below the dust or minimum output value",
+			Error::WalletModified => "the wallet was modified while the batch was in progress",
+			Error::TxTooLarge(_) => "the tx would exceed the maximum weight",
+			Error::ImportScriptMismatch => "the imported output doesn't pay to the given key",
+			Error::InvalidReserveProof(_) => "invalid proof of reserves",
+			Error::ScriptNotInWallet => "the script or address doesn't belong to the wallet",
+			Error::UnsupportedAddressType => "the operation is not supported for the address type",
+			Error::InvalidMessageSignature(_) => "invalid message signature",
+			Error::InvalidUri(_) => "invalid payment URI",
+			Error::WrongNetwork => "the address is for another network than the wallet",
+			Error::InvalidConfig(_) => "invalid wallet config",
+			Error::DuplicateRecipient => "an address is paid more than once",
+			Error::NoRecipients => "no recipients were given",
+			Error::AddressCapReached => "the maximum number of addresses was handed out",
+			Error::InvalidPayjoinProposal(_) => "invalid payjoin proposal",
+			Error::TxNotInHistory => "the tx is not in the wallet's history",
+			Error::TxNotPending => "the tx is not pending in the wallet",
+			Error::BlockTooManyTxs => "the block has more txs than allowed",
+			Error::ScriptTooLarge => "a script in the block is larger than allowed",
+			Error::DuplicateTxid(_) => "the block contains the same tx more than once",
+			Error::InvalidMerkleProof => "the merkle proof is invalid or doesn't match the txs",
+			Error::UnknownBlock => "the block is not part of the wallet's chain",
+			Error::UnknownBranch => "the wallet has no branch with the given index",
+			Error::InvalidCompactBlock(_) => "invalid compact block",
+			Error::InvalidHeader(_) => "invalid block header",
+			Error::ScriptVerification(_) => "a tx spending our outputs has an invalid script",
+			Error::InvalidAuditExport(_) => "invalid audit export",
+			Error::Backend(_) => "the backend failed",
+			Error::PsbtNotSigned => "an input of the PSBT is not signed",
+			Error::WrongSighashType => "a signature doesn't use the input's sighash type",
+			Error::InvalidTxOptions(_) => "invalid tx options",
+			Error::FeeTooHigh(_) => "the fee exceeds the configured maximum",
+			Error::ChangeNotAllowed(_) => "the tx would need change",
+			Error::InvalidSilentPayment(_) => "invalid silent payment data",
+			Error::FeeRateTooHigh(_) => "the feerate exceeds the configured maximum",
+			Error::Storage(_) => "the wallet could not be stored or loaded",
+			Error::MissingUtxoData => "the output or tx of a UTXO is not known",
+			Error::UtxoTimelocked => "a UTXO can't be spent before its timelock expires",
+			Error::InvalidMusig(_) => "invalid MuSig2 data",
+			Error::InvalidCoinjoin(_) => "invalid coinjoin tx",
+			Error::InvalidPeerMessage(_) => "invalid message from peer",
+			Error::InvalidWalletDelta(_) => "the wallet delta can't be merged",
+			Error::Io(_) => "writing the export failed",
+			Error::InvalidQrCode(_) => "invalid QR code data",
+			Error::WrongSeed => "the seed doesn't match the wallet's keys",
+			Error::NoClock => "no clock is available",
+			Error::NoEntropySource => "no source of randomness is available",
+			Error::UnknownInputKind => "the input kind of a UTXO is not known",
         }
     }
 }
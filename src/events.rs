@@ -0,0 +1,101 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Events emitted by the wallet.
+//!
+//! The wallet queues events as they happen. Applications take them from
+//! the wallet using [Wallet::take_events].
+
+use bitcoin_hashes::sha256d;
+use serde::{Deserialize, Serialize};
+
+use amount::{self, Amount};
+use wallet::Keychain;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WalletEvent {
+	/// A block was processed, adding a confirmation to all confirmed txs.
+	BlockConnected {
+		height: u32,
+		hash: sha256d::Hash,
+	},
+	/// The last processed block was disconnected, f.e. in a reorg.
+	BlockDisconnected {
+		height: u32,
+		hash: sha256d::Hash,
+	},
+	/// A tx relevant to the wallet was confirmed.
+	TxConfirmed {
+		txid: sha256d::Hash,
+		height: u32,
+		/// The value of the outputs paying to the wallet.
+		#[serde(with = "amount::as_sat")]
+		received: Amount,
+		/// The value of our own outputs spent by the tx.
+		#[serde(with = "amount::as_sat")]
+		sent: Amount,
+	},
+	/// Only a few addresses remain before reaching one of the address caps.
+	AddressCapNearing {
+		/// Not set for the cap on the total number of addresses.
+		keychain: Option<Keychain>,
+		issued: u32,
+		cap: u32,
+	},
+	/// The full amount of an expected payment was received.
+	ExpectedPaymentFulfilled {
+		id: usize,
+		txid: sha256d::Hash,
+		height: u32,
+	},
+	/// An expected payment wasn't received before its expiry height.
+	ExpectedPaymentExpired {
+		id: usize,
+	},
+	/// The wallet's tip reached the height of a scheduled trigger.
+	/// See [Wallet::schedule_at_height].
+	HeightReached {
+		id: usize,
+		height: u32,
+		name: String,
+	},
+	/// A pending tx was dropped because it didn't confirm in time.
+	PendingTxExpired {
+		txid: sha256d::Hash,
+	},
+	/// A pending tx was dropped because another tx spends the same output.
+	PendingTxConflicted {
+		txid: sha256d::Hash,
+		conflicting_txid: sha256d::Hash,
+		/// Whether the conflicting tx was confirmed or only seen in the mempool.
+		confirmed: bool,
+	},
+	/// The wallet config was replaced.
+	ConfigChanged,
+	/// The spendable balance dropped below one of the configured thresholds.
+	BalanceBelowThreshold {
+		#[serde(with = "amount::as_sat")]
+		threshold: Amount,
+		#[serde(with = "amount::as_sat")]
+		balance: Amount,
+	},
+	/// The spendable balance rose to or above one of the configured thresholds.
+	BalanceAboveThreshold {
+		#[serde(with = "amount::as_sat")]
+		threshold: Amount,
+		#[serde(with = "amount::as_sat")]
+		balance: Amount,
+	},
+}
@@ -0,0 +1,274 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Fee and transaction weight estimation.
+
+use bitcoin::{Script, TxOut};
+use serde::{Deserialize, Serialize};
+
+use amount::{self, Amount, FeeRate};
+
+/// The weight of the parts of a segwit tx that don't depend on inputs or outputs:
+/// version, locktime, the input and output counts and the segwit marker and flag.
+pub const TX_BASE_WEIGHT: u64 = (4 + 4 + 1 + 1) * 4 + 2;
+
+/// The weight of a P2WPKH input, assuming a worst-case 72-byte signature.
+pub const P2WPKH_INPUT_WEIGHT: u64 = (32 + 4 + 1 + 4) * 4 + (1 + 1 + 72 + 1 + 33);
+
+/// The number of bytes used to encode the length as a Bitcoin varint.
+pub fn varint_len(n: usize) -> u64 {
+	match n {
+		0..=0xFC => 1,
+		0xFD..=0xFFFF => 3,
+		0x10000..=0xFFFFFFFF => 5,
+		_ => 9,
+	}
+}
+
+/// The weight of an output with the given scriptPubkey.
+pub fn txout_weight(script_pubkey: &Script) -> u64 {
+	(8 + varint_len(script_pubkey.len()) + script_pubkey.len() as u64) * 4
+}
+
+/// Estimate the weight of a tx spending the given number of P2WPKH inputs
+/// and creating the given outputs.
+pub fn estimate_weight(nb_inputs: usize, outputs: &[TxOut]) -> u64 {
+	TX_BASE_WEIGHT
+		+ nb_inputs as u64 * P2WPKH_INPUT_WEIGHT
+		+ outputs.iter().map(|o| txout_weight(&o.script_pubkey)).sum::<u64>()
+}
+
+/// Estimate the weight of a tx spending inputs of the given kinds and
+/// creating the given outputs.
+pub fn estimate_weight_for_inputs(inputs: &[InputKind], outputs: &[TxOut]) -> u64 {
+	TX_BASE_WEIGHT
+		+ inputs.iter().map(|i| i.weight()).sum::<u64>()
+		+ outputs.iter().map(|o| txout_weight(&o.script_pubkey)).sum::<u64>()
+}
+
+/// The size of a worst-case ECDSA signature with sighash byte.
+const ECDSA_SIG_SIZE: u64 = 72;
+/// The size of a Schnorr signature with the default sighash.
+const SCHNORR_SIG_SIZE: u64 = 64;
+/// The size of a compressed public key.
+const PUBKEY_SIZE: u64 = 33;
+
+/// The kind of output spent by an input, determining how it is satisfied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputKind {
+	P2wpkh,
+	P2shP2wpkh,
+	P2pkh,
+	/// A taproot key path spend.
+	P2trKeySpend,
+	/// A [required]-of-[total] multisig script in P2WSH.
+	P2wshMultisig {
+		required: usize,
+		total: usize,
+	},
+	/// A [required]-of-[total] multisig script in P2SH-P2WSH.
+	P2shP2wshMultisig {
+		required: usize,
+		total: usize,
+	},
+}
+
+impl InputKind {
+	/// The kind of input spending the script, for the scripts that can be
+	/// recognized without more info. That excludes P2SH and P2WSH, of which
+	/// the spending input depends on the script behind the hash.
+	pub fn from_script(script: &Script) -> Option<InputKind> {
+		let bytes = script.as_bytes();
+		if script.is_v0_p2wpkh() {
+			Some(InputKind::P2wpkh)
+		} else if script.is_p2pkh() {
+			Some(InputKind::P2pkh)
+		} else if bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20 {
+			Some(InputKind::P2trKeySpend)
+		} else {
+			None
+		}
+	}
+
+	/// The kind of input spending an output of the descriptor.
+	/// Supported are `wpkh`, `sh(wpkh)`, `pkh`, `tr` with only a key and
+	/// `multi` or `sortedmulti` in `wsh` or `sh(wsh)`.
+	pub fn from_descriptor(descriptor: &str) -> Option<InputKind> {
+		// Strip the checksum.
+		let desc = descriptor.splitn(2, '#').next().unwrap_or("").trim();
+		let inner_of = |desc: &str, func: &str| -> Option<String> {
+			if desc.starts_with(func) && desc.ends_with(')') && desc[func.len()..].starts_with('(')
+			{
+				Some(desc[func.len() + 1..desc.len() - 1].to_owned())
+			} else {
+				None
+			}
+		};
+		let multi = |inner: &str| -> Option<(usize, usize)> {
+			let args = inner_of(inner, "multi").or_else(|| inner_of(inner, "sortedmulti"))?;
+			let mut args = args.split(',');
+			let required = args.next()?.trim().parse().ok()?;
+			let total = args.count();
+			if required == 0 || required > total {
+				return None;
+			}
+			Some((required, total))
+		};
+
+		if let Some(inner) = inner_of(desc, "sh") {
+			if inner_of(&inner, "wpkh").is_some() {
+				return Some(InputKind::P2shP2wpkh);
+			}
+			let (required, total) = multi(&inner_of(&inner, "wsh")?)?;
+			return Some(InputKind::P2shP2wshMultisig {
+				required: required,
+				total: total,
+			});
+		}
+		if let Some(inner) = inner_of(desc, "wsh") {
+			let (required, total) = multi(&inner)?;
+			return Some(InputKind::P2wshMultisig {
+				required: required,
+				total: total,
+			});
+		}
+		if let Some(inner) = inner_of(desc, "tr") {
+			// Script paths are not supported.
+			return match inner.contains(',') {
+				true => None,
+				false => Some(InputKind::P2trKeySpend),
+			};
+		}
+		if inner_of(desc, "wpkh").is_some() {
+			return Some(InputKind::P2wpkh);
+		}
+		if inner_of(desc, "pkh").is_some() {
+			return Some(InputKind::P2pkh);
+		}
+		None
+	}
+
+	/// The weight of a signed input of this kind, assuming worst-case signatures.
+	/// For non-segwit inputs, the empty witness in a segwit tx is included.
+	pub fn weight(&self) -> u64 {
+		// The outpoint, sequence and the scriptSig with its length.
+		let base = |script_sig_len: u64| {
+			(32 + 4 + 4 + varint_len(script_sig_len as usize) + script_sig_len) * 4
+		};
+		// The witness stack with the item count.
+		let witness = |items: &[u64]| {
+			varint_len(items.len())
+				+ items.iter().map(|i| varint_len(*i as usize) + i).sum::<u64>()
+		};
+		let multisig_witness = |required: usize, total: usize| {
+			// OP_k <pubkeys> OP_n OP_CHECKMULTISIG
+			let script_len = 3 + total as u64 * (1 + PUBKEY_SIZE);
+			let mut items = vec![0]; // The dummy element.
+			items.extend(vec![ECDSA_SIG_SIZE; required]);
+			items.push(script_len);
+			witness(&items)
+		};
+		match *self {
+			InputKind::P2wpkh => base(0) + witness(&[ECDSA_SIG_SIZE, PUBKEY_SIZE]),
+			InputKind::P2shP2wpkh => base(1 + 22) + witness(&[ECDSA_SIG_SIZE, PUBKEY_SIZE]),
+			InputKind::P2pkh => base(1 + ECDSA_SIG_SIZE + 1 + PUBKEY_SIZE) + witness(&[]),
+			InputKind::P2trKeySpend => base(0) + witness(&[SCHNORR_SIG_SIZE]),
+			InputKind::P2wshMultisig {
+				required,
+				total,
+			} => base(0) + multisig_witness(required, total),
+			InputKind::P2shP2wshMultisig {
+				required,
+				total,
+			} => base(1 + 34) + multisig_witness(required, total),
+		}
+	}
+}
+
+/// Convert a weight into virtual bytes.
+pub fn weight_to_vsize(weight: u64) -> u64 {
+	(weight + 3) / 4
+}
+
+/// The cost of adding something with the given weight to a tx paying the
+/// given fee for the given weight, so that the feerate stays the same.
+pub fn marginal_fee(fee: u64, tx_weight: u64, extra_weight: u64) -> u64 {
+	if tx_weight == 0 {
+		return 0;
+	}
+	(fee * extra_weight + tx_weight - 1) / tx_weight
+}
+
+/// The fee to pay for a tx.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Fee {
+	/// A fixed fee.
+	Absolute(#[serde(with = "amount::as_sat")] Amount),
+	Rate(FeeRate),
+}
+
+impl Fee {
+	/// The fee in satoshis to pay for a tx with the given weight.
+	pub fn for_weight(&self, weight: u64) -> u64 {
+		match *self {
+			Fee::Absolute(fee) => fee.as_sat(),
+			Fee::Rate(rate) => rate.fee_for_weight(weight).as_sat(),
+		}
+	}
+
+	/// The extra fee in satoshis to pay when adding the given weight to a tx
+	/// with the given weight.
+	pub fn marginal(&self, tx_weight: u64, extra_weight: u64) -> u64 {
+		match *self {
+			Fee::Absolute(fee) => marginal_fee(fee.as_sat(), tx_weight, extra_weight),
+			Fee::Rate(rate) => rate.fee_for_weight(extra_weight).as_sat(),
+		}
+	}
+}
+
+/// The size of an input spending a segwit output, with the witness discounted,
+/// as used by Bitcoin Core to calculate the dust threshold.
+const DUST_SEGWIT_INPUT_SIZE: u64 = 32 + 4 + 1 + 107 / 4 + 4;
+
+/// The size of an input spending a non-segwit output, as used by Bitcoin Core
+/// to calculate the dust threshold.
+const DUST_LEGACY_INPUT_SIZE: u64 = 32 + 4 + 1 + 107 + 4;
+
+/// The value below which an output with the given script is considered dust,
+/// given the dust relay fee in satoshis per 1000 virtual bytes.
+/// Unspendable outputs are never dust.
+pub fn dust_threshold(script_pubkey: &Script, dust_relay_fee: u64) -> u64 {
+	if script_pubkey.is_provably_unspendable() {
+		return 0;
+	}
+
+	let input_size = if is_witness_program(script_pubkey) {
+		DUST_SEGWIT_INPUT_SIZE
+	} else {
+		DUST_LEGACY_INPUT_SIZE
+	};
+	(txout_weight(script_pubkey) / 4 + input_size) * dust_relay_fee / 1000
+}
+
+/// Whether the script is a witness program of any version, like Bitcoin Core
+/// checks it: a version opcode followed by a single push of 2 to 40 bytes.
+fn is_witness_program(script: &Script) -> bool {
+	let bytes = script.as_bytes();
+	if bytes.len() < 4 || bytes.len() > 42 {
+		return false;
+	}
+	// OP_0 or OP_1 to OP_16.
+	let version_ok = bytes[0] == 0x00 || (bytes[0] >= 0x51 && bytes[0] <= 0x60);
+	version_ok && bytes[1] as usize + 2 == bytes.len()
+}
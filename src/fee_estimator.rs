@@ -0,0 +1,103 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Sources of feerate estimates.
+//!
+//! With the `bitcoind` feature, a [bitcoincore_rpc::Client] estimates fees
+//! using `estimatesmartfee`. With the `mempool-space` feature,
+//! [MempoolSpaceEstimator] uses the recommended fees of the mempool.space API.
+
+use amount::FeeRate;
+use error::Result;
+
+/// Estimates the feerate for a tx to confirm in time.
+/// Errors of the estimator should be returned as [Error::Backend].
+pub trait FeeEstimator {
+	/// The feerate for a tx to confirm within [target] blocks.
+	fn estimate_fee_rate(&self, target: u16) -> Result<FeeRate>;
+}
+
+#[cfg(feature = "bitcoind")]
+impl FeeEstimator for ::bitcoincore_rpc::Client {
+	fn estimate_fee_rate(&self, target: u16) -> Result<FeeRate> {
+		use bitcoincore_rpc::RpcApi;
+		use error::Error;
+
+		let result: ::serde_json::Value = self
+			.call("estimatesmartfee", &[target.into()])
+			.map_err(|e| Error::Backend(e.to_string()))?;
+		match result.get("feerate").and_then(|f| f.as_f64()) {
+			Some(fee_rate) => Ok(FeeRate::from_btc_per_kvb(fee_rate)),
+			// Not enough data yet, the reason is in the errors.
+			None => Err(Error::Backend(format!("no fee estimate: {}", result["errors"]))),
+		}
+	}
+}
+
+/// Uses the recommended fees of the mempool.space API, or of a self-hosted
+/// instance of it.
+#[cfg(feature = "mempool-space")]
+pub struct MempoolSpaceEstimator {
+	base_url: String,
+}
+
+#[cfg(feature = "mempool-space")]
+impl MempoolSpaceEstimator {
+	/// Use the public mempool.space instance.
+	pub fn new() -> MempoolSpaceEstimator {
+		MempoolSpaceEstimator::with_base_url("https://mempool.space")
+	}
+
+	/// Use the instance at the URL, like `https://mempool.space/testnet`.
+	pub fn with_base_url(base_url: &str) -> MempoolSpaceEstimator {
+		MempoolSpaceEstimator {
+			base_url: base_url.trim_end_matches('/').to_owned(),
+		}
+	}
+}
+
+#[cfg(feature = "mempool-space")]
+impl Default for MempoolSpaceEstimator {
+	fn default() -> MempoolSpaceEstimator {
+		MempoolSpaceEstimator::new()
+	}
+}
+
+#[cfg(feature = "mempool-space")]
+impl FeeEstimator for MempoolSpaceEstimator {
+	fn estimate_fee_rate(&self, target: u16) -> Result<FeeRate> {
+		use error::Error;
+
+		let url = format!("{}/api/v1/fees/recommended", self.base_url);
+		let response = ::ureq::get(&url).call();
+		if !response.ok() {
+			return Err(Error::Backend(format!("mempool.space status {}", response.status())));
+		}
+		let body = response.into_string().map_err(|e| Error::Backend(e.to_string()))?;
+		let fees: ::serde_json::Value =
+			::serde_json::from_str(&body).map_err(|e| Error::Backend(e.to_string()))?;
+
+		// The API only recommends fees for a few targets.
+		let key = match target {
+			0..=1 => "fastestFee",
+			2..=3 => "halfHourFee",
+			4..=6 => "hourFee",
+			_ => "economyFee",
+		};
+		match fees.get(key).or(fees.get("hourFee")).and_then(|f| f.as_f64()) {
+			Some(fee_rate) => Ok(FeeRate::from_sat_per_vb(fee_rate.ceil() as u64)),
+			None => Err(Error::Backend("unexpected mempool.space response".to_owned())),
+		}
+	}
+}
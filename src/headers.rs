@@ -0,0 +1,468 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Tracking of the block header chain.
+//!
+//! A [HeaderChain] validates block headers (proof of work, difficulty and
+//! timestamps) and keeps track of all known tips, so that the best chain can
+//! be determined without trusting the source of the headers.
+
+use std::collections::{HashMap, HashSet};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::util::uint::Uint256;
+use bitcoin::{BitcoinHash, BlockHeader, Network};
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::sha256d;
+use serde::{Deserialize, Serialize};
+
+use config::Chain;
+use error::{Error, Result};
+
+/// The number of blocks between difficulty adjustments.
+pub const DIFFCHANGE_INTERVAL: u32 = 2016;
+/// The targeted duration of a difficulty period in seconds.
+pub const TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+/// The number of previous blocks of which the median timestamp must be exceeded.
+const MEDIAN_TIME_SPAN: usize = 11;
+/// How far in the future the timestamp of a header can be, in seconds.
+const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// The compact target of the lowest allowed difficulty.
+fn max_target_bits(chain: Chain) -> u32 {
+	match chain {
+		Chain::Regtest => 0x207fffff,
+		Chain::Signet => 0x1e0377ae,
+		Chain::Bitcoin | Chain::Testnet => 0x1d00ffff,
+	}
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn unix_time() -> Option<u64> {
+	Some(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0))
+}
+
+/// There's no system time on wasm, so the future timestamps are not checked.
+#[cfg(target_arch = "wasm32")]
+fn unix_time() -> Option<u64> {
+	None
+}
+
+/// The compact target after a difficulty period that took the given timespan.
+fn retarget_bits(prev_bits: u32, timespan: u32, max_bits: u32) -> u32 {
+	let timespan = timespan.max(TARGET_TIMESPAN / 4).min(TARGET_TIMESPAN * 4);
+	let max_target = BlockHeader::u256_from_compact_target(max_bits);
+	let target = BlockHeader::u256_from_compact_target(prev_bits).mul_u32(timespan)
+		/ Uint256::from_u64(TARGET_TIMESPAN as u64).unwrap();
+	BlockHeader::compact_target_from_u256(&target.min(max_target))
+}
+
+/// The header of the genesis block of the chain.
+fn genesis_header(chain: Chain) -> BlockHeader {
+	match chain {
+		// The genesis block of signet only differs in its header fields.
+		Chain::Signet => BlockHeader {
+			time: 1598918400,
+			bits: 0x1e0377ae,
+			nonce: 52613770,
+			..genesis_block(Network::Bitcoin).header
+		},
+		_ => genesis_block(chain.network()).header,
+	}
+}
+
+/// Hard-coded checkpoints for the network, as in Bitcoin Core.
+pub fn default_checkpoints(network: Network) -> Vec<(u32, sha256d::Hash)> {
+	let checkpoints: &[(u32, &str)] = match network {
+		Network::Bitcoin => &[
+			(11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+			(33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+			(74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+			(105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+			(134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+			(168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+			(193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+			(210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+			(216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+			(225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+			(250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+			(279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+			(295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+		],
+		Network::Testnet => &[
+			(546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
+		],
+		Network::Regtest => &[],
+	};
+	checkpoints
+		.iter()
+		.map(|(h, hash)| (*h, sha256d::Hash::from_hex(hash).expect("valid checkpoint")))
+		.collect()
+}
+
+/// A validated header with its position in the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredHeader {
+	pub header: BlockHeader,
+	pub height: u32,
+	/// The total work of the chain up to and including this header,
+	/// as the little-endian words of a 256-bit integer.
+	chain_work: [u64; 4],
+}
+
+impl StoredHeader {
+	/// The total work of the chain up to and including this header.
+	pub fn chain_work(&self) -> Uint256 {
+		Uint256(self.chain_work)
+	}
+}
+
+/// A tree of validated block headers starting at the genesis block.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderChain {
+	network: Network,
+	/// The chain is signet, of which the network is testnet.
+	#[serde(default)]
+	signet: bool,
+	checkpoints: Vec<(u32, sha256d::Hash)>,
+	headers: HashMap<sha256d::Hash, StoredHeader>,
+	/// The headers without children.
+	tips: HashSet<sha256d::Hash>,
+	/// The hashes of the chain with the most work, indexed by height.
+	best_chain: Vec<sha256d::Hash>,
+}
+
+impl HeaderChain {
+	/// Create a chain with only the genesis block of the network and without
+	/// checkpoints.
+	pub fn new(network: Network) -> HeaderChain {
+		HeaderChain::for_chain(Chain::from(network))
+	}
+
+	/// Like [new], for any chain, like signet.
+	pub fn for_chain(chain: Chain) -> HeaderChain {
+		let genesis = genesis_header(chain);
+		let hash = genesis.bitcoin_hash();
+		let mut headers = HashMap::new();
+		headers.insert(
+			hash,
+			StoredHeader {
+				header: genesis,
+				height: 0,
+				chain_work: genesis.work().0,
+			},
+		);
+		let mut tips = HashSet::new();
+		tips.insert(hash);
+		HeaderChain {
+			network: chain.network(),
+			signet: chain == Chain::Signet,
+			checkpoints: Vec::new(),
+			headers: headers,
+			tips: tips,
+			best_chain: vec![hash],
+		}
+	}
+
+	/// Create a chain that enforces the [default_checkpoints] of the network.
+	pub fn with_default_checkpoints(network: Network) -> HeaderChain {
+		let mut chain = HeaderChain::new(network);
+		chain.checkpoints = default_checkpoints(network);
+		chain
+	}
+
+	/// Add a checkpoint. Headers at the height must have the given hash.
+	/// Fails if a header that conflicts with it is already in the best chain.
+	pub fn add_checkpoint(&mut self, height: u32, hash: sha256d::Hash) -> Result<()> {
+		match self.best_chain.get(height as usize) {
+			Some(h) if *h != hash => Err(Error::InvalidHeader("conflicts with checkpoint")),
+			_ => {
+				self.checkpoints.push((height, hash));
+				Ok(())
+			}
+		}
+	}
+
+	pub fn network(&self) -> Network {
+		self.network
+	}
+
+	pub fn chain(&self) -> Chain {
+		if self.signet {
+			Chain::Signet
+		} else {
+			Chain::from(self.network)
+		}
+	}
+
+	/// The tip of the chain with the most work.
+	pub fn tip(&self) -> &StoredHeader {
+		&self.headers[self.best_chain.last().expect("genesis is always there")]
+	}
+
+	/// The height of the chain with the most work.
+	pub fn height(&self) -> u32 {
+		self.best_chain.len() as u32 - 1
+	}
+
+	pub fn get_header(&self, hash: &sha256d::Hash) -> Option<&StoredHeader> {
+		self.headers.get(hash)
+	}
+
+	/// The header at the height in the chain with the most work.
+	pub fn header_at(&self, height: u32) -> Option<&StoredHeader> {
+		self.best_chain.get(height as usize).map(|h| &self.headers[h])
+	}
+
+	/// Whether the block is part of the chain with the most work.
+	pub fn is_in_best_chain(&self, hash: &sha256d::Hash) -> bool {
+		match self.headers.get(hash) {
+			Some(h) => self.best_chain.get(h.height as usize) == Some(hash),
+			None => false,
+		}
+	}
+
+	/// The number of confirmations of the block in the chain with the most work.
+	/// Returns [None] for unknown blocks and blocks on other chains.
+	pub fn confirmations(&self, hash: &sha256d::Hash) -> Option<u32> {
+		if !self.is_in_best_chain(hash) {
+			return None;
+		}
+		Some(self.height() - self.headers[hash].height + 1)
+	}
+
+	/// All tips of competing chains, the one with the most work first.
+	pub fn tips(&self) -> Vec<&StoredHeader> {
+		let mut tips: Vec<&StoredHeader> = self.tips.iter().map(|h| &self.headers[h]).collect();
+		tips.sort_by(|a, b| b.chain_work().cmp(&a.chain_work()));
+		tips
+	}
+
+	/// The ancestor of the header at the given height.
+	fn ancestor(&self, mut header: &StoredHeader, height: u32) -> &StoredHeader {
+		while header.height > height {
+			header = &self.headers[&header.header.prev_blockhash];
+		}
+		header
+	}
+
+	/// The compact target required for the header following [prev].
+	fn required_bits(&self, prev: &StoredHeader, header: &BlockHeader) -> u32 {
+		let chain = self.chain();
+		let max_bits = max_target_bits(chain);
+		if chain == Chain::Regtest {
+			return prev.header.bits;
+		}
+
+		let height = prev.height + 1;
+		if height % DIFFCHANGE_INTERVAL != 0 {
+			if chain == Chain::Testnet {
+				// Blocks more than 20 minutes apart can have the lowest difficulty.
+				if header.time > prev.header.time + 20 * 60 {
+					return max_bits;
+				}
+				// Otherwise, the difficulty of the last regular block is used.
+				let mut last = prev;
+				while last.height % DIFFCHANGE_INTERVAL != 0 && last.header.bits == max_bits {
+					last = &self.headers[&last.header.prev_blockhash];
+				}
+				return last.header.bits;
+			}
+			return prev.header.bits;
+		}
+
+		let first = self.ancestor(prev, height - DIFFCHANGE_INTERVAL);
+		let timespan = prev.header.time.saturating_sub(first.header.time);
+		retarget_bits(prev.header.bits, timespan, max_bits)
+	}
+
+	/// The median timestamp of the header and its 10 ancestors.
+	fn median_time_past(&self, mut header: &StoredHeader) -> u32 {
+		let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+		loop {
+			times.push(header.header.time);
+			if times.len() == MEDIAN_TIME_SPAN || header.height == 0 {
+				break;
+			}
+			header = &self.headers[&header.header.prev_blockhash];
+		}
+		times.sort();
+		times[times.len() / 2]
+	}
+
+	/// Validate and add the header. Its parent must be known already.
+	/// Returns whether the header became the tip of the chain with the most work.
+	///
+	/// Like in Bitcoin Core, headers more than two hours ahead of the local
+	/// clock are rejected, they can be added once the clock caught up.
+	/// There's no clock on wasm, so there they are accepted.
+	pub fn add_header(&mut self, header: BlockHeader) -> Result<bool> {
+		let hash = header.bitcoin_hash();
+		if self.headers.contains_key(&hash) {
+			return Ok(false);
+		}
+		let stored = {
+			let prev = match self.headers.get(&header.prev_blockhash) {
+				Some(prev) => prev,
+				None => return Err(Error::InvalidHeader("unknown previous block")),
+			};
+			let height = prev.height + 1;
+
+			if header.bits != self.required_bits(prev, &header) {
+				return Err(Error::InvalidHeader("wrong difficulty"));
+			}
+			if header.validate_pow(&header.target()).is_err() {
+				return Err(Error::InvalidHeader("insufficient proof of work"));
+			}
+			if header.time <= self.median_time_past(prev) {
+				return Err(Error::InvalidHeader("timestamp too old"));
+			}
+			if let Some(now) = unix_time() {
+				if u64::from(header.time) > now + MAX_FUTURE_BLOCK_TIME {
+					return Err(Error::InvalidHeader("timestamp too far in the future"));
+				}
+			}
+			if self.checkpoints.iter().any(|(h, c)| *h == height && *c != hash) {
+				return Err(Error::InvalidHeader("conflicts with checkpoint"));
+			}
+
+			StoredHeader {
+				header: header,
+				height: height,
+				chain_work: (prev.chain_work() + header.work()).0,
+			}
+		};
+
+		self.tips.remove(&header.prev_blockhash);
+		self.tips.insert(hash);
+		let is_best = stored.chain_work() > self.tip().chain_work();
+		self.headers.insert(hash, stored);
+		if is_best {
+			self.set_best_tip(hash);
+		}
+		Ok(is_best)
+	}
+
+	/// Make the header the tip of the best chain, replacing the blocks of the
+	/// previous best chain since the fork.
+	fn set_best_tip(&mut self, hash: sha256d::Hash) {
+		let mut branch = Vec::new();
+		let mut cursor = hash;
+		while !self.is_in_best_chain(&cursor) {
+			branch.push(cursor);
+			cursor = self.headers[&cursor].header.prev_blockhash;
+		}
+		let fork_height = self.headers[&cursor].height as usize;
+		self.best_chain.truncate(fork_height + 1);
+		self.best_chain.extend(branch.into_iter().rev());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::consensus::encode::deserialize;
+	use hex;
+
+	/// The headers of mainnet blocks 1 and 2.
+	const MAINNET_HEADERS: [&'static str; 2] = [
+		"010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba74\
+		4bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299",
+		"010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1\
+		c7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd61",
+	];
+
+	fn mainnet_header(height: usize) -> BlockHeader {
+		deserialize(&hex::decode(MAINNET_HEADERS[height - 1]).unwrap()).unwrap()
+	}
+
+	/// A regtest header following the previous one, with valid proof of work.
+	fn mine(prev: &BlockHeader, time: u32) -> BlockHeader {
+		let mut header = BlockHeader {
+			prev_blockhash: prev.bitcoin_hash(),
+			time: time,
+			nonce: 0,
+			..*prev
+		};
+		while header.validate_pow(&header.target()).is_err() {
+			header.nonce += 1;
+		}
+		header
+	}
+
+	#[test]
+	fn mainnet_headers() {
+		let mut chain = HeaderChain::new(Network::Bitcoin);
+		assert_eq!(chain.add_header(mainnet_header(1)), Ok(true));
+		assert_eq!(chain.add_header(mainnet_header(2)), Ok(true));
+		assert_eq!(chain.height(), 2);
+		let expected = "000000006a625f06636b8bb6ac7b960a8d03705d1ace08b1a19da3fdcc99ddbd";
+		assert_eq!(chain.tip().header.bitcoin_hash(), sha256d::Hash::from_hex(expected).unwrap());
+	}
+
+	#[test]
+	fn reject_bad_pow() {
+		let mut chain = HeaderChain::new(Network::Bitcoin);
+		let mut header = mainnet_header(1);
+		header.nonce += 1;
+		assert_eq!(
+			chain.add_header(header),
+			Err(Error::InvalidHeader("insufficient proof of work"))
+		);
+
+		// The difficulty can't be lowered to make the proof of work valid.
+		let mut header = mainnet_header(1);
+		header.bits = 0x1e00ffff;
+		assert_eq!(chain.add_header(header), Err(Error::InvalidHeader("wrong difficulty")));
+		assert_eq!(chain.height(), 0);
+	}
+
+	#[test]
+	fn reject_future_headers() {
+		let mut chain = HeaderChain::new(Network::Regtest);
+		let genesis = chain.tip().header;
+		let now = unix_time().unwrap() as u32;
+
+		let header = mine(&genesis, now + 3 * 60 * 60);
+		assert_eq!(
+			chain.add_header(header),
+			Err(Error::InvalidHeader("timestamp too far in the future"))
+		);
+		let header = mine(&genesis, now + 60 * 60);
+		assert_eq!(chain.add_header(header), Ok(true));
+	}
+
+	/// The retargets of mainnet blocks 32256, 2016, 68544 and 48384, from the
+	/// `pow_tests` of Bitcoin Core, with the timestamps of the first and last
+	/// blocks of the previous period.
+	#[test]
+	fn mainnet_retargets() {
+		let max_bits = max_target_bits(Chain::Bitcoin);
+		let vectors = [
+			(0x1d00ffff, 1261130161, 1262152739, 0x1d00d86a),
+			// Limited by the lowest difficulty.
+			(0x1d00ffff, 1231006505, 1233061996, 0x1d00ffff),
+			// Limited to a quarter of the target timespan.
+			(0x1c05a3f4, 1279008237, 1279297671, 0x1c0168fd),
+			// Limited to four times the target timespan.
+			(0x1c387f6f, 1263163443, 1269211443, 0x1d00e1fd),
+		];
+		for &(bits, first_time, last_time, expected) in vectors.iter() {
+			assert_eq!(retarget_bits(bits, last_time - first_time, max_bits), expected);
+		}
+	}
+}
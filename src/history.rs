@@ -0,0 +1,123 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! The wallet's transaction history.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use bitcoin_hashes::sha256d;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use wallet::TxEntry;
+
+/// The history entries indexed by txid, with an index to list them ordered
+/// by height. Entries of the same height are kept in the order they were added.
+///
+/// It is serialized as the ordered list of entries.
+#[derive(Debug, Clone, Default)]
+pub struct TxHistory {
+	entries: HashMap<sha256d::Hash, TxEntry>,
+	/// The txids of the entries, ordered by height.
+	order: Vec<sha256d::Hash>,
+}
+
+impl TxHistory {
+	pub fn new() -> TxHistory {
+		TxHistory::default()
+	}
+
+	pub fn len(&self) -> usize {
+		self.order.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.order.is_empty()
+	}
+
+	pub fn get(&self, txid: &sha256d::Hash) -> Option<&TxEntry> {
+		self.entries.get(txid)
+	}
+
+	/// The height of the entry must not be changed, it determines the order.
+	pub fn get_mut(&mut self, txid: &sha256d::Hash) -> Option<&mut TxEntry> {
+		self.entries.get_mut(txid)
+	}
+
+	pub fn contains(&self, txid: &sha256d::Hash) -> bool {
+		self.entries.contains_key(txid)
+	}
+
+	/// The position in [order] of the first entry with a height above [height].
+	fn position_after(&self, height: u32) -> usize {
+		let entries = &self.entries;
+		self.order
+			.binary_search_by(|t| match entries[t].height <= height {
+				true => Ordering::Less,
+				false => Ordering::Greater,
+			})
+			.unwrap_err()
+	}
+
+	/// Add the entry after all entries of the same height.
+	/// Returns false if an entry for the tx already existed, in which case
+	/// nothing is changed.
+	pub fn insert(&mut self, entry: TxEntry) -> bool {
+		let txid = entry.txid();
+		if self.entries.contains_key(&txid) {
+			return false;
+		}
+		let pos = self.position_after(entry.height);
+		self.order.insert(pos, txid);
+		self.entries.insert(txid, entry);
+		true
+	}
+
+	/// Remove the entry of the tx and return it.
+	pub fn remove(&mut self, txid: &sha256d::Hash) -> Option<TxEntry> {
+		let entry = self.entries.remove(txid)?;
+		self.order.retain(|t| t != txid);
+		Some(entry)
+	}
+
+	/// Iterate over all entries, ordered by height.
+	pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a TxEntry> + 'a {
+		self.order.iter().map(move |t| &self.entries[t])
+	}
+
+	/// Iterate over the entries with at least the given height, ordered by height.
+	pub fn iter_from_height<'a>(&'a self, height: u32) -> impl Iterator<Item = &'a TxEntry> + 'a {
+		let start = match height {
+			0 => 0,
+			h => self.position_after(h - 1),
+		};
+		self.order[start..].iter().map(move |t| &self.entries[t])
+	}
+}
+
+impl Serialize for TxHistory {
+	fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+		s.collect_seq(self.iter())
+	}
+}
+
+impl<'de> Deserialize<'de> for TxHistory {
+	fn deserialize<D: Deserializer<'de>>(d: D) -> Result<TxHistory, D::Error> {
+		let mut history = TxHistory::new();
+		for entry in Vec::<TxEntry>::deserialize(d)? {
+			history.insert(entry);
+		}
+		Ok(history)
+	}
+}
@@ -0,0 +1,231 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Labels for addresses, outputs and transactions.
+//!
+//! Labels can be imported and exported using the BIP-329 JSON Lines format.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bitcoin::{Address, OutPoint};
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::sha256d;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json;
+
+use error::{Error, Result};
+
+/// The object a label is attached to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LabelRef {
+	Tx(sha256d::Hash),
+	/// An address, in its string representation.
+	Address(String),
+	/// A tx input, identified by the outpoint it spends.
+	Input(OutPoint),
+	/// A tx output, usually a UTXO.
+	Output(OutPoint),
+}
+
+impl LabelRef {
+	pub fn address(address: &Address) -> LabelRef {
+		LabelRef::Address(address.to_string())
+	}
+
+	fn bip329_type(&self) -> &'static str {
+		match *self {
+			LabelRef::Tx(_) => "tx",
+			LabelRef::Address(_) => "addr",
+			LabelRef::Input(_) => "input",
+			LabelRef::Output(_) => "output",
+		}
+	}
+
+	fn from_bip329(tp: &str, reference: &str) -> Option<LabelRef> {
+		match tp {
+			"tx" => sha256d::Hash::from_hex(reference).ok().map(LabelRef::Tx),
+			"addr" => Some(LabelRef::Address(reference.to_owned())),
+			"input" => parse_outpoint(reference).map(LabelRef::Input),
+			"output" => parse_outpoint(reference).map(LabelRef::Output),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for LabelRef {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			LabelRef::Tx(ref txid) => write!(f, "{}", txid),
+			LabelRef::Address(ref addr) => write!(f, "{}", addr),
+			LabelRef::Input(ref o) | LabelRef::Output(ref o) => write!(f, "{}:{}", o.txid, o.vout),
+		}
+	}
+}
+
+/// Parse an outpoint in the `txid:vout` format.
+fn parse_outpoint(s: &str) -> Option<OutPoint> {
+	let mut parts = s.splitn(2, ':');
+	let txid = sha256d::Hash::from_hex(parts.next()?).ok()?;
+	let vout = parts.next()?.parse().ok()?;
+	Some(OutPoint {
+		txid: txid,
+		vout: vout,
+	})
+}
+
+/// A label with the optional extra information from BIP-329.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Label {
+	pub label: String,
+	/// The key origin of the labeled object, in descriptor format.
+	pub origin: Option<String>,
+	/// For outputs, whether the output is considered spendable.
+	pub spendable: Option<bool>,
+}
+
+impl Label {
+	pub fn new(label: String) -> Label {
+		Label {
+			label: label,
+			origin: None,
+			spendable: None,
+		}
+	}
+}
+
+/// A single line of a BIP-329 export.
+#[derive(Serialize, Deserialize)]
+struct Bip329Record {
+	#[serde(rename = "type")]
+	tp: String,
+	#[serde(rename = "ref")]
+	reference: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	label: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	origin: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	spendable: Option<bool>,
+}
+
+/// Serialize the labels ordered by the objects they are attached to.
+fn serialize_sorted<S: Serializer>(
+	labels: &HashMap<LabelRef, Label>,
+	serializer: S,
+) -> ::std::result::Result<S::Ok, S::Error> {
+	serializer.collect_map(sorted(labels))
+}
+
+/// The labels ordered by the objects they are attached to.
+fn sorted(labels: &HashMap<LabelRef, Label>) -> Vec<(&LabelRef, &Label)> {
+	let mut sorted: Vec<(&LabelRef, &Label)> = labels.iter().collect();
+	sorted.sort_by_key(|(r, _)| (r.bip329_type(), r.to_string()));
+	sorted
+}
+
+/// A collection of labels.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Labels {
+	#[serde(serialize_with = "serialize_sorted")]
+	labels: HashMap<LabelRef, Label>,
+}
+
+impl Labels {
+	pub fn new() -> Labels {
+		Default::default()
+	}
+
+	/// Set a label, replacing an existing label on the same object.
+	pub fn set(&mut self, reference: LabelRef, label: Label) -> Option<Label> {
+		self.labels.insert(reference, label)
+	}
+
+	pub fn get(&self, reference: &LabelRef) -> Option<&Label> {
+		self.labels.get(reference)
+	}
+
+	pub fn remove(&mut self, reference: &LabelRef) -> Option<Label> {
+		self.labels.remove(reference)
+	}
+
+	/// Find all objects with the given label.
+	pub fn find(&self, label: &str) -> Vec<&LabelRef> {
+		self.labels.iter().filter(|(_, l)| l.label == label).map(|(r, _)| r).collect()
+	}
+
+	pub fn len(&self) -> usize {
+		self.labels.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.labels.is_empty()
+	}
+
+	/// Export all labels in the BIP-329 JSON Lines format.
+	pub fn export_bip329(&self) -> String {
+		let mut ret = String::new();
+		for (reference, label) in sorted(&self.labels) {
+			let record = Bip329Record {
+				tp: reference.bip329_type().to_owned(),
+				reference: reference.to_string(),
+				label: Some(label.label.clone()),
+				origin: label.origin.clone(),
+				spendable: label.spendable,
+			};
+			ret.push_str(&serde_json::to_string(&record).expect("serializing can't fail"));
+			ret.push('\n');
+		}
+		ret
+	}
+
+	/// Import labels in the BIP-329 JSON Lines format.
+	/// Records of types we don't support and records without label are skipped.
+	/// Existing labels on the same objects are replaced.
+	///
+	/// Returns the number of imported labels.
+	pub fn import_bip329(&mut self, data: &str) -> Result<usize> {
+		let mut records = Vec::new();
+		for (idx, line) in data.lines().enumerate() {
+			if line.trim().is_empty() {
+				continue;
+			}
+			let record: Bip329Record =
+				serde_json::from_str(line).map_err(|_| Error::InvalidLabelRecord(idx + 1))?;
+			let label = match record.label {
+				Some(label) => label,
+				None => continue,
+			};
+			let reference = match record.tp.as_str() {
+				"tx" | "addr" | "input" | "output" => {
+					LabelRef::from_bip329(&record.tp, &record.reference)
+						.ok_or(Error::InvalidLabelRecord(idx + 1))?
+				}
+				_ => continue,
+			};
+			records.push((reference, Label {
+				label: label,
+				origin: record.origin,
+				spendable: record.spendable,
+			}));
+		}
+
+		// Only apply when all records are valid.
+		let nb = records.len();
+		for (reference, label) in records {
+			self.labels.insert(reference, label);
+		}
+		Ok(nb)
+	}
+}
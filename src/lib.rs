@@ -17,7 +17,6 @@
 //!
 
 #![crate_name = "bitcoin_wallet"]
-#![crate_type = "dylib"]
 #![crate_type = "rlib"]
 
 // Coding conventions
@@ -27,6 +26,7 @@
 #![deny(non_snake_case)]
 #![deny(unused_mut)]
 
+extern crate base64;
 extern crate bitcoin;
 extern crate bitcoin_hashes;
 extern crate byteorder;
@@ -36,11 +36,52 @@ extern crate lazy_static;
 extern crate rand;
 extern crate secp256k1;
 extern crate serde;
+extern crate serde_json;
 
 #[cfg(feature="bitcoinconsensus")] extern crate bitcoinconsensus;
+#[cfg(feature="bitcoind")] extern crate bitcoincore_rpc;
+#[cfg(feature="async")] extern crate futures;
+#[cfg(feature="parallel")] extern crate rayon;
+#[cfg(feature="mempool-space")] extern crate ureq;
 
+pub mod accounting;
+pub mod amount;
+pub mod audit;
+pub mod bip322;
+pub mod blocks;
+pub mod coinjoin;
+pub mod compact_block;
 pub mod config;
+pub mod descriptor;
 pub mod error;
+pub mod events;
+pub mod fee;
+pub mod fee_estimator;
+pub mod headers;
+pub mod history;
+pub mod labels;
+pub mod musig;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+#[cfg(feature = "notify")]
+pub mod notify;
+#[cfg(feature = "p2p")]
+pub mod p2p;
+pub mod payjoin;
+pub mod proof_of_reserves;
+pub mod pubkey_cache;
+pub mod qr;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod signer;
+pub mod silent_payments;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod simple;
+pub mod sync;
+pub mod sync_wallet;
+pub mod taproot;
+pub mod tx_builder;
+pub mod uri;
 pub mod wallet;
 
 
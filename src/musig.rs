@@ -0,0 +1,306 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Taproot wallets with MuSig2 shared keys (BIP-327).
+//!
+//! The keys of a [MusigWallet] are the aggregation of the keys of all
+//! participants at the same derivation path, sorted by [key_agg]. Its outputs
+//! are taproot outputs that can only be spent with the key path.
+//!
+//! Signing is interactive: every participant adds a public nonce to the PSBT,
+//! and when all nonces are known, a partial signature. The PSBT fields for
+//! this are those of BIP-373, see [set_pub_nonce] and [set_partial_sig].
+//! Generating nonces and signatures is left to the signers.
+
+use std::collections::HashMap;
+
+use bitcoin::util::bip32;
+use bitcoin::util::psbt::{self, raw};
+use bitcoin::{Network, Script};
+use secp256k1::PublicKey;
+
+use error::{Error, Result};
+use taproot;
+
+// The PSBT field types of BIP-371 and BIP-373.
+const PSBT_IN_TAP_INTERNAL_KEY: u8 = 0x17;
+const PSBT_IN_MUSIG2_PARTICIPANT_PUBKEYS: u8 = 0x1a;
+const PSBT_IN_MUSIG2_PUB_NONCE: u8 = 0x1b;
+const PSBT_IN_MUSIG2_PARTIAL_SIG: u8 = 0x1c;
+const PSBT_OUT_TAP_INTERNAL_KEY: u8 = 0x05;
+const PSBT_OUT_MUSIG2_PARTICIPANT_PUBKEYS: u8 = 0x08;
+
+/// Sort the keys by their serialization (KeySort).
+pub fn sort_keys(keys: &mut [PublicKey]) {
+	keys.sort_by_key(|k| k.serialize().to_vec());
+}
+
+/// Aggregate the keys in the given order (KeyAgg), without tweaks.
+pub fn key_agg(keys: &[PublicKey]) -> Result<PublicKey> {
+	if keys.is_empty() {
+		return Err(Error::InvalidMusig("no keys to aggregate"));
+	}
+	let mut serialized = Vec::with_capacity(keys.len() * 33);
+	for key in keys {
+		serialized.extend_from_slice(&key.serialize());
+	}
+	let list_hash = taproot::tagged_hash("KeyAgg list", &serialized);
+	// The first key that differs from the first key gets coefficient 1.
+	let second = keys.iter().find(|k| *k != &keys[0]);
+
+	let mut agg: Option<PublicKey> = None;
+	for key in keys {
+		let mut point = *key;
+		if Some(key) != second {
+			let mut data = list_hash.to_vec();
+			data.extend_from_slice(&key.serialize());
+			let coefficient = taproot::tagged_hash("KeyAgg coefficient", &data);
+			point.mul_assign(&::SECP, &coefficient)?;
+		}
+		agg = Some(match agg {
+			Some(agg) => agg.combine(&point)?,
+			None => point,
+		});
+	}
+	Ok(agg.expect("not empty"))
+}
+
+/// The extended public key of a participant, with the fingerprint of its
+/// master key and the path from the master key to it.
+#[derive(Debug, Clone)]
+pub struct Participant {
+	pub xpub: bip32::ExtendedPubKey,
+	pub master_fp: bip32::Fingerprint,
+	pub origin_path: bip32::DerivationPath,
+}
+
+/// A wallet of which every key is shared by the participants with MuSig2.
+#[derive(Debug, Clone)]
+pub struct MusigWallet {
+	participants: Vec<Participant>,
+	network: Network,
+}
+
+impl MusigWallet {
+	/// Needs at least two participants.
+	pub fn new(participants: Vec<Participant>, network: Network) -> Result<MusigWallet> {
+		if participants.len() < 2 {
+			return Err(Error::InvalidMusig("needs at least two participants"));
+		}
+		if participants.iter().any(|p| p.xpub.network != network) {
+			return Err(Error::WrongNetwork);
+		}
+		Ok(MusigWallet {
+			participants: participants,
+			network: network,
+		})
+	}
+
+	pub fn participants(&self) -> &[Participant] {
+		&self.participants
+	}
+
+	/// The keys of the participants at the path from their xpubs, sorted.
+	pub fn participant_keys(&self, path: &[bip32::ChildNumber]) -> Result<Vec<PublicKey>> {
+		let mut keys = Vec::with_capacity(self.participants.len());
+		for participant in &self.participants {
+			keys.push(participant.xpub.derive_pub(&::SECP, &path)?.public_key.key);
+		}
+		sort_keys(&mut keys);
+		Ok(keys)
+	}
+
+	/// The aggregate key at the path, the internal key of the taproot output.
+	pub fn aggregate_key(&self, path: &[bip32::ChildNumber]) -> Result<PublicKey> {
+		key_agg(&self.participant_keys(path)?)
+	}
+
+	/// The output key of the taproot output at the path.
+	pub fn output_key(&self, path: &[bip32::ChildNumber]) -> Result<PublicKey> {
+		taproot::output_key(&self.aggregate_key(path)?)
+	}
+
+	pub fn script_pubkey(&self, path: &[bip32::ChildNumber]) -> Result<Script> {
+		Ok(taproot::script_pubkey(&self.output_key(path)?))
+	}
+
+	/// The bech32m address of the taproot output at the path.
+	pub fn address(&self, path: &[bip32::ChildNumber]) -> Result<String> {
+		Ok(taproot::address(&self.output_key(path)?, self.network))
+	}
+
+	/// The aggregate key at the path and the serialized participant keys.
+	fn aggregate_with_keys(&self, path: &[bip32::ChildNumber]) -> Result<(PublicKey, Vec<u8>)> {
+		let keys = self.participant_keys(path)?;
+		let serialized = keys.iter().flat_map(|k| k.serialize().to_vec()).collect();
+		Ok((key_agg(&keys)?, serialized))
+	}
+
+	/// Add the key origins of the participants to the map.
+	fn add_keypaths(
+		&self,
+		keypaths: &mut HashMap<::bitcoin::PublicKey, (bip32::Fingerprint, bip32::DerivationPath)>,
+		path: &[bip32::ChildNumber],
+	) -> Result<()> {
+		for participant in &self.participants {
+			let key = participant.xpub.derive_pub(&::SECP, &path)?.public_key;
+			let full_path = path.iter().fold(participant.origin_path.clone(), |p, c| p.child(*c));
+			keypaths.insert(key, (participant.master_fp, full_path));
+		}
+		Ok(())
+	}
+
+	/// Set the data the participants need to sign the input spending the
+	/// output at the path: the internal key, the participant keys and their
+	/// origins.
+	pub fn update_psbt_input(
+		&self,
+		input: &mut psbt::Input,
+		path: &[bip32::ChildNumber],
+	) -> Result<()> {
+		let (agg, keys) = self.aggregate_with_keys(path)?;
+		input.unknown.insert(key(PSBT_IN_TAP_INTERNAL_KEY, &[]), agg.serialize()[1..].to_vec());
+		input.unknown.insert(key(PSBT_IN_MUSIG2_PARTICIPANT_PUBKEYS, &agg.serialize()), keys);
+		self.add_keypaths(&mut input.hd_keypaths, path)
+	}
+
+	/// Set the data of the output at the path, so that signers can recognize
+	/// it as a change output.
+	pub fn update_psbt_output(
+		&self,
+		output: &mut psbt::Output,
+		path: &[bip32::ChildNumber],
+	) -> Result<()> {
+		let (agg, keys) = self.aggregate_with_keys(path)?;
+		output.unknown.insert(key(PSBT_OUT_TAP_INTERNAL_KEY, &[]), agg.serialize()[1..].to_vec());
+		output.unknown.insert(key(PSBT_OUT_MUSIG2_PARTICIPANT_PUBKEYS, &agg.serialize()), keys);
+		self.add_keypaths(&mut output.hd_keypaths, path)
+	}
+}
+
+fn key(type_value: u8, key: &[u8]) -> raw::Key {
+	raw::Key {
+		type_value: type_value,
+		key: key.to_vec(),
+	}
+}
+
+/// The key of a field of a participant for the aggregate key.
+fn participant_key(type_value: u8, participant: &PublicKey, aggregate: &PublicKey) -> raw::Key {
+	let mut data = participant.serialize().to_vec();
+	data.extend_from_slice(&aggregate.serialize());
+	key(type_value, &data)
+}
+
+/// The participants of the aggregate key, as set in the input.
+pub fn psbt_participants(input: &psbt::Input, aggregate: &PublicKey) -> Result<Vec<PublicKey>> {
+	let field = key(PSBT_IN_MUSIG2_PARTICIPANT_PUBKEYS, &aggregate.serialize());
+	let value = match input.unknown.get(&field) {
+		Some(value) if value.len() % 33 == 0 => value,
+		Some(_) => return Err(Error::InvalidMusig("invalid participant keys")),
+		None => return Ok(Vec::new()),
+	};
+	let mut keys = Vec::with_capacity(value.len() / 33);
+	for chunk in value.chunks(33) {
+		keys.push(PublicKey::from_slice(chunk)?);
+	}
+	Ok(keys)
+}
+
+/// Set the public nonce of the participant for signing with the aggregate key.
+pub fn set_pub_nonce(
+	input: &mut psbt::Input,
+	participant: &PublicKey,
+	aggregate: &PublicKey,
+	nonce: &[u8; 66],
+) {
+	let key = participant_key(PSBT_IN_MUSIG2_PUB_NONCE, participant, aggregate);
+	input.unknown.insert(key, nonce.to_vec());
+}
+
+/// The public nonce of the participant for signing with the aggregate key.
+pub fn pub_nonce(
+	input: &psbt::Input,
+	participant: &PublicKey,
+	aggregate: &PublicKey,
+) -> Option<[u8; 66]> {
+	let key = participant_key(PSBT_IN_MUSIG2_PUB_NONCE, participant, aggregate);
+	let value = input.unknown.get(&key).filter(|v| v.len() == 66)?;
+	let mut nonce = [0; 66];
+	nonce.copy_from_slice(value);
+	Some(nonce)
+}
+
+/// Set the partial signature of the participant with the aggregate key.
+pub fn set_partial_sig(
+	input: &mut psbt::Input,
+	participant: &PublicKey,
+	aggregate: &PublicKey,
+	sig: &[u8; 32],
+) {
+	let key = participant_key(PSBT_IN_MUSIG2_PARTIAL_SIG, participant, aggregate);
+	input.unknown.insert(key, sig.to_vec());
+}
+
+/// The partial signature of the participant with the aggregate key.
+pub fn partial_sig(
+	input: &psbt::Input,
+	participant: &PublicKey,
+	aggregate: &PublicKey,
+) -> Option<[u8; 32]> {
+	let key = participant_key(PSBT_IN_MUSIG2_PARTIAL_SIG, participant, aggregate);
+	let value = input.unknown.get(&key).filter(|v| v.len() == 32)?;
+	let mut sig = [0; 32];
+	sig.copy_from_slice(value);
+	Some(sig)
+}
+
+/// Whether all participants of the aggregate key added a public nonce to the
+/// input, so that they can start signing.
+pub fn has_all_nonces(input: &psbt::Input, aggregate: &PublicKey) -> Result<bool> {
+	let keys = psbt_participants(input, aggregate)?;
+	Ok(!keys.is_empty() && keys.iter().all(|k| pub_nonce(input, k, aggregate).is_some()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use hex;
+
+	#[test]
+	fn key_agg_vectors() {
+		// The valid KeyAgg test vectors of BIP-327.
+		let keys: Vec<PublicKey> = [
+			"02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+			"03dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659",
+			"023590a94e768f8e1815c2f24b4d80a8e3149316c3518ce7b7ad338368d038ca66",
+		]
+		.iter()
+		.map(|k| PublicKey::from_slice(&hex::decode(k).unwrap()).unwrap())
+		.collect();
+		let vectors = [
+			(vec![0, 1, 2], "90539eede565f5d054f32cc0c220126889ed1e5d193baf15aef344fe59d4610c"),
+			(vec![2, 1, 0], "6204de8b083426dc6eaf9502d27024d53fc826bf7d2012148a0575435df54b2b"),
+			(vec![0, 0, 0], "b436e3bad62b8cd409969a224731c193d051162d8c5ae8b109306127da3aa935"),
+			(vec![0, 0, 1, 1], "69bc22bfa5d106306e48a20679de1d7389386124d07571d0d872686028c26a3e"),
+		];
+		for &(ref indices, expected) in vectors.iter() {
+			let list: Vec<PublicKey> = indices.iter().map(|i| keys[*i]).collect();
+			let agg = key_agg(&list).unwrap();
+			assert_eq!(hex::encode(&agg.serialize()[1..]), expected);
+		}
+		assert!(key_agg(&[]).is_err());
+	}
+}
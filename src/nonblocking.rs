@@ -0,0 +1,191 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Futures-based variants of the backend traits and the sync driver in [sync],
+//! for use in event loops like tokio. Only available with the `async` feature.
+//!
+//! The wallet is shared as an `Arc<Mutex<Wallet>>`. The lock is only held
+//! while a block is processed, never while waiting for the backend.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bitcoin::{Block, Transaction};
+use bitcoin_hashes::sha256d;
+use futures::future::{self, Loop};
+use futures::Future;
+
+use error::Error;
+use wallet::Wallet;
+
+/// A boxed future failing with the crate's error.
+pub type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+/// The futures-based variant of [sync::BlockSource].
+pub trait AsyncBlockSource: Send + Sync + 'static {
+	/// The height and hash of the tip of the best chain.
+	fn tip(&self) -> BoxFuture<(u32, sha256d::Hash)>;
+
+	/// The hash of the block at the height in the best chain.
+	/// Resolves to [None] for heights above the tip.
+	fn block_hash(&self, height: u32) -> BoxFuture<Option<sha256d::Hash>>;
+
+	fn block(&self, hash: &sha256d::Hash) -> BoxFuture<Block>;
+}
+
+/// The futures-based variant of [sync::Broadcaster].
+pub trait AsyncBroadcaster: Send + Sync + 'static {
+	/// The name of the backend in the recorded broadcasts.
+	fn name(&self) -> String;
+
+	/// Resolves to the txid as reported by the backend.
+	fn broadcast(&self, tx: &Transaction) -> BoxFuture<sha256d::Hash>;
+}
+
+fn lock(wallet: &Mutex<Wallet>) -> MutexGuard<Wallet> {
+	wallet.lock().expect("wallet lock poisoned")
+}
+
+/// Broadcast the pending tx and record the broadcast in the wallet.
+pub fn broadcast_pending<B: AsyncBroadcaster>(
+	wallet: Arc<Mutex<Wallet>>,
+	broadcaster: &B,
+	txid: sha256d::Hash,
+) -> BoxFuture<()> {
+	let broadcast = match lock(&wallet).get_pending_transaction(txid) {
+		Some(tx) => broadcaster.broadcast(tx),
+		None => return Box::new(future::err(Error::TxNotPending)),
+	};
+	let name = broadcaster.name();
+	Box::new(broadcast.then(move |result| {
+		let message = result.as_ref().err().map(|e| e.to_string());
+		lock(&wallet).record_broadcast(txid, name, result.is_ok(), message)?;
+		result.map(|_| ())
+	}))
+}
+
+/// Disconnect the blocks of the wallet that are not in the source's chain,
+/// like [sync::disconnect_stale_blocks]. Resolves to the height of the last
+/// block in common.
+///
+/// The fork point is searched for with [Wallet::fork_point] using the hashes
+/// of the source fetched so far. When the search needs another hash, it is
+/// fetched and the search is repeated.
+fn disconnect_stale_blocks<S: AsyncBlockSource>(
+	wallet: Arc<Mutex<Wallet>>,
+	source: Arc<S>,
+) -> BoxFuture<u32> {
+	type Hashes = HashMap<u32, Option<sha256d::Hash>>;
+	Box::new(future::loop_fn(Hashes::new(), move |mut hashes| -> BoxFuture<Loop<u32, Hashes>> {
+		let missing = Cell::new(None);
+		{
+			let mut wallet = lock(&wallet);
+			if wallet.last_block().is_none() {
+				return Box::new(future::err(Error::WalletNotFullyInitialized));
+			}
+			let fork_point = wallet.fork_point(|height| match hashes.get(&height) {
+				Some(hash) => Ok(*hash),
+				None => {
+					missing.set(Some(height));
+					Err(Error::UnknownBlock)
+				}
+			});
+			if missing.get().is_none() {
+				let result = match fork_point {
+					Ok(Some(common)) => wallet.rollback_to(common.height).map(|_| common.height),
+					Ok(None) => Err(Error::UnknownBlock),
+					Err(e) => Err(e),
+				};
+				return Box::new(future::result(result.map(Loop::Break)));
+			}
+		}
+
+		let height = missing.get().expect("checked");
+		Box::new(source.block_hash(height).map(move |hash| {
+			hashes.insert(height, hash);
+			Loop::Continue(hashes)
+		}))
+	}))
+}
+
+/// Process the blocks after [start] up to [tip_height].
+/// Resolves to the height of the last processed block and whether processing
+/// stopped at a block that doesn't follow on the last one.
+fn process_new_blocks<S, P>(
+	wallet: Arc<Mutex<Wallet>>,
+	source: Arc<S>,
+	start: u32,
+	tip_height: u32,
+	progress: Arc<Mutex<P>>,
+) -> BoxFuture<(u32, bool)>
+where
+	S: AsyncBlockSource,
+	P: FnMut(u32, u32) + Send + 'static,
+{
+	type Step = Loop<(u32, bool), u32>;
+	Box::new(future::loop_fn(start, move |height| -> BoxFuture<Step> {
+		if height >= tip_height {
+			return Box::new(future::ok(Loop::Break((height, false))));
+		}
+		let (wallet, source2, progress) = (wallet.clone(), source.clone(), progress.clone());
+		Box::new(source.block_hash(height + 1).and_then(move |hash| -> BoxFuture<Step> {
+			let hash = match hash {
+				Some(hash) => hash,
+				None => return Box::new(future::ok(Loop::Break((height, false)))),
+			};
+			Box::new(source2.block(&hash).and_then(move |block| {
+				match lock(&wallet).process_block(&block) {
+					Ok(()) => {}
+					// The block is not on top of our last block anymore.
+					Err(Error::BlockFork) => return Ok(Loop::Break((height, true))),
+					Err(e) => return Err(e),
+				}
+				let mut progress = progress.lock().expect("progress lock poisoned");
+				(&mut *progress)(height + 1, tip_height);
+				Ok(Loop::Continue(height + 1))
+			}))
+		}))
+	}))
+}
+
+/// The futures-based variant of [sync::sync_to_tip].
+pub fn sync_to_tip<S, P>(wallet: Arc<Mutex<Wallet>>, source: Arc<S>, progress: P) -> BoxFuture<u32>
+where
+	S: AsyncBlockSource,
+	P: FnMut(u32, u32) + Send + 'static,
+{
+	let progress = Arc::new(Mutex::new(progress));
+	// Whether the last round stopped at a forked block, see [sync::sync_to_tip].
+	Box::new(future::loop_fn(false, move |retried_fork| {
+		let (wallet, source, progress) = (wallet.clone(), source.clone(), progress.clone());
+		source.tip().and_then(move |(tip_height, tip_hash)| {
+			let (w, s) = (wallet.clone(), source.clone());
+			disconnect_stale_blocks(wallet.clone(), source).and_then(move |start| {
+				process_new_blocks(w, s, start, tip_height, progress).and_then(
+					move |(height, forked)| {
+						if forked && retried_fork && height == start {
+							let msg = "inconsistent block source".to_owned();
+							return Err(Error::Backend(msg));
+						}
+						Ok(match lock(&wallet).last_block().map(|b| b.hash) {
+							Some(hash) if hash == tip_hash => Loop::Break(height),
+							_ => Loop::Continue(forked),
+						})
+					},
+				)
+			})
+		})
+	}))
+}
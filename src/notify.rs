@@ -0,0 +1,230 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Adapters that forward wallet events to external services.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::{hmac, sha256, Hash, HashEngine};
+use serde::Serialize;
+use serde_json;
+
+use events::WalletEvent;
+use wallet::Wallet;
+
+/// Something that can deliver wallet events.
+pub trait Notifier {
+	fn notify(&mut self, event: &WalletEvent) -> io::Result<()>;
+}
+
+/// Take all queued events from the wallet and deliver them using the notifier.
+/// When delivery fails, the undelivered events are put back into the wallet.
+///
+/// Returns the number of delivered events.
+pub fn forward_events<N: Notifier>(wallet: &mut Wallet, notifier: &mut N) -> io::Result<usize> {
+	let events = wallet.take_events();
+	for (idx, event) in events.iter().enumerate() {
+		if let Err(e) = notifier.notify(event) {
+			wallet.restore_events(events[idx..].to_vec());
+			return Err(e);
+		}
+	}
+	Ok(events.len())
+}
+
+/// A way to make HTTP POST requests.
+pub trait HttpPost {
+	/// Post the body to the URL and return the HTTP status code.
+	fn post(&mut self, url: &str, headers: &[(&str, String)], body: &[u8]) -> io::Result<u16>;
+}
+
+/// A minimal HTTP/1.1 client for plain `http://` URLs.
+/// Use a custom [HttpPost] implementation for TLS.
+#[derive(Debug, Default)]
+pub struct PlainHttp;
+
+impl HttpPost for PlainHttp {
+	fn post(&mut self, url: &str, headers: &[(&str, String)], body: &[u8]) -> io::Result<u16> {
+		let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid http URL");
+		let rest = if url.starts_with("http://") {
+			&url[7..]
+		} else {
+			return Err(invalid());
+		};
+		let (host, path) = match rest.find('/') {
+			Some(idx) => (&rest[..idx], &rest[idx..]),
+			None => (rest, "/"),
+		};
+		if host.is_empty() {
+			return Err(invalid());
+		}
+		let addr = if host.contains(':') {
+			host.to_owned()
+		} else {
+			format!("{}:80", host)
+		};
+
+		let mut stream = TcpStream::connect(addr)?;
+		let mut request = format!(
+			"POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+			path,
+			host,
+			body.len()
+		);
+		for (name, value) in headers {
+			request.push_str(&format!("{}: {}\r\n", name, value));
+		}
+		request.push_str("\r\n");
+		stream.write_all(request.as_bytes())?;
+		stream.write_all(body)?;
+		stream.flush()?;
+
+		let mut response = String::new();
+		stream.read_to_string(&mut response)?;
+		response
+			.split_whitespace()
+			.nth(1)
+			.and_then(|s| s.parse().ok())
+			.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response"))
+	}
+}
+
+/// Posts every event as JSON to a webhook URL.
+///
+/// The body is authenticated with an HMAC-SHA256 using a shared secret,
+/// sent in hex in the `X-Wallet-Signature` header.
+pub struct WebhookNotifier<H: HttpPost> {
+	url: String,
+	secret: Vec<u8>,
+	http: H,
+}
+
+impl<H: HttpPost> WebhookNotifier<H> {
+	pub fn new(url: String, secret: Vec<u8>, http: H) -> WebhookNotifier<H> {
+		WebhookNotifier {
+			url: url,
+			secret: secret,
+			http: http,
+		}
+	}
+
+	/// Calculate the signature of a payload.
+	pub fn sign(&self, payload: &[u8]) -> String {
+		let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&self.secret);
+		engine.input(payload);
+		hmac::Hmac::<sha256::Hash>::from_engine(engine).into_inner()[..].to_hex()
+	}
+}
+
+impl<H: HttpPost> Notifier for WebhookNotifier<H> {
+	fn notify(&mut self, event: &WalletEvent) -> io::Result<()> {
+		let body = serde_json::to_vec(event).expect("serializing can't fail");
+		let headers = [
+			("Content-Type", "application/json".to_owned()),
+			("X-Wallet-Signature", format!("sha256={}", self.sign(&body))),
+		];
+		let status = self.http.post(&self.url, &headers, &body)?;
+		if status < 200 || status >= 300 {
+			return Err(io::Error::new(
+				io::ErrorKind::Other,
+				format!("webhook responded with status {}", status),
+			));
+		}
+		Ok(())
+	}
+}
+
+/// Signs Nostr events. Nostr uses BIP-340 signatures, which are not
+/// available in our secp256k1 version, so signing is left to the application.
+pub trait NostrSigner {
+	/// The x-only public key in hex.
+	fn public_key(&self) -> String;
+	/// Create a BIP-340 signature in hex over the 32-byte event id.
+	fn sign(&self, id: &[u8; 32]) -> String;
+}
+
+/// A connection to a Nostr relay.
+pub trait NostrRelay {
+	/// Publish a signed event, serialized in JSON.
+	fn publish(&mut self, event: &str) -> io::Result<()>;
+}
+
+/// A Nostr event as defined in NIP-01.
+#[derive(Serialize)]
+struct NostrEvent<'a> {
+	id: String,
+	pubkey: &'a str,
+	created_at: u64,
+	kind: u32,
+	tags: &'a [Vec<String>],
+	content: &'a str,
+	sig: String,
+}
+
+/// Publishes every event as a Nostr event with the wallet event in JSON as content.
+///
+/// Keep in mind that the content is not encrypted, so use a private relay.
+pub struct NostrNotifier<S: NostrSigner, R: NostrRelay> {
+	signer: S,
+	relay: R,
+	/// The kind of the Nostr events.
+	kind: u32,
+	/// The public keys to tag in every event, in hex.
+	recipients: Vec<String>,
+}
+
+impl<S: NostrSigner, R: NostrRelay> NostrNotifier<S, R> {
+	pub fn new(signer: S, relay: R, kind: u32, recipients: Vec<String>) -> NostrNotifier<S, R> {
+		NostrNotifier {
+			signer: signer,
+			relay: relay,
+			kind: kind,
+			recipients: recipients,
+		}
+	}
+
+	/// Create the signed Nostr event in JSON, following NIP-01.
+	pub fn create_event(&self, event: &WalletEvent, created_at: u64) -> String {
+		let content = serde_json::to_string(event).expect("serializing can't fail");
+		let pubkey = self.signer.public_key();
+		let tags: Vec<Vec<String>> =
+			self.recipients.iter().map(|r| vec!["p".to_owned(), r.clone()]).collect();
+
+		let commitment = (0, &pubkey, created_at, self.kind, &tags, &content);
+		let serialized = serde_json::to_string(&commitment).expect("serializing can't fail");
+		let id = sha256::Hash::hash(serialized.as_bytes()).into_inner();
+
+		let nostr_event = NostrEvent {
+			id: id[..].to_hex(),
+			pubkey: &pubkey,
+			created_at: created_at,
+			kind: self.kind,
+			tags: &tags,
+			content: &content,
+			sig: self.signer.sign(&id),
+		};
+		serde_json::to_string(&nostr_event).expect("serializing can't fail")
+	}
+}
+
+impl<S: NostrSigner, R: NostrRelay> Notifier for NostrNotifier<S, R> {
+	fn notify(&mut self, event: &WalletEvent) -> io::Result<()> {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("time went backwards");
+		let nostr_event = self.create_event(event, now.as_secs());
+		self.relay.publish(&nostr_event)
+	}
+}
@@ -0,0 +1,583 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! A minimal light client of the Bitcoin P2P network.
+//!
+//! A [Peer] is a connection to a full node that serves compact block filters
+//! (BIP-157). [sync] validates the peer's headers into a [HeaderChain] and
+//! uses the BIP-158 filters to only download the blocks that are relevant to
+//! the wallet, so no trusted server is needed.
+//!
+//! Every filter is checked against the filter headers (`cfheaders`) of the
+//! same peer before it is used. The filter headers are not compared with
+//! those of other peers though, so a peer can still hide txs from the wallet
+//! by serving a consistent chain of wrong filters. Downloaded blocks are
+//! checked against their headers, so a peer can't make up txs.
+
+use std::io::{Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::consensus::encode::{deserialize, serialize, Decodable, Encodable, VarInt};
+use bitcoin::util::hash::bitcoin_merkle_root;
+use bitcoin::{BitcoinHash, Block, BlockHeader, Script, Transaction};
+use bitcoin_hashes::{sha256d, Hash};
+use byteorder::{ByteOrder, LittleEndian};
+use rand;
+
+use compact_block::siphash24;
+use config::Chain;
+use error::{Error, Result};
+use headers::HeaderChain;
+use sync::Broadcaster;
+use wallet::Wallet;
+
+/// The protocol version we speak, the first one with `sendcmpct`.
+const PROTOCOL_VERSION: u32 = 70015;
+const USER_AGENT: &'static str = "/bitcoin-wallet:0.1.0/";
+/// The service flag of nodes serving witness data.
+const NODE_WITNESS: u64 = 1 << 3;
+/// The service flag of nodes serving compact block filters.
+const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+/// The inventory type of a block with witness data.
+const MSG_WITNESS_BLOCK: u32 = 0x40000002;
+/// The maximum number of headers in a `headers` message.
+const MAX_HEADERS: usize = 2000;
+/// The maximum number of filters requested with one `getcfilters` message.
+const MAX_FILTERS: u32 = 1000;
+/// The maximum number of filter hashes in a `cfheaders` message.
+const MAX_FILTER_HASHES: usize = 2000;
+/// The largest message we accept, a bit above the maximum block size.
+const MAX_MESSAGE_SIZE: usize = 8 * 1000 * 1000;
+/// How long to wait for the peer before giving up.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The type of the basic BIP-158 filter.
+const BASIC_FILTER: u8 = 0x00;
+/// The Golomb-Rice parameter of the basic filter.
+const FILTER_P: u8 = 19;
+/// The inverse false positive rate of the basic filter.
+const FILTER_M: u64 = 784931;
+
+fn io_error(e: ::std::io::Error) -> Error {
+	Error::Backend(e.to_string())
+}
+
+/// Decode the next value of a payload.
+fn decode<T: Decodable>(cursor: &mut Cursor<&[u8]>) -> Result<T> {
+	T::consensus_decode(cursor).map_err(|_| Error::InvalidPeerMessage("malformed payload"))
+}
+
+/// Append the encoded value to a payload.
+fn push<T: Encodable + ?Sized>(payload: &mut Vec<u8>, value: &T) {
+	payload.extend(serialize(value));
+}
+
+/// A connection to a full node.
+#[derive(Debug)]
+pub struct Peer {
+	stream: TcpStream,
+	/// The magic bytes of the chain's messages.
+	magic: u32,
+	/// The height of the peer's chain when we connected.
+	start_height: u32,
+}
+
+impl Peer {
+	/// Connect to the node and do the version handshake. Fails for nodes
+	/// that don't serve witness data and compact block filters.
+	pub fn connect(address: &SocketAddr, chain: Chain) -> Result<Peer> {
+		let stream = TcpStream::connect_timeout(address, TIMEOUT).map_err(io_error)?;
+		stream.set_read_timeout(Some(TIMEOUT)).map_err(io_error)?;
+		stream.set_write_timeout(Some(TIMEOUT)).map_err(io_error)?;
+		let mut peer = Peer {
+			stream: stream,
+			magic: chain.magic(),
+			start_height: 0,
+		};
+
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+		let mut version = Vec::new();
+		push(&mut version, &PROTOCOL_VERSION);
+		push(&mut version, &0u64); // We don't serve anything.
+		push(&mut version, &(timestamp.as_secs() as i64));
+		// The network addresses of the peer and us, which nodes ignore.
+		version.extend_from_slice(&[0; 26 * 2]);
+		push(&mut version, &rand::random::<u64>());
+		push(&mut version, &USER_AGENT.to_owned());
+		push(&mut version, &0i32);
+		push(&mut version, &false); // Don't relay txs to us.
+		peer.send("version", &version)?;
+
+		let (mut got_version, mut got_verack) = (false, false);
+		while !got_version || !got_verack {
+			let (command, payload) = peer.receive()?;
+			match command.as_str() {
+				"version" => {
+					peer.handle_version(&payload)?;
+					peer.send("verack", &[])?;
+					got_version = true;
+				}
+				"verack" => got_verack = true,
+				_ => {}
+			}
+		}
+		Ok(peer)
+	}
+
+	fn handle_version(&mut self, payload: &[u8]) -> Result<()> {
+		let mut cursor = Cursor::new(payload);
+		let version: u32 = decode(&mut cursor)?;
+		let services: u64 = decode(&mut cursor)?;
+		if version < PROTOCOL_VERSION {
+			return Err(Error::InvalidPeerMessage("protocol version too old"));
+		}
+		let needed = NODE_WITNESS | NODE_COMPACT_FILTERS;
+		if services & needed != needed {
+			return Err(Error::InvalidPeerMessage("peer doesn't serve filters"));
+		}
+		// Skip the timestamp, the network addresses and the nonce.
+		cursor.set_position(cursor.position() + 8 + 26 * 2 + 8);
+		let _user_agent: String = decode(&mut cursor)?;
+		let start_height: i32 = decode(&mut cursor)?;
+		self.start_height = start_height as u32;
+		Ok(())
+	}
+
+	/// The height of the peer's chain when we connected.
+	pub fn start_height(&self) -> u32 {
+		self.start_height
+	}
+
+	fn send(&self, command: &str, payload: &[u8]) -> Result<()> {
+		let mut message = Vec::with_capacity(24 + payload.len());
+		push(&mut message, &self.magic);
+		let mut command_bytes = [0; 12];
+		command_bytes[..command.len()].copy_from_slice(command.as_bytes());
+		message.extend_from_slice(&command_bytes);
+		push(&mut message, &(payload.len() as u32));
+		message.extend_from_slice(&sha256d::Hash::hash(payload)[0..4]);
+		message.extend_from_slice(payload);
+		(&self.stream).write_all(&message).map_err(io_error)
+	}
+
+	/// Receive the next message, answering pings on the way.
+	fn receive(&self) -> Result<(String, Vec<u8>)> {
+		loop {
+			let mut header = [0; 24];
+			(&self.stream).read_exact(&mut header).map_err(io_error)?;
+			if LittleEndian::read_u32(&header[0..4]) != self.magic {
+				return Err(Error::InvalidPeerMessage("wrong network magic"));
+			}
+			let command = header[4..16].iter().take_while(|b| **b != 0).map(|b| *b as char);
+			let command: String = command.collect();
+			let len = LittleEndian::read_u32(&header[16..20]) as usize;
+			if len > MAX_MESSAGE_SIZE {
+				return Err(Error::InvalidPeerMessage("message too large"));
+			}
+			let mut payload = vec![0; len];
+			(&self.stream).read_exact(&mut payload).map_err(io_error)?;
+			if sha256d::Hash::hash(&payload)[0..4] != header[20..24] {
+				return Err(Error::InvalidPeerMessage("wrong checksum"));
+			}
+
+			if command == "ping" {
+				self.send("pong", &payload)?;
+				continue;
+			}
+			return Ok((command, payload));
+		}
+	}
+
+	/// Receive messages until one with the command, ignoring all others.
+	fn receive_command(&self, command: &str) -> Result<Vec<u8>> {
+		loop {
+			let (received, payload) = self.receive()?;
+			if received == command {
+				return Ok(payload);
+			}
+			if received == "notfound" {
+				return Err(Error::InvalidPeerMessage("peer doesn't have the data"));
+			}
+		}
+	}
+
+	/// Get the headers following the first block of the locator that the
+	/// peer knows, at most 2000.
+	pub fn get_headers(&self, locator: &[sha256d::Hash]) -> Result<Vec<BlockHeader>> {
+		let mut payload = Vec::new();
+		push(&mut payload, &PROTOCOL_VERSION);
+		push(&mut payload, &VarInt(locator.len() as u64));
+		for hash in locator {
+			push(&mut payload, hash);
+		}
+		push(&mut payload, &sha256d::Hash::default()); // No stop hash.
+		self.send("getheaders", &payload)?;
+
+		let payload = self.receive_command("headers")?;
+		let mut cursor = Cursor::new(&payload[..]);
+		let count: VarInt = decode(&mut cursor)?;
+		if count.0 as usize > MAX_HEADERS {
+			return Err(Error::InvalidPeerMessage("too many headers"));
+		}
+		let mut headers = Vec::with_capacity(count.0 as usize);
+		for _ in 0..count.0 {
+			headers.push(decode(&mut cursor)?);
+			// The number of txs, which is always zero.
+			let _: VarInt = decode(&mut cursor)?;
+		}
+		Ok(headers)
+	}
+
+	/// Get the basic filters of the blocks from the start height up to the
+	/// stop block at the stop height, at most 1000. Returns the block hashes
+	/// with their filters, in order.
+	pub fn get_filters(
+		&self,
+		start_height: u32,
+		stop_height: u32,
+		stop_hash: &sha256d::Hash,
+	) -> Result<Vec<(sha256d::Hash, Vec<u8>)>> {
+		if stop_height < start_height || stop_height - start_height >= MAX_FILTERS {
+			return Err(Error::InvalidPeerMessage("invalid filter range"));
+		}
+		let mut payload = Vec::new();
+		push(&mut payload, &BASIC_FILTER);
+		push(&mut payload, &start_height);
+		push(&mut payload, stop_hash);
+		self.send("getcfilters", &payload)?;
+
+		let mut filters = Vec::new();
+		for _ in start_height..=stop_height {
+			let payload = self.receive_command("cfilter")?;
+			let mut cursor = Cursor::new(&payload[..]);
+			let filter_type: u8 = decode(&mut cursor)?;
+			if filter_type != BASIC_FILTER {
+				return Err(Error::InvalidPeerMessage("wrong filter type"));
+			}
+			let block_hash = decode(&mut cursor)?;
+			filters.push((block_hash, decode(&mut cursor)?));
+		}
+		Ok(filters)
+	}
+
+	/// Get the hashes of the basic filters of the blocks from the start height
+	/// up to the stop block. Returns the filter header of the block before the
+	/// start height with the filter hashes, in order.
+	pub fn get_filter_hashes(
+		&self,
+		start_height: u32,
+		stop_hash: &sha256d::Hash,
+	) -> Result<(sha256d::Hash, Vec<sha256d::Hash>)> {
+		let mut payload = Vec::new();
+		push(&mut payload, &BASIC_FILTER);
+		push(&mut payload, &start_height);
+		push(&mut payload, stop_hash);
+		self.send("getcfheaders", &payload)?;
+
+		let payload = self.receive_command("cfheaders")?;
+		let mut cursor = Cursor::new(&payload[..]);
+		let filter_type: u8 = decode(&mut cursor)?;
+		if filter_type != BASIC_FILTER {
+			return Err(Error::InvalidPeerMessage("wrong filter type"));
+		}
+		if decode::<sha256d::Hash>(&mut cursor)? != *stop_hash {
+			return Err(Error::InvalidPeerMessage("filter headers for wrong block"));
+		}
+		let prev_header = decode(&mut cursor)?;
+		let count: VarInt = decode(&mut cursor)?;
+		if count.0 as usize > MAX_FILTER_HASHES {
+			return Err(Error::InvalidPeerMessage("too many filter hashes"));
+		}
+		let mut filter_hashes = Vec::with_capacity(count.0 as usize);
+		for _ in 0..count.0 {
+			filter_hashes.push(decode(&mut cursor)?);
+		}
+		Ok((prev_header, filter_hashes))
+	}
+
+	/// Get the block with witness data. The block is checked against its
+	/// hash, but the txs are not validated.
+	pub fn get_block(&self, hash: &sha256d::Hash) -> Result<Block> {
+		let mut payload = Vec::new();
+		push(&mut payload, &VarInt(1));
+		push(&mut payload, &MSG_WITNESS_BLOCK);
+		push(&mut payload, hash);
+		self.send("getdata", &payload)?;
+
+		let payload = self.receive_command("block")?;
+		let block: Block =
+			deserialize(&payload).map_err(|_| Error::InvalidPeerMessage("malformed block"))?;
+		if block.bitcoin_hash() != *hash {
+			return Err(Error::InvalidPeerMessage("wrong block"));
+		}
+		let merkle_root = bitcoin_merkle_root(block.txdata.iter().map(|t| t.txid()).collect());
+		if merkle_root != block.header.merkle_root {
+			return Err(Error::InvalidPeerMessage("merkle root mismatch"));
+		}
+		Ok(block)
+	}
+}
+
+impl Broadcaster for Peer {
+	fn name(&self) -> String {
+		format!("p2p:{}", self.stream.peer_addr().map(|a| a.to_string()).unwrap_or_default())
+	}
+
+	/// Send the tx to the peer. Peers don't acknowledge txs, so the returned
+	/// txid is our own.
+	fn broadcast(&self, tx: &Transaction) -> Result<sha256d::Hash> {
+		self.send("tx", &serialize(tx))?;
+		Ok(tx.txid())
+	}
+}
+
+/// Reads the bits of a Golomb-Rice coded set, most significant bit first.
+struct BitReader<'a> {
+	data: &'a [u8],
+	position: usize,
+}
+
+impl<'a> BitReader<'a> {
+	fn read_bit(&mut self) -> Option<bool> {
+		let byte = self.data.get(self.position / 8)?;
+		let bit = byte & (0x80 >> (self.position % 8)) != 0;
+		self.position += 1;
+		Some(bit)
+	}
+
+	fn read_bits(&mut self, nb: u8) -> Option<u64> {
+		let mut value = 0;
+		for _ in 0..nb {
+			value = (value << 1) | self.read_bit()? as u64;
+		}
+		Some(value)
+	}
+}
+
+/// Whether any of the scripts is in the basic BIP-158 filter of the block.
+/// Like for all filters, there are false positives.
+pub fn filter_matches(
+	filter: &[u8],
+	block_hash: &sha256d::Hash,
+	scripts: &[Script],
+) -> Result<bool> {
+	let invalid = || Error::InvalidPeerMessage("malformed filter");
+	let mut cursor = Cursor::new(filter);
+	let nb_items = decode::<VarInt>(&mut cursor).map_err(|_| invalid())?.0;
+	if nb_items == 0 {
+		return Ok(false);
+	}
+
+	let k0 = LittleEndian::read_u64(&block_hash[0..8]);
+	let k1 = LittleEndian::read_u64(&block_hash[8..16]);
+	let range = u128::from(nb_items) * u128::from(FILTER_M);
+	let mut queries: Vec<u64> = scripts
+		.iter()
+		.filter(|s| !s.is_empty())
+		.map(|s| ((u128::from(siphash24(k0, k1, s.as_bytes())) * range) >> 64) as u64)
+		.collect();
+	queries.sort();
+	queries.dedup();
+
+	let mut reader = BitReader {
+		data: &filter[cursor.position() as usize..],
+		position: 0,
+	};
+	let mut queries = queries.into_iter().peekable();
+	let mut value = 0u64;
+	for _ in 0..nb_items {
+		let mut quotient = 0u64;
+		while reader.read_bit().ok_or_else(invalid)? {
+			quotient += 1;
+		}
+		value += (quotient << FILTER_P) + reader.read_bits(FILTER_P).ok_or_else(invalid)?;
+		loop {
+			match queries.peek() {
+				None => return Ok(false),
+				Some(query) if *query == value => return Ok(true),
+				Some(query) if *query > value => break,
+				Some(_) => {}
+			}
+			queries.next();
+		}
+	}
+	Ok(false)
+}
+
+/// The BIP-157 filter header of the filter with the given hash, committing
+/// to the filter headers of all previous blocks.
+pub fn filter_header(filter_hash: &sha256d::Hash, prev_header: &sha256d::Hash) -> sha256d::Hash {
+	let mut data = filter_hash[..].to_vec();
+	data.extend_from_slice(&prev_header[..]);
+	sha256d::Hash::hash(&data)
+}
+
+/// A locator of the best chain of the header chain.
+fn chain_locator(chain: &HeaderChain) -> Vec<sha256d::Hash> {
+	let mut locator = Vec::new();
+	let mut height = chain.height();
+	let mut step = 1;
+	loop {
+		locator.push(chain.header_at(height).expect("in best chain").header.bitcoin_hash());
+		if height == 0 {
+			return locator;
+		}
+		if locator.len() >= 10 {
+			step *= 2;
+		}
+		height = height.saturating_sub(step);
+	}
+}
+
+/// Add the peer's headers to the chain and return the height of the best
+/// chain. Invalid headers fail the sync.
+pub fn sync_headers(chain: &mut HeaderChain, peer: &Peer) -> Result<u32> {
+	loop {
+		let headers = peer.get_headers(&chain_locator(chain))?;
+		for header in &headers {
+			chain.add_header(*header)?;
+		}
+		if headers.len() < MAX_HEADERS {
+			return Ok(chain.height());
+		}
+	}
+}
+
+/// Sync the headers and the wallet with the peer and return the height of
+/// the wallet's new tip. A wallet without any processed blocks starts at the
+/// tip of the chain.
+///
+/// Blocks whose filter doesn't match any of the wallet's scripts are
+/// processed without their txs. The filter headers of the batches of filters
+/// must connect, but the one before the first filter is taken from the peer.
+pub fn sync(wallet: &mut Wallet, chain: &mut HeaderChain, peer: &Peer) -> Result<u32> {
+	let tip_height = sync_headers(chain, peer)?;
+	let block_hash = |height: u32| chain.header_at(height).map(|h| h.header.bitcoin_hash());
+
+	let mut height = match wallet.last_block() {
+		Some(last) if block_hash(last.height) == Some(last.hash) => last.height,
+		Some(_) => match wallet.fork_point(|h| Ok(block_hash(h)))? {
+			Some(common) => {
+				wallet.rollback_to(common.height)?;
+				common.height
+			}
+			None => return Err(Error::UnknownBlock),
+		},
+		None => {
+			let tip = block_hash(tip_height).expect("tip is in best chain");
+			wallet.set_last_block(tip, tip_height);
+			return Ok(tip_height);
+		}
+	};
+
+	let mut scripts = wallet.relevant_scripts()?;
+	let mut last_filter_header = None;
+	while height < tip_height {
+		let stop_height = ::std::cmp::min(height + MAX_FILTERS, tip_height);
+		let stop_hash = block_hash(stop_height).expect("below tip");
+		let (mut cf_header, filter_hashes) = peer.get_filter_hashes(height + 1, &stop_hash)?;
+		if filter_hashes.len() as u32 != stop_height - height {
+			return Err(Error::InvalidPeerMessage("wrong number of filter hashes"));
+		}
+		if last_filter_header.map_or(false, |last| last != cf_header) {
+			return Err(Error::InvalidPeerMessage("filter headers don't connect"));
+		}
+
+		let filters = peer.get_filters(height + 1, stop_height, &stop_hash)?;
+		for ((hash, filter), filter_hash) in filters.into_iter().zip(filter_hashes) {
+			if block_hash(height + 1) != Some(hash) {
+				return Err(Error::InvalidPeerMessage("filter for wrong block"));
+			}
+			if sha256d::Hash::hash(&filter) != filter_hash {
+				return Err(Error::InvalidPeerMessage("filter doesn't match its header"));
+			}
+			cf_header = filter_header(&filter_hash, &cf_header);
+
+			if filter_matches(&filter, &hash, &scripts)? {
+				wallet.process_block(&peer.get_block(&hash)?)?;
+				// The block can have extended the lookahead.
+				scripts = wallet.relevant_scripts()?;
+			} else {
+				let header = chain.header_at(height + 1).expect("below tip").header;
+				wallet.process_block(&Block {
+					header: header,
+					txdata: Vec::new(),
+				})?;
+			}
+			height += 1;
+		}
+		last_filter_header = Some(cf_header);
+	}
+	Ok(height)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::Network;
+	use bitcoin_hashes::hex::FromHex;
+	use hex;
+
+	/// Block 2 of testnet, from the BIP-158 test vectors.
+	const TESTNET_BLOCK_2: &'static str = "0100000006128e87be8b1b4dea47a7247d5528d2702c96826c7a\
+		648497e773b800000000e241352e3bec0a95a6217e10c3abb54adfa05abb12c126695595580fb92e222032e7\
+		494dffff001d00d235340101000000010000000000000000000000000000000000000000000000000000000000\
+		000000ffffffff0e0432e7494d010e062f503253482fffffffff0100f2052a010000002321038a7f6ef1c8ca0c\
+		588aa53fa860128077c9e6c11e6830f4d7ee4e763a56b7718fac00000000";
+
+	#[test]
+	fn bip158_genesis_vector() {
+		let genesis = genesis_block(Network::Testnet);
+		let hash = genesis.bitcoin_hash();
+		let filter = hex::decode("019dfca8").unwrap();
+		let script = genesis.txdata[0].output[0].script_pubkey.clone();
+
+		assert!(filter_matches(&filter, &hash, &[script.clone()]).unwrap());
+		assert!(!filter_matches(&filter, &hash, &[Script::from(vec![0x51])]).unwrap());
+		assert!(!filter_matches(&filter, &hash, &[]).unwrap());
+
+		let expected = "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750";
+		assert_eq!(
+			filter_header(&sha256d::Hash::hash(&filter), &sha256d::Hash::default()),
+			sha256d::Hash::from_hex(expected).unwrap()
+		);
+	}
+
+	#[test]
+	fn bip158_block_vector() {
+		let block: Block = deserialize(&hex::decode(TESTNET_BLOCK_2).unwrap()).unwrap();
+		let hash = block.bitcoin_hash();
+		let expected = "000000006c02c8ea6e4ff69651f7fcde348fb9d557a06e6957b65552002a7820";
+		assert_eq!(hash, sha256d::Hash::from_hex(expected).unwrap());
+		let filter = hex::decode("0174a170").unwrap();
+		let script = block.txdata[0].output[0].script_pubkey.clone();
+
+		assert!(filter_matches(&filter, &hash, &[script.clone()]).unwrap());
+		// The filter is keyed by the block hash.
+		let genesis_hash = genesis_block(Network::Testnet).bitcoin_hash();
+		assert!(!filter_matches(&filter, &genesis_hash, &[script]).unwrap());
+	}
+
+	#[test]
+	fn malformed_filters() {
+		let hash = genesis_block(Network::Testnet).bitcoin_hash();
+		let script = Script::from(vec![0x51]);
+		assert!(!filter_matches(&[0x00], &hash, &[script.clone()]).unwrap());
+		assert!(filter_matches(&[], &hash, &[script.clone()]).is_err());
+		assert!(filter_matches(&[0x01, 0x9d], &hash, &[script]).is_err());
+	}
+}
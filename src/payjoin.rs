@@ -0,0 +1,473 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Sending payjoin (BIP-78) payments.
+//!
+//! The flow is as follows:
+//! - parse the BIP-21 URI using [PayjoinUri::parse],
+//! - create the original tx using [create_original],
+//! - sign and finalize the original PSBT,
+//! - post the request from [PayjoinSender::request] to the receiver,
+//! - check the receiver's response using [PayjoinSender::process_proposal],
+//! - sign and broadcast the returned PSBT.
+//!
+//! If anything goes wrong, the signed original tx can be broadcast instead.
+//! The wallet doesn't do any networking itself.
+
+use base64;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::util::psbt;
+use bitcoin::{OutPoint, Script};
+
+use amount::{Amount, FeeRate};
+use error::{Error, Result};
+use fee::{self, InputKind};
+use uri::Uri;
+use wallet::Wallet;
+
+/// A BIP-21 payment URI with a payjoin endpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayjoinUri {
+	pub uri: Uri,
+	/// The URL of the payjoin endpoint.
+	pub endpoint: String,
+	/// Whether the receiver may change the payment output.
+	pub output_substitution: bool,
+}
+
+impl PayjoinUri {
+	/// Fails if the URI has no payjoin endpoint.
+	pub fn from_uri(uri: Uri) -> Result<PayjoinUri> {
+		let endpoint = match uri.extra("pj") {
+			Some(endpoint) => endpoint.to_owned(),
+			None => return Err(Error::InvalidUri("no payjoin endpoint")),
+		};
+		let output_substitution = uri.extra("pjos") != Some("0");
+		Ok(PayjoinUri {
+			uri: uri,
+			endpoint: endpoint,
+			output_substitution: output_substitution,
+		})
+	}
+
+	pub fn parse(uri: &str) -> Result<PayjoinUri> {
+		PayjoinUri::from_uri(Uri::parse(uri)?)
+	}
+}
+
+/// An ongoing payjoin payment.
+#[derive(Debug, Clone)]
+pub struct PayjoinSender {
+	uri: PayjoinUri,
+	/// The original PSBT as created by the wallet, with all our input data.
+	original: psbt::PartiallySignedTransaction,
+	/// The index of our change output, from which the receiver can take fee.
+	change_index: Option<usize>,
+	max_fee_contribution: u64,
+	/// The feerate of the original tx in satoshis per virtual byte.
+	min_fee_rate: u64,
+	/// The fee of the original tx.
+	original_fee: u64,
+	/// The weights of the inputs of the original tx once signed, in order.
+	input_weights: Vec<u64>,
+}
+
+/// Create the original tx paying the URI using [Wallet::create_payment].
+/// The receiver may take at most [max_fee_contribution] from our change for
+/// the extra fee, and no more than the fee of its inputs at the feerate.
+///
+/// Like any created tx, the original tx is committed.
+pub fn create_original(
+	wallet: &mut Wallet,
+	uri: PayjoinUri,
+	fee_rate: FeeRate,
+	max_fee_contribution: Amount,
+) -> Result<PayjoinSender> {
+	let created = wallet.create_payment(&uri.uri, fee_rate)?;
+	let tx = &created.psbt.global.unsigned_tx;
+	let mut input_weights = Vec::with_capacity(tx.input.len());
+	for (txin, input) in tx.input.iter().zip(created.psbt.inputs.iter()) {
+		let script = input_script(input, &txin.previous_output).ok_or(Error::MissingUtxoData)?;
+		let kind = match wallet.address_type_of(script) {
+			Some(address_type) => address_type.input_kind(),
+			None => InputKind::from_script(script).ok_or(Error::UnknownInputKind)?,
+		};
+		input_weights.push(kind.weight());
+	}
+	Ok(PayjoinSender {
+		uri: uri,
+		change_index: created.change_index(),
+		max_fee_contribution: max_fee_contribution.as_sat(),
+		min_fee_rate: fee_rate.as_sat_per_vb(),
+		original_fee: created.fee.as_sat(),
+		input_weights: input_weights,
+		original: created.psbt,
+	})
+}
+
+/// The scriptPubkey of the output spent by the PSBT input.
+fn input_script<'a>(input: &'a psbt::Input, prevout: &OutPoint) -> Option<&'a Script> {
+	if let Some(ref txout) = input.witness_utxo {
+		return Some(&txout.script_pubkey);
+	}
+	let tx = input.non_witness_utxo.as_ref()?;
+	tx.output.get(prevout.vout as usize).map(|o| &o.script_pubkey)
+}
+
+/// The value of the output spent by the PSBT input.
+fn input_value(input: &psbt::Input, prevout: &OutPoint) -> Option<u64> {
+	if let Some(ref txout) = input.witness_utxo {
+		return Some(txout.value);
+	}
+	let tx = input.non_witness_utxo.as_ref()?;
+	tx.output.get(prevout.vout as usize).map(|o| o.value)
+}
+
+/// The weight of the finalized PSBT input in a segwit tx.
+fn finalized_input_weight(input: &psbt::Input) -> u64 {
+	let script_sig_len = input.final_script_sig.as_ref().map(|s| s.len()).unwrap_or(0);
+	let base = 32 + 4 + 4 + fee::varint_len(script_sig_len) + script_sig_len as u64;
+	let witness: &[Vec<u8>] = match input.final_script_witness {
+		Some(ref witness) => witness,
+		None => &[],
+	};
+	let witness_len = fee::varint_len(witness.len())
+		+ witness.iter().map(|i| fee::varint_len(i.len()) + i.len() as u64).sum::<u64>();
+	base * 4 + witness_len
+}
+
+impl PayjoinSender {
+	/// The original PSBT, to be signed and finalized before making the request.
+	pub fn original_psbt(&self) -> &psbt::PartiallySignedTransaction {
+		&self.original
+	}
+
+	/// The URL and body of the HTTP POST request to the receiver.
+	/// The body is the signed and finalized original PSBT in base64.
+	pub fn request(&self, signed_original: &psbt::PartiallySignedTransaction) -> (String, String) {
+		let mut url = self.uri.endpoint.clone();
+		url.push(if url.contains('?') { '&' } else { '?' });
+		url.push_str("v=1");
+		if let Some(idx) = self.change_index {
+			url.push_str(&format!(
+				"&additionalfeeoutputindex={}&maxadditionalfeecontribution={}",
+				idx, self.max_fee_contribution,
+			));
+		}
+		url.push_str(&format!("&minfeerate={}", self.min_fee_rate));
+		if !self.uri.output_substitution {
+			url.push_str("&disableoutputsubstitution=true");
+		}
+		(url, base64::encode(&serialize(signed_original)))
+	}
+
+	/// Check the receiver's proposal, given as the base64 response body.
+	/// Returns the proposal with our input data restored, ready to sign.
+	///
+	/// The feerate is checked with the actual weight of the receiver's
+	/// finalized inputs and the expected weight of ours once signed.
+	pub fn process_proposal(&self, response: &str) -> Result<psbt::PartiallySignedTransaction> {
+		let invalid = |reason| Error::InvalidPayjoinProposal(reason);
+		let data = base64::decode(response.trim()).map_err(|_| invalid("invalid base64"))?;
+		let mut proposal: psbt::PartiallySignedTransaction =
+			deserialize(&data).map_err(|_| invalid("invalid PSBT"))?;
+
+		let original_tx = &self.original.global.unsigned_tx;
+		let tx = proposal.global.unsigned_tx.clone();
+		if tx.version != original_tx.version || tx.lock_time != original_tx.lock_time {
+			return Err(invalid("tx version or locktime changed"));
+		}
+		if proposal.inputs.len() != tx.input.len() {
+			return Err(invalid("invalid PSBT"));
+		}
+
+		// Check the inputs, restoring the data of ours.
+		let sequence = original_tx.input[0].sequence;
+		let mut total_in = 0;
+		let mut nb_ours = 0;
+		let mut inputs_weight = 0;
+		let mut receiver_inputs_weight = 0;
+		for (txin, input) in tx.input.iter().zip(proposal.inputs.iter_mut()) {
+			if txin.sequence != sequence {
+				return Err(invalid("input sequence changed"));
+			}
+			let original_idx =
+				original_tx.input.iter().position(|i| i.previous_output == txin.previous_output);
+			match original_idx {
+				Some(idx) => {
+					if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+						return Err(invalid("our inputs are not cleared"));
+					}
+					*input = self.original.inputs[idx].clone();
+					inputs_weight += self.input_weights[idx];
+					nb_ours += 1;
+				}
+				None => {
+					if input.final_script_sig.is_none() && input.final_script_witness.is_none() {
+						return Err(invalid("receiver inputs are not finalized"));
+					}
+					receiver_inputs_weight += finalized_input_weight(input);
+				}
+			}
+			total_in += input_value(input, &txin.previous_output)
+				.ok_or_else(|| invalid("missing input data"))?;
+		}
+		if nb_ours != original_tx.input.len() {
+			return Err(invalid("our inputs were removed"));
+		}
+		if nb_ours == tx.input.len() {
+			return Err(invalid("no receiver inputs"));
+		}
+
+		// Check the outputs. Only the payment output and our change output
+		// may be changed, no outputs may be added or removed.
+		if tx.output.len() != original_tx.output.len() {
+			return Err(invalid("outputs were added or removed"));
+		}
+		let payment_script = self.uri.uri.address.script_pubkey();
+		let mut fee_contribution = 0;
+		let outputs = tx.output.iter().zip(original_tx.output.iter());
+		for (idx, (output, original)) in outputs.enumerate() {
+			if Some(idx) == self.change_index && output.script_pubkey == original.script_pubkey {
+				fee_contribution = original.value.saturating_sub(output.value);
+			} else if original.script_pubkey == payment_script {
+				if !self.uri.output_substitution
+					&& (output.script_pubkey != original.script_pubkey
+						|| output.value < original.value)
+				{
+					return Err(invalid("payment output was substituted"));
+				}
+			} else if output != original {
+				return Err(invalid("unexpected output"));
+			}
+		}
+		if fee_contribution > self.max_fee_contribution {
+			return Err(invalid("fee contribution too high"));
+		}
+
+		// We only pay for the receiver's inputs at the original feerate, and
+		// all of our contribution must go to the fee.
+		let total_out: u64 = tx.output.iter().map(|o| o.value).sum();
+		let fee = total_in.checked_sub(total_out).ok_or_else(|| invalid("negative fee"))?;
+		let receiver_inputs_fee = self.min_fee_rate * fee::weight_to_vsize(receiver_inputs_weight);
+		if fee_contribution > receiver_inputs_fee {
+			return Err(invalid("fee contribution exceeds the fee of the receiver's inputs"));
+		}
+		if fee_contribution > fee.saturating_sub(self.original_fee) {
+			return Err(invalid("fee contribution exceeds the added fee"));
+		}
+
+		inputs_weight += receiver_inputs_weight;
+		let outputs_weight = tx.output.iter().map(|o| fee::txout_weight(&o.script_pubkey));
+		let weight = fee::TX_BASE_WEIGHT + inputs_weight + outputs_weight.sum::<u64>();
+		if fee < self.min_fee_rate * fee::weight_to_vsize(weight) {
+			return Err(invalid("feerate too low"));
+		}
+
+		// Restore the data of our outputs.
+		for (idx, output) in proposal.outputs.iter_mut().enumerate() {
+			if let Some(original) = self.original.outputs.get(idx) {
+				*output = original.clone();
+			}
+		}
+		Ok(proposal)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::{BitcoinHash, Block, BlockHeader, Network, Transaction, TxIn, TxOut};
+	use bitcoin_hashes::{sha256d, Hash};
+
+	const FEE_RATE: u64 = 10;
+	const MAX_FEE_CONTRIBUTION: u64 = 10_000;
+	/// The value of the input added by the receiver.
+	const RECEIVER_VALUE: u64 = 200_000;
+
+	/// A sender paying 0.001 BTC from a wallet with a single UTXO.
+	fn sender() -> PayjoinSender {
+		let mut wallet = Wallet::new_for_testing(&[42; 32], Network::Regtest);
+		let genesis = genesis_block(Network::Regtest);
+		wallet.set_last_block(genesis.bitcoin_hash(), 0);
+		let funding = Transaction {
+			version: 1,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: OutPoint {
+					txid: sha256d::Hash::hash(b"funding"),
+					vout: 0,
+				},
+				script_sig: Script::new(),
+				sequence: 0xFFFFFFFF,
+				witness: vec![],
+			}],
+			output: vec![TxOut {
+				value: 1_000_000,
+				script_pubkey: wallet.new_receive_address().unwrap().script_pubkey(),
+			}],
+		};
+		let block = Block {
+			header: BlockHeader {
+				prev_blockhash: genesis.bitcoin_hash(),
+				..genesis.header
+			},
+			txdata: vec![funding],
+		};
+		wallet.process_block(&block).unwrap();
+
+		let mut receiver = Wallet::new_for_testing(&[1; 32], Network::Regtest);
+		let address = receiver.new_receive_address().unwrap();
+		let uri = format!("bitcoin:{}?amount=0.001&pj=https://example.com/pj", address);
+		let uri = PayjoinUri::parse(&uri).unwrap();
+		let fee_rate = FeeRate::from_sat_per_vb(FEE_RATE);
+		let max_fee_contribution = Amount::from_sat(MAX_FEE_CONTRIBUTION);
+		create_original(&mut wallet, uri, fee_rate, max_fee_contribution).unwrap()
+	}
+
+	/// A finalized receiver input spending the script.
+	fn receiver_input(
+		sender: &PayjoinSender,
+		script_pubkey: Script,
+		script_sig: Option<Script>,
+		witness: Option<Vec<Vec<u8>>>,
+	) -> (TxIn, psbt::Input) {
+		let txin = TxIn {
+			previous_output: OutPoint {
+				txid: sha256d::Hash::hash(b"receiver"),
+				vout: 0,
+			},
+			script_sig: Script::new(),
+			sequence: sender.original.global.unsigned_tx.input[0].sequence,
+			witness: vec![],
+		};
+		let input = psbt::Input {
+			witness_utxo: Some(TxOut {
+				value: RECEIVER_VALUE,
+				script_pubkey: script_pubkey,
+			}),
+			final_script_sig: script_sig,
+			final_script_witness: witness,
+			..Default::default()
+		};
+		(txin, input)
+	}
+
+	fn p2wpkh_input(sender: &PayjoinSender) -> (TxIn, psbt::Input) {
+		let mut script = vec![0x00, 0x14];
+		script.extend_from_slice(&[7; 20]);
+		let witness = vec![vec![1; 72], vec![2; 33]];
+		receiver_input(sender, Script::from(script), None, Some(witness))
+	}
+
+	fn p2pkh_input(sender: &PayjoinSender) -> (TxIn, psbt::Input) {
+		let mut script = vec![0x76, 0xa9, 0x14];
+		script.extend_from_slice(&[7; 20]);
+		script.extend_from_slice(&[0x88, 0xac]);
+		let script_sig = Script::from(vec![3; 1 + 72 + 1 + 33]);
+		receiver_input(sender, Script::from(script), Some(script_sig), None)
+	}
+
+	/// The fee of the input at the original feerate.
+	fn input_fee(input: &(TxIn, psbt::Input)) -> u64 {
+		FEE_RATE * fee::weight_to_vsize(finalized_input_weight(&input.1))
+	}
+
+	/// The receiver's proposal adding the input, adding [payment_extra] to the
+	/// payment output and taking [contribution] from our change.
+	fn propose(
+		sender: &PayjoinSender,
+		input: (TxIn, psbt::Input),
+		payment_extra: u64,
+		contribution: u64,
+	) -> String {
+		let mut proposal = sender.original.clone();
+		for input in proposal.inputs.iter_mut() {
+			*input = Default::default();
+		}
+		proposal.global.unsigned_tx.input.push(input.0);
+		proposal.inputs.push(input.1);
+		let payment_script = sender.uri.uri.address.script_pubkey();
+		for (idx, output) in proposal.global.unsigned_tx.output.iter_mut().enumerate() {
+			if output.script_pubkey == payment_script {
+				output.value += payment_extra;
+			} else if Some(idx) == sender.change_index {
+				output.value -= contribution;
+			}
+		}
+		base64::encode(&serialize(&proposal))
+	}
+
+	#[test]
+	fn valid_proposal() {
+		let sender = sender();
+		let input = p2wpkh_input(&sender);
+		let fee = input_fee(&input);
+		// The receiver adds a bit of fee itself.
+		let response = propose(&sender, input, RECEIVER_VALUE - 100, fee);
+		let proposal = sender.process_proposal(&response).unwrap();
+		assert_eq!(proposal.inputs[0], sender.original.inputs[0]);
+		assert_eq!(proposal.global.unsigned_tx.input.len(), 2);
+	}
+
+	#[test]
+	fn fee_contribution_limits() {
+		let sender = sender();
+		let invalid = |reason| Err(Error::InvalidPayjoinProposal(reason));
+
+		let input = p2wpkh_input(&sender);
+		let fee = input_fee(&input);
+		let response = propose(&sender, input, RECEIVER_VALUE, 2 * fee);
+		assert_eq!(
+			sender.process_proposal(&response),
+			invalid("fee contribution exceeds the fee of the receiver's inputs")
+		);
+
+		// The contribution goes to the payment output instead of the fee.
+		let input = p2wpkh_input(&sender);
+		let response = propose(&sender, input, RECEIVER_VALUE + fee, fee);
+		assert_eq!(
+			sender.process_proposal(&response),
+			invalid("fee contribution exceeds the added fee")
+		);
+
+		let input = p2wpkh_input(&sender);
+		let contribution = MAX_FEE_CONTRIBUTION + 1;
+		let response = propose(&sender, input, RECEIVER_VALUE - contribution, contribution);
+		assert_eq!(sender.process_proposal(&response), invalid("fee contribution too high"));
+	}
+
+	#[test]
+	fn receiver_input_types() {
+		let sender = sender();
+		let p2wpkh_fee = input_fee(&p2wpkh_input(&sender));
+
+		// A P2PKH input paying the fee of a P2WPKH one is not enough.
+		let input = p2pkh_input(&sender);
+		let fee = input_fee(&input);
+		assert!(fee > p2wpkh_fee);
+		let response = propose(&sender, input, RECEIVER_VALUE - p2wpkh_fee, 0);
+		assert_eq!(
+			sender.process_proposal(&response),
+			Err(Error::InvalidPayjoinProposal("feerate too low"))
+		);
+
+		// But we pay for it, when asked.
+		let input = p2pkh_input(&sender);
+		let response = propose(&sender, input, RECEIVER_VALUE - 100, fee);
+		assert!(sender.process_proposal(&response).is_ok());
+	}
+}
@@ -0,0 +1,238 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Proof of reserves in the style of BIP-127.
+//!
+//! A proof is a tx that can never be valid on the network. Its first input
+//! spends a non-existing output derived from a message, so that the proof
+//! can't be reused for another message. All other inputs spend the UTXOs of
+//! which ownership is proven and sign like in a regular tx. The single output
+//! pays the total value to an unspendable script.
+//!
+//! The first input is not signed. The signatures of the other inputs commit
+//! to it.
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::{OutPoint, Script, TxIn};
+use bitcoin_hashes::{sha256d, Hash};
+
+#[cfg(feature = "bitcoinconsensus")]
+use bitcoin::{Transaction, TxOut};
+#[cfg(feature = "bitcoinconsensus")]
+use error::{Error, Result};
+
+/// The prefix of the message committed to in the first input.
+pub const MESSAGE_PREFIX: &'static str = "Proof-of-Reserves: ";
+
+/// The non-existing outpoint spent by the first input of a proof for the message.
+pub fn challenge_outpoint(message: &str) -> OutPoint {
+	let mut data = MESSAGE_PREFIX.as_bytes().to_vec();
+	data.extend_from_slice(message.as_bytes());
+	OutPoint {
+		txid: sha256d::Hash::hash(&data),
+		vout: 0,
+	}
+}
+
+/// The first input of a proof for the message.
+pub fn challenge_txin(message: &str) -> TxIn {
+	TxIn {
+		previous_output: challenge_outpoint(message),
+		script_sig: Script::new(),
+		sequence: 0xFFFFFFFF,
+		witness: vec![],
+	}
+}
+
+/// The script of the output of a proof.
+pub fn unspendable_script() -> Script {
+	Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script()
+}
+
+/// Verify a signed proof for the message and return the proven amount.
+///
+/// [prevout] should return the outputs spent by the proof, but only if they
+/// are still unspent. A UTXO can only be spent once by the proof and the
+/// output must pay exactly the total value of the UTXOs.
+#[cfg(feature = "bitcoinconsensus")]
+pub fn verify_proof<F>(proof: &Transaction, message: &str, prevout: F) -> Result<u64>
+where
+	F: Fn(&OutPoint) -> Option<TxOut>,
+{
+	use std::collections::HashSet;
+
+	use bitcoin::consensus::encode::serialize;
+	use bitcoinconsensus;
+
+	if proof.input.len() < 2 {
+		return Err(Error::InvalidReserveProof("no UTXOs"));
+	}
+	if proof.input[0].previous_output != challenge_outpoint(message) {
+		return Err(Error::InvalidReserveProof("wrong message"));
+	}
+	if proof.output.len() != 1 || proof.output[0].script_pubkey != unspendable_script() {
+		return Err(Error::InvalidReserveProof("invalid output"));
+	}
+
+	let serialized = serialize(proof);
+	let mut total: u64 = 0;
+	let mut spent = HashSet::with_capacity(proof.input.len());
+	for (idx, input) in proof.input.iter().enumerate().skip(1) {
+		if !spent.insert(input.previous_output) {
+			return Err(Error::InvalidReserveProof("UTXO spent twice"));
+		}
+		let prev = match prevout(&input.previous_output) {
+			Some(prev) => prev,
+			None => return Err(Error::InvalidReserveProof("unknown or spent UTXO")),
+		};
+		let script = prev.script_pubkey.as_bytes();
+		if bitcoinconsensus::verify(script, prev.value, &serialized, idx).is_err() {
+			return Err(Error::InvalidReserveProof("invalid signature"));
+		}
+		total = match total.checked_add(prev.value) {
+			Some(total) => total,
+			None => return Err(Error::InvalidReserveProof("invalid UTXO value")),
+		};
+	}
+	if proof.output[0].value != total {
+		return Err(Error::InvalidReserveProof("output value differs from the UTXOs"));
+	}
+	Ok(total)
+}
+
+#[cfg(all(test, feature = "bitcoinconsensus"))]
+mod tests {
+	use super::*;
+
+	use std::collections::HashMap;
+
+	use bitcoin::util::{bip32, psbt};
+	use bitcoin::{Address, Network};
+
+	use signer::HotSigner;
+
+	const MESSAGE: &'static str = "Our reserves on 2019-06-01";
+
+	fn outpoint(vout: u32) -> OutPoint {
+		OutPoint {
+			txid: sha256d::Hash::hash(b"funding tx"),
+			vout: vout,
+		}
+	}
+
+	/// The outputs of 1 BTC to our key at the outpoints of [outpoint].
+	fn prevouts() -> HashMap<OutPoint, TxOut> {
+		let xpriv = bip32::ExtendedPrivKey::new_master(Network::Regtest, &[1; 32]).unwrap();
+		let xpub = bip32::ExtendedPubKey::from_private(&::SECP, &xpriv);
+		let script = Address::p2wpkh(&xpub.public_key, Network::Regtest).script_pubkey();
+		(0..3)
+			.map(|vout| {
+				let txout = TxOut {
+					value: 100_000_000,
+					script_pubkey: script.clone(),
+				};
+				(outpoint(vout), txout)
+			})
+			.collect()
+	}
+
+	/// A proof for the message spending the outpoints, signed by our key.
+	fn signed_proof(message: &str, outpoints: &[OutPoint], value: u64) -> Transaction {
+		let xpriv = bip32::ExtendedPrivKey::new_master(Network::Regtest, &[1; 32]).unwrap();
+		let xpub = bip32::ExtendedPubKey::from_private(&::SECP, &xpriv);
+		let prevouts = prevouts();
+
+		let mut inputs = vec![challenge_txin(message)];
+		let mut psbt_inputs = vec![psbt::Input::default()];
+		for outpoint in outpoints {
+			inputs.push(TxIn {
+				previous_output: *outpoint,
+				script_sig: Script::new(),
+				sequence: 0xFFFFFFFF,
+				witness: vec![],
+			});
+			let mut psbt_input = psbt::Input::default();
+			psbt_input.witness_utxo = Some(prevouts[outpoint].clone());
+			psbt_input.hd_keypaths.insert(xpub.public_key, (xpub.fingerprint(), Vec::new().into()));
+			psbt_inputs.push(psbt_input);
+		}
+		let tx = Transaction {
+			version: 1,
+			lock_time: 0,
+			input: inputs,
+			output: vec![TxOut {
+				value: value,
+				script_pubkey: unspendable_script(),
+			}],
+		};
+		let mut psbt = psbt::PartiallySignedTransaction {
+			global: psbt::Global::from_unsigned_tx(tx).unwrap(),
+			inputs: psbt_inputs,
+			outputs: vec![Default::default()],
+		};
+		let mut signer = HotSigner::with_cache_key(xpriv, 10, [0; 32]);
+		assert_eq!(signer.sign_psbt(&mut psbt).unwrap(), outpoints.len());
+
+		let mut proof = psbt.global.unsigned_tx;
+		for (txin, input) in proof.input.iter_mut().zip(psbt.inputs.iter()).skip(1) {
+			let (pubkey, sig) = input.partial_sigs.iter().next().unwrap();
+			txin.witness = vec![sig.clone(), pubkey.to_bytes()];
+		}
+		proof
+	}
+
+	#[test]
+	fn verify_valid_proof() {
+		let prevouts = prevouts();
+		let proof = signed_proof(MESSAGE, &[outpoint(0), outpoint(2)], 200_000_000);
+		let proven = verify_proof(&proof, MESSAGE, |o| prevouts.get(o).cloned());
+		assert_eq!(proven, Ok(200_000_000));
+
+		// The UTXOs must still be unspent.
+		let unspent = |o: &OutPoint| prevouts.get(o).cloned().filter(|_| *o != outpoint(2));
+		assert!(verify_proof(&proof, MESSAGE, unspent).is_err());
+	}
+
+	#[test]
+	fn verify_proof_wrong_challenge() {
+		let prevouts = prevouts();
+		let other = "Our reserves on 2019-07-01";
+		let proof = signed_proof(MESSAGE, &[outpoint(0)], 100_000_000);
+		let proven = verify_proof(&proof, other, |o| prevouts.get(o).cloned());
+		assert_eq!(proven, Err(Error::InvalidReserveProof("wrong message")));
+
+		// Changing the challenge of the proof invalidates its signatures.
+		let mut proof = proof;
+		proof.input[0] = challenge_txin(other);
+		let proven = verify_proof(&proof, other, |o| prevouts.get(o).cloned());
+		assert_eq!(proven, Err(Error::InvalidReserveProof("invalid signature")));
+	}
+
+	#[test]
+	fn verify_proof_duplicated_input() {
+		let prevouts = prevouts();
+		let proof = signed_proof(MESSAGE, &[outpoint(1), outpoint(1)], 200_000_000);
+		let proven = verify_proof(&proof, MESSAGE, |o| prevouts.get(o).cloned());
+		assert_eq!(proven, Err(Error::InvalidReserveProof("UTXO spent twice")));
+	}
+
+	#[test]
+	fn verify_proof_wrong_output_value() {
+		let prevouts = prevouts();
+		let proof = signed_proof(MESSAGE, &[outpoint(0), outpoint(1)], 300_000_000);
+		let proven = verify_proof(&proof, MESSAGE, |o| prevouts.get(o).cloned());
+		assert_eq!(proven, Err(Error::InvalidReserveProof("output value differs from the UTXOs")));
+	}
+}
@@ -0,0 +1,99 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! A cache of the public keys derived by the wallet.
+//!
+//! Deriving a key from the xpub takes an EC operation per step of the path.
+//! The [PubkeyCache] keeps the extended keys of the keychains, so that a key
+//! is derived in a single step, and the derived keys, so that the keys of
+//! addresses, scripts and PSBT inputs are only derived once.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bitcoin::util::bip32;
+use secp256k1::PublicKey;
+
+use error::Result;
+use wallet::Keychain;
+
+#[derive(Debug, Default)]
+struct Cached {
+	/// The xpubs the keys of every generation were derived from.
+	xpubs: HashMap<u32, bip32::ExtendedPubKey>,
+	/// The extended keys of the keychains, per key generation.
+	keychains: HashMap<(u32, Keychain), bip32::ExtendedPubKey>,
+	/// The derived keys, per key generation.
+	keys: HashMap<(u32, Keychain, bip32::ChildNumber), PublicKey>,
+}
+
+/// A cache of derived public keys. It's not bounded, it holds a key for
+/// every address of the wallet.
+///
+/// Clones share the cache, so that cloning the wallet, like for every
+/// [BlockBatch], doesn't copy all keys. The keys of a generation are only
+/// dropped when a clone derives them from another xpub, like when clones
+/// rotate their keys independently.
+#[derive(Debug, Clone, Default)]
+pub struct PubkeyCache {
+	cached: Arc<Mutex<Cached>>,
+}
+
+impl PubkeyCache {
+	pub fn new() -> PubkeyCache {
+		Default::default()
+	}
+
+	/// The key at [keychain_path]/[child] of the xpub of the generation.
+	pub fn derive(
+		&self,
+		xpub: &bip32::ExtendedPubKey,
+		generation: u32,
+		keychain: Keychain,
+		keychain_path: &bip32::DerivationPath,
+		child: bip32::ChildNumber,
+	) -> Result<PublicKey> {
+		let mut cached = self.cached.lock().expect("key cache lock poisoned");
+		if cached.xpubs.get(&generation) != Some(xpub) {
+			cached.keychains.retain(|k, _| k.0 != generation);
+			cached.keys.retain(|k, _| k.0 != generation);
+			cached.xpubs.insert(generation, *xpub);
+		}
+		if let Some(key) = cached.keys.get(&(generation, keychain, child)) {
+			return Ok(*key);
+		}
+
+		let parent = match cached.keychains.get(&(generation, keychain)) {
+			Some(parent) => *parent,
+			None => xpub.derive_pub(&::SECP, keychain_path)?,
+		};
+		let key = parent.ckd_pub(&::SECP, child)?.public_key;
+		cached.keychains.insert((generation, keychain), parent);
+		cached.keys.insert((generation, keychain, child), key);
+		Ok(key)
+	}
+
+	/// The number of cached keys.
+	pub fn len(&self) -> usize {
+		self.cached.lock().expect("key cache lock poisoned").keys.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	pub fn clear(&self) {
+		*self.cached.lock().expect("key cache lock poisoned") = Cached::default();
+	}
+}
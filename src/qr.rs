@@ -0,0 +1,1369 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! PSBTs in animated QR codes, for airgapped signers.
+//!
+//! PSBTs are too large for a single QR code, so they are split into parts
+//! that are shown one after the other. Two encodings are supported:
+//!
+//! - BBQr, used by Coldcard. [bbqr_split] splits a PSBT and a [BbqrJoiner]
+//!   joins the parts of the signed PSBT or tx. Parts are created in hex or
+//!   base32, zlib compressed parts are only read.
+//! - UR `crypto-psbt` (BCR-2020-006), used by SeedSigner, Keystone and others.
+//!   An [UrEncoder] creates the parts, including the fountain coded parts
+//!   that let scanners recover from missed parts, and an [UrDecoder] joins
+//!   them.
+//!
+//! Parts are created in upper case so that they fit the alphanumeric mode of
+//! QR codes. Scanned parts are accepted in any case.
+
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::Transaction;
+use bitcoin_hashes::{sha256, Hash};
+use hex;
+
+use error::{Error, Result};
+
+fn invalid(reason: &'static str) -> Error {
+	Error::InvalidQrCode(reason)
+}
+
+/// The CRC-32 checksum (ISO-HDLC) of the data, used by UR.
+fn crc32(data: &[u8]) -> u32 {
+	let mut crc = 0xffffffffu32;
+	for byte in data {
+		crc ^= *byte as u32;
+		for _ in 0..8 {
+			let mask = (!(crc & 1)).wrapping_add(1);
+			crc = (crc >> 1) ^ (0xedb88320 & mask);
+		}
+	}
+	!crc
+}
+
+// BBQr
+
+const BASE32_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const BASE36_ALPHABET: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// The encoding of the data in BBQr parts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BbqrEncoding {
+	Hex,
+	/// RFC 4648 base32, which fits more data in a QR code than hex.
+	Base32,
+}
+
+impl BbqrEncoding {
+	fn code(self) -> char {
+		match self {
+			BbqrEncoding::Hex => 'H',
+			BbqrEncoding::Base32 => '2',
+		}
+	}
+
+	/// Parts, except the last, must hold a whole number of bytes.
+	fn chars_per_unit(self) -> usize {
+		match self {
+			BbqrEncoding::Hex => 2,
+			BbqrEncoding::Base32 => 8,
+		}
+	}
+}
+
+fn base32_encode(data: &[u8]) -> String {
+	let mut ret = String::with_capacity((data.len() * 8 + 4) / 5);
+	let (mut buffer, mut nb_bits) = (0u32, 0);
+	for byte in data {
+		buffer = (buffer << 8) | *byte as u32;
+		nb_bits += 8;
+		while nb_bits >= 5 {
+			nb_bits -= 5;
+			ret.push(BASE32_ALPHABET[(buffer >> nb_bits) as usize & 0x1f] as char);
+		}
+	}
+	if nb_bits > 0 {
+		ret.push(BASE32_ALPHABET[(buffer << (5 - nb_bits)) as usize & 0x1f] as char);
+	}
+	ret
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+	let mut ret = Vec::with_capacity(data.len() * 5 / 8);
+	let (mut buffer, mut nb_bits) = (0u32, 0);
+	for c in data.bytes() {
+		let value = BASE32_ALPHABET.iter().position(|a| *a == c.to_ascii_uppercase())?;
+		buffer = (buffer << 5) | value as u32;
+		nb_bits += 5;
+		if nb_bits >= 8 {
+			nb_bits -= 8;
+			ret.push((buffer >> nb_bits) as u8);
+		}
+	}
+	Some(ret)
+}
+
+fn base36_encode(value: usize) -> String {
+	let digit = |v: usize| BASE36_ALPHABET[v] as char;
+	format!("{}{}", digit(value / 36), digit(value % 36))
+}
+
+fn base36_decode(data: &str) -> Option<usize> {
+	data.bytes().try_fold(0, |acc, c| {
+		let value = BASE36_ALPHABET.iter().position(|a| *a == c.to_ascii_uppercase())?;
+		Some(acc * 36 + value)
+	})
+}
+
+/// Split the data in BBQr parts of at most [max_part_len] characters,
+/// including the 8 characters of the header. All parts, except the last,
+/// have the same length.
+fn bbqr_split_data(
+	data: &[u8],
+	file_type: char,
+	encoding: BbqrEncoding,
+	max_part_len: usize,
+) -> Result<Vec<String>> {
+	let encoded = match encoding {
+		BbqrEncoding::Hex => hex::encode(data).to_uppercase(),
+		BbqrEncoding::Base32 => base32_encode(data),
+	};
+	let unit = encoding.chars_per_unit();
+	let max_units = max_part_len.saturating_sub(8) / unit;
+	if max_units == 0 {
+		return Err(invalid("maximum part length is too small"));
+	}
+	let nb_units = (encoded.len() + unit - 1) / unit;
+	let nb_parts = (nb_units + max_units - 1) / max_units;
+	if nb_parts > 36 * 36 - 1 {
+		return Err(invalid("too much data for BBQr"));
+	}
+	// Spread the data evenly over the parts.
+	let part_len = (nb_units + nb_parts - 1) / nb_parts * unit;
+	let parts = encoded
+		.as_bytes()
+		.chunks(part_len)
+		.enumerate()
+		.map(|(idx, chunk)| {
+			format!(
+				"B${}{}{}{}{}",
+				encoding.code(),
+				file_type,
+				base36_encode(nb_parts),
+				base36_encode(idx),
+				String::from_utf8_lossy(chunk),
+			)
+		})
+		.collect();
+	Ok(parts)
+}
+
+/// Split the PSBT in BBQr parts of at most [max_part_len] characters.
+/// The capacity of a QR code in alphanumeric mode ranges from 25 characters
+/// for version 1 to 4296 for version 40.
+pub fn bbqr_split(
+	psbt: &PartiallySignedTransaction,
+	encoding: BbqrEncoding,
+	max_part_len: usize,
+) -> Result<Vec<String>> {
+	bbqr_split_data(&serialize(psbt), 'P', encoding, max_part_len)
+}
+
+/// Joins scanned BBQr parts. Parts can be added in any order and more than
+/// once.
+#[derive(Debug, Clone, Default)]
+pub struct BbqrJoiner {
+	/// The encoding and the file type.
+	header: Option<(char, char)>,
+	parts: Vec<Option<String>>,
+}
+
+impl BbqrJoiner {
+	pub fn new() -> BbqrJoiner {
+		Default::default()
+	}
+
+	/// Add a scanned part. Returns whether all parts are scanned.
+	pub fn add_part(&mut self, part: &str) -> Result<bool> {
+		let part = part.trim();
+		if part.len() < 8 || !part.is_ascii() || !part.starts_with("B$") {
+			return Err(invalid("not a BBQr part"));
+		}
+		let mut chars = part[2..4].chars();
+		let header = (
+			chars.next().unwrap().to_ascii_uppercase(),
+			chars.next().unwrap().to_ascii_uppercase(),
+		);
+		let nb_parts = base36_decode(&part[4..6]).ok_or_else(|| invalid("invalid part count"))?;
+		let idx = base36_decode(&part[6..8]).ok_or_else(|| invalid("invalid part index"))?;
+		if idx >= nb_parts {
+			return Err(invalid("part index exceeds the part count"));
+		}
+		match self.header {
+			Some(h) if h != header || self.parts.len() != nb_parts => {
+				return Err(invalid("part of another BBQr code"));
+			}
+			Some(_) => {}
+			None => {
+				self.header = Some(header);
+				self.parts = vec![None; nb_parts];
+			}
+		}
+		self.parts[idx] = Some(part[8..].to_owned());
+		Ok(self.is_complete())
+	}
+
+	pub fn is_complete(&self) -> bool {
+		self.header.is_some() && self.parts.iter().all(|p| p.is_some())
+	}
+
+	/// The file type of the scanned data, like `P` for PSBTs and `T` for txs.
+	pub fn file_type(&self) -> Option<char> {
+		self.header.map(|(_, file_type)| file_type)
+	}
+
+	/// The joined data of all parts.
+	pub fn data(&self) -> Result<Vec<u8>> {
+		if !self.is_complete() {
+			return Err(invalid("not all parts are scanned"));
+		}
+		let encoded: String = self.parts.iter().map(|p| p.as_ref().unwrap().as_str()).collect();
+		match self.header.unwrap().0 {
+			'H' => hex::decode(&encoded).map_err(|_| invalid("invalid hex")),
+			'2' => base32_decode(&encoded).ok_or_else(|| invalid("invalid base32")),
+			'Z' => {
+				let compressed = base32_decode(&encoded).ok_or_else(|| invalid("invalid base32"))?;
+				inflate(&compressed).ok_or_else(|| invalid("invalid compressed data"))
+			}
+			_ => Err(invalid("unknown encoding")),
+		}
+	}
+
+	/// The joined PSBT, for BBQr codes of file type `P`.
+	pub fn psbt(&self) -> Result<PartiallySignedTransaction> {
+		if self.file_type() != Some('P') {
+			return Err(invalid("not a PSBT"));
+		}
+		deserialize(&self.data()?).map_err(|_| invalid("invalid PSBT"))
+	}
+
+	/// The joined tx, for BBQr codes of file type `T`, which signers create
+	/// when they finalize the tx.
+	pub fn transaction(&self) -> Result<Transaction> {
+		if self.file_type() != Some('T') {
+			return Err(invalid("not a tx"));
+		}
+		deserialize(&self.data()?).map_err(|_| invalid("invalid tx"))
+	}
+}
+
+/// The maximum size of decompressed BBQr data. Compressed data can expand
+/// about a thousandfold, so scanned data could exhaust the memory otherwise.
+const MAX_INFLATED_LEN: usize = 1 << 24;
+
+/// Decompress raw DEFLATE data (RFC 1951), as in BBQr parts with zlib
+/// encoding. Fails for data larger than [MAX_INFLATED_LEN].
+fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+	const LENGTH_BASE: [u16; 29] = [
+		3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+		131, 163, 195, 227, 258,
+	];
+	const LENGTH_EXTRA: [u8; 29] =
+		[0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+	const DIST_BASE: [u16; 30] = [
+		1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+		2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+	];
+	const DIST_EXTRA: [u8; 30] = [
+		0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12,
+		13, 13,
+	];
+	const CODE_LENGTH_ORDER: [usize; 19] =
+		[16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+	let mut reader = InflateReader {
+		data: data,
+		pos: 0,
+		bit: 0,
+	};
+	let mut out = Vec::new();
+	loop {
+		let last = reader.bits(1)? == 1;
+		match reader.bits(2)? {
+			0 => {
+				// Stored block, aligned to the next byte.
+				if reader.bit > 0 {
+					reader.bit = 0;
+					reader.pos += 1;
+				}
+				let header = data.get(reader.pos..reader.pos + 4)?;
+				let len = header[0] as usize | (header[1] as usize) << 8;
+				let nlen = header[2] as usize | (header[3] as usize) << 8;
+				if len != !nlen & 0xffff {
+					return None;
+				}
+				reader.pos += 4;
+				if out.len() + len > MAX_INFLATED_LEN {
+					return None;
+				}
+				out.extend_from_slice(data.get(reader.pos..reader.pos + len)?);
+				reader.pos += len;
+			}
+			block_type @ 1..=2 => {
+				let (lengths, distances) = if block_type == 1 {
+					let mut lengths = [8u8; 288];
+					for l in lengths[144..256].iter_mut() {
+						*l = 9;
+					}
+					for l in lengths[256..280].iter_mut() {
+						*l = 7;
+					}
+					(Huffman::new(&lengths), Huffman::new(&[5; 30]))
+				} else {
+					let nb_lengths = reader.bits(5)? as usize + 257;
+					let nb_distances = reader.bits(5)? as usize + 1;
+					let nb_code_lengths = reader.bits(4)? as usize + 4;
+					let mut code_lengths = [0u8; 19];
+					for idx in CODE_LENGTH_ORDER.iter().take(nb_code_lengths) {
+						code_lengths[*idx] = reader.bits(3)? as u8;
+					}
+					let code_lengths = Huffman::new(&code_lengths);
+					let mut all = Vec::with_capacity(nb_lengths + nb_distances);
+					while all.len() < nb_lengths + nb_distances {
+						let (value, repeat) = match code_lengths.decode(&mut reader)? {
+							sym @ 0..=15 => (sym as u8, 1),
+							16 => (*all.last()?, 3 + reader.bits(2)?),
+							17 => (0, 3 + reader.bits(3)?),
+							18 => (0, 11 + reader.bits(7)?),
+							_ => return None,
+						};
+						for _ in 0..repeat {
+							all.push(value);
+						}
+					}
+					if all.len() != nb_lengths + nb_distances {
+						return None;
+					}
+					(Huffman::new(&all[..nb_lengths]), Huffman::new(&all[nb_lengths..]))
+				};
+				loop {
+					let symbol = lengths.decode(&mut reader)? as usize;
+					if symbol < 256 {
+						if out.len() >= MAX_INFLATED_LEN {
+							return None;
+						}
+						out.push(symbol as u8);
+						continue;
+					} else if symbol == 256 {
+						break;
+					}
+					let idx = symbol - 257;
+					let len = *LENGTH_BASE.get(idx)? as usize
+						+ reader.bits(*LENGTH_EXTRA.get(idx)? as u32)? as usize;
+					let idx = distances.decode(&mut reader)? as usize;
+					let dist = *DIST_BASE.get(idx)? as usize
+						+ reader.bits(*DIST_EXTRA.get(idx)? as u32)? as usize;
+					if dist > out.len() || out.len() + len > MAX_INFLATED_LEN {
+						return None;
+					}
+					for _ in 0..len {
+						let byte = out[out.len() - dist];
+						out.push(byte);
+					}
+				}
+			}
+			_ => return None,
+		}
+		if last {
+			return Some(out);
+		}
+	}
+}
+
+/// Reads DEFLATE data, least significant bit first.
+struct InflateReader<'a> {
+	data: &'a [u8],
+	pos: usize,
+	bit: u32,
+}
+
+impl<'a> InflateReader<'a> {
+	fn bits(&mut self, count: u32) -> Option<u32> {
+		let mut ret = 0;
+		for i in 0..count {
+			let byte = *self.data.get(self.pos)?;
+			ret |= ((byte as u32 >> self.bit) & 1) << i;
+			self.bit += 1;
+			if self.bit == 8 {
+				self.bit = 0;
+				self.pos += 1;
+			}
+		}
+		Some(ret)
+	}
+}
+
+/// A canonical Huffman code of DEFLATE.
+struct Huffman {
+	/// The number of codes of each length.
+	counts: [u16; 16],
+	/// The symbols ordered by their code.
+	symbols: Vec<u16>,
+}
+
+impl Huffman {
+	fn new(lengths: &[u8]) -> Huffman {
+		let mut counts = [0u16; 16];
+		for len in lengths {
+			counts[*len as usize] += 1;
+		}
+		let mut offsets = [0u16; 16];
+		for len in 1..15 {
+			offsets[len + 1] = offsets[len] + counts[len];
+		}
+		let mut symbols = vec![0; lengths.len()];
+		for (symbol, len) in lengths.iter().enumerate() {
+			if *len != 0 {
+				symbols[offsets[*len as usize] as usize] = symbol as u16;
+				offsets[*len as usize] += 1;
+			}
+		}
+		Huffman {
+			counts: counts,
+			symbols: symbols,
+		}
+	}
+
+	fn decode(&self, reader: &mut InflateReader) -> Option<u16> {
+		let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+		for len in 1..16 {
+			code |= reader.bits(1)? as i32;
+			let count = self.counts[len] as i32;
+			if code - count < first {
+				return self.symbols.get((index + code - first) as usize).cloned();
+			}
+			index += count;
+			first = (first + count) << 1;
+			code <<= 1;
+		}
+		None
+	}
+}
+
+// UR
+
+/// The UR type of PSBTs.
+const UR_TYPE: &'static str = "crypto-psbt";
+
+/// The fragments of UR parts are at least this long.
+const UR_MIN_FRAGMENT_LEN: usize = 10;
+
+/// The maximum length of a UR message. Scanned parts claim the length of the
+/// whole message, so they could make the decoder allocate too much otherwise.
+const MAX_UR_MESSAGE: usize = 1 << 24;
+
+/// The 256 bytewords, of which UR uses the first and last letter.
+const BYTEWORDS: &'static str = "\
+	ableacidalsoapexaquaarchatomauntawayaxisbackbaldbarnbeltbetabiasbluebodybragbrewbulbbuzz\
+	calmcashcatschefcityclawcodecolacookcostcruxcurlcuspcyandarkdatadaysdelidicedietdoordown\
+	drawdropdrumdulldutyeacheasyechoedgeepicevenexamexiteyesfactfairfernfigsfilmfishfizzflap\
+	flewfluxfoxyfreefrogfuelfundgalagamegeargemsgiftgirlglowgoodgraygrimgurugushgyrohalfhang\
+	hardhawkheathelphighhillholyhopehornhutsicedideaidleinchinkyintoirisironitemjadejazzjoin\
+	joltjowljudojugsjumpjunkjurykeepkenokeptkeyskickkilnkingkitekiwiknoblamblavalazyleaflegs\
+	liarlimplionlistlogoloudloveluaulucklungmainmanymathmazememomenumeowmildmintmissmonknail\
+	navyneednewsnextnoonnotenumbobeyoboeomitonyxopenovalowlspaidpartpeckplaypluspoempoolpose\
+	puffpumapurrquadquizraceramprealredorichroadrockroofrubyruinrunsrustsafesagascarsetssilk\
+	skewslotsoapsolosongstubsurfswantacotasktaxitenttiedtimetinytoiltombtoystriptunatwinugly\
+	undouniturgeuservastveryvetovialvibeviewvisavoidvowswallwandwarmwaspwavewaxywebswhatwhen\
+	whizwolfworkyankyawnyellyogayurtzapszerozestzinczonezoom";
+
+/// The minimal bytewords encoding of the data with its checksum.
+fn bytewords_encode(data: &[u8]) -> String {
+	let words = BYTEWORDS.as_bytes();
+	let mut checksum = [0; 4];
+	checksum.copy_from_slice(&crc32(data).to_be_bytes());
+	let mut ret = String::with_capacity((data.len() + 4) * 2);
+	for byte in data.iter().chain(checksum.iter()) {
+		let word = &words[*byte as usize * 4..*byte as usize * 4 + 4];
+		ret.push(word[0].to_ascii_uppercase() as char);
+		ret.push(word[3].to_ascii_uppercase() as char);
+	}
+	ret
+}
+
+fn bytewords_decode(data: &str) -> Option<Vec<u8>> {
+	let words = BYTEWORDS.as_bytes();
+	let data = data.as_bytes();
+	if data.len() % 2 != 0 {
+		return None;
+	}
+	let mut ret = Vec::with_capacity(data.len() / 2);
+	for pair in data.chunks(2) {
+		let (first, last) = (pair[0].to_ascii_lowercase(), pair[1].to_ascii_lowercase());
+		ret.push(words.chunks(4).position(|w| w[0] == first && w[3] == last)? as u8);
+	}
+	if ret.len() < 4 {
+		return None;
+	}
+	let split = ret.len() - 4;
+	if ret[split..] != crc32(&ret[..split]).to_be_bytes() {
+		return None;
+	}
+	ret.truncate(split);
+	Some(ret)
+}
+
+fn cbor_push_header(buf: &mut Vec<u8>, major: u8, value: u64) {
+	let major = major << 5;
+	if value < 24 {
+		buf.push(major | value as u8);
+	} else if value <= 0xff {
+		buf.push(major | 24);
+		buf.push(value as u8);
+	} else if value <= 0xffff {
+		buf.push(major | 25);
+		buf.extend_from_slice(&(value as u16).to_be_bytes());
+	} else if value <= 0xffffffff {
+		buf.push(major | 26);
+		buf.extend_from_slice(&(value as u32).to_be_bytes());
+	} else {
+		buf.push(major | 27);
+		buf.extend_from_slice(&value.to_be_bytes());
+	}
+}
+
+/// Read a CBOR header of the major type, returning its value.
+fn cbor_read_header(data: &mut &[u8], major: u8) -> Option<u64> {
+	let (first, rest) = data.split_first()?;
+	if first >> 5 != major {
+		return None;
+	}
+	let len = match first & 0x1f {
+		v @ 0..=23 => {
+			*data = rest;
+			return Some(v as u64);
+		}
+		24 => 1,
+		25 => 2,
+		26 => 4,
+		27 => 8,
+		_ => return None,
+	};
+	let bytes = rest.get(..len)?;
+	*data = &rest[len..];
+	Some(bytes.iter().fold(0, |acc, b| acc << 8 | *b as u64))
+}
+
+fn cbor_read_bytes<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+	let len = cbor_read_header(data, 2)? as usize;
+	let bytes = data.get(..len)?;
+	*data = &data[len..];
+	Some(bytes)
+}
+
+/// The xoshiro256** generator UR uses to pick the fragments of a part.
+struct Xoshiro256 {
+	s: [u64; 4],
+}
+
+impl Xoshiro256 {
+	fn new(seed: &[u8]) -> Xoshiro256 {
+		let hash = sha256::Hash::hash(seed);
+		let mut s = [0; 4];
+		for (i, chunk) in hash[..].chunks(8).enumerate() {
+			s[i] = chunk.iter().fold(0, |acc, b| acc << 8 | *b as u64);
+		}
+		Xoshiro256 {
+			s: s,
+		}
+	}
+
+	fn next(&mut self) -> u64 {
+		let result = self.s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+		let t = self.s[1] << 17;
+		self.s[2] ^= self.s[0];
+		self.s[3] ^= self.s[1];
+		self.s[1] ^= self.s[2];
+		self.s[0] ^= self.s[3];
+		self.s[2] ^= t;
+		self.s[3] = self.s[3].rotate_left(45);
+		result
+	}
+
+	fn next_double(&mut self) -> f64 {
+		self.next() as f64 / (u64::max_value() as f64 + 1.0)
+	}
+
+	fn next_int(&mut self, low: usize, high: usize) -> usize {
+		(self.next_double() * (high - low + 1) as f64) as usize + low
+	}
+}
+
+/// The indices of the fragments XORed into the part with the sequence number.
+/// The first [seq_len] parts hold a single fragment each.
+fn choose_fragments(seq_num: u32, seq_len: usize, checksum: u32) -> Vec<usize> {
+	if seq_num as usize <= seq_len {
+		return vec![seq_num as usize - 1];
+	}
+
+	let mut seed = seq_num.to_be_bytes().to_vec();
+	seed.extend_from_slice(&checksum.to_be_bytes());
+	let mut rng = Xoshiro256::new(&seed);
+
+	// Pick the degree with probability 1/degree, with Walker's alias method.
+	let n = seq_len;
+	let total: f64 = (1..=n).map(|i| 1.0 / i as f64).sum();
+	let mut probs: Vec<f64> = (1..=n).map(|i| 1.0 / i as f64 * n as f64 / total).collect();
+	let (mut small, mut large) = (Vec::new(), Vec::new());
+	for i in (0..n).rev() {
+		if probs[i] < 1.0 {
+			small.push(i);
+		} else {
+			large.push(i);
+		}
+	}
+	let mut alias_probs = vec![1.0; n];
+	let mut aliases = vec![0; n];
+	while !small.is_empty() && !large.is_empty() {
+		let (a, g) = (small.pop().unwrap(), large.pop().unwrap());
+		alias_probs[a] = probs[a];
+		aliases[a] = g;
+		probs[g] += probs[a] - 1.0;
+		if probs[g] < 1.0 {
+			small.push(g);
+		} else {
+			large.push(g);
+		}
+	}
+	let (r1, r2) = (rng.next_double(), rng.next_double());
+	let i = (n as f64 * r1) as usize;
+	let degree = if r2 < alias_probs[i] {
+		i + 1
+	} else {
+		aliases[i] + 1
+	};
+
+	let mut remaining: Vec<usize> = (0..n).collect();
+	let mut shuffled = Vec::with_capacity(n);
+	while !remaining.is_empty() {
+		let idx = rng.next_int(0, remaining.len() - 1);
+		shuffled.push(remaining.remove(idx));
+	}
+	shuffled.truncate(degree);
+	shuffled
+}
+
+/// Creates the UR parts of a PSBT.
+#[derive(Debug, Clone)]
+pub struct UrEncoder {
+	/// The CBOR encoded PSBT.
+	message: Vec<u8>,
+	checksum: u32,
+	fragments: Vec<Vec<u8>>,
+}
+
+impl UrEncoder {
+	/// Split the PSBT in fragments of at most [max_fragment_len] bytes.
+	/// A part holds the fragment in two characters per byte, plus about 30
+	/// characters for its header and metadata.
+	pub fn new(psbt: &PartiallySignedTransaction, max_fragment_len: usize) -> UrEncoder {
+		let psbt = serialize(psbt);
+		let mut message = Vec::with_capacity(psbt.len() + 9);
+		cbor_push_header(&mut message, 2, psbt.len() as u64);
+		message.extend_from_slice(&psbt);
+		UrEncoder::from_message(message, max_fragment_len)
+	}
+
+	/// Split the CBOR encoded message.
+	fn from_message(message: Vec<u8>, max_fragment_len: usize) -> UrEncoder {
+		// The smallest number of fragments of at most the maximum length.
+		let max_fragment_len = max_fragment_len.max(UR_MIN_FRAGMENT_LEN);
+		let max_count = (message.len() + UR_MIN_FRAGMENT_LEN - 1) / UR_MIN_FRAGMENT_LEN;
+		let mut fragment_len = message.len();
+		for count in 1..=max_count {
+			fragment_len = (message.len() + count - 1) / count;
+			if fragment_len <= max_fragment_len {
+				break;
+			}
+		}
+		let mut fragments: Vec<Vec<u8>> =
+			message.chunks(fragment_len).map(|f| f.to_vec()).collect();
+		// The last fragment is padded with zeros.
+		if let Some(last) = fragments.last_mut() {
+			last.resize(fragment_len, 0);
+		}
+
+		UrEncoder {
+			checksum: crc32(&message),
+			message: message,
+			fragments: fragments,
+		}
+	}
+
+	/// The number of parts needed to transfer the PSBT without loss.
+	pub fn seq_len(&self) -> usize {
+		self.fragments.len()
+	}
+
+	/// The part with the sequence number, starting at 1. Parts after the
+	/// first [seq_len] ones combine multiple fragments, so that scanners can
+	/// recover missed parts. Show the parts with increasing sequence numbers
+	/// until the signer has scanned all of them.
+	pub fn part(&self, seq_num: u32) -> String {
+		assert!(seq_num > 0, "sequence numbers start at 1");
+		if self.fragments.len() == 1 {
+			return format!("UR:{}/{}", UR_TYPE.to_uppercase(), bytewords_encode(&self.message));
+		}
+
+		let mut fragment = vec![0; self.fragments[0].len()];
+		for idx in choose_fragments(seq_num, self.seq_len(), self.checksum) {
+			for (byte, other) in fragment.iter_mut().zip(self.fragments[idx].iter()) {
+				*byte ^= *other;
+			}
+		}
+		let mut part = Vec::with_capacity(fragment.len() + 20);
+		cbor_push_header(&mut part, 4, 5);
+		cbor_push_header(&mut part, 0, seq_num as u64);
+		cbor_push_header(&mut part, 0, self.seq_len() as u64);
+		cbor_push_header(&mut part, 0, self.message.len() as u64);
+		cbor_push_header(&mut part, 0, self.checksum as u64);
+		cbor_push_header(&mut part, 2, fragment.len() as u64);
+		part.extend_from_slice(&fragment);
+		format!(
+			"UR:{}/{}-{}/{}",
+			UR_TYPE.to_uppercase(),
+			seq_num,
+			self.seq_len(),
+			bytewords_encode(&part),
+		)
+	}
+
+	/// The first [seq_len] parts, which hold all fragments once.
+	pub fn parts(&self) -> Vec<String> {
+		(1..=self.seq_len() as u32).map(|seq_num| self.part(seq_num)).collect()
+	}
+}
+
+/// Joins scanned UR parts of a PSBT. Parts can be added in any order and
+/// more than once.
+#[derive(Debug, Clone, Default)]
+pub struct UrDecoder {
+	/// The sequence length, message length and checksum of the parts.
+	params: Option<(usize, usize, u32)>,
+	fragments: Vec<Option<Vec<u8>>>,
+	/// Scanned parts combining fragments that are not all known yet.
+	mixed: Vec<(Vec<usize>, Vec<u8>)>,
+	/// The message of a single-part UR.
+	message: Option<Vec<u8>>,
+}
+
+impl UrDecoder {
+	pub fn new() -> UrDecoder {
+		Default::default()
+	}
+
+	/// Add a scanned part. Returns whether the PSBT is complete.
+	pub fn add_part(&mut self, part: &str) -> Result<bool> {
+		let part = part.trim().to_lowercase();
+		let mut components = part.split('/');
+		let scheme = components.next().unwrap_or("");
+		if !scheme.starts_with("ur:") || &scheme[3..] != UR_TYPE {
+			return Err(invalid("not a crypto-psbt UR"));
+		}
+		let (seq, data) = match (components.next(), components.next(), components.next()) {
+			(Some(data), None, _) => (None, data),
+			(Some(seq), Some(data), None) => (Some(seq), data),
+			_ => return Err(invalid("invalid UR")),
+		};
+		let data = bytewords_decode(data).ok_or_else(|| invalid("invalid bytewords"))?;
+		if seq.is_none() {
+			self.message = Some(data);
+			return Ok(true);
+		}
+
+		let mut cursor = &data[..];
+		let malformed = || invalid("malformed part");
+		if cbor_read_header(&mut cursor, 4) != Some(5) {
+			return Err(malformed());
+		}
+		let seq_num = cbor_read_header(&mut cursor, 0).ok_or_else(malformed)?;
+		let seq_len = cbor_read_header(&mut cursor, 0).ok_or_else(malformed)? as usize;
+		let message_len = cbor_read_header(&mut cursor, 0).ok_or_else(malformed)? as usize;
+		let checksum = cbor_read_header(&mut cursor, 0).ok_or_else(malformed)? as u32;
+		let fragment = cbor_read_bytes(&mut cursor).ok_or_else(malformed)?.to_vec();
+		if seq_num == 0 || seq_num > u32::max_value() as u64 {
+			return Err(malformed());
+		}
+		if seq_len == 0 || seq_len > message_len || message_len > MAX_UR_MESSAGE {
+			return Err(malformed());
+		}
+		match fragment.len().checked_mul(seq_len) {
+			Some(len) if len >= message_len => {}
+			_ => return Err(malformed()),
+		}
+		// The sequence in the path must match the one of the part.
+		let mut seq = seq.unwrap().split('-').map(|s| s.parse::<u64>().ok());
+		match (seq.next(), seq.next(), seq.next()) {
+			(Some(Some(n)), Some(Some(l)), None) if n == seq_num && l == seq_len as u64 => {}
+			_ => return Err(malformed()),
+		}
+
+		let params = (seq_len, message_len, checksum);
+		match self.params {
+			Some(p) if p != params => return Err(invalid("part of another UR")),
+			Some(_) => {}
+			None => {
+				self.params = Some(params);
+				self.fragments = vec![None; seq_len];
+			}
+		}
+		let fragment_len = self.mixed.first().map(|m| m.1.len()).or_else(|| {
+			self.fragments.iter().filter_map(|f| f.as_ref()).map(|f| f.len()).next()
+		});
+		if fragment_len.map(|l| l != fragment.len()).unwrap_or(false) {
+			return Err(invalid("part of another UR"));
+		}
+
+		self.mixed.push((choose_fragments(seq_num as u32, seq_len, checksum), fragment));
+		self.reduce();
+		Ok(self.is_complete())
+	}
+
+	/// Remove the known fragments from the mixed parts, until no more
+	/// fragments can be recovered.
+	fn reduce(&mut self) {
+		loop {
+			let mut progress = false;
+			for (indices, data) in self.mixed.iter_mut() {
+				let fragments = &self.fragments;
+				let known: Vec<usize> =
+					indices.iter().cloned().filter(|i| fragments[*i].is_some()).collect();
+				for idx in known {
+					for (byte, other) in data.iter_mut().zip(fragments[idx].as_ref().unwrap()) {
+						*byte ^= *other;
+					}
+					indices.retain(|i| *i != idx);
+				}
+				if indices.len() == 1 {
+					self.fragments[indices[0]] = Some(data.clone());
+					indices.clear();
+					progress = true;
+				}
+			}
+			self.mixed.retain(|(indices, _)| !indices.is_empty());
+			if !progress {
+				return;
+			}
+		}
+	}
+
+	pub fn is_complete(&self) -> bool {
+		self.message.is_some()
+			|| (!self.fragments.is_empty() && self.fragments.iter().all(|f| f.is_some()))
+	}
+
+	/// The progress of the scan, the number of recovered fragments and
+	/// the total number of fragments.
+	pub fn progress(&self) -> (usize, usize) {
+		if self.message.is_some() {
+			return (1, 1);
+		}
+		(self.fragments.iter().filter(|f| f.is_some()).count(), self.fragments.len())
+	}
+
+	/// The joined PSBT.
+	pub fn psbt(&self) -> Result<PartiallySignedTransaction> {
+		let message = self.message()?;
+		let mut cursor = &message[..];
+		let psbt = cbor_read_bytes(&mut cursor).ok_or_else(|| invalid("malformed PSBT"))?;
+		deserialize(psbt).map_err(|_| invalid("invalid PSBT"))
+	}
+
+	/// The joined CBOR encoded message.
+	fn message(&self) -> Result<Vec<u8>> {
+		Ok(match self.message {
+			Some(ref message) => message.clone(),
+			None => {
+				if !self.is_complete() {
+					return Err(invalid("not all parts are scanned"));
+				}
+				let (_, message_len, checksum) = self.params.unwrap();
+				let mut message: Vec<u8> =
+					self.fragments.iter().flat_map(|f| f.as_ref().unwrap().clone()).collect();
+				message.truncate(message_len);
+				if crc32(&message) != checksum {
+					return Err(invalid("checksum mismatch"));
+				}
+				message
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::{OutPoint, Script, TxIn, TxOut};
+
+	/// A PSBT of a tx with many outputs, so that it needs multiple parts.
+	fn test_psbt() -> PartiallySignedTransaction {
+		let tx = Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: OutPoint::null(),
+				script_sig: Script::new(),
+				sequence: 0xfffffffd,
+				witness: Vec::new(),
+			}],
+			output: (0..50u8)
+				.map(|i| TxOut {
+					value: 1000 * i as u64,
+					script_pubkey: Script::from([&[0x00, 0x14][..], &[i; 20][..]].concat()),
+				})
+				.collect(),
+		};
+		PartiallySignedTransaction::from_unsigned_tx(tx).unwrap()
+	}
+
+	/// The message of the UR test vectors, random bytes seeded with "Wolf".
+	fn make_message(len: usize) -> Vec<u8> {
+		let mut rng = Xoshiro256::new(b"Wolf");
+		(0..len).map(|_| rng.next_int(0, 255) as u8).collect()
+	}
+
+	/// The UR test vectors encode the message as a CBOR byte string.
+	fn make_message_ur(len: usize) -> Vec<u8> {
+		let mut message = Vec::new();
+		cbor_push_header(&mut message, 2, len as u64);
+		message.extend_from_slice(&make_message(len));
+		message
+	}
+
+	/// The test vectors are of the `bytes` UR type.
+	fn as_bytes_ur(part: &str) -> String {
+		part.to_lowercase().replace("ur:crypto-psbt/", "ur:bytes/")
+	}
+
+	fn as_psbt_ur(part: &str) -> String {
+		part.replace("ur:bytes/", "ur:crypto-psbt/")
+	}
+
+	#[test]
+	fn crc32_vectors() {
+		assert_eq!(crc32(b"123456789"), 0xcbf43926);
+		assert_eq!(crc32(b"Hello, world!"), 0xebe6c6e6);
+		assert_eq!(crc32(&make_message(256)), 23570951);
+	}
+
+	#[test]
+	fn xoshiro_vectors() {
+		let mut rng = Xoshiro256::new(b"Wolf");
+		let numbers: Vec<u64> = (0..100).map(|_| rng.next() % 100).collect();
+		let expected = vec![
+			42, 81, 85, 8, 82, 84, 76, 73, 70, 88, 2, 74, 40, 48, 77, 54, 88, 7, 5, 88, 37, 25, 82,
+			13, 69, 59, 30, 39, 11, 82, 19, 99, 45, 87, 30, 15, 32, 22, 89, 44, 92, 77, 29, 78, 4,
+			92, 44, 68, 92, 69, 1, 42, 89, 50, 37, 84, 63, 34, 32, 3, 17, 62, 40, 98, 82, 89, 24,
+			43, 85, 39, 15, 3, 99, 29, 20, 42, 27, 10, 85, 66, 50, 35, 69, 70, 70, 74, 30, 13, 72,
+			54, 11, 5, 70, 55, 91, 52, 10, 43, 43, 52,
+		];
+		assert_eq!(numbers, expected);
+	}
+
+	#[test]
+	fn choose_fragments_vectors() {
+		let message = make_message(1024);
+		let checksum = crc32(&message);
+		let mut chosen: Vec<Vec<usize>> = (1..=21)
+			.map(|seq_num| {
+				let mut indices = choose_fragments(seq_num, 11, checksum);
+				indices.sort();
+				indices
+			})
+			.collect();
+		let mixed = chosen.split_off(11);
+		assert_eq!(chosen, (0..11).map(|i| vec![i]).collect::<Vec<_>>());
+		let expected = vec![
+			vec![9],
+			vec![2, 5, 6, 8, 9, 10],
+			vec![8],
+			vec![1, 5],
+			vec![1],
+			vec![0, 2, 4, 5, 8, 10],
+			vec![5],
+			vec![2],
+			vec![2],
+			vec![0, 1, 3, 4, 5, 7, 9, 10],
+		];
+		assert_eq!(mixed, expected);
+	}
+
+	#[test]
+	fn bytewords_vectors() {
+		let data = vec![0x00, 0x01, 0x02, 0x80, 0xff];
+		assert_eq!(bytewords_encode(&data), "AEADAOLAZMJENDEOTI");
+		assert_eq!(bytewords_decode("aeadaolazmjendeoti"), Some(data));
+		// Wrong checksum, unknown word and odd length.
+		assert_eq!(bytewords_decode("aeadaolazmjendeota"), None);
+		assert_eq!(bytewords_decode("aeadaolazmjendeoxx"), None);
+		assert_eq!(bytewords_decode("aeadaolazmjendeot"), None);
+	}
+
+	#[test]
+	fn ur_single_part_vector() {
+		let encoder = UrEncoder::from_message(make_message_ur(50), 100);
+		let expected = "ur:bytes/hdeymejtswhhylkepmykhhtsytsnoyoyaxaedsuttydmmhhpktpmsrjtgwdpf\
+			nsboxgwlbaawzuefywkdplrsrjynbvygabwjldapfcsdwkbrkch";
+		assert_eq!(encoder.seq_len(), 1);
+		assert_eq!(as_bytes_ur(&encoder.part(1)), expected);
+
+		let mut decoder = UrDecoder::new();
+		assert_eq!(decoder.add_part(&as_psbt_ur(expected)), Ok(true));
+		assert_eq!(decoder.message(), Ok(make_message_ur(50)));
+	}
+
+	const MULTIPART_VECTORS: [&'static str; 20] = [
+		"ur:bytes/1-9/lpadascfadaxcywenbpljkhdcahkadaemejtswhhylkepmykhhtsytsnoyoy\
+			axaedsuttydmmhhpktpmsrjtdkgslpgh",
+		"ur:bytes/2-9/lpaoascfadaxcywenbpljkhdcagwdpfnsboxgwlbaawzuefywkdplrsrjynb\
+			vygabwjldapfcsgmghhkhstlrdcxaefz",
+		"ur:bytes/3-9/lpaxascfadaxcywenbpljkhdcahelbknlkuejnbadmssfhfrdpsbiegecpas\
+			vssovlgeykssjykklronvsjksopdzmol",
+		"ur:bytes/4-9/lpaaascfadaxcywenbpljkhdcasotkhemthydawydtaxneurlkosgwcekone\
+			rtkbrlwmplssjtammdplolsbrdzcrtas",
+		"ur:bytes/5-9/lpahascfadaxcywenbpljkhdcatbbdfmssrkzmcwnezelennjpfzbgmuktrh\
+			tejscktelgfpdlrkfyfwdajldejokbwf",
+		"ur:bytes/6-9/lpamascfadaxcywenbpljkhdcackjlhkhybssklbwefectpfnbbectrlject\
+			pavyrolkzczcpkmwidmwoxkilghdsowp",
+		"ur:bytes/7-9/lpatascfadaxcywenbpljkhdcavszmwnjkwtclrtvaynhpahrtoxmwvwatme\
+			dibkaegdosftvandiodagdhthtrlnnhy",
+		"ur:bytes/8-9/lpayascfadaxcywenbpljkhdcadmsponkkbbhgsoltjntegepmttmoonftnb\
+			uoiyrehfrtsabzsttorodklubbuyaetk",
+		"ur:bytes/9-9/lpasascfadaxcywenbpljkhdcajskecpmdckihdyhphfotjojtfmlnwmadsp\
+			axrkytbztpbauotbgtgtaeaevtgavtny",
+		"ur:bytes/10-9/lpbkascfadaxcywenbpljkhdcahkadaemejtswhhylkepmykhhtsytsnoyoy\
+			axaedsuttydmmhhpktpmsrjtwdkiplzs",
+		"ur:bytes/11-9/lpbdascfadaxcywenbpljkhdcahelbknlkuejnbadmssfhfrdpsbiegecpas\
+			vssovlgeykssjykklronvsjkvetiiapk",
+		"ur:bytes/12-9/lpbnascfadaxcywenbpljkhdcarllaluzmdmgstospeyiefmwejlwtpedamk\
+			tksrvlcygmzemovovllarodtmtbnptrs",
+		"ur:bytes/13-9/lpbtascfadaxcywenbpljkhdcamtkgtpknghchchyketwsvwgwfdhpgmgtyl\
+			ctotzopdrpayoschcmhplffziachrfgd",
+		"ur:bytes/14-9/lpbaascfadaxcywenbpljkhdcapazewnvonnvdnsbyleynwtnsjkjndeoldy\
+			dkbkdslgjkbbkortbelomueekgvstegt",
+		"ur:bytes/15-9/lpbsascfadaxcywenbpljkhdcaynmhpddpzmversbdqdfyrehnqzlugmjzmn\
+			mtwmrouohtstgsbsahpawkditkckynwt",
+		"ur:bytes/16-9/lpbeascfadaxcywenbpljkhdcawygekobamwtlihsnpalnsghenskkiynthd\
+			zotsimtojetprsttmukirlrsbtamjtpd",
+		"ur:bytes/17-9/lpbyascfadaxcywenbpljkhdcamklgftaxykpewyrtqzhydntpnytyisincx\
+			mhtbceaykolduortotiaiaiafhiaoyce",
+		"ur:bytes/18-9/lpbgascfadaxcywenbpljkhdcahkadaemejtswhhylkepmykhhtsytsnoyoy\
+			axaedsuttydmmhhpktpmsrjtntwkbkwy",
+		"ur:bytes/19-9/lpbwascfadaxcywenbpljkhdcadekicpaajootjzpsdrbalpeywllbdsnbin\
+			aerkurspbncxgslgftvtsrjtksplcpeo",
+		"ur:bytes/20-9/lpbbascfadaxcywenbpljkhdcayapmrleeleaxpasfrtrdkncffwjyjzgyet\
+			dmlewtkpktgllepfrltataztksmhkbot",
+	];
+
+	#[test]
+	fn ur_multipart_vectors() {
+		let encoder = UrEncoder::from_message(make_message_ur(256), 30);
+		assert_eq!(encoder.seq_len(), 9);
+		for (idx, expected) in MULTIPART_VECTORS.iter().enumerate() {
+			assert_eq!(as_bytes_ur(&encoder.part(idx as u32 + 1)), *expected);
+		}
+
+		let mut decoder = UrDecoder::new();
+		for (idx, part) in MULTIPART_VECTORS[..9].iter().enumerate() {
+			assert_eq!(decoder.add_part(&as_psbt_ur(part)), Ok(idx == 8));
+		}
+		assert_eq!(decoder.message(), Ok(make_message_ur(256)));
+	}
+
+	#[test]
+	fn ur_recovers_missed_parts() {
+		let encoder = UrEncoder::from_message(make_message_ur(1024), 100);
+		// Skip two of the single-fragment parts.
+		let mut decoder = UrDecoder::new();
+		let mut seq_num = 0;
+		loop {
+			seq_num += 1;
+			assert!(seq_num < 100, "not recovered");
+			if seq_num == 3 || seq_num == 7 {
+				continue;
+			}
+			if decoder.add_part(&encoder.part(seq_num)).unwrap() {
+				break;
+			}
+		}
+		assert_eq!(decoder.message(), Ok(make_message_ur(1024)));
+
+		// Only the parts mixing multiple fragments.
+		let mut decoder = UrDecoder::new();
+		let mut seq_num = encoder.seq_len() as u32;
+		while !decoder.add_part(&encoder.part(seq_num + 1).to_lowercase()).unwrap() {
+			seq_num += 1;
+			assert!(seq_num < 200, "not recovered");
+		}
+		assert_eq!(decoder.message(), Ok(make_message_ur(1024)));
+	}
+
+	#[test]
+	fn ur_psbt_round_trip() {
+		let psbt = test_psbt();
+		for max_fragment_len in &[1, 50, 200, 10000] {
+			let parts = UrEncoder::new(&psbt, *max_fragment_len).parts();
+			let mut decoder = UrDecoder::new();
+			for (idx, part) in parts.iter().rev().enumerate() {
+				assert_eq!(decoder.add_part(part), Ok(idx == parts.len() - 1));
+				// Adding a part again changes nothing.
+				assert_eq!(decoder.add_part(part), Ok(idx == parts.len() - 1));
+			}
+			assert_eq!(decoder.progress(), (parts.len(), parts.len()));
+			assert_eq!(decoder.psbt(), Ok(psbt.clone()));
+		}
+	}
+
+	#[test]
+	fn ur_invalid_parts() {
+		let encoder = UrEncoder::new(&test_psbt(), 100);
+		let part = encoder.part(1);
+		let mut decoder = UrDecoder::new();
+		assert!(decoder.add_part(&as_bytes_ur(&part)).is_err());
+		assert!(decoder.add_part(&part[..part.len() - 2]).is_err());
+		assert!(decoder.add_part("ur:crypto-psbt/1-2/3/lpad").is_err());
+
+		// A part of another UR.
+		decoder.add_part(&part).unwrap();
+		let other = UrEncoder::from_message(make_message_ur(1024), 100);
+		assert!(decoder.add_part(&other.part(2)).is_err());
+		assert!(decoder.psbt().is_err());
+	}
+
+	/// A part with the given header fields and path.
+	fn crafted_part(path: &str, seq_num: u64, seq_len: u64, message_len: u64) -> String {
+		let mut part = Vec::new();
+		cbor_push_header(&mut part, 4, 5);
+		cbor_push_header(&mut part, 0, seq_num);
+		cbor_push_header(&mut part, 0, seq_len);
+		cbor_push_header(&mut part, 0, message_len);
+		cbor_push_header(&mut part, 0, 0x12345678);
+		cbor_push_header(&mut part, 2, 10);
+		part.extend_from_slice(&[0; 10]);
+		format!("ur:crypto-psbt/{}/{}", path, bytewords_encode(&part))
+	}
+
+	#[test]
+	fn ur_malicious_parts() {
+		// A well-formed part is accepted.
+		let mut decoder = UrDecoder::new();
+		assert_eq!(decoder.add_part(&crafted_part("1-2", 1, 2, 20)), Ok(false));
+
+		// Huge sequence lengths would allocate a fragment slot for each.
+		let huge = u32::max_value() as u64;
+		let mut decoder = UrDecoder::new();
+		assert!(decoder.add_part(&crafted_part(&format!("1-{}", huge), 1, huge, huge)).is_err());
+		assert!(decoder.add_part(&crafted_part("1-100", 1, 100, 20)).is_err());
+		let path = format!("1-{}", 1u64 << 62);
+		assert!(decoder.add_part(&crafted_part(&path, 1, 1 << 62, 1 << 62)).is_err());
+		assert!(decoder.fragments.is_empty());
+
+		// The path must agree with the part.
+		assert!(decoder.add_part(&crafted_part("2-2", 1, 2, 20)).is_err());
+		assert!(decoder.add_part(&crafted_part("1-3", 1, 2, 20)).is_err());
+		assert!(decoder.add_part(&crafted_part("1-2-3", 1, 2, 20)).is_err());
+		assert!(decoder.add_part(&crafted_part("x-2", 1, 2, 20)).is_err());
+		assert!(decoder.add_part(&crafted_part("1-2", (1 << 32) + 1, 2, 20)).is_err());
+		assert!(decoder.params.is_none());
+	}
+
+	#[test]
+	fn base32_vectors() {
+		// RFC 4648, without padding like BBQr.
+		let vectors = [
+			("", ""),
+			("f", "MY"),
+			("fo", "MZXQ"),
+			("foo", "MZXW6"),
+			("foob", "MZXW6YQ"),
+			("fooba", "MZXW6YTB"),
+			("foobar", "MZXW6YTBOI"),
+		];
+		for &(data, encoded) in vectors.iter() {
+			assert_eq!(base32_encode(data.as_bytes()), encoded);
+			assert_eq!(base32_decode(encoded), Some(data.as_bytes().to_vec()));
+			assert_eq!(base32_decode(&encoded.to_lowercase()), Some(data.as_bytes().to_vec()));
+		}
+		assert_eq!(base32_decode("MZXW1"), None);
+	}
+
+	#[test]
+	fn bbqr_round_trip() {
+		let psbt = test_psbt();
+		let data = serialize(&psbt);
+		for &encoding in [BbqrEncoding::Hex, BbqrEncoding::Base32].iter() {
+			let parts = bbqr_split(&psbt, encoding, 300).unwrap();
+			assert!(parts.len() > 1);
+			assert!(parts.iter().all(|p| p.len() <= 300));
+			assert!(parts[..parts.len() - 1].iter().all(|p| p.len() == parts[0].len()));
+			let header = format!("B${}P{}", encoding.code(), base36_encode(parts.len()));
+			assert!(parts.iter().all(|p| p.starts_with(&header)));
+
+			let mut joiner = BbqrJoiner::new();
+			for (idx, part) in parts.iter().rev().enumerate() {
+				assert_eq!(joiner.add_part(part), Ok(idx == parts.len() - 1));
+			}
+			assert_eq!(joiner.file_type(), Some('P'));
+			assert_eq!(joiner.data(), Ok(data.clone()));
+			assert_eq!(joiner.psbt(), Ok(psbt.clone()));
+			assert!(joiner.transaction().is_err());
+		}
+
+		let parts = bbqr_split(&psbt, BbqrEncoding::Hex, 100000).unwrap();
+		assert_eq!(parts.len(), 1);
+		assert_eq!(parts[0], format!("B$HP0100{}", hex::encode(&data).to_uppercase()));
+		assert!(bbqr_split(&psbt, BbqrEncoding::Base32, 15).is_err());
+	}
+
+	#[test]
+	fn bbqr_invalid_parts() {
+		let mut joiner = BbqrJoiner::new();
+		assert!(joiner.add_part("B$HP01").is_err());
+		assert!(joiner.add_part("X$HP0100AA").is_err());
+		assert!(joiner.add_part("B$HP0102AA").is_err());
+		assert!(joiner.add_part("B$HP0200AA").is_ok());
+		assert!(joiner.add_part("B$2P0201AA").is_err());
+		assert!(joiner.add_part("B$HP0301AA").is_err());
+		assert!(joiner.data().is_err());
+	}
+
+	/// The CC0 dedication compressed with zlib at level 9, a dynamic Huffman block.
+	const DYNAMIC_DEFLATE: &'static str = "\
+		KWHECDWCGAGAJP5SI6IFAX6DA7ONUNEWIJODSDVBX4TW2T64KY5SXTJ6BWIQJ4QNFGA422WVHEFVUYLRMTVI6E2TR\
+		NSH5K3XET5AQWCYC4FGCUGOLBWNWXOXCSQME4GJC4M3TSFIM5ZS2K6OIVCRYPVNVD3IUTROK4Q5RWU4OUA5XG5UUC\
+		NWP3WKGLQ7TNY6TG2YN264BZE5P4NLDXRB3AZ3SXMKOHY";
+	const DYNAMIC_PLAIN: &'static str = "To the extent possible under law, the author(s) have \
+		dedicated all copyright and related and neighboring rights to this software to the public \
+		domain worldwide. This software is distributed without any warranty.";
+
+	#[test]
+	fn inflate_vectors() {
+		// A fixed Huffman block.
+		let fixed = [203, 72, 205, 201, 201, 87, 200, 64, 34, 203, 243, 139, 114, 82, 0];
+		assert_eq!(inflate(&fixed), Some(b"hello hello hello world".to_vec()));
+		// A stored block.
+		let stored = [1, 12, 0, 243, 255, 115, 116, 111, 114, 101, 100, 32, 100, 97, 116, 97, 33];
+		assert_eq!(inflate(&stored), Some(b"stored data!".to_vec()));
+		let dynamic = base32_decode(DYNAMIC_DEFLATE).unwrap();
+		assert_eq!(inflate(&dynamic), Some(DYNAMIC_PLAIN.as_bytes().to_vec()));
+
+		// Truncated data and a wrong stored length.
+		assert_eq!(inflate(&dynamic[..dynamic.len() - 1]), None);
+		assert_eq!(inflate(&[1, 12, 0, 243, 254, 115]), None);
+		assert_eq!(inflate(&[]), None);
+	}
+
+	#[test]
+	fn bbqr_zlib_parts() {
+		let (first, second) = DYNAMIC_DEFLATE.split_at(DYNAMIC_DEFLATE.len() / 16 * 8);
+		let mut joiner = BbqrJoiner::new();
+		assert_eq!(joiner.add_part(&format!("B$ZU0201{}", second)), Ok(false));
+		assert_eq!(joiner.add_part(&format!("B$ZU0200{}", first)), Ok(true));
+		assert_eq!(joiner.file_type(), Some('U'));
+		assert_eq!(joiner.data(), Ok(DYNAMIC_PLAIN.as_bytes().to_vec()));
+	}
+
+	/// Writes DEFLATE data, least significant bit first.
+	struct BitWriter {
+		data: Vec<u8>,
+		nb_bits: usize,
+	}
+
+	impl BitWriter {
+		fn push(&mut self, value: u32, count: usize) {
+			for i in 0..count {
+				if self.nb_bits % 8 == 0 {
+					self.data.push(0);
+				}
+				*self.data.last_mut().unwrap() |= ((value >> i & 1) as u8) << (self.nb_bits % 8);
+				self.nb_bits += 1;
+			}
+		}
+
+		/// Huffman codes are written most significant bit first.
+		fn push_code(&mut self, code: u32, len: usize) {
+			for i in (0..len).rev() {
+				self.push(code >> i & 1, 1);
+			}
+		}
+	}
+
+	#[test]
+	fn inflate_limits_size() {
+		// A fixed Huffman block of a zero byte and copies of 258 bytes at distance 1.
+		let bomb = |copies: usize| {
+			let mut writer = BitWriter {
+				data: Vec::new(),
+				nb_bits: 0,
+			};
+			writer.push(1, 1);
+			writer.push(1, 2);
+			writer.push_code(0x30, 8);
+			for _ in 0..copies {
+				// Length symbol 285 and distance symbol 0.
+				writer.push_code(0xc5, 8);
+				writer.push_code(0, 5);
+			}
+			writer.push_code(0, 7);
+			writer.data
+		};
+		let copies = (MAX_INFLATED_LEN - 1) / 258;
+		assert_eq!(inflate(&bomb(copies)).map(|d| d.len()), Some(1 + copies * 258));
+		assert_eq!(inflate(&bomb(copies + 1)), None);
+	}
+
+	#[test]
+	fn fuzz_inflate() {
+		let samples = [
+			vec![203, 72, 205, 201, 201, 87, 200, 64, 34, 203, 243, 139, 114, 82, 0],
+			vec![1, 12, 0, 243, 255, 115, 116, 111, 114, 101, 100, 32, 100, 97, 116, 97, 33],
+			base32_decode(DYNAMIC_DEFLATE).unwrap(),
+		];
+		let mut rng = Xoshiro256::new(b"inflate");
+		for i in 0..20000 {
+			// Random data, or a sample with a few bits flipped, bytes replaced or cut off.
+			let mut data: Vec<u8> = if i % 4 == 0 {
+				(0..rng.next_int(0, 64)).map(|_| rng.next_int(0, 255) as u8).collect()
+			} else {
+				samples[rng.next_int(0, samples.len() - 1)].clone()
+			};
+			for _ in 0..rng.next_int(1, 8) {
+				if i % 4 == 0 || data.is_empty() {
+					break;
+				}
+				let pos = rng.next_int(0, data.len() - 1);
+				match rng.next_int(0, 2) {
+					0 => data[pos] ^= 1 << rng.next_int(0, 7),
+					1 => data[pos] = rng.next_int(0, 255) as u8,
+					_ => data.truncate(pos),
+				}
+			}
+			if let Some(inflated) = inflate(&data) {
+				assert!(inflated.len() <= MAX_INFLATED_LEN);
+			}
+		}
+	}
+}
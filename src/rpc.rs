@@ -0,0 +1,71 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! A bitcoind backend using its JSON-RPC API.
+//!
+//! A [bitcoincore_rpc::Client] is a [BlockSource] and a [Broadcaster], and
+//! a [FeeEstimator] as described in [fee_estimator]. Use [sync_with_bitcoind]
+//! to sync the wallet with the node in one call.
+
+use bitcoin::{Block, Transaction};
+use bitcoin_hashes::sha256d;
+use bitcoincore_rpc::{self, Client, RpcApi};
+
+use error::{Error, Result};
+use sync::{self, BlockSource, Broadcaster};
+use wallet::Wallet;
+
+fn backend_error(e: bitcoincore_rpc::Error) -> Error {
+	Error::Backend(e.to_string())
+}
+
+impl BlockSource for Client {
+	fn tip(&self) -> Result<(u32, sha256d::Hash)> {
+		// A single call, so that the height and hash match.
+		let info = self.get_blockchain_info().map_err(backend_error)?;
+		Ok((info.blocks as u32, info.bestblockhash))
+	}
+
+	fn block_hash(&self, height: u32) -> Result<Option<sha256d::Hash>> {
+		if u64::from(height) > self.get_block_count().map_err(backend_error)? {
+			return Ok(None);
+		}
+		self.get_block_hash(u64::from(height)).map(Some).map_err(backend_error)
+	}
+
+	fn block(&self, hash: &sha256d::Hash) -> Result<Block> {
+		self.get_block(hash).map_err(backend_error)
+	}
+}
+
+impl Broadcaster for Client {
+	fn name(&self) -> String {
+		"bitcoind".to_owned()
+	}
+
+	fn broadcast(&self, tx: &Transaction) -> Result<sha256d::Hash> {
+		self.send_raw_transaction(tx).map_err(backend_error)
+	}
+}
+
+/// Sync the wallet to the tip of the node and return the height of the new
+/// tip. A wallet without any processed blocks starts at the current tip.
+pub fn sync_with_bitcoind(wallet: &mut Wallet, client: &Client) -> Result<u32> {
+	if wallet.last_block().is_none() {
+		let (height, hash) = client.tip()?;
+		wallet.set_last_block(hash, height);
+		return Ok(height);
+	}
+	sync::sync_to_tip(wallet, client, |_, _| {})
+}
@@ -0,0 +1,409 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Signing of wallet PSBTs with a private key held in memory, for hot wallets.
+//!
+//! Derived child keys are kept in a [KeyCache] so that signing large txs
+//! doesn't derive the same keys over and over again.
+//!
+//! Once signed, [finalize_psbt] turns the PSBT into a tx that can be broadcast.
+
+use bitcoin::blockdata::script::Builder;
+use bitcoin::blockdata::transaction::SigHashType;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::util::{bip32, psbt};
+use bitcoin::{Address, Script, Transaction};
+use bitcoin_hashes::{hmac, sha256, sha256d, Hash, HashEngine};
+#[cfg(not(target_arch = "wasm32"))]
+use rand::{self, Rng};
+use secp256k1;
+
+use error::{Error, Result};
+
+/// A size-bounded LRU cache of derived private keys.
+///
+/// Keys are stored encrypted with a random key that only lives in memory, so
+/// that the plaintext keys are never kept around longer than needed for signing.
+/// The keystream is HMAC-SHA256 of a unique nonce per entry under that key.
+pub struct KeyCache {
+	capacity: usize,
+	key: [u8; 32],
+	next_nonce: u64,
+	/// The cached keys with their nonce, least recently used first.
+	entries: Vec<(bip32::DerivationPath, u64, [u8; 32])>,
+}
+
+impl KeyCache {
+	/// Create a cache holding at most [capacity] keys, encrypted with a random
+	/// key from the OS. Not available on wasm, use [with_key] there.
+	#[cfg(not(target_arch = "wasm32"))]
+	pub fn new(capacity: usize) -> KeyCache {
+		let mut key = [0; 32];
+		rand::thread_rng().fill_bytes(&mut key);
+		KeyCache::with_key(capacity, key)
+	}
+
+	/// Create a cache holding at most [capacity] keys, encrypted with [key].
+	/// The key should be random and not be used for anything else.
+	pub fn with_key(capacity: usize, key: [u8; 32]) -> KeyCache {
+		KeyCache {
+			capacity: capacity,
+			key: key,
+			next_nonce: 0,
+			entries: Vec::with_capacity(capacity),
+		}
+	}
+
+	fn keystream(&self, nonce: u64) -> [u8; 32] {
+		let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&self.key);
+		for i in 0..8 {
+			engine.input(&[(nonce >> (8 * i)) as u8]);
+		}
+		hmac::Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+	}
+
+	fn xor(data: &mut [u8; 32], keystream: &[u8; 32]) {
+		for (d, k) in data.iter_mut().zip(keystream.iter()) {
+			*d ^= k;
+		}
+	}
+
+	/// Get the key for the path, marking it as recently used.
+	pub fn get(&mut self, path: &bip32::DerivationPath) -> Option<secp256k1::SecretKey> {
+		let idx = self.entries.iter().position(|e| e.0 == *path)?;
+		let entry = self.entries.remove(idx);
+		let mut data = entry.2;
+		KeyCache::xor(&mut data, &self.keystream(entry.1));
+		self.entries.push(entry);
+		secp256k1::SecretKey::from_slice(&data).ok()
+	}
+
+	/// Add the key for the path, evicting the least recently used key when full.
+	pub fn insert(&mut self, path: bip32::DerivationPath, key: &secp256k1::SecretKey) {
+		if self.capacity == 0 {
+			return;
+		}
+		self.entries.retain(|e| e.0 != path);
+		if self.entries.len() >= self.capacity {
+			self.entries.remove(0);
+		}
+
+		let nonce = self.next_nonce;
+		self.next_nonce += 1;
+		let mut data = [0; 32];
+		data.copy_from_slice(&key[..]);
+		KeyCache::xor(&mut data, &self.keystream(nonce));
+		self.entries.push((path, nonce, data));
+	}
+
+	/// Remove all keys from the cache.
+	pub fn clear(&mut self) {
+		self.entries.clear();
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+}
+
+/// Signs PSBTs created by the wallet.
+///
+/// The private key should be the one of the wallet's xpub, since the
+/// derivation paths in the wallet's PSBTs are relative to the xpub.
+pub struct HotSigner {
+	xpriv: bip32::ExtendedPrivKey,
+	cache: KeyCache,
+}
+
+impl HotSigner {
+	/// Create a signer that caches at most [cache_size] derived keys.
+	/// Not available on wasm, use [with_cache_key] there.
+	#[cfg(not(target_arch = "wasm32"))]
+	pub fn new(xpriv: bip32::ExtendedPrivKey, cache_size: usize) -> HotSigner {
+		HotSigner {
+			xpriv: xpriv,
+			cache: KeyCache::new(cache_size),
+		}
+	}
+
+	/// Create a signer of which the key cache is encrypted with [cache_key],
+	/// see [KeyCache::with_key].
+	pub fn with_cache_key(
+		xpriv: bip32::ExtendedPrivKey,
+		cache_size: usize,
+		cache_key: [u8; 32],
+	) -> HotSigner {
+		HotSigner {
+			xpriv: xpriv,
+			cache: KeyCache::with_key(cache_size, cache_key),
+		}
+	}
+
+	fn derive(&mut self, path: &bip32::DerivationPath) -> Result<secp256k1::SecretKey> {
+		if let Some(key) = self.cache.get(path) {
+			return Ok(key);
+		}
+		let key = self.xpriv.derive_priv(&::SECP, path)?.private_key.key;
+		self.cache.insert(path.clone(), &key);
+		Ok(key)
+	}
+
+	/// Sign all inputs of the PSBT that spend P2WPKH, P2SH-P2WPKH or P2PKH
+	/// outputs of our keys. Returns the number of signed inputs.
+	///
+	/// Inputs are signed with their PSBT sighash type, or SIGHASH_ALL if not set.
+	pub fn sign_psbt(&mut self, psbt: &mut psbt::PartiallySignedTransaction) -> Result<usize> {
+		let tx = psbt.global.unsigned_tx.clone();
+		let mut nb_signed = 0;
+		for (idx, input) in psbt.inputs.iter_mut().enumerate() {
+			let keypaths: Vec<_> =
+				input.hd_keypaths.iter().map(|(pk, (_, path))| (*pk, path.clone())).collect();
+			for (pubkey, path) in keypaths {
+				let key = self.derive(&path)?;
+				if secp256k1::PublicKey::from_secret_key(&::SECP, &key) != pubkey.key {
+					// Not our key.
+					continue;
+				}
+
+				let script_code = Address::p2pkh(&pubkey, self.xpriv.network).script_pubkey();
+				let sighash_type = input.sighash_type.unwrap_or(SigHashType::All).as_u32();
+				let sighash = if let Some(ref prev) = input.witness_utxo {
+					segwit_sighash(&tx, idx, &script_code, prev.value, sighash_type)
+				} else if input.non_witness_utxo.is_some() {
+					tx.signature_hash(idx, &script_code, sighash_type)
+				} else {
+					continue;
+				};
+
+				let msg = secp256k1::Message::from_slice(&sighash[..]).expect("32 bytes");
+				let mut sig = ::SECP.sign(&msg, &key).serialize_der().to_vec();
+				sig.push(sighash_type as u8);
+				input.partial_sigs.insert(pubkey, sig);
+				nb_signed += 1;
+			}
+		}
+		Ok(nb_signed)
+	}
+
+	/// Forget all cached keys.
+	pub fn clear_cache(&mut self) {
+		self.cache.clear();
+	}
+}
+
+/// The BIP-143 signature hash of the input with the given sighash type.
+fn segwit_sighash(
+	tx: &Transaction,
+	idx: usize,
+	script_code: &Script,
+	value: u64,
+	sighash_type: u32,
+) -> sha256d::Hash {
+	let anyone_can_pay = sighash_type & 0x80 != 0;
+	let base_type = sighash_type & 0x1f;
+	let (single, none) = (base_type == 0x03, base_type == 0x02);
+
+	let mut prevouts = Vec::new();
+	let mut sequences = Vec::new();
+	let mut outputs = Vec::new();
+	if !anyone_can_pay {
+		for txin in &tx.input {
+			prevouts.extend(serialize(&txin.previous_output));
+			if !single && !none {
+				sequences.extend(serialize(&txin.sequence));
+			}
+		}
+	}
+	if !single && !none {
+		for txout in &tx.output {
+			outputs.extend(serialize(txout));
+		}
+	} else if single && idx < tx.output.len() {
+		outputs.extend(serialize(&tx.output[idx]));
+	}
+	// Empty parts are committed to as zero hashes.
+	let hash = |data: &[u8]| {
+		if data.is_empty() {
+			[0u8; 32]
+		} else {
+			sha256d::Hash::hash(data).into_inner()
+		}
+	};
+
+	let txin = &tx.input[idx];
+	let mut preimage = serialize(&tx.version);
+	preimage.extend(&hash(&prevouts));
+	preimage.extend(&hash(&sequences));
+	preimage.extend(serialize(&txin.previous_output));
+	preimage.extend(serialize(script_code));
+	preimage.extend(serialize(&value));
+	preimage.extend(serialize(&txin.sequence));
+	preimage.extend(&hash(&outputs));
+	preimage.extend(serialize(&tx.lock_time));
+	preimage.extend(serialize(&sighash_type));
+	sha256d::Hash::hash(&preimage)
+}
+
+/// Build the final tx from a PSBT signed by a [HotSigner]. Every input must
+/// spend a P2WPKH, P2SH-P2WPKH or P2PKH output and have a single signature.
+/// When an input has a sighash type, the signature must use it.
+pub fn finalize_psbt(psbt: &psbt::PartiallySignedTransaction) -> Result<Transaction> {
+	let mut tx = psbt.global.unsigned_tx.clone();
+	for (txin, input) in tx.input.iter_mut().zip(psbt.inputs.iter()) {
+		let (pubkey, sig) = match input.partial_sigs.iter().next() {
+			Some(s) => s,
+			None => return Err(Error::PsbtNotSigned),
+		};
+		let sighash_type = input.sighash_type.map(|t| t.as_u32() as u8);
+		if sighash_type.is_some() && sig.last().cloned() != sighash_type {
+			return Err(Error::WrongSighashType);
+		}
+		let witness = vec![sig.clone(), pubkey.to_bytes()];
+		match (&input.redeem_script, input.witness_utxo.is_some()) {
+			(Some(redeem_script), _) => {
+				txin.script_sig = Builder::new().push_slice(redeem_script.as_bytes()).into_script();
+				txin.witness = witness;
+			}
+			(None, true) => txin.witness = witness,
+			(None, false) => {
+				txin.script_sig =
+					Builder::new().push_slice(&witness[0]).push_slice(&witness[1]).into_script();
+			}
+		}
+	}
+	Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::consensus::encode::deserialize;
+	use hex;
+
+	fn sighash(tx: &str, idx: usize, script_code: &str, value: u64, sighash_type: u32) -> String {
+		let tx: Transaction = deserialize(&hex::decode(tx).unwrap()).unwrap();
+		let script_code = Script::from(hex::decode(script_code).unwrap());
+		hex::encode(&segwit_sighash(&tx, idx, &script_code, value, sighash_type)[..])
+	}
+
+	#[test]
+	fn bip143_native_p2wpkh() {
+		let tx = "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f000000\
+			0000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100\
+			000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d59\
+			88ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000";
+		assert_eq!(
+			sighash(tx, 1, "76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac", 600000000, 0x01),
+			"c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670",
+		);
+	}
+
+	#[test]
+	fn bip143_p2sh_p2wpkh() {
+		let tx = "0100000001db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a5477010000\
+			0000feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388\
+			ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac92040000";
+		assert_eq!(
+			sighash(tx, 0, "76a91479091972186c449eb1ded22b78e40d009bdf008988ac", 1000000000, 0x01),
+			"64f3b0f4dd2bb3aa1ce8566d220cc74dda9df97d8490cc81d89d735c92e59fb6",
+		);
+	}
+
+	/// The native P2WSH example, signing with SIGHASH_SINGLE before and after
+	/// the OP_CODESEPARATOR.
+	#[test]
+	fn bip143_single() {
+		let tx = "0100000002fe3dc9208094f3ffd12645477b3dc56f60ec4fa8e6f5d67c565d1c6b9216b36e000000\
+			0000ffffffff0815cf020f013ed6cf91d29f4202e8a58726b1ac6c79da47c23d1bee0a6925f800000000\
+			00ffffffff0100f2052a010000001976a914a30741f8145e5acadf23f4b6ac83f78e10f9e8c788ac0000\
+			0000";
+		let script = "21026dccc749adc2a9d0d89497ac511f760f45c47dc5ed9cf352a58ac706453880aead\
+			ab210255a9626aebf5e29c0e6538428ba0d1dcf6ca98ffdf086aa8ced5e0d0215ea465ac";
+		assert_eq!(
+			sighash(tx, 1, script, 4900000000, 0x03),
+			"82dde6e4f1e94d02c2b7ad03d2115d691f48d064e9d52f58194a6637e4194391",
+		);
+		let script = "210255a9626aebf5e29c0e6538428ba0d1dcf6ca98ffdf086aa8ced5e0d0215ea465ac";
+		assert_eq!(
+			sighash(tx, 1, script, 4900000000, 0x03),
+			"fef7bd749cce710c5c052bd796df1af0d935e59cea63736268bcbe2d2134fc47",
+		);
+	}
+
+	/// The native P2WSH example with SIGHASH_SINGLE|SIGHASH_ANYONECANPAY.
+	#[test]
+	fn bip143_single_anyonecanpay() {
+		let tx = "0100000002e9b542c5176808107ff1df906f46bb1f2583b16112b95ee5380665ba7fcfc0010000\
+			000000ffffffff80e68831516392fcd100d186b3c2c7b95c80b53c77e77c35ba03a66b429a2a1b000000\
+			0000ffffffff0280969800000000001976a914de4b231626ef508c9a74a8517e6783c0546d6b2888ac80\
+			969800000000001976a9146648a8cd4531e1ec47f35916de8e259237294d1e88ac00000000";
+		let script = "0063ab68210392972e2eb617b2388771abe27235fd5ac44af8e61693261550447a4c3e39\
+			da98ac";
+		assert_eq!(
+			sighash(tx, 0, script, 16777215, 0x83),
+			"e9071e75e25b8a1e298a72f0d2e9f4f95a0f5cdf86a533cda597eb402ed13b3a",
+		);
+		let script = "68210392972e2eb617b2388771abe27235fd5ac44af8e61693261550447a4c3e39da98ac";
+		assert_eq!(
+			sighash(tx, 1, script, 16777215, 0x83),
+			"cd72f1f1a433ee9df816857fad88d8ebd97e09a75cd481583eb841c330275e54",
+		);
+	}
+
+	/// What the sighash types commit to, using the tx of [bip143_single_anyonecanpay].
+	#[test]
+	fn sighash_type_commitments() {
+		let tx = "0100000002e9b542c5176808107ff1df906f46bb1f2583b16112b95ee5380665ba7fcfc0010000\
+			000000ffffffff80e68831516392fcd100d186b3c2c7b95c80b53c77e77c35ba03a66b429a2a1b000000\
+			0000ffffffff0280969800000000001976a914de4b231626ef508c9a74a8517e6783c0546d6b2888ac80\
+			969800000000001976a9146648a8cd4531e1ec47f35916de8e259237294d1e88ac00000000";
+		let tx: Transaction = deserialize(&hex::decode(tx).unwrap()).unwrap();
+		let script_code = Script::from(hex::decode("51").unwrap());
+		let hash = |tx: &Transaction, sighash_type| {
+			segwit_sighash(tx, 0, &script_code, 16777215, sighash_type)
+		};
+
+		let mut other_output = tx.clone();
+		other_output.output[1].value += 1;
+		let mut own_output = tx.clone();
+		own_output.output[0].value += 1;
+		let mut other_input = tx.clone();
+		other_input.input[1].previous_output.vout += 1;
+		let mut other_sequence = tx.clone();
+		other_sequence.input[1].sequence = 0;
+
+		for &(sighash_type, outputs, own_out, inputs, sequences) in [
+			(0x01, true, true, true, true),
+			(0x02, false, false, true, false),
+			(0x03, false, true, true, false),
+			(0x81, true, true, false, false),
+			(0x82, false, false, false, false),
+			(0x83, false, true, false, false),
+		]
+		.iter()
+		{
+			let original = hash(&tx, sighash_type);
+			let commits = |changed: &Transaction| hash(changed, sighash_type) != original;
+			assert_eq!(commits(&other_output), outputs, "type {:#x}", sighash_type);
+			assert_eq!(commits(&own_output), own_out, "type {:#x}", sighash_type);
+			assert_eq!(commits(&other_input), inputs, "type {:#x}", sighash_type);
+			assert_eq!(commits(&other_sequence), sequences, "type {:#x}", sighash_type);
+		}
+	}
+}
@@ -0,0 +1,317 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Receiving silent payments (BIP-352).
+//!
+//! A [SilentPaymentReceiver] holds the scan key and the spend public key of
+//! the wallet's silent payment address. Senders derive a new taproot output
+//! for every payment, which the receiver finds by scanning txs with the tweak
+//! data of their inputs, see [input_tweak]. Found outputs are imported into
+//! the wallet as UTXOs with a [UtxoKey::SilentPayment] key.
+//!
+//! The tweak of an output is set on the inputs spending it in the wallet's
+//! PSBTs under the proprietary key [psbt_tweak_key]. The signer adds it to the
+//! spend key, see [spend_key].
+//!
+//! Labels are not supported.
+
+use std::collections::HashMap;
+
+use bitcoin::consensus::encode::serialize;
+use bitcoin::util::bip32;
+use bitcoin::util::psbt::raw;
+use bitcoin::{Block, Network, OutPoint, Transaction, TxOut};
+use bitcoin_hashes::sha256d;
+use secp256k1::{PublicKey, SecretKey};
+
+use error::{Error, Result};
+use taproot::{bech32m_encode, tagged_hash, to_base32};
+use wallet::{UtxoKey, Wallet};
+
+/// The identifier of the proprietary PSBT input field holding the tweak.
+const PSBT_PROPRIETARY_ID: &'static [u8] = b"bip352";
+
+/// The proprietary PSBT input key of the tweak of a silent payment output.
+pub fn psbt_tweak_key() -> raw::Key {
+	let mut key = vec![PSBT_PROPRIETARY_ID.len() as u8];
+	key.extend_from_slice(PSBT_PROPRIETARY_ID);
+	key.push(0x00); // subtype
+	raw::Key {
+		type_value: 0xFC,
+		key: key,
+	}
+}
+
+/// The tweak data of a tx: the sum of the eligible input public keys,
+/// multiplied by the input hash. The public keys of taproot inputs must be
+/// the ones with an even Y coordinate.
+///
+/// [outpoints] are all outpoints spent by the tx, [input_pubkeys] the public
+/// keys of the eligible inputs.
+pub fn input_tweak(outpoints: &[OutPoint], input_pubkeys: &[PublicKey]) -> Result<PublicKey> {
+	let invalid = || Error::InvalidSilentPayment("no eligible inputs");
+	let (first, rest) = input_pubkeys.split_first().ok_or_else(invalid)?;
+	let mut sum = *first;
+	for pubkey in rest {
+		sum = sum.combine(pubkey)?;
+	}
+	let smallest = outpoints.iter().map(|o| serialize(o)).min().ok_or_else(invalid)?;
+
+	let mut data = smallest;
+	data.extend_from_slice(&sum.serialize());
+	let input_hash = tagged_hash("BIP0352/Inputs", &data);
+	sum.mul_assign(&::SECP, &input_hash)?;
+	Ok(sum)
+}
+
+/// The private key to spend an output with the tweak, given the private
+/// spend key. Signers should negate it when the output key has an odd Y
+/// coordinate.
+pub fn spend_key(spend_key: &SecretKey, tweak: &[u8; 32]) -> Result<SecretKey> {
+	let mut key = *spend_key;
+	key.add_assign(tweak)?;
+	Ok(key)
+}
+
+/// The x-only public key of a taproot output.
+fn taproot_output_key(txout: &TxOut) -> Option<&[u8]> {
+	let bytes = txout.script_pubkey.as_bytes();
+	if bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20 {
+		Some(&bytes[2..])
+	} else {
+		None
+	}
+}
+
+/// The keys to find silent payments to the wallet.
+#[derive(Debug, Clone)]
+pub struct SilentPaymentReceiver {
+	scan_key: SecretKey,
+	spend_pubkey: PublicKey,
+	network: Network,
+}
+
+impl SilentPaymentReceiver {
+	pub fn new(scan_key: SecretKey, spend_pubkey: PublicKey, network: Network) -> Self {
+		SilentPaymentReceiver {
+			scan_key: scan_key,
+			spend_pubkey: spend_pubkey,
+			network: network,
+		}
+	}
+
+	/// Derive the keys of the first account from the master key, the scan key
+	/// at `m/352'/<coin>'/0'/1'/0` and the spend key at `m/352'/<coin>'/0'/0'/0`.
+	pub fn from_master(master: &bip32::ExtendedPrivKey) -> Result<Self> {
+		let coin = match master.network {
+			Network::Bitcoin => 0,
+			_ => 1,
+		};
+		let path = |branch: u32| -> Result<bip32::DerivationPath> {
+			Ok(vec![
+				bip32::ChildNumber::from_hardened_idx(352)?,
+				bip32::ChildNumber::from_hardened_idx(coin)?,
+				bip32::ChildNumber::from_hardened_idx(0)?,
+				bip32::ChildNumber::from_hardened_idx(branch)?,
+				bip32::ChildNumber::from_normal_idx(0)?,
+			]
+			.into())
+		};
+		let scan = master.derive_priv(&::SECP, &path(1)?)?;
+		let spend = master.derive_priv(&::SECP, &path(0)?)?;
+		let spend_pubkey = PublicKey::from_secret_key(&::SECP, &spend.private_key.key);
+		Ok(SilentPaymentReceiver::new(scan.private_key.key, spend_pubkey, master.network))
+	}
+
+	/// The silent payment address, `sp1q...` on mainnet and `tsp1q...` on
+	/// the test networks.
+	pub fn address(&self) -> String {
+		let hrp = match self.network {
+			Network::Bitcoin => "sp",
+			_ => "tsp",
+		};
+		let scan_pubkey = PublicKey::from_secret_key(&::SECP, &self.scan_key);
+		let mut payload = scan_pubkey.serialize().to_vec();
+		payload.extend_from_slice(&self.spend_pubkey.serialize());
+		// Version 0.
+		let mut data = vec![0];
+		data.extend(to_base32(&payload));
+		bech32m_encode(hrp, &data)
+	}
+
+	/// Find the outputs paying to us, given the tweak data of the tx.
+	/// Returns the index and the tweak of every found output.
+	pub fn scan_outputs(
+		&self,
+		tweak: &PublicKey,
+		outputs: &[TxOut],
+	) -> Result<Vec<(u32, [u8; 32])>> {
+		let mut shared_secret = *tweak;
+		shared_secret.mul_assign(&::SECP, &self.scan_key[..])?;
+		let shared_secret = shared_secret.serialize();
+
+		let mut found = Vec::new();
+		loop {
+			let k = found.len() as u32;
+			let mut data = shared_secret.to_vec();
+			data.extend_from_slice(&[(k >> 24) as u8, (k >> 16) as u8, (k >> 8) as u8, k as u8]);
+			let t_k = tagged_hash("BIP0352/SharedSecret", &data);
+			let mut p_k = self.spend_pubkey;
+			p_k.add_exp_assign(&::SECP, &t_k)?;
+			let x_only = &p_k.serialize()[1..];
+
+			let found_vout = outputs.iter().position(|o| taproot_output_key(o) == Some(x_only));
+			match found_vout {
+				Some(vout) => found.push((vout as u32, t_k)),
+				None => return Ok(found),
+			}
+		}
+	}
+
+	/// Find the outputs of the tx paying to us, given the public keys of its
+	/// eligible inputs.
+	pub fn scan_transaction(
+		&self,
+		tx: &Transaction,
+		input_pubkeys: &[PublicKey],
+	) -> Result<Vec<(u32, [u8; 32])>> {
+		if !tx.output.iter().any(|o| taproot_output_key(o).is_some()) {
+			return Ok(Vec::new());
+		}
+		let outpoints: Vec<OutPoint> = tx.input.iter().map(|i| i.previous_output).collect();
+		self.scan_outputs(&input_tweak(&outpoints, input_pubkeys)?, &tx.output)
+	}
+
+	/// Scan the block with the tweak data of its txs and import the outputs
+	/// paying to us into the wallet. Returns the number of imported outputs.
+	///
+	/// Txs without tweak data are skipped.
+	pub fn scan_block(
+		&self,
+		wallet: &mut Wallet,
+		block: &Block,
+		height: u32,
+		tweaks: &HashMap<sha256d::Hash, PublicKey>,
+	) -> Result<usize> {
+		let mut nb_found = 0;
+		for tx in &block.txdata {
+			let txid = tx.txid();
+			let tweak = match tweaks.get(&txid) {
+				Some(tweak) => tweak,
+				None => continue,
+			};
+			for (vout, t_k) in self.scan_outputs(tweak, &tx.output)? {
+				let outpoint = OutPoint {
+					txid: txid,
+					vout: vout,
+				};
+				let txout = tx.output[vout as usize].clone();
+				wallet.import_utxo(outpoint, txout, UtxoKey::SilentPayment(t_k), height)?;
+				nb_found += 1;
+			}
+		}
+		Ok(nb_found)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::Script;
+	use bitcoin_hashes::hex::FromHex;
+	use hex;
+
+	fn secret_key(hex_key: &str) -> SecretKey {
+		SecretKey::from_slice(&hex::decode(hex_key).unwrap()).unwrap()
+	}
+
+	fn taproot_output(x_only: &str) -> TxOut {
+		let mut script = vec![0x51, 0x20];
+		script.extend(hex::decode(x_only).unwrap());
+		TxOut {
+			value: 1000,
+			script_pubkey: Script::from(script),
+		}
+	}
+
+	/// The "Simple send: two inputs" case of the BIP-352 test vectors.
+	#[test]
+	fn simple_send_vector() {
+		let outpoints = [
+			OutPoint {
+				txid: sha256d::Hash::from_hex(
+					"f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+				)
+				.unwrap(),
+				vout: 0,
+			},
+			OutPoint {
+				txid: sha256d::Hash::from_hex(
+					"a1075db55d416d3ca199f55b6084e2115b9345e16c5cf302fc80e9d5fbf5d48d",
+				)
+				.unwrap(),
+				vout: 0,
+			},
+		];
+		let input_pubkeys: Vec<PublicKey> = [
+			"eadc78165ff1f8ea94ad7cfdc54990738a4c53f6e0507b42154201b8e5dff3b1",
+			"93f5ed907ad5b2bdbbdcb5d9116ebc0a4e1f92f910d5260237fa45a9408aad16",
+		]
+		.iter()
+		.map(|k| PublicKey::from_secret_key(&::SECP, &secret_key(k)))
+		.collect();
+
+		let tweak = input_tweak(&outpoints, &input_pubkeys).unwrap();
+		assert_eq!(
+			hex::encode(&tweak.serialize()[..]),
+			"024ac253c216532e961988e2a8ce266a447c894c781e52ef6cee902361db960004",
+		);
+		// The order of the inputs doesn't matter.
+		let reversed: Vec<PublicKey> = input_pubkeys.iter().rev().cloned().collect();
+		assert_eq!(input_tweak(&[outpoints[1], outpoints[0]], &reversed).unwrap(), tweak);
+
+		let spend_priv =
+			secret_key("9d6ad855ce3417ef84e836892e5a56392bfba05fa5d97ccea30e266f540e08b3");
+		let receiver = SilentPaymentReceiver::new(
+			secret_key("0f694e068028a717f8af6b9411f9a133dd3565258714cc226594b34db90c1f2c"),
+			PublicKey::from_secret_key(&::SECP, &spend_priv),
+			Network::Bitcoin,
+		);
+		assert_eq!(
+			receiver.address(),
+			"sp1qqgste7k9hx0qftg6qmwlkqtwuy6cycyavzmzj85c6qdfhjdpdjtdgqjuexzk6murw56suy3e0rd2cgqv\
+			ycxttddwsvgxe2usfpxumr70xc9pkqwv",
+		);
+
+		let output = "3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1";
+		let other = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+		let outputs = [taproot_output(other), taproot_output(output)];
+		let found = receiver.scan_outputs(&tweak, &outputs).unwrap();
+		assert_eq!(found.len(), 1);
+		assert_eq!(found[0].0, 1);
+		assert_eq!(
+			hex::encode(&found[0].1[..]),
+			"f438b40179a3c4262de12986c0e6cce0634007cdc79c1dcd3e20b9ebc2e7eef6",
+		);
+
+		// The tweaked spend key is the key of the output.
+		let key = spend_key(&spend_priv, &found[0].1).unwrap();
+		let pubkey = PublicKey::from_secret_key(&::SECP, &key);
+		assert_eq!(hex::encode(&pubkey.serialize()[1..]), output);
+
+		assert!(receiver.scan_outputs(&tweak, &outputs[..1]).unwrap().is_empty());
+		assert!(input_tweak(&outpoints, &[]).is_err());
+	}
+}
@@ -0,0 +1,197 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! A simple wallet to get started with.
+//!
+//! A [SimpleWallet] combines a [Wallet] using BIP-84 keys derived from a seed,
+//! a [HotSigner], a chain backend and a file the wallet is stored in. The
+//! wallet is stored after every change. For anything more advanced, the
+//! underlying [Wallet] is available through [SimpleWallet::wallet_mut].
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bitcoin::util::bip32;
+use bitcoin::{Address, Network};
+use bitcoin_hashes::sha256d;
+use serde_json;
+
+use amount::{Amount, FeeRate};
+use config::WalletConfig;
+use error::{Error, Result};
+use signer::HotSigner;
+use sync::{self, BlockSource, Broadcaster};
+use wallet::{TxEntry, TxFilter, Wallet};
+
+/// The number of derived keys the signer keeps in memory.
+const SIGNER_CACHE_SIZE: usize = 100;
+
+/// A wallet with a software signer that syncs with a single backend and is
+/// stored in a file.
+pub struct SimpleWallet<B: BlockSource + Broadcaster> {
+	wallet: Wallet,
+	signer: HotSigner,
+	backend: B,
+	path: PathBuf,
+}
+
+/// The BIP-84 path of the first account, `m/84'/<coin>'/0'`.
+fn account_path(network: Network) -> Result<bip32::DerivationPath> {
+	let coin = match network {
+		Network::Bitcoin => 0,
+		_ => 1,
+	};
+	Ok(vec![
+		bip32::ChildNumber::from_hardened_idx(84)?,
+		bip32::ChildNumber::from_hardened_idx(coin)?,
+		bip32::ChildNumber::from_hardened_idx(0)?,
+	]
+	.into())
+}
+
+/// The account key of the seed and the master key fingerprint.
+fn account_key(
+	seed: &[u8],
+	network: Network,
+) -> Result<(bip32::ExtendedPrivKey, bip32::Fingerprint)> {
+	let master = bip32::ExtendedPrivKey::new_master(network, seed)?;
+	let account = master.derive_priv(&::SECP, &account_path(network)?)?;
+	Ok((account, master.fingerprint(&::SECP)))
+}
+
+impl<B: BlockSource + Broadcaster> SimpleWallet<B> {
+	/// Create a new wallet for the seed that is stored at [path].
+	/// The wallet starts at the backend's tip, so it doesn't see any earlier
+	/// payments to the seed's addresses.
+	pub fn create<P: AsRef<Path>>(
+		seed: &[u8],
+		network: Network,
+		backend: B,
+		path: P,
+	) -> Result<SimpleWallet<B>> {
+		let (account, master_fp) = account_key(seed, network)?;
+		let mut wallet = Wallet::new_with_change_path(
+			WalletConfig::new(network),
+			bip32::ExtendedPubKey::from_private(&::SECP, &account),
+			master_fp,
+			vec![bip32::ChildNumber::from_normal_idx(0)?].into(),
+			Some(vec![bip32::ChildNumber::from_normal_idx(1)?].into()),
+		);
+		wallet.set_xpub_origin_path(account_path(network)?);
+		let (height, hash) = backend.tip()?;
+		wallet.set_last_block(hash, height);
+
+		let simple = SimpleWallet {
+			wallet: wallet,
+			signer: HotSigner::new(account, SIGNER_CACHE_SIZE),
+			backend: backend,
+			path: path.as_ref().to_owned(),
+		};
+		simple.save()?;
+		Ok(simple)
+	}
+
+	/// Open the wallet for the seed stored at [path].
+	///
+	/// Fails with [Error::WrongSeed] when the wallet is of another seed.
+	pub fn open<P: AsRef<Path>>(
+		seed: &[u8],
+		network: Network,
+		backend: B,
+		path: P,
+	) -> Result<SimpleWallet<B>> {
+		let (account, _) = account_key(seed, network)?;
+		let data = fs::read(path.as_ref()).map_err(|e| Error::Storage(e.to_string()))?;
+		let wallet: Wallet =
+			serde_json::from_slice(&data).map_err(|e| Error::Storage(e.to_string()))?;
+		if bip32::ExtendedPubKey::from_private(&::SECP, &account) != *wallet.extended_pubkey() {
+			return Err(Error::WrongSeed);
+		}
+		Ok(SimpleWallet {
+			wallet: wallet,
+			signer: HotSigner::new(account, SIGNER_CACHE_SIZE),
+			backend: backend,
+			path: path.as_ref().to_owned(),
+		})
+	}
+
+	/// Store the wallet. The file is replaced at once, so that a crash while
+	/// storing doesn't leave a partially written wallet behind.
+	pub fn save(&self) -> Result<()> {
+		let data = serde_json::to_vec(&self.wallet).expect("serializing can't fail");
+		let tmp_path = self.path.with_extension("tmp");
+		fs::write(&tmp_path, &data).map_err(|e| Error::Storage(e.to_string()))?;
+		fs::rename(&tmp_path, &self.path).map_err(|e| Error::Storage(e.to_string()))
+	}
+
+	/// Sync the wallet with the backend and return the height of the tip.
+	pub fn sync(&mut self) -> Result<u32> {
+		let height = sync::sync_to_tip(&mut self.wallet, &self.backend, |_, _| {})?;
+		self.save()?;
+		Ok(height)
+	}
+
+	/// The total value of the wallet's coins.
+	pub fn balance(&self) -> Amount {
+		self.wallet.get_balance(None)
+	}
+
+	/// Hand out a new receive address.
+	pub fn address(&mut self) -> Result<Address> {
+		let address = self.wallet.new_receive_address()?;
+		self.save()?;
+		Ok(address)
+	}
+
+	/// Send the amount to the address at the feerate and return the txid.
+	/// The tx is only committed once the broadcast succeeded, so that its
+	/// coins can be spent again when it fails.
+	pub fn send(
+		&mut self,
+		address: &Address,
+		amount: Amount,
+		fee_rate: FeeRate,
+	) -> Result<sha256d::Hash> {
+		let psbt = self
+			.wallet
+			.build_tx()
+			.add_recipient(address, amount)
+			.fee_rate(fee_rate)
+			.without_commit()
+			.finish()?
+			.psbt;
+		let result = self.wallet.sign_and_broadcast(psbt, &mut self.signer, &self.backend);
+		self.save()?;
+		result
+	}
+
+	/// The wallet's history, ordered by height.
+	pub fn history(&self) -> Vec<&TxEntry> {
+		self.wallet.list_transactions(&TxFilter::default())
+	}
+
+	pub fn wallet(&self) -> &Wallet {
+		&self.wallet
+	}
+
+	/// Changes made through the wallet are only stored on the next change made
+	/// through the [SimpleWallet] or by calling [save].
+	pub fn wallet_mut(&mut self) -> &mut Wallet {
+		&mut self.wallet
+	}
+
+	pub fn backend(&self) -> &B {
+		&self.backend
+	}
+}
@@ -0,0 +1,132 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Syncing the wallet with a source of blocks.
+//!
+//! [sync_to_tip] fetches the blocks the wallet is missing from a [BlockSource],
+//! like a Bitcoin Core node, and processes them. When the last blocks of the
+//! wallet are no longer part of the source's chain, they are disconnected
+//! until the last block both have in common.
+//!
+//! Pending txs can be broadcast with [broadcast_pending] using a [Broadcaster].
+//!
+//! With the `async` feature, futures-based variants are available in
+//! [nonblocking].
+
+use bitcoin::{Block, Transaction};
+use bitcoin_hashes::sha256d;
+
+use error::{Error, Result};
+use wallet::Wallet;
+
+/// A source of the blocks of the best chain.
+/// Errors of the source should be returned as [Error::Backend].
+pub trait BlockSource {
+	/// The height and hash of the tip of the best chain.
+	fn tip(&self) -> Result<(u32, sha256d::Hash)>;
+
+	/// The hash of the block at the height in the best chain.
+	/// Returns [None] for heights above the tip.
+	fn block_hash(&self, height: u32) -> Result<Option<sha256d::Hash>>;
+
+	fn block(&self, hash: &sha256d::Hash) -> Result<Block>;
+}
+
+/// A backend that broadcasts txs to the network.
+/// Errors of the backend should be returned as [Error::Backend].
+pub trait Broadcaster {
+	/// The name of the backend in the recorded broadcasts.
+	fn name(&self) -> String;
+
+	/// Broadcast the tx and return its txid as reported by the backend.
+	fn broadcast(&self, tx: &Transaction) -> Result<sha256d::Hash>;
+}
+
+/// Broadcast the pending tx and record the broadcast in the wallet.
+pub fn broadcast_pending<B: Broadcaster>(
+	wallet: &mut Wallet,
+	broadcaster: &B,
+	txid: sha256d::Hash,
+) -> Result<()> {
+	let result = match wallet.get_pending_transaction(txid) {
+		Some(tx) => broadcaster.broadcast(tx),
+		None => return Err(Error::TxNotPending),
+	};
+	let message = result.as_ref().err().map(|e| e.to_string());
+	wallet.record_broadcast(txid, broadcaster.name(), result.is_ok(), message)?;
+	result.map(|_| ())
+}
+
+/// Disconnect the blocks of the wallet that are not in the source's chain.
+/// Returns the height of the last block in common.
+fn disconnect_stale_blocks<S: BlockSource>(wallet: &mut Wallet, source: &S) -> Result<u32> {
+	let (height, hash) = match wallet.last_block() {
+		Some(block) => (block.height, block.hash),
+		None => return Err(Error::WalletNotFullyInitialized),
+	};
+	// Most of the time, there is no fork.
+	if source.block_hash(height)? == Some(hash) {
+		return Ok(height);
+	}
+	match wallet.fork_point(|h| source.block_hash(h))? {
+		Some(common) => {
+			wallet.rollback_to(common.height)?;
+			Ok(common.height)
+		}
+		None => Err(Error::UnknownBlock),
+	}
+}
+
+/// Sync the wallet to the tip of the source's chain and return the height of
+/// the new tip. After every processed block, [progress] is called with the
+/// height of the block and the height of the source's tip.
+///
+/// When the source's chain changes during the sync, the sync continues on
+/// the new chain. When a block of the source doesn't follow on the previous
+/// one, even after retrying, the sync fails with [Error::Backend].
+pub fn sync_to_tip<S, P>(wallet: &mut Wallet, source: &S, mut progress: P) -> Result<u32>
+where
+	S: BlockSource,
+	P: FnMut(u32, u32),
+{
+	let mut retried_fork = false;
+	loop {
+		let (tip_height, tip_hash) = source.tip()?;
+		let mut height = disconnect_stale_blocks(wallet, source)?;
+		while height < tip_height {
+			let hash = match source.block_hash(height + 1)? {
+				Some(hash) => hash,
+				None => break,
+			};
+			match wallet.process_block(&source.block(&hash)?) {
+				Ok(()) => retried_fork = false,
+				// The block is not on top of our last block anymore.
+				Err(Error::BlockFork) if !retried_fork => {
+					retried_fork = true;
+					break;
+				}
+				Err(Error::BlockFork) => {
+					return Err(Error::Backend("inconsistent block source".to_owned()));
+				}
+				Err(e) => return Err(e),
+			}
+			height += 1;
+			progress(height, tip_height);
+		}
+
+		if wallet.last_block().map(|b| b.hash) == Some(tip_hash) {
+			return Ok(height);
+		}
+	}
+}
@@ -0,0 +1,81 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! A wallet handle that can be shared between threads.
+//!
+//! Readers take a snapshot of the wallet, which never blocks on writers.
+//! Writers modify a copy of the wallet that replaces the snapshot when done,
+//! like a [BlockBatch]. This makes every write cost a copy of the wallet, so
+//! blocks are best processed in batches using [SyncWallet::process_blocks].
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use bitcoin::Block;
+
+use error::Result;
+use wallet::Wallet;
+
+/// A wallet that can be read concurrently with block processing.
+pub struct SyncWallet {
+	wallet: RwLock<Arc<Wallet>>,
+	/// Held while a write is in progress, so that writes don't overwrite
+	/// each other.
+	writer: Mutex<()>,
+}
+
+impl SyncWallet {
+	pub fn new(wallet: Wallet) -> SyncWallet {
+		SyncWallet {
+			wallet: RwLock::new(Arc::new(wallet)),
+			writer: Mutex::new(()),
+		}
+	}
+
+	/// The current state of the wallet. Later writes don't change the snapshot,
+	/// so multiple queries on it are consistent with each other.
+	pub fn snapshot(&self) -> Arc<Wallet> {
+		// The lock is only held to replace the snapshot, so it can't be
+		// poisoned with an inconsistent state.
+		self.wallet.read().unwrap_or_else(|e| e.into_inner()).clone()
+	}
+
+	/// Modify the wallet. When [f] fails, the wallet is not changed.
+	pub fn write<F, T>(&self, f: F) -> Result<T>
+	where
+		F: FnOnce(&mut Wallet) -> Result<T>,
+	{
+		// A failed write didn't change anything, so poisoning can be ignored.
+		let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+		let mut working = (*self.snapshot()).clone();
+		let ret = f(&mut working)?;
+		*self.wallet.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(working);
+		Ok(ret)
+	}
+
+	pub fn process_block(&self, block: &Block) -> Result<()> {
+		self.write(|w| w.process_block(block))
+	}
+
+	/// Process the blocks in a single write. Unlike [Wallet::process_blocks],
+	/// nothing is processed when one of the blocks fails.
+	pub fn process_blocks(&self, blocks: &[Block]) -> Result<()> {
+		self.write(|w| w.process_blocks(blocks))
+	}
+
+	/// Take the wallet out of the handle.
+	pub fn into_inner(self) -> Wallet {
+		let wallet = self.wallet.into_inner().unwrap_or_else(|e| e.into_inner());
+		Arc::try_unwrap(wallet).unwrap_or_else(|w| (*w).clone())
+	}
+}
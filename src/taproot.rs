@@ -0,0 +1,198 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Taproot primitives that rust-bitcoin doesn't provide yet: tagged hashes,
+//! key path output keys (BIP-341) and bech32m encoding (BIP-350).
+//!
+//! Public keys are kept as full points. Their x-only form is the serialized
+//! key without the first byte.
+
+use bitcoin::blockdata::script::Builder;
+use bitcoin::{Network, Script};
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use secp256k1::PublicKey;
+
+use error::Result;
+
+/// The order of the curve minus one, multiplying by it negates a point.
+const MINUS_ONE: [u8; 32] = [
+	0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+	0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x40,
+];
+
+/// A BIP-340 tagged hash.
+pub fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+	let tag_hash = sha256::Hash::hash(tag.as_bytes());
+	let mut engine = sha256::Hash::engine();
+	engine.input(&tag_hash[..]);
+	engine.input(&tag_hash[..]);
+	engine.input(data);
+	sha256::Hash::from_engine(engine).into_inner()
+}
+
+/// The point with the same x coordinate and an even Y coordinate.
+pub fn with_even_y(key: &PublicKey) -> Result<PublicKey> {
+	let mut key = *key;
+	if key.serialize()[0] == 0x03 {
+		key.mul_assign(&::SECP, &MINUS_ONE)?;
+	}
+	Ok(key)
+}
+
+/// The output key of a taproot output that can only be spent with the key
+/// path of the internal key.
+pub fn output_key(internal_key: &PublicKey) -> Result<PublicKey> {
+	let mut key = with_even_y(internal_key)?;
+	let tweak = tagged_hash("TapTweak", &key.serialize()[1..]);
+	key.add_exp_assign(&::SECP, &tweak)?;
+	Ok(key)
+}
+
+/// The script of a taproot output with the output key.
+pub fn script_pubkey(output_key: &PublicKey) -> Script {
+	Builder::new().push_int(1).push_slice(&output_key.serialize()[1..]).into_script()
+}
+
+/// The address of a taproot output with the output key.
+pub fn address(output_key: &PublicKey, network: Network) -> String {
+	let hrp = match network {
+		Network::Bitcoin => "bc",
+		Network::Testnet => "tb",
+		Network::Regtest => "bcrt",
+	};
+	// Witness version 1.
+	let mut data = vec![1];
+	data.extend(to_base32(&output_key.serialize()[1..]));
+	bech32m_encode(hrp, &data)
+}
+
+const BECH32_CHARSET: &'static [u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+	const GEN: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+	let mut chk: u32 = 1;
+	for v in values {
+		let top = chk >> 25;
+		chk = (chk & 0x1ffffff) << 5 ^ u32::from(*v);
+		for (i, g) in GEN.iter().enumerate() {
+			if (top >> i) & 1 == 1 {
+				chk ^= g;
+			}
+		}
+	}
+	chk
+}
+
+/// Encode the 5-bit values with bech32m. Unlike the bech32 crate, there's no
+/// limit on the length, silent payment addresses exceed 90 characters.
+pub fn bech32m_encode(hrp: &str, data: &[u8]) -> String {
+	let mut values: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+	values.push(0);
+	values.extend(hrp.bytes().map(|c| c & 31));
+	values.extend_from_slice(data);
+	values.extend_from_slice(&[0; 6]);
+	let checksum = bech32_polymod(&values) ^ 0x2bc830a3;
+
+	let mut encoded = format!("{}1", hrp);
+	let checksum_values = (0..6).map(|i| (checksum >> (5 * (5 - i))) as u8 & 31);
+	for v in data.iter().cloned().chain(checksum_values) {
+		encoded.push(BECH32_CHARSET[v as usize] as char);
+	}
+	encoded
+}
+
+/// Regroup the bytes into 5-bit values, padding the last one with zeros.
+pub fn to_base32(data: &[u8]) -> Vec<u8> {
+	let mut ret = Vec::with_capacity((data.len() * 8 + 4) / 5);
+	let (mut acc, mut bits) = (0u32, 0);
+	for b in data {
+		acc = acc << 8 | u32::from(*b);
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			ret.push((acc >> bits) as u8 & 31);
+		}
+	}
+	if bits > 0 {
+		ret.push((acc << (5 - bits)) as u8 & 31);
+	}
+	ret
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use hex;
+
+	fn key(hex_key: &str) -> PublicKey {
+		PublicKey::from_slice(&hex::decode(hex_key).unwrap()).unwrap()
+	}
+
+	#[test]
+	fn bech32m_vectors() {
+		// The valid bech32m strings of BIP-350.
+		let vectors = [
+			"a1lqfn3a",
+			"an83characterlonghumanreadablepartthatcontainsthetheexcludedcharactersbioandnumber1\
+			1sg7hg6",
+			"abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx",
+			"11llllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllll\
+			ludsr8",
+			"split1checkupstagehandshakeupstreamerranterredcaperredlc445v",
+			"?1v759aa",
+		];
+		for encoded in vectors.iter() {
+			let sep = encoded.rfind('1').unwrap();
+			let data: Vec<u8> = encoded[sep + 1..encoded.len() - 6]
+				.bytes()
+				.map(|c| BECH32_CHARSET.iter().position(|v| *v == c).unwrap() as u8)
+				.collect();
+			assert_eq!(bech32m_encode(&encoded[..sep], &data), *encoded);
+		}
+	}
+
+	#[test]
+	fn address_vectors() {
+		// The witness v1 address of BIP-350.
+		let generator = key("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+		assert_eq!(
+			address(&generator, Network::Bitcoin),
+			"bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+		);
+	}
+
+	#[test]
+	fn output_key_vectors() {
+		// The key path only output of the BIP-341 wallet test vectors.
+		let internal = key("02d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d");
+		let output = output_key(&internal).unwrap();
+		assert_eq!(
+			hex::encode(&output.serialize()[1..]),
+			"53a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+		);
+		assert_eq!(
+			hex::encode(script_pubkey(&output).as_bytes()),
+			"512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+		);
+		assert_eq!(
+			address(&output, Network::Bitcoin),
+			"bc1p2wsldez5mud2yam29q22wgfh9439spgduvct83k3pm50fcxa5dps59h4z5",
+		);
+
+		// The output key only depends on the x coordinate of the internal key.
+		let odd = key("03d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d");
+		assert_eq!(output_key(&odd).unwrap(), output);
+	}
+}
@@ -0,0 +1,206 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! A builder for txs, created with [Wallet::build_tx].
+//!
+//! The builder collects the recipients, inputs and options of a tx, which is
+//! created by [TxBuilder::finish]. Errors in the arguments are returned there.
+
+use bitcoin::blockdata::transaction::SigHashType;
+use bitcoin::{Address, OutPoint, Script, TxOut};
+
+use amount::{Amount, FeeRate};
+use config::ChangeTypePolicy;
+use error::{Error, Result};
+use fee::Fee;
+use fee_estimator::FeeEstimator;
+use wallet::{CreatedTx, TxOptions, Wallet};
+
+/// Builds a tx of the wallet. Like [Wallet::create_transaction], the created
+/// tx is committed, unless [TxBuilder::without_commit] is used.
+pub struct TxBuilder<'a> {
+	wallet: &'a mut Wallet,
+	outputs: Vec<TxOut>,
+	utxos: Vec<OutPoint>,
+	fee: Option<Fee>,
+	drain_wallet: bool,
+	options: TxOptions,
+	/// The first error of the given arguments, returned by [finish].
+	error: Option<Error>,
+}
+
+impl<'a> TxBuilder<'a> {
+	pub fn new(wallet: &'a mut Wallet) -> TxBuilder<'a> {
+		TxBuilder {
+			wallet: wallet,
+			outputs: Vec::new(),
+			utxos: Vec::new(),
+			fee: None,
+			drain_wallet: false,
+			options: TxOptions::default(),
+			error: None,
+		}
+	}
+
+	fn check_network(&mut self, address: &Address) {
+		if address.network != self.wallet.config().network && self.error.is_none() {
+			self.error = Some(Error::WrongNetwork);
+		}
+	}
+
+	/// Pay the amount to the address.
+	pub fn add_recipient(mut self, address: &Address, amount: Amount) -> Self {
+		self.check_network(address);
+		self.outputs.push(TxOut {
+			value: amount.as_sat(),
+			script_pubkey: address.script_pubkey(),
+		});
+		self
+	}
+
+	pub fn add_output(mut self, output: TxOut) -> Self {
+		self.outputs.push(output);
+		self
+	}
+
+	/// Spend the UTXO. More UTXOs are selected when the given ones don't
+	/// cover the payments.
+	pub fn add_utxo(mut self, outpoint: OutPoint) -> Self {
+		self.utxos.push(outpoint);
+		self
+	}
+
+	pub fn add_utxos(mut self, outpoints: &[OutPoint]) -> Self {
+		self.utxos.extend_from_slice(outpoints);
+		self
+	}
+
+	/// Pay a fee at the feerate.
+	pub fn fee_rate(mut self, fee_rate: FeeRate) -> Self {
+		self.fee = Some(Fee::Rate(fee_rate));
+		self
+	}
+
+	/// Pay the feerate the estimator estimates for the tx to confirm within
+	/// [target] blocks.
+	pub fn confirmation_target<E: FeeEstimator>(mut self, estimator: &E, target: u16) -> Self {
+		match estimator.estimate_fee_rate(target) {
+			Ok(fee_rate) => self.fee = Some(Fee::Rate(fee_rate)),
+			Err(e) => {
+				if self.error.is_none() {
+					self.error = Some(e);
+				}
+			}
+		}
+		self
+	}
+
+	/// Pay exactly the given fee.
+	pub fn fee_absolute(mut self, fee: Amount) -> Self {
+		self.fee = Some(Fee::Absolute(fee));
+		self
+	}
+
+	/// Signal that the tx can be replaced (BIP-125).
+	pub fn enable_rbf(mut self) -> Self {
+		self.options.rbf = true;
+		self
+	}
+
+	pub fn version(mut self, version: u32) -> Self {
+		self.options.version = version;
+		self
+	}
+
+	pub fn lock_time(mut self, lock_time: u32) -> Self {
+		self.options.lock_time = lock_time;
+		self
+	}
+
+	/// Set the sequence of an input given with [add_utxo].
+	pub fn sequence(mut self, outpoint: OutPoint, sequence: u32) -> Self {
+		self.options.sequences.insert(outpoint, sequence);
+		self
+	}
+
+	/// Set the sighash type of an input given with [add_utxo].
+	pub fn sighash_type(mut self, outpoint: OutPoint, sighash_type: SigHashType) -> Self {
+		self.options.sighash_types.insert(outpoint, sighash_type);
+		self
+	}
+
+	/// Send everything that is not paid to the recipients or the fee to the
+	/// address instead of to change, like when sweeping into another wallet.
+	pub fn drain_to(mut self, address: &Address) -> Self {
+		self.check_network(address);
+		self.options.drain_to = Some(address.script_pubkey());
+		self
+	}
+
+	/// Like [drain_to], but for any script, like one of a multi-party
+	/// construction.
+	pub fn change_script(mut self, script_pubkey: Script) -> Self {
+		self.options.drain_to = Some(script_pubkey);
+		self
+	}
+
+	/// Fail with [Error::ChangeNotAllowed] when the tx would need change.
+	pub fn no_change(mut self) -> Self {
+		self.options.no_change = true;
+		self
+	}
+
+	/// Spend all UTXOs that coin selection would pick, see
+	/// [Wallet::is_utxo_selectable]. Use with [drain_to].
+	pub fn drain_wallet(mut self) -> Self {
+		self.drain_wallet = true;
+		self
+	}
+
+	/// Don't commit the created tx, see [Wallet::commit_on_broadcast].
+	pub fn without_commit(mut self) -> Self {
+		self.options.commit = false;
+		self
+	}
+
+	/// Use the policy for the change type instead of the one of the config.
+	pub fn change_policy(mut self, policy: ChangeTypePolicy) -> Self {
+		self.options.change_type = Some(policy);
+		self
+	}
+
+	/// Create and commit the tx.
+	///
+	/// Possible errors, in addition to those of [Wallet::create_transaction]:
+	/// - [Error::WrongNetwork]
+	/// - [Error::InvalidTxOptions]
+	pub fn finish(mut self) -> Result<CreatedTx> {
+		if let Some(e) = self.error {
+			return Err(e);
+		}
+		let fee = match self.fee {
+			Some(fee) => fee,
+			None => return Err(Error::InvalidTxOptions("no fee set")),
+		};
+		if self.drain_wallet {
+			for utxo in self.wallet.list_utxos() {
+				let selectable = self.wallet.is_utxo_selectable(utxo);
+				if selectable && !self.utxos.contains(&utxo.outpoint) {
+					self.utxos.push(utxo.outpoint);
+				}
+			}
+		}
+		self.wallet.create_transaction_with_options(self.outputs, self.utxos, fee, &self.options)
+	}
+}
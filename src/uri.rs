@@ -0,0 +1,262 @@
+// Rust Bitcoin Wallet
+// Written in 2019 by
+//   Steven Roose <steven@stevenroose.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! BIP-21 payment URIs.
+
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::Address;
+
+use amount::Amount;
+use error::{Error, Result};
+
+/// A BIP-21 payment URI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uri {
+	pub address: Address,
+	pub amount: Option<Amount>,
+	pub label: Option<String>,
+	pub message: Option<String>,
+	/// All other parameters, percent-decoded.
+	pub extras: Vec<(String, String)>,
+}
+
+impl Uri {
+	pub fn new(address: Address) -> Uri {
+		Uri {
+			address: address,
+			amount: None,
+			label: None,
+			message: None,
+			extras: Vec::new(),
+		}
+	}
+
+	/// Get the value of a parameter not covered by the other fields.
+	pub fn extra(&self, key: &str) -> Option<&str> {
+		self.extras.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+	}
+
+	pub fn parse(uri: &str) -> Result<Uri> {
+		let invalid = |reason| Error::InvalidUri(reason);
+		if uri.len() < 8 || !uri.is_char_boundary(8) || !uri[..8].eq_ignore_ascii_case("bitcoin:") {
+			return Err(invalid("not a bitcoin URI"));
+		}
+		let mut parts = uri[8..].splitn(2, '?');
+		let address = match Address::from_str(parts.next().unwrap_or("")) {
+			Ok(address) => address,
+			Err(_) => return Err(invalid("invalid address")),
+		};
+
+		let mut ret = Uri::new(address);
+		for param in parts.next().unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+			let mut kv = param.splitn(2, '=');
+			let key = kv.next().unwrap_or("");
+			let value = percent_decode(kv.next().unwrap_or(""))
+				.ok_or_else(|| invalid("invalid percent-encoding"))?;
+			match key {
+				"amount" => {
+					let amount = parse_btc_amount(&value).ok_or_else(|| invalid("invalid amount"))?;
+					ret.amount = Some(Amount::from_sat(amount));
+				}
+				"label" => ret.label = Some(value),
+				"message" => ret.message = Some(value),
+				// Required parameters we don't know make the URI unusable.
+				k if k.starts_with("req-") => return Err(invalid("unknown required parameter")),
+				k => ret.extras.push((k.to_owned(), value)),
+			}
+		}
+		Ok(ret)
+	}
+}
+
+impl FromStr for Uri {
+	type Err = Error;
+	fn from_str(s: &str) -> Result<Uri> {
+		Uri::parse(s)
+	}
+}
+
+impl fmt::Display for Uri {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "bitcoin:{}", self.address)?;
+		let mut sep = '?';
+		if let Some(amount) = self.amount {
+			write!(f, "{}amount={}", sep, format_btc_amount(amount.as_sat()))?;
+			sep = '&';
+		}
+		if let Some(ref label) = self.label {
+			write!(f, "{}label={}", sep, percent_encode(label))?;
+			sep = '&';
+		}
+		if let Some(ref message) = self.message {
+			write!(f, "{}message={}", sep, percent_encode(message))?;
+			sep = '&';
+		}
+		for (key, value) in &self.extras {
+			write!(f, "{}{}={}", sep, key, percent_encode(value))?;
+			sep = '&';
+		}
+		Ok(())
+	}
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+	let bytes = s.as_bytes();
+	let mut ret = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		if bytes[i] == b'%' {
+			let hex = s.get(i + 1..i + 3)?;
+			if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+				return None;
+			}
+			ret.push(u8::from_str_radix(hex, 16).ok()?);
+			i += 3;
+		} else {
+			ret.push(bytes[i]);
+			i += 1;
+		}
+	}
+	String::from_utf8(ret).ok()
+}
+
+fn percent_encode(s: &str) -> String {
+	let mut ret = String::with_capacity(s.len());
+	for b in s.bytes() {
+		match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+				ret.push(b as char)
+			}
+			_ => ret.push_str(&format!("%{:02X}", b)),
+		}
+	}
+	ret
+}
+
+/// Parse an amount in BTC with at most 8 decimals into satoshis.
+fn parse_btc_amount(s: &str) -> Option<u64> {
+	let mut parts = s.splitn(2, '.');
+	let whole = parts.next()?;
+	let frac = parts.next().unwrap_or("");
+	let is_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+	if whole.is_empty() || !is_digits(whole) || frac.len() > 8 || !is_digits(frac) {
+		return None;
+	}
+	let whole: u64 = whole.parse().ok()?;
+	let frac: u64 = format!("{:0<8}", frac).parse().ok()?;
+	whole.checked_mul(100_000_000)?.checked_add(frac)
+}
+
+/// Format an amount in satoshis in BTC without trailing zeros.
+fn format_btc_amount(amount: u64) -> String {
+	let frac = format!("{:08}", amount % 100_000_000);
+	let frac = frac.trim_end_matches('0');
+	if frac.is_empty() {
+		format!("{}", amount / 100_000_000)
+	} else {
+		format!("{}.{}", amount / 100_000_000, frac)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ADDRESS: &'static str = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+
+	fn parse(params: &str) -> Result<Uri> {
+		Uri::parse(&format!("bitcoin:{}{}", ADDRESS, params))
+	}
+
+	#[test]
+	fn round_trip() {
+		let mut uri = Uri::new(Address::from_str(ADDRESS).unwrap());
+		assert_eq!(uri.to_string(), format!("bitcoin:{}", ADDRESS));
+		assert_eq!(Uri::parse(&uri.to_string()), Ok(uri.clone()));
+
+		uri.amount = Some(Amount::from_sat(2_030_000_000));
+		uri.label = Some("Luke-Jr".to_owned());
+		uri.message = Some("Donation for project xyz & co.".to_owned());
+		uri.extras.push(("pj".to_owned(), "https://example.com/pj?v=1".to_owned()));
+		let s = uri.to_string();
+		assert_eq!(
+			s,
+			format!(
+				"bitcoin:{}?amount=20.3&label=Luke-Jr&message=Donation%20for%20project%20xyz%20\
+				 %26%20co.&pj=https%3A%2F%2Fexample.com%2Fpj%3Fv%3D1",
+				ADDRESS
+			)
+		);
+		assert_eq!(Uri::parse(&s), Ok(uri.clone()));
+		assert_eq!(uri.extra("pj"), Some("https://example.com/pj?v=1"));
+	}
+
+	#[test]
+	fn scheme() {
+		assert!(Uri::parse(&format!("BITCOIN:{}", ADDRESS)).is_ok());
+		let not_bitcoin = Err(Error::InvalidUri("not a bitcoin URI"));
+		assert_eq!(Uri::parse(&format!("litecoin:{}", ADDRESS)), not_bitcoin);
+		assert_eq!(Uri::parse(ADDRESS), not_bitcoin);
+		assert_eq!(Uri::parse("bitcoin:notanaddress"), Err(Error::InvalidUri("invalid address")));
+	}
+
+	#[test]
+	fn percent_decoding() {
+		let uri = parse("?label=Luke%20Jr%C3%A9&message=a%2bb+c").unwrap();
+		assert_eq!(uri.label, Some("Luke Jré".to_owned()));
+		// A plus is not a space in BIP-21.
+		assert_eq!(uri.message, Some("a+b+c".to_owned()));
+
+		let invalid = Err(Error::InvalidUri("invalid percent-encoding"));
+		for params in &["?label=%2", "?label=%zz", "?label=%+1", "?label=%FF", "?label=%C3"] {
+			assert_eq!(parse(params), invalid, "{}", params);
+		}
+	}
+
+	#[test]
+	fn required_parameters() {
+		let uri = parse("?somethingyoudontunderstand=50&somethingelseyoudontget=999").unwrap();
+		assert_eq!(uri.extra("somethingyoudontunderstand"), Some("50"));
+		assert_eq!(uri.extra("somethingelseyoudontget"), Some("999"));
+
+		let params = "?req-somethingyoudontunderstand=50&req-somethingelseyoudontget=999";
+		assert_eq!(parse(params), Err(Error::InvalidUri("unknown required parameter")));
+	}
+
+	#[test]
+	fn amounts() {
+		let amount = |s: &str| parse(&format!("?amount={}", s)).map(|u| u.amount.unwrap().as_sat());
+		assert_eq!(amount("50"), Ok(5_000_000_000));
+		assert_eq!(amount("20.3"), Ok(2_030_000_000));
+		assert_eq!(amount("0.00000001"), Ok(1));
+		assert_eq!(amount("000.10000000"), Ok(10_000_000));
+		assert_eq!(amount("184467440737.09551615"), Ok(u64::max_value()));
+
+		let invalid = Err(Error::InvalidUri("invalid amount"));
+		let overflow = "184467440737.09551616";
+		for s in &["", ".5", "1.123456789", "1e3", "-1", "+1", "1,5", "0x10", overflow] {
+			assert_eq!(amount(*s), invalid, "{}", s);
+		}
+	}
+
+	#[test]
+	fn format_amounts() {
+		assert_eq!(format_btc_amount(0), "0");
+		assert_eq!(format_btc_amount(1), "0.00000001");
+		assert_eq!(format_btc_amount(2_030_000_000), "20.3");
+		assert_eq!(format_btc_amount(5_000_000_000), "50");
+	}
+}
@@ -1,14 +1,42 @@
-use std::collections::{HashMap, HashSet};
-use std::{collections, fmt};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::Hash as StdHash;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{cmp, collections, fmt, io};
 
+use bitcoin::util::merkleblock::MerkleBlock;
+use bitcoin::blockdata::transaction::SigHashType;
 use bitcoin::util::{bip32, psbt};
-use bitcoin::{Address, BitcoinHash, Block, OutPoint, Script, Transaction, TxIn, TxOut};
-use bitcoin_hashes::sha256d;
-use rand::{self, Rng};
-use serde::{Deserialize, Serialize};
+use bitcoin::{
+	Address, BitcoinHash, Block, BlockHeader, Network, OutPoint, PublicKey, Script, Transaction,
+	TxIn, TxOut,
+};
+use bitcoin_hashes::{sha256, sha256d, Hash};
+use rand::{Rng, SeedableRng, StdRng};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json;
 
-use config::WalletConfig;
+use accounting::{self, AccountingLot, AccountingTag, CsvOptions};
+use amount::{self, Amount, FeeRate};
+use audit::{AuditEntry, AuditExport};
+use bip322;
+use blocks::BlockHashes;
+use compact_block::{CompactBlock, Reconstruction};
+use config::{ChangeTypePolicy, DuplicateRecipientPolicy, WalletConfig, COINBASE_MATURITY};
+use descriptor::{self, CoreImportRequest, CoreTimestamp};
 use error::{Error, Result};
+use events::WalletEvent;
+use fee::{self, Fee, InputKind};
+use headers::HeaderChain;
+use history::TxHistory;
+use labels::{Label, LabelRef, Labels};
+use proof_of_reserves;
+use pubkey_cache::PubkeyCache;
+use signer::{self, HotSigner};
+use silent_payments;
+use sync::Broadcaster;
+use tx_builder::TxBuilder;
+use uri::Uri;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KnownBlock {
@@ -16,63 +44,976 @@ pub struct KnownBlock {
 	pub hash: sha256d::Hash,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum AddressType {
 	P2wpkh,
+	P2shP2wpkh,
+	P2pkh,
 }
 
 impl AddressType {
 	pub fn all_types() -> &'static [AddressType] {
-		&[AddressType::P2wpkh]
+		&[AddressType::P2wpkh, AddressType::P2shP2wpkh, AddressType::P2pkh]
 	}
+
+	/// The address type of a scriptPubkey. P2SH scripts are not recognized,
+	/// since they can wrap any script. Use [Wallet::address_type_of] to
+	/// recognize our own P2SH-P2WPKH scripts.
+	pub fn from_script(script: &Script) -> Option<AddressType> {
+		if script.is_v0_p2wpkh() {
+			Some(AddressType::P2wpkh)
+		} else if script.is_p2pkh() {
+			Some(AddressType::P2pkh)
+		} else {
+			None
+		}
+	}
+
+	/// The address of the type for the key.
+	pub fn address(&self, pubkey: &PublicKey, network: Network) -> Address {
+		match *self {
+			AddressType::P2wpkh => Address::p2wpkh(pubkey, network),
+			AddressType::P2shP2wpkh => Address::p2shwpkh(pubkey, network),
+			AddressType::P2pkh => Address::p2pkh(pubkey, network),
+		}
+	}
+
+	/// The kind of input spending an output of the address type.
+	pub fn input_kind(&self) -> InputKind {
+		match *self {
+			AddressType::P2wpkh => InputKind::P2wpkh,
+			AddressType::P2shP2wpkh => InputKind::P2shP2wpkh,
+			AddressType::P2pkh => InputKind::P2pkh,
+		}
+	}
+}
+
+impl Default for AddressType {
+	fn default() -> AddressType {
+		AddressType::P2wpkh
+	}
+}
+
+/// The chain of keys a key is derived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Keychain {
+	/// The keys used for receive addresses.
+	External,
+	/// The keys used for change.
+	Internal,
+	/// The keys of a dedicated branch with the given index, derived under a
+	/// reserved child of the base derivation path, f.e. for a single customer.
+	/// See [Wallet::create_branch].
+	Branch(u32),
+}
+
+impl Default for Keychain {
+	fn default() -> Keychain {
+		Keychain::External
+	}
+}
+
+/// Information about a script owned by the wallet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScriptInfo {
+	pub keychain: Keychain,
+	/// The child number of the key in the keychain.
+	pub child: bip32::ChildNumber,
+	pub address_type: AddressType,
+	/// The generation of the wallet keys the script was derived from.
+	/// See [Wallet::rotate_keys].
+	#[serde(default)]
+	pub generation: u32,
 }
 
 /// A UTXO owned by our wallet.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Utxo {
 	pub outpoint: OutPoint,
-	pub value: u64,
+	#[serde(with = "amount::as_sat")]
+	pub value: Amount,
 	pub height: u32,
 
+	/// The keychain of the key that is needed to spend this output.
+	#[serde(default)]
+	keychain: Keychain,
 	/// The child number of the key that is needed to spend this output.
 	child_number: bip32::ChildNumber,
+	/// The generation of the wallet keys of the key needed to spend this output.
+	#[serde(default)]
+	generation: u32,
 
 	/// This UTXO has been used in the following txs.
-	used_in_tx: HashSet<sha256d::Hash>,
+	used_in_tx: BTreeSet<sha256d::Hash>,
+
+	/// Locked UTXOs are not selected automatically when creating txs.
+	#[serde(default)]
+	locked: bool,
+
+	/// The output itself. Only missing for UTXOs stored by older versions,
+	/// of which the output is taken from the history.
+	#[serde(default)]
+	prevout: Option<TxOut>,
+	/// The tx that created the output, for P2PKH outputs of which PSBTs
+	/// need the full tx.
+	#[serde(default)]
+	prev_tx: Option<Transaction>,
+	/// Set for imported UTXOs that are not spent by a key of the wallet.
+	/// The child number and keychain have no meaning for these.
+	#[serde(default)]
+	external: bool,
+	/// How the output is spent, when it can't be derived from the script.
+	/// See [Wallet::set_utxo_input_kind].
+	#[serde(default)]
+	input_kind: Option<InputKind>,
+	/// The tweak of the spend key for silent payment outputs.
+	#[serde(default)]
+	silent_payment_tweak: Option<[u8; 32]>,
+	/// The timelock of the script, see [Wallet::set_utxo_timelock].
+	#[serde(default)]
+	timelock: Option<Timelock>,
+	/// The output was created by a coinbase tx, so it can only be spent
+	/// after [COINBASE_MATURITY] blocks.
+	#[serde(default)]
+	coinbase: bool,
 }
 
 impl Utxo {
 	pub fn is_available(&self) -> bool {
 		self.used_in_tx.is_empty()
 	}
+
+	pub fn is_locked(&self) -> bool {
+		self.locked
+	}
+
+	pub fn timelock(&self) -> Option<Timelock> {
+		self.timelock
+	}
+
+	pub fn is_coinbase(&self) -> bool {
+		self.coinbase
+	}
+
+	/// Whether the timelock of the UTXO, if any, and the coinbase maturity
+	/// allow spending it in the block after the given tip.
+	pub fn is_mature(&self, tip: u32) -> bool {
+		if self.coinbase && tip + 1 < self.height + COINBASE_MATURITY {
+			return false;
+		}
+		match self.timelock {
+			Some(Timelock::Height(height)) => height <= tip,
+			Some(Timelock::Blocks(blocks)) => tip + 1 >= self.height + u32::from(blocks),
+			None => true,
+		}
+	}
+}
+
+/// A timelock in the script of a UTXO that must be satisfied to spend it.
+/// Only timelocks in blocks are supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Timelock {
+	/// An absolute timelock (OP_CHECKLOCKTIMEVERIFY) at the block height.
+	Height(u32),
+	/// A relative timelock (OP_CHECKSEQUENCEVERIFY) of the number of blocks
+	/// after the UTXO confirmed.
+	Blocks(u16),
+}
+
+/// The key that can spend an imported UTXO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UtxoKey {
+	/// A key derived from the wallet's current keys.
+	Derived(Keychain, bip32::ChildNumber),
+	/// A key that is not part of the wallet, a signer should know it.
+	External,
+	/// The silent payment spend key with the given tweak added,
+	/// see [silent_payments].
+	SilentPayment([u8; 32]),
+}
+
+/// Filter for [Wallet::get_utxos_filtered]. The default matches all UTXOs.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoFilter {
+	/// Only list UTXOs with at least this many confirmations.
+	pub min_confirmations: Option<u32>,
+	/// Only list UTXOs with at least this value.
+	pub min_value: Option<Amount>,
+	/// Only list UTXOs with at most this value.
+	pub max_value: Option<Amount>,
+	/// Only list UTXOs that are selected automatically when creating txs:
+	/// not used in a tx, not locked and mature.
+	pub spendable_only: bool,
+	/// Only list UTXOs paying to scripts of this type.
+	pub address_type: Option<AddressType>,
+}
+
+/// A [Utxo] with the info about it that is not stored in it.
+#[derive(Debug, Clone)]
+pub struct UtxoInfo {
+	pub outpoint: OutPoint,
+	pub value: Amount,
+	pub height: u32,
+	pub confirmations: u32,
+	/// Missing for scripts that have no address or when the output is unknown.
+	pub address: Option<Address>,
+	pub address_type: Option<AddressType>,
+	/// The keychain and the path of the key relative to the wallet's xpub.
+	/// Missing for imported UTXOs that are not spent by a key of the wallet.
+	pub derivation: Option<(Keychain, bip32::DerivationPath)>,
+	/// See [Wallet::is_utxo_spendable].
+	pub spendable: bool,
+	pub locked: bool,
+	/// The label of the output, or else the one of its address.
+	pub label: Option<String>,
+}
+
+/// An entry of the `listunspent` RPC of Bitcoin Core.
+#[derive(Debug, Serialize)]
+struct ListUnspentEntry {
+	txid: sha256d::Hash,
+	vout: u32,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	address: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	label: Option<String>,
+	#[serde(rename = "scriptPubKey")]
+	script_pubkey: String,
+	/// In BTC.
+	amount: f64,
+	confirmations: u32,
+	spendable: bool,
+	solvable: bool,
+	safe: bool,
+}
+
+/// A UTXO paying to a watch-only script. These can't be spent by the wallet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchedUtxo {
+	pub outpoint: OutPoint,
+	pub txout: TxOut,
+	pub height: u32,
+}
+
+/// An entry in the wallet's transaction history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxEntry {
+	pub tx: Transaction,
+	/// The height of the block the tx was confirmed in.
+	pub height: u32,
+	/// The hash of the block the tx was confirmed in.
+	pub block_hash: sha256d::Hash,
+	/// The timestamp of the block the tx was confirmed in.
+	pub timestamp: u32,
+	/// The total value of our own outputs spent by the tx.
+	#[serde(with = "amount::as_sat")]
+	pub sent: Amount,
+	/// The total value of the outputs of the tx that pay to us.
+	#[serde(with = "amount::as_sat")]
+	pub received: Amount,
+	/// The fee paid by the tx, only known when all its inputs are known.
+	#[serde(with = "amount::as_sat_opt")]
+	pub fee: Option<Amount>,
+	/// Whether the tx pays to one of our addresses that already received funds before.
+	#[serde(default)]
+	pub reused_address: bool,
+	/// The total value of the watch-only outputs spent by the tx.
+	#[serde(default = "zero_amount", with = "amount::as_sat")]
+	pub watched_sent: Amount,
+	/// The total value of the outputs of the tx that pay to watch-only scripts.
+	#[serde(default = "zero_amount", with = "amount::as_sat")]
+	pub watched_received: Amount,
+	/// The txid of the tx when its inputs were pruned, see [Wallet::compact].
+	#[serde(default)]
+	pub pruned_txid: Option<sha256d::Hash>,
+	/// Whether the tx was a coinbase tx, for txs of which the inputs were pruned.
+	#[serde(default)]
+	pub pruned_coinbase: bool,
+}
+
+impl TxEntry {
+	pub fn txid(&self) -> sha256d::Hash {
+		self.pruned_txid.unwrap_or_else(|| self.tx.txid())
+	}
+
+	/// Whether the inputs of the tx were pruned, see [Wallet::compact].
+	pub fn is_pruned(&self) -> bool {
+		self.pruned_txid.is_some()
+	}
+
+	/// Whether the tx is a coinbase tx, also when its inputs were pruned.
+	pub fn is_coinbase(&self) -> bool {
+		self.pruned_coinbase || self.tx.is_coin_base()
+	}
+
+	/// The net effect of the tx on the wallet's balance, in satoshis.
+	pub fn net_amount(&self) -> i64 {
+		self.received.as_sat() as i64 - self.sent.as_sat() as i64
+	}
+
+	/// Whether the tx was sending funds out of the wallet.
+	pub fn is_outgoing(&self) -> bool {
+		self.sent > self.received
+	}
+
+	pub fn direction(&self) -> TxDirection {
+		if self.is_outgoing() {
+			TxDirection::Outgoing
+		} else {
+			TxDirection::Incoming
+		}
+	}
+}
+
+/// The direction of the funds in a tx, relative to the wallet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxDirection {
+	Incoming,
+	Outgoing,
+}
+
+/// Filter and pagination options for [Wallet::list_transactions].
+#[derive(Debug, Clone, Default)]
+pub struct TxFilter {
+	/// Only list txs confirmed at or above this height.
+	pub min_height: Option<u32>,
+	/// Only list txs confirmed at or below this height.
+	pub max_height: Option<u32>,
+	/// Only list txs in this direction.
+	pub direction: Option<TxDirection>,
+	/// Only list txs with an absolute net amount of at least this value.
+	pub min_amount: Option<Amount>,
+	/// The number of matching txs to skip.
+	pub offset: usize,
+	/// The maximum number of txs to list.
+	pub limit: Option<usize>,
+}
+
+impl TxFilter {
+	fn matches(&self, entry: &TxEntry) -> bool {
+		if let Some(min) = self.min_height {
+			if entry.height < min {
+				return false;
+			}
+		}
+		if let Some(max) = self.max_height {
+			if entry.height > max {
+				return false;
+			}
+		}
+		if let Some(direction) = self.direction {
+			if entry.direction() != direction {
+				return false;
+			}
+		}
+		if let Some(min) = self.min_amount {
+			if entry.net_amount().abs() < min.as_sat() as i64 {
+				return false;
+			}
+		}
+		true
+	}
+}
+
+/// Details about a tx that is known to the wallet.
+#[derive(Debug, Clone)]
+pub struct TxDetails<'a> {
+	pub tx: &'a Transaction,
+	/// The history entry of the tx, if it is confirmed.
+	pub entry: Option<&'a TxEntry>,
+	/// The number of confirmations, zero if unconfirmed.
+	pub confirmations: u32,
+	/// Whether the tx is a pending tx committed by the wallet.
+	pub pending: bool,
+	/// The indices of the outputs that pay to the wallet.
+	pub our_outputs: Vec<u32>,
+}
+
+impl<'a> TxDetails<'a> {
+	/// The height of the block the tx was confirmed in.
+	pub fn height(&self) -> Option<u32> {
+		self.entry.map(|e| e.height)
+	}
+
+	/// The hash of the block the tx was confirmed in.
+	pub fn block_hash(&self) -> Option<sha256d::Hash> {
+		self.entry.map(|e| e.block_hash)
+	}
+}
+
+/// Limits for every tx in a payout run.
+#[derive(Debug, Clone)]
+pub struct PayoutLimits {
+	pub max_inputs: usize,
+	/// The maximum number of outputs, including change.
+	pub max_outputs: usize,
+	/// The maximum weight of the signed tx.
+	pub max_weight: u64,
+}
+
+impl PayoutLimits {
+	fn allows(&self, tx: &Transaction) -> bool {
+		tx.input.len() <= self.max_inputs
+			&& tx.output.len() <= self.max_outputs
+			&& fee::estimate_weight(tx.input.len(), &tx.output) <= self.max_weight
+	}
+}
+
+/// The change of a created tx.
+#[derive(Debug, Clone)]
+pub struct ChangeInfo {
+	/// The indices of the change outputs in the tx.
+	/// There are multiple when the change is split.
+	pub output_indices: Vec<usize>,
+	/// The total value of the change outputs.
+	pub value: Amount,
+	pub keychain: Keychain,
+	/// The children the change outputs pay to, in the order of [output_indices].
+	pub children: Vec<bip32::ChildNumber>,
+}
+
+/// A tx created by the wallet, with what the wallet decided while creating
+/// it.
+#[derive(Debug, Clone)]
+pub struct CreatedTx {
+	pub psbt: psbt::PartiallySignedTransaction,
+	/// The fee paid by the tx, including change that was too small to create.
+	pub fee: Amount,
+	/// The feerate in satoshis per virtual byte, based on [vsize].
+	pub fee_rate: f64,
+	/// The outpoints spent by the tx, in the order of the tx inputs.
+	pub selected_utxos: Vec<OutPoint>,
+	/// Not set when the tx has no change.
+	pub change: Option<ChangeInfo>,
+	/// The estimated weight of the signed tx.
+	pub weight: u64,
+	/// The estimated virtual size of the signed tx.
+	pub vsize: u64,
+}
+
+impl CreatedTx {
+	pub fn txid(&self) -> sha256d::Hash {
+		self.psbt.global.unsigned_tx.txid()
+	}
+
+	/// The index of the change output, the first one when the change is split.
+	pub fn change_index(&self) -> Option<usize> {
+		self.change.as_ref().and_then(|c| c.output_indices.first().cloned())
+	}
+
+	/// The total value paid to the outputs that are not change.
+	pub fn sent_value(&self) -> Amount {
+		let change = self.change.as_ref().map(|c| &c.output_indices[..]).unwrap_or(&[]);
+		let outputs = self.psbt.global.unsigned_tx.output.iter().enumerate();
+		Amount::from_sat(outputs.filter(|(i, _)| !change.contains(i)).map(|(_, o)| o.value).sum())
+	}
+}
+
+/// Options for creating a tx with [Wallet::create_transaction_with_options].
+#[derive(Debug, Clone)]
+pub struct TxOptions {
+	/// The tx version, 1 by default. Relative locktimes need version 2.
+	pub version: u32,
+	/// The absolute locktime, 0 by default. When set, inputs without a
+	/// sequence get 0xFFFFFFFE so that the locktime is enforced.
+	pub lock_time: u32,
+	/// The sequences of the given inputs, 0xFFFFFFFF by default.
+	pub sequences: HashMap<OutPoint, u32>,
+	/// Signal replaceability (BIP-125): inputs without a sequence get 0xFFFFFFFD.
+	pub rbf: bool,
+	/// Overrides the change type policy of the config.
+	pub change_type: Option<ChangeTypePolicy>,
+	/// The address type of the change, overrides any change type policy.
+	pub change_address_type: Option<AddressType>,
+	/// Send the change to this script instead of a change address. The change
+	/// is not split or rounded and must not be dust.
+	pub drain_to: Option<Script>,
+	/// Fail with [Error::ChangeNotAllowed] instead of creating change.
+	/// Excess too small for a change output still goes to the fee.
+	pub no_change: bool,
+	/// The sighash types to set on the PSBT inputs of the given inputs.
+	/// Inputs without a sighash type are signed with SIGHASH_ALL.
+	///
+	/// When any of them is SIGHASH_SINGLE, the given inputs and outputs keep
+	/// their order at the start of the tx, so that every given input is at the
+	/// index of the output it commits to. Extra inputs and change follow them.
+	pub sighash_types: HashMap<OutPoint, SigHashType>,
+	/// Keep the given inputs and outputs in order at the start of the tx.
+	pub keep_order: bool,
+	/// Given inputs that are not of the wallet, with the PSBT data to sign
+	/// them. The spent output must be set as witness or non-witness UTXO.
+	pub foreign_inputs: HashMap<OutPoint, psbt::Input>,
+	/// Commit the created tx, so that its inputs are not used again. True by
+	/// default. Otherwise, use [Wallet::commit_on_broadcast] or
+	/// [Wallet::sign_and_broadcast] to commit it only once it's broadcast.
+	pub commit: bool,
+}
+
+impl Default for TxOptions {
+	fn default() -> TxOptions {
+		TxOptions {
+			version: 1,
+			lock_time: 0,
+			sequences: HashMap::new(),
+			rbf: false,
+			change_type: None,
+			change_address_type: None,
+			drain_to: None,
+			no_change: false,
+			sighash_types: HashMap::new(),
+			keep_order: false,
+			foreign_inputs: HashMap::new(),
+			commit: true,
+		}
+	}
+}
+
+impl TxOptions {
+	/// The sequence of the input.
+	fn sequence(&self, outpoint: &OutPoint) -> u32 {
+		match self.sequences.get(outpoint) {
+			Some(sequence) => *sequence,
+			None if self.rbf => 0xFFFFFFFD,
+			None if self.lock_time != 0 => 0xFFFFFFFE,
+			None => 0xFFFFFFFF,
+		}
+	}
+
+	/// Whether the given inputs and outputs keep their order.
+	fn keep_order(&self) -> bool {
+		self.keep_order || self.sighash_types.values().any(|t| t.as_u32() & 0x1f == 0x03)
+	}
+
+	/// The value and the input kind of a foreign input.
+	fn foreign_input(&self, outpoint: &OutPoint) -> Option<Result<(u64, InputKind)>> {
+		let input = self.foreign_inputs.get(outpoint)?;
+		let prevout = match (&input.witness_utxo, &input.non_witness_utxo) {
+			(Some(txout), _) => Some(txout),
+			(None, Some(tx)) => tx.output.get(outpoint.vout as usize),
+			(None, None) => None,
+		};
+		// P2SH is only recognized when the redeem script is P2WPKH.
+		let wrapped_p2wpkh = input.redeem_script.as_ref().map(|s| s.is_v0_p2wpkh());
+		Some(match prevout {
+			Some(txout) if txout.script_pubkey.is_p2sh() && wrapped_p2wpkh == Some(true) => {
+				Ok((txout.value, InputKind::P2shP2wpkh))
+			}
+			Some(txout) => match InputKind::from_script(&txout.script_pubkey) {
+				Some(kind) => Ok((txout.value, kind)),
+				None => Err(Error::UnsupportedAddressType),
+			},
+			None => Err(Error::InvalidTxOptions("foreign input without UTXO")),
+		})
+	}
+}
+
+/// The change of the wallet's balance in a block, see [Wallet::balance_history].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BalancePoint {
+	pub height: u32,
+	/// The net effect of the block's txs on the balance, in satoshis.
+	pub delta: i64,
+	/// The balance after the block, in satoshis.
+	pub balance: i64,
+}
+
+/// Statistics of the wallet, see [Wallet::stats].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletStats {
+	pub utxo_count: usize,
+	pub total_value: Amount,
+	/// Zero when there are no UTXOs.
+	pub average_value: Amount,
+	/// Zero when there are no UTXOs.
+	pub median_value: Amount,
+	/// The number of addresses handed out over all keychains.
+	pub derived_addresses: u32,
+	/// The number of the wallet's scripts that received funds.
+	pub used_addresses: usize,
+	pub history_len: usize,
+	/// The height of the last tx in the history.
+	pub last_activity_height: Option<u32>,
+}
+
+/// An inconsistency in the wallet's state, found by [Wallet::check_integrity].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityIssue {
+	/// Neither the UTXO nor the history has the output of the UTXO.
+	MissingUtxoData(OutPoint),
+	/// The value of the UTXO differs from the value of its output.
+	UtxoValueMismatch(OutPoint),
+	/// The key of the UTXO doesn't derive to the script of its output.
+	UtxoKeyMismatch(OutPoint),
+	/// The UTXO is used in a tx that is not pending, so it is never
+	/// available again.
+	StaleUtxoUsage(OutPoint, sha256d::Hash),
+	/// The entry of the script index with the script hash doesn't derive to
+	/// the script.
+	ScriptIndexMismatch(sha256::Hash),
+}
+
+/// The result of [Wallet::check_integrity].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+	pub issues: Vec<IntegrityIssue>,
+	pub utxos_checked: usize,
+	pub scripts_checked: usize,
+}
+
+impl IntegrityReport {
+	pub fn is_ok(&self) -> bool {
+		self.issues.is_empty()
+	}
+}
+
+/// A group of UTXOs that are known to belong together to an observer of the
+/// blockchain, because their scripts were spent together or are change of
+/// such spends.
+#[derive(Debug, Clone)]
+pub struct CoinCluster {
+	pub utxos: Vec<OutPoint>,
+	/// The total value of the UTXOs.
+	pub value: Amount,
+}
+
+/// A pending tx that was dropped because another tx spends the same output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConflictedTx {
+	pub tx: Transaction,
+	/// The tx that spends the same output.
+	pub conflicting_txid: sha256d::Hash,
+	/// Whether the conflicting tx was confirmed or only seen in the mempool.
+	pub confirmed: bool,
+}
+
+/// The status of a pending tx.
+#[derive(Debug, Clone)]
+pub struct PendingStatus {
+	/// Our UTXOs spent by the tx.
+	pub inputs_held: Vec<OutPoint>,
+	/// The number of blocks since the tx was committed.
+	pub age: u32,
+	/// Only known when all inputs are ours.
+	pub fee: Option<Amount>,
+	/// The estimated feerate in satoshis per virtual byte, if the fee is known.
+	pub fee_rate: Option<f64>,
+	/// Whether the tx signals replaceability as in BIP-125.
+	pub replaceable: bool,
+	/// Other pending txs spending any of the same outputs.
+	pub conflicts: Vec<sha256d::Hash>,
+	/// All recorded broadcasts of the tx, oldest first.
+	pub broadcasts: Vec<BroadcastRecord>,
+}
+
+/// A broadcast of a pending tx to a backend.
+/// See [Wallet::record_broadcast].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BroadcastRecord {
+	/// The name of the backend the tx was sent to.
+	pub backend: String,
+	/// The UNIX time of the broadcast.
+	pub time: u64,
+	/// Whether the backend accepted the tx.
+	pub acknowledged: bool,
+	/// The error or other message returned by the backend.
+	pub message: Option<String>,
+}
+
+/// The status of an [ExpectedPayment].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpectedPaymentStatus {
+	Pending,
+	/// The full amount was received, the last part in the given tx.
+	Fulfilled {
+		txid: sha256d::Hash,
+		height: u32,
+	},
+	/// The payment wasn't received in time.
+	Expired,
+}
+
+/// A payment the wallet expects to receive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpectedPayment {
+	pub id: usize,
+	pub address: Address,
+	#[serde(with = "amount::as_sat")]
+	pub amount: Amount,
+	/// The payment expires when not fulfilled in a block up to this height.
+	pub expiry_height: Option<u32>,
+	/// The amount received so far. Payments can be made in multiple txs.
+	#[serde(with = "amount::as_sat")]
+	pub received: Amount,
+	pub status: ExpectedPaymentStatus,
+}
+
+/// A trigger for an event when the wallet's tip reaches a certain height.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeightTrigger {
+	pub id: usize,
+	pub height: u32,
+	/// A name for the application to recognize the trigger by.
+	pub name: String,
+}
+
+/// A dedicated branch of receive addresses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Branch {
+	pub index: u32,
+	pub label: Option<String>,
+	last_sourced_child: Option<bip32::ChildNumber>,
+}
+
+/// A payment received on a dedicated branch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BranchPayment {
+	pub outpoint: OutPoint,
+	#[serde(with = "amount::as_sat")]
+	pub value: Amount,
+	pub height: u32,
+	/// The child number of the address in the branch.
+	pub child: bip32::ChildNumber,
+}
+
+/// The changes of a wallet after a height, see [Wallet::export_delta].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletDelta {
+	/// The fingerprint of the xpub of the wallet, to only merge deltas of the
+	/// same wallet.
+	pub xpub_fingerprint: bip32::Fingerprint,
+	/// The generation of the keys of the wallet.
+	pub generation: u32,
+	/// The height after which the changes were made.
+	pub since_height: u32,
+	/// The processed blocks from the height on.
+	pub blocks: Vec<KnownBlock>,
+	/// The last sourced child of every keychain.
+	pub last_sourced: Vec<(Keychain, bip32::ChildNumber)>,
+	/// The UTXOs that confirmed after the height, not used in any tx.
+	pub new_utxos: Vec<Utxo>,
+	/// The outpoints spent by the txs in the history after the height.
+	pub spent_outpoints: Vec<OutPoint>,
+	/// The history after the height.
+	pub history: Vec<TxEntry>,
+}
+
+/// A set of txs created together to make a large number of payouts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayoutRun {
+	pub id: usize,
+	/// The txids of the txs in the run.
+	pub txids: Vec<sha256d::Hash>,
+	/// For every tx in the run, the indices of the payouts it makes.
+	pub payouts: Vec<Vec<usize>>,
+}
+
+/// A set of keys that was replaced by newer keys using [Wallet::rotate_keys].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetiredKeys {
+	pub generation: u32,
+	pub extended_pubkey: bip32::ExtendedPubKey,
+	pub master_fp: bip32::Fingerprint,
+	pub base_derivation_path: bip32::DerivationPath,
+	pub change_derivation_path: Option<bip32::DerivationPath>,
+	/// Funds received on these keys are detected up to and including this height.
+	pub watch_until: u32,
+	/// Set when the grace period ended and the keys are no longer watched.
+	pub expired: bool,
+}
+
+/// An optional feature used by a wallet, recorded in its serialized state.
+///
+/// Versions of the crate that don't know a feature refuse to load a state
+/// that uses it, instead of silently dropping the data they can't interpret.
+/// Once used, a feature stays recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum WalletFeature {
+	/// UTXOs spent with a taproot key spend.
+	Taproot,
+	/// UTXOs spent with a multisig script.
+	Multisig,
+	/// UTXOs with scripts that are not derived from the wallet's keys.
+	ImportedScripts,
+	/// UTXOs received as silent payments, which carry their key tweak.
+	SilentPayments,
+}
+
+/// A batch of blocks that is processed on a copy of the wallet state.
+///
+/// While the batch is in progress, the wallet itself keeps its state from before
+/// the batch, so reads never observe a partially synced state. The wallet should
+/// not be modified until the batch is committed or dropped.
+pub struct BlockBatch {
+	base_version: u64,
+	working: Wallet,
+}
+
+impl BlockBatch {
+	pub fn process_block(&mut self, block: &Block) -> Result<()> {
+		self.working.process_block(block)
+	}
+
+	pub fn process_blocks(&mut self, blocks: &[Block]) -> Result<()> {
+		self.working.process_blocks(blocks)
+	}
+
+	pub fn process_filtered_block(
+		&mut self,
+		merkle_block: &MerkleBlock,
+		txs: &[Transaction],
+	) -> Result<()> {
+		self.working.process_filtered_block(merkle_block, txs)
+	}
+
+	/// The wallet state as it will be after committing the batch.
+	pub fn wallet(&self) -> &Wallet {
+		&self.working
+	}
+
+	/// Apply the result of the batch to the wallet at once.
+	///
+	/// Fails with [Error::WalletModified] if the wallet was modified since
+	/// the batch was started, in which case nothing is changed.
+	pub fn commit(self, wallet: &mut Wallet) -> Result<()> {
+		if wallet.version != self.base_version {
+			return Err(Error::WalletModified);
+		}
+		*wallet = self.working;
+		Ok(())
+	}
 }
 
 /// The wallet.
-#[derive(Serialize, Deserialize)]
+///
+/// The serialization is deterministic: the same state always serializes the
+/// same, entries of maps and sets are ordered by key.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Wallet {
+	/// The optional features used by the wallet.
+	#[serde(default, deserialize_with = "deserialize_features")]
+	features: BTreeSet<WalletFeature>,
+
 	config: WalletConfig,
 
 	// address source
 	extended_pubkey: bip32::ExtendedPubKey,
 	master_fp: bip32::Fingerprint,
+	/// The path of the xpub relative to the master key, when it can't be
+	/// inferred from the xpub itself.
+	#[serde(default)]
+	xpub_origin_path: Option<bip32::DerivationPath>,
 	base_derivation_path: bip32::DerivationPath,
 	last_sourced_child: Option<bip32::ChildNumber>,
+	/// When not set, change is sourced from the external keychain.
+	#[serde(default)]
+	change_derivation_path: Option<bip32::DerivationPath>,
+	#[serde(default)]
+	last_sourced_change_child: Option<bip32::ChildNumber>,
+	#[serde(default)]
+	branches: BTreeMap<u32, Branch>,
+	/// The generation of the current keys, incremented on every key rotation.
+	#[serde(default)]
+	generation: u32,
+	#[serde(default)]
+	retired_keys: Vec<RetiredKeys>,
 
 	// UTXOs
+	#[serde(serialize_with = "serialize_by_outpoint")]
 	owned_utxos: HashMap<OutPoint, Utxo>,
+	/// The outputs of our pending txs paying to our scripts, which are only
+	/// selected with [WalletConfig::spend_unconfirmed]. Their height is 0.
+	#[serde(default, serialize_with = "serialize_by_outpoint")]
+	unconfirmed_change: HashMap<OutPoint, Utxo>,
+
+	// watch-only
+	#[serde(default, serialize_with = "serialize_sorted_set")]
+	watched_scripts: HashSet<Script>,
+	#[serde(default, serialize_with = "serialize_by_outpoint")]
+	watched_utxos: HashMap<OutPoint, WatchedUtxo>,
 
 	// script index
-	//TODO(stevenroose) consider mapping based on script hash
-	script_index: HashMap<Script, bip32::ChildNumber>,
+	/// Our scripts, keyed by their SHA-256 hash to save memory.
+	#[serde(serialize_with = "serialize_sorted_map", deserialize_with = "deserialize_script_index")]
+	script_index: HashMap<sha256::Hash, ScriptInfo>,
+	/// The number of outputs received on each of our scripts.
+	#[serde(default, serialize_with = "serialize_sorted_map")]
+	script_usage: HashMap<Script, u32>,
 
 	// block processing
 	last_known_block: Option<KnownBlock>,
+	/// The hashes of the processed blocks by height, down to the reorg safety
+	/// depth of the config.
+	#[serde(default)]
+	block_hashes: BlockHashes,
+	/// The time the wallet was created, used as a starting point for rescans.
+	#[serde(default)]
+	birthday: Option<u32>,
 
 	// ongoing and mempool
 	pending_txs: Vec<Transaction>,
+	/// The height of the last known block when each pending tx was committed.
+	#[serde(default, serialize_with = "serialize_sorted_map")]
+	pending_heights: HashMap<sha256d::Hash, u32>,
+	/// The UNIX time when each pending tx was committed.
+	#[serde(default, serialize_with = "serialize_sorted_map")]
+	pending_times: HashMap<sha256d::Hash, u64>,
+	/// The recorded broadcasts of pending txs.
+	#[serde(default, serialize_with = "serialize_sorted_map")]
+	broadcasts: HashMap<sha256d::Hash, Vec<BroadcastRecord>>,
+	#[serde(default)]
+	conflicted_txs: Vec<ConflictedTx>,
 
 	// history
-	tx_history: Vec<Transaction>, //TODO(stevenroose) consider hashmap
+	tx_history: TxHistory,
+
+	// payout runs
+	#[serde(default)]
+	payout_runs: Vec<PayoutRun>,
+	/// The id of the next payout run, ids are never reused.
+	#[serde(default)]
+	next_payout_run_id: usize,
+
+	// expected payments
+	#[serde(default)]
+	expected_payments: Vec<ExpectedPayment>,
+
+	// height triggers
+	#[serde(default)]
+	height_triggers: Vec<HeightTrigger>,
+	#[serde(default)]
+	next_height_trigger_id: usize,
+
+	// labels
+	#[serde(default)]
+	labels: Labels,
+	/// Accounting tags set explicitly, per history entry.
+	#[serde(default, serialize_with = "serialize_sorted_map")]
+	accounting_tags: HashMap<sha256d::Hash, AccountingTag>,
+
+	// events that were not taken yet
+	#[serde(default)]
+	events: Vec<WalletEvent>,
+	/// The spendable balance at the last threshold check. Missing in wallets
+	/// stored before it was tracked, it is then set at the first check.
+	#[serde(default)]
+	last_spendable_balance: Option<u64>,
+
+	/// The derived public keys, see [PubkeyCache].
+	#[serde(skip)]
+	pubkey_cache: PubkeyCache,
+
+	/// Incremented on every modification, used to detect modifications during a batch.
+	#[serde(skip)]
+	version: u64,
+	/// When set, randomness in tx creation is derived from this seed.
+	#[serde(skip)]
+	rng_seed: Option<Vec<usize>>,
+	/// When set, used instead of the OS for randomness in tx creation.
+	#[serde(skip)]
+	entropy_source: Option<fn(&mut [u8])>,
+	/// When set, used instead of the system time.
+	#[serde(skip)]
+	clock: Option<fn() -> u64>,
 }
 
 impl Wallet {
@@ -81,373 +1022,5186 @@ impl Wallet {
 		xpub: bip32::ExtendedPubKey,
 		master_fingerprint: bip32::Fingerprint,
 		base_path: bip32::DerivationPath,
+	) -> Wallet {
+		Wallet::new_with_change_path(config, xpub, master_fingerprint, base_path, None)
+	}
+
+	/// Create a new wallet that derives receive addresses from [base_path] and
+	/// change addresses from [change_path], both relative to the xpub.
+	/// Without [change_path], change is derived from [base_path] as well.
+	pub fn new_with_change_path(
+		config: WalletConfig,
+		xpub: bip32::ExtendedPubKey,
+		master_fingerprint: bip32::Fingerprint,
+		base_path: bip32::DerivationPath,
+		change_path: Option<bip32::DerivationPath>,
 	) -> Wallet {
 		let wallet = Wallet {
+			features: BTreeSet::new(),
 			config: config,
 			extended_pubkey: xpub,
 			master_fp: master_fingerprint,
+			xpub_origin_path: None,
 			base_derivation_path: base_path,
 			last_sourced_child: None,
+			change_derivation_path: change_path,
+			last_sourced_change_child: None,
+			branches: BTreeMap::new(),
+			generation: 0,
+			retired_keys: Vec::new(),
 			owned_utxos: HashMap::new(),
+			unconfirmed_change: HashMap::new(),
+			watched_scripts: HashSet::new(),
+			watched_utxos: HashMap::new(),
 			script_index: HashMap::new(),
+			script_usage: HashMap::new(),
 			last_known_block: None,
+			block_hashes: BlockHashes::new(),
+			birthday: None,
 			pending_txs: Vec::new(),
-			tx_history: Vec::new(),
+			pending_heights: HashMap::new(),
+			pending_times: HashMap::new(),
+			broadcasts: HashMap::new(),
+			conflicted_txs: Vec::new(),
+			tx_history: TxHistory::new(),
+			payout_runs: Vec::new(),
+			next_payout_run_id: 0,
+			expected_payments: Vec::new(),
+			height_triggers: Vec::new(),
+			next_height_trigger_id: 0,
+			labels: Labels::new(),
+			accounting_tags: HashMap::new(),
+			events: Vec::new(),
+			last_spendable_balance: Some(0),
+			pubkey_cache: PubkeyCache::new(),
+			version: 0,
+			rng_seed: None,
+			entropy_source: None,
+			clock: None,
 		};
 		wallet
 	}
 
-	fn get_history_tx(&self, txid: sha256d::Hash) -> Option<&Transaction> {
-		self.tx_history.iter().find(|t| t.txid() == txid)
+	/// Create a wallet for use in tests.
+	///
+	/// The master key is derived from [seed], receive addresses use path m/0
+	/// and change addresses m/1. Tx creation uses an RNG seeded with [seed],
+	/// so that the same sequence of calls always creates the same txs.
+	/// Signers can derive the master key from [seed] using BIP-32.
+	///
+	/// Since the wallet is in-memory, no separate persistence is needed:
+	/// the wallet can be serialized and deserialized like any other.
+	/// Note that a deserialized wallet uses the regular RNG again, see [set_rng_seed].
+	pub fn new_for_testing(seed: &[u8], network: Network) -> Wallet {
+		let xpriv = bip32::ExtendedPrivKey::new_master(network, seed).expect("invalid seed");
+		let xpub = bip32::ExtendedPubKey::from_private(&::SECP, &xpriv);
+		let mut wallet = Wallet::new_with_change_path(
+			WalletConfig::new(network),
+			xpub,
+			xpriv.fingerprint(&::SECP),
+			vec![bip32::ChildNumber::from_normal_idx(0).unwrap()].into(),
+			Some(vec![bip32::ChildNumber::from_normal_idx(1).unwrap()].into()),
+		);
+		wallet.set_rng_seed(Some(seed));
+		wallet
 	}
 
-	fn get_address(&self, idx: bip32::ChildNumber, address_type: AddressType) -> Address {
-		let path = self.base_derivation_path.child(idx);
-		let xpub = self.extended_pubkey.derive_pub(&::SECP, &path).expect("derivation failure");
-		match address_type {
-			AddressType::P2wpkh => Address::p2wpkh(&xpub.public_key, self.config.network),
-		}
+	/// Derive all randomness in tx creation from [seed], or use the regular
+	/// RNG again when [None]. Coin selection, the order of inputs and outputs
+	/// and the change amounts then only depend on the seed and the state of the
+	/// wallet, so that the same calls on the same wallet create the same PSBTs.
+	///
+	/// The seed is not serialized. Don't use the same seed for txs that are
+	/// broadcast, as the randomness protects the wallet's privacy.
+	pub fn set_rng_seed(&mut self, seed: Option<&[u8]>) {
+		self.rng_seed = seed.map(rng_seed_from_bytes);
 	}
 
-	fn index_script_pubkeys(&mut self, child: bip32::ChildNumber) {
-		for address_type in AddressType::all_types() {
-			let address = self.get_address(child, *address_type);
-			self.script_index.insert(address.script_pubkey(), child);
+	/// The RNG used for tx creation.
+	///
+	/// Fails with [Error::NoEntropySource] when there is no OS randomness,
+	/// like on wasm, and no entropy source or seed is set.
+	fn rng(&self) -> Result<StdRng> {
+		match self.rng_seed {
+			Some(ref seed) => {
+				// Mix in the version so that subsequent calls don't repeat.
+				let mut seed = seed.clone();
+				seed.push(self.version as usize);
+				Ok(StdRng::from_seed(&seed[..]))
+			}
+			None => match self.entropy_source {
+				Some(source) => {
+					let mut bytes = [0u8; 32];
+					source(&mut bytes);
+					Ok(StdRng::from_seed(&rng_seed_from_bytes(&bytes)[..]))
+				}
+				None => os_rng(),
+			},
 		}
 	}
 
-	/// Increases the wallet's latest address child number and returns it.
-	fn next_address_child(&mut self) -> bip32::ChildNumber {
-		self.last_sourced_child = Some(match self.last_sourced_child {
-			None => bip32::ChildNumber::from_normal_idx(0).unwrap(),
-			Some(cn) => cn.increment().expect("BIP32 child number overflow"),
-		});
-		self.last_sourced_child.unwrap()
+	/// Use [source] to fill buffers with random bytes instead of the OS.
+	/// This is needed on platforms without OS randomness like wasm, where
+	/// the source can for example call the browser's `crypto.getRandomValues`.
+	///
+	/// Like the RNG seed of [new_for_testing], the source is not serialized.
+	pub fn set_entropy_source(&mut self, source: fn(&mut [u8])) {
+		self.entropy_source = Some(source);
 	}
 
-	/// Undo the last [next_address_child].
-	fn rollback_address_child(&mut self) {
-		self.last_sourced_child = Some(match self.last_sourced_child {
-			None => bip32::ChildNumber::from_normal_idx(0).unwrap(),
-			// manually decrement
-			Some(bip32::ChildNumber::Normal {
-				index: idx,
-			}) => bip32::ChildNumber::from_normal_idx(idx.checked_sub(1).unwrap_or(0)).unwrap(),
-			Some(bip32::ChildNumber::Hardened {
-				index: idx,
-			}) => bip32::ChildNumber::from_hardened_idx(idx.checked_sub(1).unwrap_or(0)).unwrap(),
-		});
+	/// Use [clock] for the current UNIX time in seconds instead of the system
+	/// time, which is not available on wasm. The clock is not serialized.
+	pub fn set_clock(&mut self, clock: fn() -> u64) {
+		self.clock = Some(clock);
 	}
 
-	pub fn new_receive_address(&mut self) -> Address {
-		let idx = self.next_address_child();
-		self.index_script_pubkeys(idx);
-		self.get_address(idx, AddressType::P2wpkh)
+	/// The current UNIX time in seconds, [None] when there is no system time,
+	/// like on wasm, and no clock is set.
+	fn now(&self) -> Option<u64> {
+		match self.clock {
+			Some(clock) => Some(clock()),
+			None => unix_time(),
+		}
 	}
 
-	/// Check if the tx is relevant for the wallet.
-	pub fn is_relevant_tx(&self, tx: &Transaction) -> bool {
-		tx.input.iter().any(|i| self.owned_utxos.contains_key(&i.previous_output))
-			|| tx.output.iter().any(|o| self.script_index.contains_key(&o.script_pubkey))
+	pub fn config(&self) -> &WalletConfig {
+		&self.config
 	}
 
-	fn process_transaction(&mut self, tx: &Transaction, block_height: u32) {
-		let mut relevant = false;
-		// Find if spending any of our own UTXOs.
-		for input in &tx.input {
-			if self.owned_utxos.remove(&input.previous_output).is_some() {
-				relevant = true;
-			}
+	/// The xpub the wallet's current keys are derived from.
+	pub fn extended_pubkey(&self) -> &bip32::ExtendedPubKey {
+		&self.extended_pubkey
+	}
+
+	/// Replace the config of the wallet. The new policies apply to all
+	/// following operations. The chain of the wallet can't be changed, so
+	/// neither the network nor [WalletConfig::signet].
+	pub fn set_config(&mut self, config: WalletConfig) -> Result<()> {
+		if config.network != self.config.network || config.signet != self.config.signet {
+			return Err(Error::InvalidConfig("the chain can't be changed"));
 		}
+		config.validate()?;
 
-		// Find if sending to any of our own outputs.
-		for (idx, output) in tx.output.iter().enumerate() {
-			if let Some(child) = self.script_index.get(&output.script_pubkey) {
-				let outpoint = OutPoint {
-					txid: tx.txid(),
-					vout: idx as u32,
-				};
+		self.version += 1;
+		self.config = config;
+		self.index_lookaheads();
+		self.events.push(WalletEvent::ConfigChanged);
+		Ok(())
+	}
 
-				self.owned_utxos.insert(
-					outpoint,
-					Utxo {
-						outpoint: outpoint,
-						value: output.value,
-						height: block_height,
-						child_number: *child,
-						used_in_tx: HashSet::new(),
-					},
-				);
-				relevant = true;
-			}
-		}
+	fn get_history_entry(&self, txid: sha256d::Hash) -> Option<&TxEntry> {
+		self.tx_history.get(&txid)
+	}
 
-		if relevant {
-			self.tx_history.push(tx.clone());
-		}
+	/// Find the output referenced by the outpoint in our history.
+	fn get_history_txout(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+		self.get_history_entry(outpoint.txid).and_then(|e| e.tx.output.get(outpoint.vout as usize))
 	}
 
-	/// Use this only when you know what you are doing. This might make the wallet lose track of
-	/// some of its own UTXOs.
-	pub fn set_last_block(&mut self, block_hash: sha256d::Hash, height: u32) {
-		self.last_known_block = Some(KnownBlock {
-			hash: block_hash,
-			height: height,
-		});
+	/// Calculate the fee of the tx, only possible if all the outputs it spends are known.
+	fn calculate_fee(&self, tx: &Transaction) -> Option<u64> {
+		if tx.is_coin_base() {
+			return None;
+		}
+
+		let mut total_in = 0;
+		for input in &tx.input {
+			total_in += self.get_history_txout(&input.previous_output)?.value;
+		}
+		let total_out: u64 = tx.output.iter().map(|o| o.value).sum();
+		total_in.checked_sub(total_out)
 	}
 
-	pub fn process_block(&mut self, block: &Block) -> Result<()> {
-		if self.last_known_block.is_none() {
-			return Err(Error::WalletNotFullyInitialized);
+	/// The keychain change is sourced from.
+	fn change_keychain(&self) -> Keychain {
+		match self.change_derivation_path {
+			Some(_) => Keychain::Internal,
+			None => Keychain::External,
 		}
+	}
 
-		// Ensure the block follows on the last known block.
-		if block.header.prev_blockhash != self.last_known_block.as_ref().unwrap().hash {
-			//TODO(stevenroose) implement reorg logic
-			return Err(Error::BlockFork);
+	/// The derivation path of the keychain, relative to the xpub.
+	fn keychain_path(&self, keychain: Keychain) -> bip32::DerivationPath {
+		keychain_path(&self.base_derivation_path, self.change_derivation_path.as_ref(), keychain)
+	}
+
+	/// The derivation path of the child key in the keychain, relative to the xpub.
+	fn derivation_path(
+		&self,
+		keychain: Keychain,
+		idx: bip32::ChildNumber,
+	) -> bip32::DerivationPath {
+		self.keychain_path(keychain).child(idx)
+	}
+
+	/// Derive the key of any generation, returning the public key and its
+	/// master fingerprint and derivation path. Keys are cached.
+	fn derive_key(
+		&self,
+		generation: u32,
+		keychain: Keychain,
+		idx: bip32::ChildNumber,
+	) -> Result<(PublicKey, bip32::Fingerprint, bip32::DerivationPath)> {
+		let (xpub, master_fp, path) = if generation == self.generation {
+			(&self.extended_pubkey, self.master_fp, self.keychain_path(keychain))
+		} else {
+			let keys = self
+				.retired_keys
+				.iter()
+				.find(|k| k.generation == generation)
+				.expect("keys of unknown generation");
+			let path = keychain_path(
+				&keys.base_derivation_path,
+				keys.change_derivation_path.as_ref(),
+				keychain,
+			);
+			(&keys.extended_pubkey, keys.master_fp, path)
+		};
+		let pubkey = self.pubkey_cache.derive(xpub, generation, keychain, &path, idx)?;
+		Ok((pubkey, master_fp, path.child(idx)))
+	}
+
+	fn derive_pubkey(&self, keychain: Keychain, idx: bip32::ChildNumber) -> PublicKey {
+		self.derive_key(self.generation, keychain, idx).expect("derivation failure").0
+	}
+
+	fn get_address(
+		&self,
+		keychain: Keychain,
+		idx: bip32::ChildNumber,
+		address_type: AddressType,
+	) -> Address {
+		address_type.address(&self.derive_pubkey(keychain, idx), self.config.network)
+	}
+
+	/// Determine the address type to use for change in a tx with the given payments.
+	fn change_address_type(&self, policy: ChangeTypePolicy, payments: &[TxOut]) -> AddressType {
+		match policy {
+			ChangeTypePolicy::WalletDefault => AddressType::default(),
+			ChangeTypePolicy::MatchPayments => {
+				let mut types = payments.iter().map(|o| self.address_type_of(&o.script_pubkey));
+				match types.next() {
+					Some(Some(first)) if types.all(|t| t == Some(first)) => first,
+					_ => AddressType::default(),
+				}
+			}
 		}
-		let new_height = self.last_known_block.as_ref().unwrap().height + 1;
+	}
 
-		for tx in &block.txdata {
-			self.process_transaction(&tx, new_height)
+	fn index_script_pubkeys(&mut self, keychain: Keychain, child: bip32::ChildNumber) {
+		for address_type in AddressType::all_types() {
+			let address = self.get_address(keychain, child, *address_type);
+			self.script_index.insert(
+				script_hash(&address.script_pubkey()),
+				ScriptInfo {
+					keychain: keychain,
+					child: child,
+					address_type: *address_type,
+					generation: self.generation,
+				},
+			);
 		}
+	}
 
-		self.last_known_block = Some(KnownBlock {
-			height: new_height,
-			hash: block.bitcoin_hash(),
+	fn last_sourced_child_mut(&mut self, keychain: Keychain) -> &mut Option<bip32::ChildNumber> {
+		match (keychain, self.change_derivation_path.is_some()) {
+			(Keychain::Internal, true) => &mut self.last_sourced_change_child,
+			(Keychain::Branch(b), _) => {
+				&mut self.branches.get_mut(&b).expect("unknown branch").last_sourced_child
+			}
+			_ => &mut self.last_sourced_child,
+		}
+	}
+
+	/// Increases the keychain's latest address child number and returns it.
+	fn next_address_child(&mut self, keychain: Keychain) -> bip32::ChildNumber {
+		self.version += 1;
+		let last = self.last_sourced_child_mut(keychain);
+		let is_branch = match keychain {
+			Keychain::Branch(_) => true,
+			_ => false,
+		};
+		*last = Some(match *last {
+			None => bip32::ChildNumber::from_normal_idx(0).unwrap(),
+			Some(cn) => cn
+				.increment()
+				.ok()
+				// The last child of the base path is the root of the branches.
+				.filter(|c| is_branch || *c != branches_root_child())
+				.expect("BIP32 child number overflow"),
 		});
+		last.unwrap()
+	}
+
+	/// Source the next child of the keychain of which none of the scripts
+	/// ever received funds, so that we never reuse addresses.
+	fn next_unused_child(&mut self, keychain: Keychain) -> bip32::ChildNumber {
+		loop {
+			let child = self.next_address_child(keychain);
+			if !self.is_child_used(keychain, child) {
+				return child;
+			}
+		}
+	}
+
+	/// The number of addresses sourced from the keychain.
+	fn nb_sourced(&self, keychain: Keychain) -> u32 {
+		let last = match (keychain, self.change_derivation_path.is_some()) {
+			(Keychain::Internal, true) => self.last_sourced_change_child,
+			(Keychain::Branch(b), _) => self.branches.get(&b).and_then(|b| b.last_sourced_child),
+			_ => self.last_sourced_child,
+		};
+		last.map(|c| u32::from(c) + 1).unwrap_or(0)
+	}
+
+	/// Check the address caps after sourcing receive addresses from the
+	/// keychain, given the number of addresses sourced before. Emits an event
+	/// when nearing a cap. Change is never checked, so that spending keeps
+	/// working once a cap is reached.
+	fn check_address_caps(&mut self, keychain: Keychain, sourced_before: u32) -> Result<()> {
+		let caps = self.config.address_caps;
+		let keychain_cap = match keychain {
+			Keychain::External => caps.max_external,
+			Keychain::Internal | Keychain::Branch(_) => None,
+		};
+		let sourced = self.nb_sourced(keychain);
+		let branches: u32 =
+			self.branches.keys().map(|b| self.nb_sourced(Keychain::Branch(*b))).sum();
+		let total = self.nb_sourced(Keychain::External) + branches;
+		let total_before = total - (sourced - sourced_before);
 
+		let checks = [
+			(Some(keychain), keychain_cap, sourced, sourced_before),
+			(None, caps.max_total, total, total_before),
+		];
+		for &(event_keychain, cap, count, before) in checks.iter() {
+			let cap = match cap {
+				Some(cap) => cap,
+				None => continue,
+			};
+			if count > cap {
+				return Err(Error::AddressCapReached);
+			}
+			let remaining = cap - count;
+			let remaining_before = cap.saturating_sub(before);
+			if remaining <= caps.warn_remaining && remaining_before > caps.warn_remaining {
+				self.events.push(WalletEvent::AddressCapNearing {
+					keychain: event_keychain,
+					issued: count,
+					cap: cap,
+				});
+			}
+		}
 		Ok(())
 	}
 
-	pub fn get_balance(&self, minimum_confirmations: Option<u32>) -> u64 {
-		let current_height = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
-		let max_height = match minimum_confirmations {
-			None => current_height,
-			Some(minconf) => current_height.checked_sub(minconf).unwrap_or(0) + 1,
+	/// Reset the keychain's latest address child number.
+	fn set_last_sourced_child(&mut self, keychain: Keychain, child: Option<bip32::ChildNumber>) {
+		self.version += 1;
+		*self.last_sourced_child_mut(keychain) = child;
+		self.index_lookahead(keychain);
+	}
+
+	/// Index the scripts of the [WalletConfig::gap_limit] children after the
+	/// last sourced child of the keychain.
+	fn index_lookahead(&mut self, keychain: Keychain) {
+		// Without a change keychain, change comes from the receive keychain.
+		let keychain = match keychain {
+			Keychain::Internal if self.change_derivation_path.is_none() => Keychain::External,
+			keychain => keychain,
 		};
-		let confirmed =
-			self.owned_utxos.values().filter(|u| u.height <= max_height).map(|u| u.value).sum();
-		//TODO(stevenroose) unconfirmed
-		confirmed
+		let first = self.nb_sourced(keychain);
+		for idx in first..first.saturating_add(self.config.gap_limit) {
+			match bip32::ChildNumber::from_normal_idx(idx) {
+				Ok(child) => self.index_script_pubkeys(keychain, child),
+				Err(_) => break,
+			}
+		}
 	}
 
-	/// Returns an iterator over the [Utxo]s owned by the wallet.
-	pub fn get_utxos(&self) -> collections::hash_map::Values<OutPoint, Utxo> {
-		self.owned_utxos.values()
+	/// Index the lookahead of all keychains.
+	fn index_lookaheads(&mut self) {
+		self.index_lookahead(Keychain::External);
+		if self.change_derivation_path.is_some() {
+			self.index_lookahead(Keychain::Internal);
+		}
+		let branches: Vec<u32> = self.branches.keys().cloned().collect();
+		for index in branches {
+			self.index_lookahead(Keychain::Branch(index));
+		}
 	}
 
-	/// Commit to the tx by considering the UTXOs it spends as used in the tx.
-	/// The tx will also be kept as pending.
-	/// No check is done to prevent adding the same tx twice.
-	pub fn commit_transaction(&mut self, tx: Transaction) {
-		let txid = tx.txid();
-		for input in &tx.input {
-			if let Some(utxo) = self.owned_utxos.get_mut(&input.previous_output) {
-				utxo.used_in_tx.insert(txid);
+	/// Hand out the children of the keychain that were sourced after [last]
+	/// again, f.e. the change of txs that were dropped right after they were
+	/// created. Children that received funds stay sourced.
+	fn release_children(&mut self, keychain: Keychain, last: Option<bip32::ChildNumber>) {
+		let first = last.map(|c| u32::from(c) + 1).unwrap_or(0);
+		let mut new_last = last;
+		for idx in first..self.nb_sourced(keychain) {
+			let child = bip32::ChildNumber::from_normal_idx(idx).expect("sourced");
+			if self.is_child_used(keychain, child) {
+				new_last = Some(child);
 			}
 		}
-		self.pending_txs.push(tx);
+		for idx in new_last.map(|c| u32::from(c) + 1).unwrap_or(0)..self.nb_sourced(keychain) {
+			let child = bip32::ChildNumber::from_normal_idx(idx).expect("sourced");
+			for address_type in AddressType::all_types() {
+				let script = self.get_address(keychain, child, *address_type).script_pubkey();
+				self.script_index.remove(&script_hash(&script));
+			}
+		}
+		self.set_last_sourced_child(keychain, new_last);
 	}
 
-	/// Drop a transaction that is considered pending by the wallet.
-	/// This also frees the UTXOs the transaction was spending to being used
-	/// again in new txs.
-	pub fn drop_pending_transaction(&mut self, txid: sha256d::Hash) -> bool {
-		for (_, utxo) in self.owned_utxos.iter_mut() {
-			utxo.used_in_tx.remove(&txid);
+	/// Hand out a new receive address.
+	///
+	/// Fails when the address caps in the config don't allow any more addresses.
+	pub fn new_receive_address(&mut self) -> Result<Address> {
+		let last_before = self.last_sourced_child;
+		let sourced_before = self.nb_sourced(Keychain::External);
+		let idx = self.next_unused_child(Keychain::External);
+		if let Err(e) = self.check_address_caps(Keychain::External, sourced_before) {
+			self.set_last_sourced_child(Keychain::External, last_before);
+			return Err(e);
 		}
+		self.index_script_pubkeys(Keychain::External, idx);
+		self.index_lookahead(Keychain::External);
+		Ok(self.get_address(Keychain::External, idx, AddressType::P2wpkh))
+	}
 
-		let len_before = self.pending_txs.len();
-		self.pending_txs.retain(|tx| tx.txid() != txid);
-		self.pending_txs.len() < len_before
+	/// Create a dedicated branch of receive addresses with the given index,
+	/// derived at `<base path>/2147483647/<index>/*`. The last non-hardened
+	/// child of the base path is reserved for branches, so they never share
+	/// keys with the receive addresses. Payments received on the branch can be
+	/// listed using [branch_payments]. Creating an existing branch only updates
+	/// its label.
+	pub fn create_branch(&mut self, index: u32, label: Option<String>) -> Result<()> {
+		bip32::ChildNumber::from_normal_idx(index)?;
+		self.version += 1;
+		self.branches
+			.entry(index)
+			.or_insert_with(|| Branch {
+				index: index,
+				label: None,
+				last_sourced_child: None,
+			})
+			.label = label;
+		Ok(())
 	}
 
-	/// - Returns the change index if there was change.
-	/// - This method does not commit the tx inputs.
-	fn create_transaction_with_change(
-		&mut self,
-		mut outputs: Vec<TxOut>,
-		use_inputs: Vec<OutPoint>,
-		change_child: bip32::ChildNumber,
-		fee: u64,
-	) -> Result<(psbt::PartiallySignedTransaction, Option<usize>)> {
-		let mut rng = rand::thread_rng();
+	pub fn get_branch(&self, index: u32) -> Option<&Branch> {
+		self.branches.get(&index)
+	}
 
-		// Check all given inputs.
-		let mut total_in = 0;
-		let mut in_utxos = HashMap::new();
-		for outpoint in &use_inputs {
-			if let Some(utxo) = self.owned_utxos.get(outpoint) {
-				if in_utxos.insert(outpoint, utxo).is_some() {
-					return Err(Error::DuplicateUtxo);
-				}
-				total_in += utxo.value;
-			} else {
-				return Err(Error::UtxoNotInWallet);
-			}
+	pub fn list_branches(&self) -> Vec<&Branch> {
+		self.branches.values().collect()
+	}
+
+	/// Hand out a new address of the branch.
+	pub fn new_branch_address(&mut self, index: u32) -> Result<Address> {
+		if !self.branches.contains_key(&index) {
+			return Err(Error::UnknownBranch);
+		}
+		let keychain = Keychain::Branch(index);
+		let last_before = self.branches[&index].last_sourced_child;
+		let sourced_before = self.nb_sourced(keychain);
+		let idx = self.next_unused_child(keychain);
+		if let Err(e) = self.check_address_caps(keychain, sourced_before) {
+			self.set_last_sourced_child(keychain, last_before);
+			return Err(e);
 		}
+		self.index_script_pubkeys(keychain, idx);
+		self.index_lookahead(keychain);
+		Ok(self.get_address(keychain, idx, AddressType::P2wpkh))
+	}
 
-		// Count the total output value.
-		let mut total_out = 0;
-		for output in &outputs {
-			total_out += output.value;
+	/// The branch the script belongs to, if any.
+	pub fn branch_of_script(&self, script: &Script) -> Option<u32> {
+		match self.script_index.get(&script_hash(script)).map(|i| i.keychain) {
+			Some(Keychain::Branch(b)) => Some(b),
+			_ => None,
 		}
+	}
 
-		// Add random extra inputs from our own UTXOs until sufficient.
-		if total_out + fee > total_in {
-			// To do this more efficiently, we keep a vector of the
-			// remaining UTXOs in the wallet.
-			let mut remaining_utxos = Vec::with_capacity(self.owned_utxos.len() - in_utxos.len());
-			for (outpoint, utxo) in self.owned_utxos.iter() {
-				if !in_utxos.contains_key(outpoint) && utxo.is_available() {
-					remaining_utxos.push(outpoint);
-				}
+	/// All payments received on addresses of the branch, in the order they
+	/// were confirmed.
+	pub fn branch_payments(&self, index: u32) -> Vec<BranchPayment> {
+		let mut payments = Vec::new();
+		for entry in self.tx_history.iter() {
+			for (vout, output) in entry.tx.output.iter().enumerate() {
+				let info = match self.script_index.get(&script_hash(&output.script_pubkey)) {
+					Some(info) if info.keychain == Keychain::Branch(index) => info,
+					_ => continue,
+				};
+				payments.push(BranchPayment {
+					outpoint: OutPoint {
+						txid: entry.txid(),
+						vout: vout as u32,
+					},
+					value: Amount::from_sat(output.value),
+					height: entry.height,
+					child: info.child,
+				});
 			}
+		}
+		payments
+	}
 
-			while total_out + fee > total_in {
-				if remaining_utxos.is_empty() {
-					return Err(Error::InsufficientFunds);
-				}
+	/// Derive the receive address [offset] positions after the last one handed out,
+	/// without handing it out. So offset 0 gives the next receive address.
+	///
+	/// Note that funds sent to a peeked address are only detected once the
+	/// address has been handed out using [new_receive_address].
+	pub fn peek_address(&self, offset: u32) -> Address {
+		let next = self.last_sourced_child.map(|c| u32::from(c) + 1).unwrap_or(0);
+		let child = bip32::ChildNumber::from_normal_idx(next + offset).expect("index overflow");
+		self.get_address(Keychain::External, child, AddressType::P2wpkh)
+	}
 
-				let rand_idx = rng.gen_range(0, remaining_utxos.len());
-				let outpoint = remaining_utxos.remove(rand_idx);
-				let utxo = self.owned_utxos.get(outpoint).expect("added ourself above");
-				total_in += utxo.value;
-				in_utxos.insert(&utxo.outpoint, &utxo);
-			}
+	/// Check that the address is the receive address at the index of the
+	/// receive descriptor, for any of the supported address types.
+	/// The address is derived again from the xpub, the script index is not used.
+	pub fn verify_address(&self, address: &Address, index: u32) -> bool {
+		if address.network != self.config.network {
+			return false;
 		}
-
-		// Add change.
-		let change_amount = total_in - total_out - fee;
-		let change_idx = if change_amount > 0 {
-			let change_addr = self.get_address(change_child, AddressType::P2wpkh);
-			let change_idx = rng.gen_range(0, outputs.len());
-			outputs.insert(
-				change_idx,
-				TxOut {
-					value: change_amount,
-					script_pubkey: change_addr.script_pubkey(),
-				},
-			);
-			Some(change_idx)
-		} else {
-			None
+		let child = match bip32::ChildNumber::from_normal_idx(index) {
+			Ok(child) => child,
+			Err(_) => return false,
 		};
+		let script_pubkey = address.script_pubkey();
+		AddressType::all_types().iter().any(|t| {
+			self.get_address(Keychain::External, child, *t).script_pubkey() == script_pubkey
+		})
+	}
 
-		// Shuffle inputs and prepare PSBT data.
-		let mut prevouts: Vec<&OutPoint> = in_utxos.keys().map(|o| *o).collect();
-		rng.shuffle(&mut prevouts);
-		let mut inputs = vec![];
-		let mut psbt_inputs = vec![];
-		for prevout in &prevouts {
-			let utxo = in_utxos.get(prevout).unwrap();
-			inputs.push(TxIn {
-				previous_output: *prevout.clone(),
-				script_sig: Script::new(),
-				sequence: 0xFFFFFFFF,
-				witness: vec![],
-			});
-			psbt_inputs.push(psbt::Input {
-				witness_utxo: {
-					//TODO(stevenroose) don't assume segwit
-					let prev = self.get_history_tx(prevout.txid).expect("missing history");
-					assert!(prevout.vout < prev.output.len() as u32);
-					Some(prev.output[prevout.vout as usize].clone())
-				},
-				hd_keypaths: {
-					let path = self.base_derivation_path.child(utxo.child_number);
-					let pubkey = self.extended_pubkey.derive_pub(&::SECP, &path)?.public_key;
-					let mut ret = HashMap::new();
-					ret.insert(pubkey, (self.master_fp, path));
-					ret
-				},
-				..Default::default()
-			});
-		}
+	/// Check a range of consecutive receive addresses, starting at [first_index],
+	/// like [verify_address]. Returns the indices of the addresses that don't match.
+	pub fn verify_address_range(&self, first_index: u32, addresses: &[Address]) -> Vec<u32> {
+		addresses
+			.iter()
+			.zip(first_index..)
+			.filter(|(addr, idx)| !self.verify_address(addr, *idx))
+			.map(|(_, idx)| idx)
+			.collect()
+	}
 
-		// PSBT output for change.
-		let mut psbt_outputs: Vec<psbt::Output> = vec![Default::default(); outputs.len()];
-		if let Some(idx) = change_idx {
-			let path = self.base_derivation_path.child(change_child);
-			let pubkey = self.extended_pubkey.derive_pub(&::SECP, &path)?.public_key;
-			psbt_outputs[idx].hd_keypaths.insert(pubkey, (self.master_fp, path));
+	/// Whether any of the scripts of the child key ever received funds.
+	fn is_child_used(&self, keychain: Keychain, child: bip32::ChildNumber) -> bool {
+		AddressType::all_types().iter().any(|t| {
+			let script = self.get_address(keychain, child, *t).script_pubkey();
+			self.script_usage.contains_key(&script)
+		})
+	}
+
+	/// The number of outputs the script received.
+	pub fn script_usage_count(&self, script: &Script) -> u32 {
+		self.script_usage.get(script).cloned().unwrap_or(0)
+	}
+
+	/// The number of outputs the address received.
+	pub fn address_usage_count(&self, address: &Address) -> u32 {
+		self.script_usage_count(&address.script_pubkey())
+	}
+
+	/// List all our addresses that received funds more than once.
+	pub fn list_reused_addresses(&self) -> Vec<Address> {
+		self.script_usage
+			.iter()
+			.filter(|(_, n)| **n > 1)
+			.filter_map(|(s, _)| Address::from_script(s, self.config.network))
+			.collect()
+	}
+
+	/// List all receive addresses that were handed out, but never received funds.
+	pub fn list_unused_addresses(&self) -> Vec<Address> {
+		let nb_sourced = self.last_sourced_child.map(|c| u32::from(c) + 1).unwrap_or(0);
+		(0..nb_sourced)
+			.map(|i| bip32::ChildNumber::from_normal_idx(i).unwrap())
+			.filter(|c| !self.is_child_used(Keychain::External, *c))
+			.map(|c| self.get_address(Keychain::External, c, AddressType::P2wpkh))
+			.collect()
+	}
+
+	/// Find the keychain and derivation path of the key needed to spend the UTXO.
+	/// The path is relative to the xpub of the key's generation.
+	pub fn derivation_of_utxo(
+		&self,
+		outpoint: &OutPoint,
+	) -> Option<(Keychain, bip32::DerivationPath)> {
+		let utxo = self.owned_utxos.get(outpoint)?;
+		let (_, _, path) = self.derive_key(utxo.generation, utxo.keychain, utxo.child_number).ok()?;
+		Some((utxo.keychain, path))
+	}
+
+	/// Find the keychain and derivation path of the key behind the script.
+	/// The path is relative to the xpub of the key's generation.
+	pub fn derivation_of_script(
+		&self,
+		script: &Script,
+	) -> Option<(Keychain, bip32::DerivationPath)> {
+		let info = self.script_index.get(&script_hash(script))?;
+		let (_, _, path) = self.derive_key(info.generation, info.keychain, info.child).ok()?;
+		Some((info.keychain, path))
+	}
+
+	/// Set the path of the xpub relative to the master key. This is only needed
+	/// for the origin info in descriptors when the xpub is not the master key
+	/// itself or a direct child of it.
+	pub fn set_xpub_origin_path(&mut self, path: bip32::DerivationPath) {
+		self.version += 1;
+		self.xpub_origin_path = Some(path);
+	}
+
+	/// The path of the xpub relative to the master key, if known.
+	fn xpub_origin(&self) -> Option<bip32::DerivationPath> {
+		if let Some(ref path) = self.xpub_origin_path {
+			return Some(path.clone());
+		}
+		match self.extended_pubkey.depth {
+			0 => Some(Vec::new().into()),
+			1 => Some(vec![self.extended_pubkey.child_number].into()),
+			_ => None,
 		}
+	}
 
-		// Create the unsigned tx.
-		let tx = Transaction {
-			version: 1,
-			lock_time: 0,
-			input: inputs,
-			output: outputs,
+	/// The descriptor of the scripts of the given type in the keychain.
+	/// Origin info is only included when the path of the xpub is known.
+	fn descriptor(&self, keychain: Keychain, address_type: AddressType) -> String {
+		let path = self.keychain_path(keychain);
+		// Paths are displayed with a leading "m".
+		let origin = match self.xpub_origin() {
+			Some(origin) => {
+				use bitcoin_hashes::hex::ToHex;
+				let origin = origin.to_string();
+				format!("[{}{}]", self.master_fp[..].to_hex(), origin.trim_start_matches('m'))
+			}
+			None => String::new(),
 		};
+		let key = format!(
+			"{}{}{}/*",
+			origin,
+			self.extended_pubkey,
+			path.to_string().trim_start_matches('m'),
+		);
+		let desc = match address_type {
+			AddressType::P2wpkh => format!("wpkh({})", key),
+			AddressType::P2shP2wpkh => format!("sh(wpkh({}))", key),
+			AddressType::P2pkh => format!("pkh({})", key),
+		};
+		descriptor::with_checksum(&desc).expect("invalid descriptor")
+	}
 
-		Ok((
-			psbt::PartiallySignedTransaction {
-				global: psbt::Global::from_unsigned_tx(tx).expect("only when non-empty sigs"),
-				inputs: psbt_inputs,
-				outputs: psbt_outputs,
-			},
-			change_idx,
-		))
+	/// The descriptor of the receive addresses, with checksum.
+	pub fn receive_descriptor(&self) -> String {
+		self.descriptor(Keychain::External, AddressType::default())
+	}
+
+	/// The descriptor of the change addresses of the wallet's default type,
+	/// with checksum. This is the receive descriptor if the wallet has no
+	/// separate change keychain.
+	pub fn change_descriptor(&self) -> String {
+		self.descriptor(self.change_keychain(), AddressType::default())
+	}
+
+	/// Set the time the wallet was created, as a UNIX timestamp.
+	pub fn set_birthday(&mut self, timestamp: u32) {
+		self.version += 1;
+		self.birthday = Some(timestamp);
 	}
 
+	/// The time the wallet was created. If not set explicitly, the time of the
+	/// first block with a tx of the wallet is used.
+	pub fn birthday(&self) -> Option<u32> {
+		self.birthday.or_else(|| self.tx_history.iter().map(|e| e.timestamp).min())
+	}
+
+	/// Export the wallet's current keys as JSON that can be passed to the
+	/// importdescriptors and importmulti RPCs of Bitcoin Core.
 	///
-	/// Possible errors:
-	/// - [Error::Bip32]
-	/// - [Error::DuplicateUtxo]
-	/// - [Error::InsufficientFunds]
-	/// - [Error::UtxoNotInWallet]
-	pub fn create_transaction(
+	/// The range of every descriptor covers all sourced addresses and
+	/// [descriptor::CORE_IMPORT_LOOKAHEAD] more. Core rescans from the wallet's
+	/// birthday. Keys of earlier generations are not exported.
+	pub fn export_core_import(&self) -> String {
+		let timestamp = match self.birthday() {
+			Some(t) => CoreTimestamp::Time(t),
+			None => CoreTimestamp::Now,
+		};
+		let mut keychains = vec![(Keychain::External, self.last_sourced_child)];
+		if self.change_derivation_path.is_some() {
+			keychains.push((Keychain::Internal, self.last_sourced_change_child));
+		}
+		for branch in self.branches.values() {
+			keychains.push((Keychain::Branch(branch.index), branch.last_sourced_child));
+		}
+
+		let mut requests = Vec::new();
+		for (keychain, last_child) in keychains {
+			let nb_used = last_child.map(|c| u32::from(c) + 1).unwrap_or(0);
+			for address_type in AddressType::all_types() {
+				requests.push(CoreImportRequest {
+					desc: self.descriptor(keychain, *address_type),
+					range: (0, nb_used + descriptor::CORE_IMPORT_LOOKAHEAD - 1),
+					timestamp: timestamp,
+					internal: keychain == Keychain::Internal,
+				});
+			}
+		}
+		serde_json::to_string(&requests).expect("serializing can't fail")
+	}
+
+	/// Replace the wallet's keys with new ones. New receive addresses and change
+	/// are derived from the new keys only, but funds sent to addresses of the old
+	/// keys are still detected during [grace_period] blocks. Funds received on
+	/// the old keys can still be spent after the grace period.
+	pub fn rotate_keys(
 		&mut self,
-		outputs: Vec<TxOut>,
-		use_inputs: Vec<OutPoint>,
-		fee: u64,
-	) -> Result<psbt::PartiallySignedTransaction> {
-		let change_child = self.next_address_child();
-		let (psbt, change_idx) =
-			match self.create_transaction_with_change(outputs, use_inputs, change_child, fee) {
-				Ok(res) => res,
-				Err(e) => {
-					self.rollback_address_child();
-					return Err(e);
-				}
-			};
-		if change_idx.is_none() {
-			self.rollback_address_child();
+		xpub: bip32::ExtendedPubKey,
+		master_fingerprint: bip32::Fingerprint,
+		base_path: bip32::DerivationPath,
+		change_path: Option<bip32::DerivationPath>,
+		grace_period: u32,
+	) {
+		self.version += 1;
+		let height = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		self.retired_keys.push(RetiredKeys {
+			generation: self.generation,
+			extended_pubkey: self.extended_pubkey,
+			master_fp: self.master_fp,
+			base_derivation_path: self.base_derivation_path.clone(),
+			change_derivation_path: self.change_derivation_path.clone(),
+			watch_until: height + grace_period,
+			expired: false,
+		});
+
+		self.generation += 1;
+		self.extended_pubkey = xpub;
+		self.master_fp = master_fingerprint;
+		self.xpub_origin_path = None;
+		self.base_derivation_path = base_path;
+		self.change_derivation_path = change_path;
+		self.last_sourced_child = None;
+		self.last_sourced_change_child = None;
+		for branch in self.branches.values_mut() {
+			branch.last_sourced_child = None;
 		}
-		self.commit_transaction(psbt.global.unsigned_tx.clone());
-		Ok(psbt)
 	}
-}
 
-impl fmt::Debug for Wallet {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		use bitcoin_hashes::hex::ToHex;
+	/// The keys that were replaced using [rotate_keys].
+	pub fn retired_keys(&self) -> &[RetiredKeys] {
+		&self.retired_keys
+	}
 
-		write!(f, "--- Wallet ---\n")?;
-		write!(f, "config: {:?}\n", self.config)?;
-		write!(f, "extended_pubkey: {}\n", self.extended_pubkey)?;
-		write!(f, "master_fp: {}\n", self.master_fp[..].to_hex())?;
-		write!(f, "base_derivation_path: {}\n", self.base_derivation_path)?;
-		write!(f, "last_sourced_child: {:?}\n", self.last_sourced_child)?;
-		write!(f, "owned_utxos (len: {}):\n", self.owned_utxos.len())?;
-		for utxo in self.owned_utxos.values() {
-			write!(f, "- {:?}\n", utxo)?;
+	/// Stop watching the scripts of retired keys for which the grace period ended.
+	fn expire_retired_keys(&mut self, height: u32) {
+		let mut expired = Vec::new();
+		for keys in self.retired_keys.iter_mut() {
+			if !keys.expired && keys.watch_until < height {
+				keys.expired = true;
+				expired.push(keys.generation);
+			}
 		}
-		write!(f, "script_index (len: {}):\n", self.script_index.len())?;
-		for (script, cn) in self.script_index.iter() {
-			write!(f, "- {}: {}\n", script.to_hex(), cn)?;
+		if !expired.is_empty() {
+			self.script_index.retain(|_, i| !expired.contains(&i.generation));
 		}
-		write!(f, "last_known_block: {:?}\n", self.last_known_block)?;
-		write!(f, "pending_txs (len: {}):\n", self.pending_txs.len())?;
-		for tx in self.pending_txs.iter() {
-			write!(f, "- {:?}\n", tx)?;
+	}
+
+	/// Whether the script belongs to the wallet.
+	pub fn is_mine_script(&self, script: &Script) -> bool {
+		self.script_index.contains_key(&script_hash(script))
+	}
+
+	/// Whether the address belongs to the wallet.
+	pub fn is_mine_address(&self, address: &Address) -> bool {
+		self.is_mine_script(&address.script_pubkey())
+	}
+
+	/// Get information about a script owned by the wallet.
+	pub fn get_script_info(&self, script: &Script) -> Option<ScriptInfo> {
+		self.script_index.get(&script_hash(script)).cloned()
+	}
+
+	/// The address type of the script. Unlike [AddressType::from_script], this
+	/// recognizes our own P2SH-P2WPKH scripts. Other P2SH scripts give [None].
+	pub fn address_type_of(&self, script: &Script) -> Option<AddressType> {
+		match self.get_script_info(script) {
+			Some(info) => Some(info.address_type),
+			None => AddressType::from_script(script),
 		}
-		write!(f, "tx_history (len: {}):\n", self.tx_history.len())?;
-		for tx in self.tx_history.iter() {
-			write!(f, "- {:?}\n", tx)?;
+	}
+
+	/// The address type of the UTXO's script. Our scripts are recognized from
+	/// the UTXO's key, also when they are no longer in the script index.
+	fn utxo_address_type(&self, utxo: &Utxo) -> Option<AddressType> {
+		let script = self.utxo_script_pubkey(utxo)?;
+		if utxo.external {
+			return AddressType::from_script(script);
 		}
-		write!(f, "--------------")
+		let (pubkey, _, _) =
+			self.derive_key(utxo.generation, utxo.keychain, utxo.child_number).ok()?;
+		let network = self.config.network;
+		let mut types = AddressType::all_types().iter().cloned();
+		types.find(|t| t.address(&pubkey, network).script_pubkey() == *script)
+	}
+
+	/// All scripts the wallet looks for in blocks: our scripts, including the
+	/// lookahead, the scripts of imported UTXOs and the watched scripts. Like
+	/// for matching block filters.
+	///
+	/// Only hashes of our scripts are stored, so they are derived again.
+	pub fn relevant_scripts(&self) -> Result<Vec<Script>> {
+		let mut scripts = Vec::with_capacity(self.script_index.len() + self.watched_scripts.len());
+		for info in self.script_index.values() {
+			scripts.extend(self.indexed_script(info)?);
+		}
+		for utxo in self.owned_utxos.values().filter(|u| u.external) {
+			scripts.extend(self.utxo_script_pubkey(utxo).cloned());
+		}
+		scripts.extend(self.watched_scripts.iter().cloned());
+		Ok(scripts)
+	}
+
+	fn script_pubkey_for(&self, pubkey: &PublicKey, address_type: AddressType) -> Script {
+		let address = match address_type {
+			AddressType::P2wpkh => Address::p2wpkh(pubkey, self.config.network),
+			AddressType::P2shP2wpkh => Address::p2shwpkh(pubkey, self.config.network),
+			AddressType::P2pkh => Address::p2pkh(pubkey, self.config.network),
+		};
+		address.script_pubkey()
+	}
+
+	/// Whether the wallet has the keys of the generation.
+	fn has_generation(&self, generation: u32) -> bool {
+		let retired = self.retired_keys.iter().any(|k| k.generation == generation);
+		generation == self.generation || retired
+	}
+
+	/// Derive the script of the index entry. [None] when the keys of its
+	/// generation are unknown.
+	fn indexed_script(&self, info: &ScriptInfo) -> Result<Option<Script>> {
+		if !self.has_generation(info.generation) {
+			return Ok(None);
+		}
+		let (pubkey, _, _) = self.derive_key(info.generation, info.keychain, info.child)?;
+		Ok(Some(self.script_pubkey_for(&pubkey, info.address_type)))
+	}
+
+	/// Create a PSBT that, once signed, gives a BIP-322 signature of the message
+	/// with the key behind the address. Use [bip322::signature_from_psbt] on
+	/// the signed PSBT to get the signature.
+	///
+	/// Only P2WPKH and P2SH-P2WPKH addresses are supported.
+	pub fn message_signing_psbt(
+		&self,
+		address: &Address,
+		message: &[u8],
+	) -> Result<psbt::PartiallySignedTransaction> {
+		let script_pubkey = address.script_pubkey();
+		let info = match self.script_index.get(&script_hash(&script_pubkey)) {
+			Some(info) => info,
+			None => return Err(Error::ScriptNotInWallet),
+		};
+		let (pubkey, master_fp, path) =
+			self.derive_key(info.generation, info.keychain, info.child)?;
+
+		let to_spend = bip322::to_spend(&script_pubkey, message);
+		let mut psbt_input = psbt::Input {
+			witness_utxo: Some(to_spend.output[0].clone()),
+			..Default::default()
+		};
+		psbt_input.hd_keypaths.insert(pubkey, (master_fp, path));
+		match info.address_type {
+			AddressType::P2wpkh => {}
+			AddressType::P2shP2wpkh => {
+				let redeem_addr = Address::p2wpkh(&pubkey, self.config.network);
+				psbt_input.redeem_script = Some(redeem_addr.script_pubkey());
+			}
+			AddressType::P2pkh => return Err(Error::UnsupportedAddressType),
+		}
+
+		let tx = bip322::to_sign(&to_spend);
+		Ok(psbt::PartiallySignedTransaction {
+			global: psbt::Global::from_unsigned_tx(tx).expect("only when non-empty sigs"),
+			inputs: vec![psbt_input],
+			outputs: vec![Default::default()],
+		})
+	}
+
+	/// Check if the tx is relevant for the wallet.
+	pub fn is_relevant_tx(&self, tx: &Transaction) -> bool {
+		tx.input.iter().any(|i| {
+			self.owned_utxos.contains_key(&i.previous_output)
+				|| self.watched_utxos.contains_key(&i.previous_output)
+		}) || tx.output.iter().any(|o| {
+			self.is_mine_script(&o.script_pubkey)
+				|| self.watched_scripts.contains(&o.script_pubkey)
+		})
+	}
+
+	/// Watch the script for incoming funds. Funds on watched scripts are tracked
+	/// separately and are not spendable by the wallet.
+	pub fn watch_script(&mut self, script: Script) {
+		self.version += 1;
+		self.watched_scripts.insert(script);
+	}
+
+	/// Watch the address for incoming funds. See [watch_script].
+	pub fn watch_address(&mut self, address: &Address) {
+		self.watch_script(address.script_pubkey());
+	}
+
+	/// Stop watching the script and forget about its UTXOs.
+	pub fn unwatch_script(&mut self, script: &Script) -> bool {
+		self.version += 1;
+		self.watched_utxos.retain(|_, u| u.txout.script_pubkey != *script);
+		self.watched_scripts.remove(script)
+	}
+
+	/// Returns an iterator over the UTXOs on watch-only scripts.
+	pub fn get_watched_utxos(&self) -> collections::hash_map::Values<OutPoint, WatchedUtxo> {
+		self.watched_utxos.values()
+	}
+
+	/// List the UTXOs on watch-only scripts, ordered by outpoint.
+	pub fn list_watched_utxos(&self) -> Vec<&WatchedUtxo> {
+		sorted_by_outpoint(&self.watched_utxos).into_iter().map(|(_, u)| u).collect()
+	}
+
+	/// Drop the pending txs that spend any of the same outputs as the tx, which
+	/// was confirmed or seen in the mempool. A pending tx confirming is not
+	/// a conflict, it's just no longer pending.
+	fn process_pending_conflicts(&mut self, tx: &Transaction, confirmed: bool) {
+		if self.pending_txs.is_empty() {
+			return;
+		}
+
+		let txid = tx.txid();
+		if confirmed {
+			self.pending_heights.remove(&txid);
+			self.pending_times.remove(&txid);
+			self.broadcasts.remove(&txid);
+			self.pending_txs.retain(|p| p.txid() != txid);
+		}
+
+		let conflicts: Vec<Transaction> = self
+			.pending_txs
+			.iter()
+			.filter(|p| p.txid() != txid)
+			.filter(|p| {
+				p.input.iter().any(|i| {
+					tx.input.iter().any(|other| other.previous_output == i.previous_output)
+				})
+			})
+			.cloned()
+			.collect();
+		for conflict in conflicts {
+			let conflict_txid = conflict.txid();
+			self.drop_pending_transaction(conflict_txid);
+			self.events.push(WalletEvent::PendingTxConflicted {
+				txid: conflict_txid,
+				conflicting_txid: txid,
+				confirmed: confirmed,
+			});
+			self.conflicted_txs.push(ConflictedTx {
+				tx: conflict,
+				conflicting_txid: txid,
+				confirmed: confirmed,
+			});
+		}
+	}
+
+	/// Check a tx seen in the mempool for conflicts with our pending txs.
+	/// Conflicting pending txs are dropped, see [list_conflicted_transactions].
+	pub fn process_mempool_transaction(&mut self, tx: &Transaction) {
+		self.version += 1;
+		self.process_pending_conflicts(tx, false);
+	}
+
+	/// The pending txs that were dropped because of a conflicting tx.
+	/// They are forgotten once the conflicting tx is confirmed deeper than
+	/// [WalletConfig::reorg_safety_depth].
+	pub fn list_conflicted_transactions(&self) -> &[ConflictedTx] {
+		&self.conflicted_txs
+	}
+
+	/// Forget the conflicted txs of which the conflicting tx is confirmed
+	/// below the height, so they can't become valid again.
+	fn prune_conflicted_transactions(&mut self, safe_height: u32) {
+		let history = &self.tx_history;
+		self.conflicted_txs.retain(|c| match history.get(&c.conflicting_txid) {
+			Some(entry) => entry.height >= safe_height,
+			None => true,
+		});
+	}
+
+	/// Txs that are already in the history are skipped.
+	fn process_transaction(&mut self, tx: &Transaction, block: &KnownBlock, timestamp: u32) {
+		if self.tx_history.contains(&tx.txid()) {
+			return;
+		}
+		self.process_pending_conflicts(tx, true);
+
+		let block_height = block.height;
+		let mut relevant = false;
+		let mut sent = 0;
+		let mut received = 0;
+		let mut reused_address = false;
+		let mut watched_sent = 0;
+		let mut watched_received = 0;
+		// Find if spending any of our own UTXOs.
+		for input in &tx.input {
+			if let Some(utxo) = self.owned_utxos.remove(&input.previous_output) {
+				sent += utxo.value.as_sat();
+				relevant = true;
+			}
+			if let Some(utxo) = self.watched_utxos.remove(&input.previous_output) {
+				watched_sent += utxo.txout.value;
+				relevant = true;
+			}
+		}
+
+		// Find if sending to any of our own outputs.
+		for (idx, output) in tx.output.iter().enumerate() {
+			if let Some(&info) = self.script_index.get(&script_hash(&output.script_pubkey)) {
+				let outpoint = OutPoint {
+					txid: tx.txid(),
+					vout: idx as u32,
+				};
+
+				// Our pending txs might already spend the change of our txs.
+				let used_in_tx = match self.unconfirmed_change.remove(&outpoint) {
+					Some(utxo) => utxo.used_in_tx,
+					None => BTreeSet::new(),
+				};
+				self.owned_utxos.insert(
+					outpoint,
+					Utxo {
+						outpoint: outpoint,
+						value: Amount::from_sat(output.value),
+						height: block_height,
+						keychain: info.keychain,
+						child_number: info.child,
+						generation: info.generation,
+						used_in_tx: used_in_tx,
+						locked: false,
+						prevout: Some(output.clone()),
+						prev_tx: full_prev_tx(output, tx),
+						external: false,
+						input_kind: None,
+						silent_payment_tweak: None,
+						timelock: None,
+						coinbase: tx.is_coin_base(),
+					},
+				);
+				// A payment to the lookahead, the address was handed out elsewhere.
+				let lookahead = u32::from(info.child) >= self.nb_sourced(info.keychain);
+				if info.generation == self.generation && lookahead {
+					self.set_last_sourced_child(info.keychain, Some(info.child));
+				}
+				let usage = self.script_usage.entry(output.script_pubkey.clone()).or_insert(0);
+				if *usage > 0 {
+					reused_address = true;
+				}
+				*usage += 1;
+				received += output.value;
+				relevant = true;
+			} else if self.watched_scripts.contains(&output.script_pubkey) {
+				let outpoint = OutPoint {
+					txid: tx.txid(),
+					vout: idx as u32,
+				};
+				self.watched_utxos.insert(
+					outpoint,
+					WatchedUtxo {
+						outpoint: outpoint,
+						txout: output.clone(),
+						height: block_height,
+					},
+				);
+				watched_received += output.value;
+				relevant = true;
+			}
+		}
+
+		self.process_expected_payments(tx, block_height);
+
+		if relevant {
+			let fee = self.calculate_fee(tx);
+			self.tx_history.insert(TxEntry {
+				tx: tx.clone(),
+				height: block_height,
+				block_hash: block.hash,
+				timestamp: timestamp,
+				sent: Amount::from_sat(sent),
+				received: Amount::from_sat(received),
+				fee: fee.map(Amount::from_sat),
+				reused_address: reused_address,
+				watched_sent: Amount::from_sat(watched_sent),
+				watched_received: Amount::from_sat(watched_received),
+				pruned_txid: None,
+				pruned_coinbase: false,
+			});
+			self.events.push(WalletEvent::TxConfirmed {
+				txid: tx.txid(),
+				height: block_height,
+				received: Amount::from_sat(received),
+				sent: Amount::from_sat(sent),
+			});
+		}
+	}
+
+	/// Add the outputs of the tx to the expected payments they pay.
+	fn process_expected_payments(&mut self, tx: &Transaction, height: u32) {
+		for payment in self.expected_payments.iter_mut() {
+			if payment.status != ExpectedPaymentStatus::Pending {
+				continue;
+			}
+			let script_pubkey = payment.address.script_pubkey();
+			let paid: u64 = tx
+				.output
+				.iter()
+				.filter(|o| o.script_pubkey == script_pubkey)
+				.map(|o| o.value)
+				.sum();
+			if paid == 0 {
+				continue;
+			}
+
+			payment.received += Amount::from_sat(paid);
+			if payment.received >= payment.amount {
+				payment.status = ExpectedPaymentStatus::Fulfilled {
+					txid: tx.txid(),
+					height: height,
+				};
+				self.events.push(WalletEvent::ExpectedPaymentFulfilled {
+					id: payment.id,
+					txid: tx.txid(),
+					height: height,
+				});
+			}
+		}
+	}
+
+	/// Mark the pending expected payments that weren't fulfilled in time as expired.
+	fn expire_expected_payments(&mut self, height: u32) {
+		for payment in self.expected_payments.iter_mut() {
+			if payment.status == ExpectedPaymentStatus::Pending
+				&& payment.expiry_height.map(|h| h < height).unwrap_or(false)
+			{
+				payment.status = ExpectedPaymentStatus::Expired;
+				self.events.push(WalletEvent::ExpectedPaymentExpired {
+					id: payment.id,
+				});
+			}
+		}
+	}
+
+	/// Register a payment the wallet expects to receive on the address, which
+	/// doesn't have to be one of the wallet's. Returns the id of the payment.
+	pub fn expect_payment(
+		&mut self,
+		address: Address,
+		amount: Amount,
+		expiry_height: Option<u32>,
+	) -> usize {
+		self.version += 1;
+		let id = self.expected_payments.iter().map(|p| p.id + 1).max().unwrap_or(0);
+		self.expected_payments.push(ExpectedPayment {
+			id: id,
+			address: address,
+			amount: amount,
+			expiry_height: expiry_height,
+			received: Amount::from_sat(0),
+			status: ExpectedPaymentStatus::Pending,
+		});
+		id
+	}
+
+	/// All registered expected payments with their status.
+	pub fn list_expected_payments(&self) -> &[ExpectedPayment] {
+		&self.expected_payments
+	}
+
+	/// Forget about the expected payment.
+	pub fn remove_expected_payment(&mut self, id: usize) -> bool {
+		self.version += 1;
+		let len_before = self.expected_payments.len();
+		self.expected_payments.retain(|p| p.id != id);
+		self.expected_payments.len() < len_before
+	}
+
+	/// Schedule a [WalletEvent::HeightReached] event for when the wallet's tip
+	/// reaches the height, f.e. when a timelock matures. If the tip is already
+	/// at or beyond the height, the event is fired immediately.
+	/// Returns the id of the trigger.
+	pub fn schedule_at_height(&mut self, height: u32, name: String) -> usize {
+		self.version += 1;
+		let id = self.next_height_trigger_id;
+		self.next_height_trigger_id += 1;
+		self.height_triggers.push(HeightTrigger {
+			id: id,
+			height: height,
+			name: name,
+		});
+		if let Some(tip) = self.last_known_block.as_ref().map(|b| b.height) {
+			self.fire_height_triggers(tip);
+		}
+		id
+	}
+
+	/// All scheduled height triggers that didn't fire yet.
+	pub fn list_height_triggers(&self) -> &[HeightTrigger] {
+		&self.height_triggers
+	}
+
+	/// Cancel the height trigger so that it won't fire.
+	pub fn cancel_height_trigger(&mut self, id: usize) -> bool {
+		self.version += 1;
+		let len_before = self.height_triggers.len();
+		self.height_triggers.retain(|t| t.id != id);
+		self.height_triggers.len() < len_before
+	}
+
+	/// Fire the events of the height triggers that were reached.
+	fn fire_height_triggers(&mut self, height: u32) {
+		let (reached, pending): (Vec<_>, Vec<_>) =
+			self.height_triggers.drain(..).partition(|t| t.height <= height);
+		self.height_triggers = pending;
+		for trigger in reached {
+			self.events.push(WalletEvent::HeightReached {
+				id: trigger.id,
+				height: trigger.height,
+				name: trigger.name,
+			});
+		}
+	}
+
+	/// Set how the UTXO is spent, so that the weight of its input is estimated
+	/// correctly when creating txs. Only needed for imported UTXOs of which
+	/// the kind can't be derived from the script, like P2WSH multisig. Until
+	/// it is set, such UTXOs are not selected and fail when given as input.
+	pub fn set_utxo_input_kind(&mut self, outpoint: &OutPoint, kind: InputKind) -> Result<()> {
+		match self.owned_utxos.get_mut(outpoint) {
+			Some(utxo) => utxo.input_kind = Some(kind),
+			None => return Err(Error::UtxoNotInWallet),
+		}
+		self.version += 1;
+		self.record_input_kind_feature(kind);
+		Ok(())
+	}
+
+	/// Set the timelock in the script of the UTXO, like for imported UTXOs of
+	/// CSV or CLTV scripts. Until the timelock is satisfied at the current
+	/// height, the UTXO is not spendable. Created txs spending it have their
+	/// locktime or the sequence of the input set accordingly.
+	pub fn set_utxo_timelock(
+		&mut self,
+		outpoint: &OutPoint,
+		timelock: Option<Timelock>,
+	) -> Result<()> {
+		match self.owned_utxos.get_mut(outpoint) {
+			Some(utxo) => utxo.timelock = timelock,
+			None => return Err(Error::UtxoNotInWallet),
+		}
+		self.version += 1;
+		Ok(())
+	}
+
+	/// Whether the UTXO can be selected when creating txs: it's not spent by
+	/// a pending tx, not locked and its timelock is satisfied.
+	pub fn is_utxo_spendable(&self, utxo: &Utxo) -> bool {
+		let tip = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		utxo.is_available() && !utxo.locked && utxo.is_mature(tip)
+	}
+
+	/// Whether coin selection picks the UTXO: it's spendable and it has the
+	/// confirmations required by [WalletConfig::min_spend_confirmations].
+	///
+	/// The change of our own txs is trusted, so it is exempt from the minimum
+	/// and it is picked unconfirmed with [WalletConfig::spend_unconfirmed].
+	/// UTXOs of which the input kind is not known are never picked.
+	pub fn is_utxo_selectable(&self, utxo: &Utxo) -> bool {
+		if !self.is_utxo_spendable(utxo) || self.utxo_input_kind(utxo).is_err() {
+			return false;
+		}
+		if self.unconfirmed_change.contains_key(&utxo.outpoint) {
+			return self.config.spend_unconfirmed;
+		}
+		let tip = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		let confirmations = (tip + 1).saturating_sub(utxo.height);
+		confirmations >= self.config.min_spend_confirmations || self.is_trusted(utxo)
+	}
+
+	/// Whether the UTXO is the change of a tx that spent our UTXOs.
+	fn is_trusted(&self, utxo: &Utxo) -> bool {
+		self.get_history_entry(utxo.outpoint.txid).map(|e| e.is_outgoing()).unwrap_or(false)
+	}
+
+	/// The UTXOs coin selection can pick, see [is_utxo_selectable].
+	/// They are ordered so that a seeded RNG always selects the same.
+	fn selectable_utxos(&self) -> Vec<&Utxo> {
+		let mut utxos = self.list_utxos();
+		if self.config.spend_unconfirmed {
+			utxos.extend(sorted_by_outpoint(&self.unconfirmed_change).into_iter().map(|(_, u)| u));
+		}
+		utxos.retain(|u| self.is_utxo_selectable(u));
+		utxos
+	}
+
+	/// A UTXO of the wallet, or the change of one of our pending txs.
+	fn get_utxo(&self, outpoint: &OutPoint) -> Option<&Utxo> {
+		self.owned_utxos.get(outpoint).or_else(|| self.unconfirmed_change.get(outpoint))
+	}
+
+	/// The optional features used by the wallet.
+	pub fn features(&self) -> &BTreeSet<WalletFeature> {
+		&self.features
+	}
+
+	/// Record the feature needed to spend inputs of the kind, if any.
+	fn record_input_kind_feature(&mut self, kind: InputKind) {
+		match kind {
+			InputKind::P2trKeySpend => {
+				self.features.insert(WalletFeature::Taproot);
+			}
+			InputKind::P2wshMultisig { .. } | InputKind::P2shP2wshMultisig { .. } => {
+				self.features.insert(WalletFeature::Multisig);
+			}
+			_ => {}
+		}
+	}
+
+	/// The kind of input spending the UTXO. Imported UTXOs of which the script
+	/// is not recognized, like P2SH or P2WSH, need the kind to be set with
+	/// [Wallet::set_utxo_input_kind].
+	fn utxo_input_kind(&self, utxo: &Utxo) -> Result<InputKind> {
+		if let Some(kind) = utxo.input_kind {
+			return Ok(kind);
+		}
+		let script = self.utxo_script_pubkey(utxo).ok_or(Error::MissingUtxoData)?;
+		if !utxo.external {
+			if let Some(info) = self.script_index.get(&script_hash(script)) {
+				return Ok(info.address_type.input_kind());
+			}
+		}
+		InputKind::from_script(script).ok_or(Error::UnknownInputKind)
+	}
+
+	/// The weight of an input spending the UTXO.
+	/// Only for UTXOs of which the kind is known, like selectable ones.
+	fn utxo_input_weight(&self, utxo: &Utxo) -> u64 {
+		self.utxo_input_kind(utxo).expect("UTXO of unknown kind").weight()
+	}
+
+	/// Import a UTXO from an external source, so that it can be spent without
+	/// the wallet having processed the tx that created it.
+	///
+	/// For derived keys, the script of the output must match the key.
+	/// P2PKH outputs can't be signed for without the tx that created them,
+	/// they fail with [Error::MissingUtxoData], use [import_utxo_with_tx].
+	pub fn import_utxo(
+		&mut self,
+		outpoint: OutPoint,
+		txout: TxOut,
+		key: UtxoKey,
+		height: u32,
+	) -> Result<()> {
+		if txout.script_pubkey.is_p2pkh() {
+			return Err(Error::MissingUtxoData);
+		}
+		self.import_utxo_inner(outpoint, txout, None, key, height)
+	}
+
+	/// Like [import_utxo], but given the tx that created the output, which is
+	/// needed to spend P2PKH outputs.
+	pub fn import_utxo_with_tx(
+		&mut self,
+		tx: &Transaction,
+		vout: u32,
+		key: UtxoKey,
+		height: u32,
+	) -> Result<()> {
+		let txout = match tx.output.get(vout as usize) {
+			Some(txout) => txout.clone(),
+			None => return Err(Error::MissingUtxoData),
+		};
+		let outpoint = OutPoint {
+			txid: tx.txid(),
+			vout: vout,
+		};
+		let prev_tx = full_prev_tx(&txout, tx);
+		self.import_utxo_inner(outpoint, txout, prev_tx, key, height)
+	}
+
+	fn import_utxo_inner(
+		&mut self,
+		outpoint: OutPoint,
+		txout: TxOut,
+		prev_tx: Option<Transaction>,
+		key: UtxoKey,
+		height: u32,
+	) -> Result<()> {
+		if self.owned_utxos.contains_key(&outpoint) {
+			return Err(Error::DuplicateUtxo);
+		}
+
+		let (keychain, child) = match key {
+			UtxoKey::Derived(keychain, child) => {
+				let matches = AddressType::all_types().iter().any(|t| {
+					self.get_address(keychain, child, *t).script_pubkey() == txout.script_pubkey
+				});
+				if !matches {
+					return Err(Error::ImportScriptMismatch);
+				}
+
+				// Make sure we never hand out the address again.
+				let last = *self.last_sourced_child_mut(keychain);
+				if last.map(|l| u32::from(l) < u32::from(child)).unwrap_or(true) {
+					self.set_last_sourced_child(keychain, Some(child));
+				}
+				self.index_script_pubkeys(keychain, child);
+				*self.script_usage.entry(txout.script_pubkey.clone()).or_insert(0) += 1;
+				(keychain, child)
+			}
+			UtxoKey::External | UtxoKey::SilentPayment(_) => {
+				(Keychain::External, bip32::ChildNumber::from_normal_idx(0)?)
+			}
+		};
+		let (external, silent_payment_tweak) = match key {
+			UtxoKey::Derived(..) => (false, None),
+			UtxoKey::External => (true, None),
+			UtxoKey::SilentPayment(tweak) => (true, Some(tweak)),
+		};
+
+		self.version += 1;
+		if key == UtxoKey::External {
+			self.features.insert(WalletFeature::ImportedScripts);
+		}
+		if silent_payment_tweak.is_some() {
+			self.features.insert(WalletFeature::SilentPayments);
+		}
+		if let Some(kind) = InputKind::from_script(&txout.script_pubkey) {
+			self.record_input_kind_feature(kind);
+		}
+		self.owned_utxos.insert(
+			outpoint,
+			Utxo {
+				outpoint: outpoint,
+				value: Amount::from_sat(txout.value),
+				height: height,
+				keychain: keychain,
+				child_number: child,
+				generation: self.generation,
+				used_in_tx: BTreeSet::new(),
+				locked: false,
+				prevout: Some(txout),
+				prev_tx: prev_tx,
+				external: external,
+				input_kind: None,
+				silent_payment_tweak: silent_payment_tweak,
+				timelock: None,
+				coinbase: false,
+			},
+		);
+		Ok(())
+	}
+
+	/// Use this only when you know what you are doing. This might make the wallet lose track of
+	/// some of its own UTXOs.
+	pub fn set_last_block(&mut self, block_hash: sha256d::Hash, height: u32) {
+		self.version += 1;
+		self.last_known_block = Some(KnownBlock {
+			hash: block_hash,
+			height: height,
+		});
+		self.block_hashes.insert(height, block_hash);
+	}
+
+	/// The last block processed by the wallet.
+	pub fn last_block(&self) -> Option<&KnownBlock> {
+		self.last_known_block.as_ref()
+	}
+
+	/// Whether the block was already processed by the wallet.
+	pub fn is_block_processed(&self, block_hash: &sha256d::Hash) -> bool {
+		self.block_hashes.contains_hash(block_hash)
+	}
+
+	/// Start processing a batch of blocks without changing the wallet until
+	/// the batch is committed. See [BlockBatch].
+	pub fn begin_batch(&self) -> BlockBatch {
+		BlockBatch {
+			base_version: self.version,
+			working: self.clone(),
+		}
+	}
+
+	/// Process the next block. Blocks that were already processed are skipped.
+	pub fn process_block(&mut self, block: &Block) -> Result<()> {
+		self.connect_block(&block.header, &block.txdata)
+	}
+
+	/// Process the next blocks, in order. When the first blocks are processed
+	/// and a later one fails, the first blocks stay processed.
+	///
+	/// With the `parallel` feature, the txs of all blocks are first checked for
+	/// relevance in parallel, after which only the relevant txs are applied.
+	/// This speeds up the initial sync, where most txs are not relevant.
+	pub fn process_blocks(&mut self, blocks: &[Block]) -> Result<()> {
+		#[cfg(feature = "parallel")]
+		return self.process_blocks_parallel(blocks);
+		#[cfg(not(feature = "parallel"))]
+		return self.process_blocks_sequential(blocks);
+	}
+
+	#[cfg(not(feature = "parallel"))]
+	fn process_blocks_sequential(&mut self, blocks: &[Block]) -> Result<()> {
+		for block in blocks {
+			self.process_block(block)?;
+		}
+		Ok(())
+	}
+
+	#[cfg(feature = "parallel")]
+	fn process_blocks_parallel(&mut self, blocks: &[Block]) -> Result<()> {
+		use rayon::prelude::*;
+
+		// Besides txs involving our outputs and scripts, txs paying to expected
+		// payments and txs conflicting with our pending txs must be applied.
+		let expected_scripts: HashSet<Script> = self
+			.expected_payments
+			.iter()
+			.filter(|p| p.status == ExpectedPaymentStatus::Pending)
+			.map(|p| p.address.script_pubkey())
+			.collect();
+		let pending_inputs: HashSet<OutPoint> = self
+			.pending_txs
+			.iter()
+			.flat_map(|tx| tx.input.iter().map(|i| i.previous_output))
+			.collect();
+
+		// The relevance of each tx in the state from before the batch.
+		let relevance: Vec<Result<Vec<bool>>> = {
+			let wallet = &*self;
+			blocks
+				.par_iter()
+				.map(|block| {
+					wallet.check_block_limits(&block.txdata)?;
+					let relevant = block.txdata.iter().map(|tx| {
+						wallet.is_relevant_tx(tx)
+							|| tx.input.iter().any(|i| pending_inputs.contains(&i.previous_output))
+							|| tx.output.iter().any(|o| expected_scripts.contains(&o.script_pubkey))
+					});
+					Ok(relevant.collect())
+				})
+				.collect()
+		};
+
+		// Txs spending outputs of relevant txs earlier in the batch can be
+		// relevant as well, so these are included in the order of the blocks.
+		let mut batch_outputs = HashSet::new();
+		for (block, relevance) in blocks.iter().zip(relevance) {
+			let mut txs = Vec::new();
+			for (tx, relevant) in block.txdata.iter().zip(relevance?) {
+				if !relevant && !tx.input.iter().any(|i| batch_outputs.contains(&i.previous_output))
+				{
+					continue;
+				}
+				let txid = tx.txid();
+				for vout in 0..tx.output.len() {
+					batch_outputs.insert(OutPoint {
+						txid: txid,
+						vout: vout as u32,
+					});
+				}
+				txs.push(tx.clone());
+			}
+			self.connect_block(&block.header, &txs)?;
+		}
+		Ok(())
+	}
+
+	/// Process the next block for light clients that only have the block's
+	/// header and a merkle proof for the wallet's txs in it.
+	/// All given txs must be matched by the proof.
+	pub fn process_filtered_block(
+		&mut self,
+		merkle_block: &MerkleBlock,
+		txs: &[Transaction],
+	) -> Result<()> {
+		let mut matches = Vec::new();
+		let mut indices = Vec::new();
+		if merkle_block.extract_matches(&mut matches, &mut indices).is_err() {
+			return Err(Error::InvalidMerkleProof);
+		}
+
+		// Process the txs in the order they appear in the block.
+		let mut ordered = Vec::with_capacity(txs.len());
+		for tx in txs {
+			match matches.iter().position(|m| *m == tx.txid()) {
+				Some(pos) => ordered.push((indices[pos], tx.clone())),
+				None => return Err(Error::InvalidMerkleProof),
+			}
+		}
+		ordered.sort_by_key(|t| t.0);
+		let txs: Vec<Transaction> = ordered.into_iter().map(|t| t.1).collect();
+		self.connect_block(&merkle_block.header, &txs)
+	}
+
+	/// Process the next block given as a BIP-152 compact block, reconstructed
+	/// using the wallet's pending txs and the given known txs, like the mempool.
+	///
+	/// When txs are missing, nothing is processed and the indices of the missing
+	/// txs are returned. They can be requested from the peer, after which this
+	/// method can be called again with the received txs added to [known].
+	pub fn process_compact_block<'a, I>(
+		&mut self,
+		compact_block: &CompactBlock,
+		known: I,
+	) -> Result<Vec<usize>>
+	where
+		I: IntoIterator<Item = &'a Transaction>,
+	{
+		if self.is_block_processed(&compact_block.header.bitcoin_hash()) {
+			return Ok(Vec::new());
+		}
+
+		let reconstruction = {
+			let known = self.pending_txs.iter().chain(known);
+			compact_block.reconstruct(known)?
+		};
+		match reconstruction {
+			Reconstruction::Complete(txs) => {
+				self.connect_block(&compact_block.header, &txs)?;
+				Ok(Vec::new())
+			}
+			Reconstruction::Missing(missing) => Ok(missing),
+		}
+	}
+
+	/// Add a tx of a block that was already processed, proven by the merkle
+	/// proof, f.e. because a light client only learned later that the tx was
+	/// relevant. Txs that are already in the history are skipped.
+	///
+	/// Txs should be added in the order they were confirmed in, otherwise
+	/// outputs spent by txs processed before might be considered unspent.
+	///
+	/// The wallet only keeps the hashes of the last
+	/// [WalletConfig::reorg_safety_depth] blocks, proofs of older blocks fail
+	/// with [Error::UnknownBlock] and should be verified against the header
+	/// chain with [process_transaction_with_header_proof].
+	pub fn process_transaction_with_proof(
+		&mut self,
+		tx: &Transaction,
+		merkle_block: &MerkleBlock,
+	) -> Result<()> {
+		let height = match self.block_hashes.height(&merkle_block.header.bitcoin_hash()) {
+			Some(height) => height,
+			None => return Err(Error::UnknownBlock),
+		};
+		self.process_proven_transaction(tx, merkle_block, height)
+	}
+
+	/// Like [process_transaction_with_proof], but the block is looked up in the
+	/// validated header chain instead, so the proof can be of any block.
+	///
+	/// Both the block of the proof and the wallet's last block must be in the
+	/// best chain of the headers, otherwise the wallet and the headers disagree
+	/// about the chain and [Error::UnknownBlock] is returned. Blocks after the
+	/// wallet's last block should be processed with [process_block] first.
+	pub fn process_transaction_with_header_proof(
+		&mut self,
+		tx: &Transaction,
+		merkle_block: &MerkleBlock,
+		headers: &HeaderChain,
+	) -> Result<()> {
+		let last_height = match self.last_known_block {
+			Some(ref last) if headers.is_in_best_chain(&last.hash) => last.height,
+			Some(_) => return Err(Error::UnknownBlock),
+			None => return Err(Error::WalletNotFullyInitialized),
+		};
+		if headers.chain() != self.config.chain() {
+			return Err(Error::UnknownBlock);
+		}
+
+		let block_hash = merkle_block.header.bitcoin_hash();
+		let height = match headers.get_header(&block_hash) {
+			Some(stored) if stored.height <= last_height => stored.height,
+			_ => return Err(Error::UnknownBlock),
+		};
+		if !headers.is_in_best_chain(&block_hash) {
+			return Err(Error::UnknownBlock);
+		}
+		self.process_proven_transaction(tx, merkle_block, height)
+	}
+
+	/// Add the tx of the block at the given height after checking its merkle proof.
+	fn process_proven_transaction(
+		&mut self,
+		tx: &Transaction,
+		merkle_block: &MerkleBlock,
+		height: u32,
+	) -> Result<()> {
+		let mut matches = Vec::new();
+		let mut indices = Vec::new();
+		if merkle_block.extract_matches(&mut matches, &mut indices).is_err()
+			|| !matches.contains(&tx.txid())
+		{
+			return Err(Error::InvalidMerkleProof);
+		}
+		if self.get_history_entry(tx.txid()).is_some() {
+			return Ok(());
+		}
+
+		let block = KnownBlock {
+			height: height,
+			hash: merkle_block.header.bitcoin_hash(),
+		};
+		self.process_transaction(tx, &block, merkle_block.header.time);
+		self.check_balance_thresholds();
+		self.version += 1;
+		Ok(())
+	}
+
+	/// Connect the block with the given header and txs to the wallet's chain.
+	fn connect_block(&mut self, header: &BlockHeader, txs: &[Transaction]) -> Result<()> {
+		if self.last_known_block.is_none() {
+			return Err(Error::WalletNotFullyInitialized);
+		}
+
+		if self.is_block_processed(&header.bitcoin_hash()) {
+			return Ok(());
+		}
+
+		// Ensure the block follows on the last known block.
+		if header.prev_blockhash != self.last_known_block.as_ref().unwrap().hash {
+			// Reorgs are done by disconnecting blocks first, see [disconnect_block].
+			return Err(Error::BlockFork);
+		}
+		let new_block = KnownBlock {
+			height: self.last_known_block.as_ref().unwrap().height + 1,
+			hash: header.bitcoin_hash(),
+		};
+
+		self.check_block_limits(txs)?;
+		#[cfg(feature = "bitcoinconsensus")]
+		self.verify_block_scripts(txs)?;
+
+		for tx in txs {
+			self.process_transaction(&tx, &new_block, header.time)
+		}
+
+		self.expire_retired_keys(new_block.height);
+		self.expire_expected_payments(new_block.height);
+		self.events.push(WalletEvent::BlockConnected {
+			height: new_block.height,
+			hash: new_block.hash,
+		});
+		self.fire_height_triggers(new_block.height);
+		self.block_hashes.insert(new_block.height, new_block.hash);
+		let safe_height = new_block.height.saturating_sub(self.config.reorg_safety_depth);
+		self.block_hashes.prune_below(safe_height);
+		self.prune_conflicted_transactions(safe_height);
+		self.last_known_block = Some(new_block);
+		self.expire_pending_transactions();
+		self.check_balance_thresholds();
+		self.version += 1;
+
+		Ok(())
+	}
+
+	/// Disconnect the last processed block, f.e. when it was reorganized out
+	/// of the chain. The wallet's txs in the block are removed from the history
+	/// and the ones spending our coins become pending again, so that they can
+	/// be confirmed in another block.
+	///
+	/// Fails with [Error::UnknownBlock] when the previous block is not known,
+	/// like for the block set with [set_last_block] or for blocks deeper than
+	/// the reorg safety depth of the config.
+	pub fn disconnect_block(&mut self) -> Result<()> {
+		let last = match self.last_known_block.clone() {
+			Some(block) => block,
+			None => return Err(Error::WalletNotFullyInitialized),
+		};
+		let prev_hash = match last.height.checked_sub(1).and_then(|h| self.block_hashes.get(h)) {
+			Some(hash) => *hash,
+			None => return Err(Error::UnknownBlock),
+		};
+
+		let entries: Vec<TxEntry> =
+			self.tx_history.iter_from_height(last.height).cloned().collect();
+		for entry in entries.iter().rev() {
+			self.disconnect_transaction(entry);
+		}
+		self.block_hashes.remove(last.height);
+		self.last_known_block = Some(KnownBlock {
+			height: last.height - 1,
+			hash: prev_hash,
+		});
+		for entry in entries.into_iter().filter(|e| e.sent > Amount::from_sat(0)) {
+			if !self.pending_txs.iter().any(|t| t.txid() == entry.txid()) {
+				self.commit_transaction(entry.tx);
+			}
+		}
+
+		self.events.push(WalletEvent::BlockDisconnected {
+			height: last.height,
+			hash: last.hash,
+		});
+		self.check_balance_thresholds();
+		self.version += 1;
+		Ok(())
+	}
+
+	/// A locator of the wallet's chain: the last 10 blocks, followed by blocks
+	/// with exponentially increasing spacing down to the first known block.
+	/// Like the locators of the P2P protocol, it allows to find the point
+	/// where another chain forks off the wallet's with few lookups.
+	pub fn block_locator(&self) -> Vec<KnownBlock> {
+		let tip = self.last_known_block.as_ref();
+		let (first, tip) = match (self.block_hashes.first_height(), tip) {
+			(Some(first), Some(tip)) => (first, tip.height),
+			_ => return Vec::new(),
+		};
+		let mut locator = Vec::new();
+		let mut height = tip;
+		let mut step = 1;
+		loop {
+			if let Some(hash) = self.block_hashes.get(height) {
+				locator.push(KnownBlock {
+					height: height,
+					hash: *hash,
+				});
+			}
+			if height <= first {
+				return locator;
+			}
+			if locator.len() >= 10 {
+				step *= 2;
+			}
+			height = cmp::max(height.saturating_sub(step), first);
+		}
+	}
+
+	/// Find the last block the wallet's chain has in common with another
+	/// chain, given the hash of the block at a height in that chain, [None]
+	/// for heights above its tip. Returns [None] when none of the wallet's
+	/// blocks are in the chain.
+	///
+	/// Only the blocks of the [block_locator] and a binary search between
+	/// them are looked up. The wallet can then be rolled back to the block
+	/// with [rollback_to].
+	pub fn fork_point<F>(&self, mut block_hash: F) -> Result<Option<KnownBlock>>
+	where
+		F: FnMut(u32) -> Result<Option<sha256d::Hash>>,
+	{
+		let locator = self.block_locator();
+		// The lowest height known not to be in common.
+		let mut forked = None;
+		for block in locator {
+			if block_hash(block.height)? != Some(block.hash) {
+				forked = Some(block.height);
+				continue;
+			}
+
+			// All blocks below a common block are also in common.
+			let mut common = block;
+			if let Some(mut forked) = forked {
+				while forked - common.height > 1 {
+					let middle = common.height + (forked - common.height) / 2;
+					let hash = match self.block_hashes.get(middle) {
+						Some(hash) => *hash,
+						// Unknown blocks can't be checked.
+						None => break,
+					};
+					if block_hash(middle)? == Some(hash) {
+						common = KnownBlock {
+							height: middle,
+							hash: hash,
+						};
+					} else {
+						forked = middle;
+					}
+				}
+			}
+			return Ok(Some(common));
+		}
+		Ok(None)
+	}
+
+	/// Disconnect blocks until the block at the height is the last one.
+	/// See [disconnect_block].
+	pub fn rollback_to(&mut self, height: u32) -> Result<()> {
+		while self.last_known_block.as_ref().map(|b| b.height > height).unwrap_or(false) {
+			self.disconnect_block()?;
+		}
+		Ok(())
+	}
+
+	/// Undo the changes to the UTXOs and the history made when the tx of the
+	/// entry was confirmed. Spent outputs are only restored when the txs that
+	/// created them are in the history.
+	fn disconnect_transaction(&mut self, entry: &TxEntry) {
+		let txid = entry.txid();
+		for (idx, output) in entry.tx.output.iter().enumerate() {
+			let outpoint = OutPoint {
+				txid: txid,
+				vout: idx as u32,
+			};
+			if self.owned_utxos.remove(&outpoint).is_some() {
+				if let Some(usage) = self.script_usage.get_mut(&output.script_pubkey) {
+					*usage = usage.saturating_sub(1);
+				}
+			}
+			self.watched_utxos.remove(&outpoint);
+		}
+		self.tx_history.remove(&txid);
+
+		for input in &entry.tx.input {
+			let prev = input.previous_output;
+			let (txout, height, prev_tx, coinbase) = match self.tx_history.get(&prev.txid) {
+				Some(e) => match e.tx.output.get(prev.vout as usize) {
+					// The inputs of pruned txs are missing.
+					Some(txout) if e.is_pruned() => {
+						(txout.clone(), e.height, None, e.is_coinbase())
+					}
+					Some(txout) => {
+						let prev_tx = full_prev_tx(txout, &e.tx);
+						(txout.clone(), e.height, prev_tx, e.tx.is_coin_base())
+					}
+					None => continue,
+				},
+				None => continue,
+			};
+			if let Some(&info) = self.script_index.get(&script_hash(&txout.script_pubkey)) {
+				self.owned_utxos.insert(
+					prev,
+					Utxo {
+						outpoint: prev,
+						value: Amount::from_sat(txout.value),
+						height: height,
+						keychain: info.keychain,
+						child_number: info.child,
+						generation: info.generation,
+						used_in_tx: BTreeSet::new(),
+						locked: false,
+						prevout: Some(txout.clone()),
+						prev_tx: prev_tx,
+						external: false,
+						input_kind: None,
+						silent_payment_tweak: None,
+						timelock: None,
+						coinbase: coinbase,
+					},
+				);
+			} else if self.watched_scripts.contains(&txout.script_pubkey) {
+				let utxo = WatchedUtxo {
+					outpoint: prev,
+					txout: txout,
+					height: height,
+				};
+				self.watched_utxos.insert(prev, utxo);
+			}
+		}
+	}
+
+	/// Check the txs of a block against the configured [BlockLimits].
+	fn check_block_limits(&self, txs: &[Transaction]) -> Result<()> {
+		let limits = self.config.block_limits;
+		if limits.max_txs.map(|max| txs.len() > max).unwrap_or(false) {
+			return Err(Error::BlockTooManyTxs);
+		}
+
+		if let Some(max) = limits.max_script_size {
+			for tx in txs {
+				let inputs = tx.input.iter().map(|i| &i.script_sig);
+				if inputs.chain(tx.output.iter().map(|o| &o.script_pubkey)).any(|s| s.len() > max) {
+					return Err(Error::ScriptTooLarge);
+				}
+			}
+		}
+
+		if limits.reject_duplicate_txids {
+			let mut txids = HashSet::with_capacity(txs.len());
+			for tx in txs {
+				let txid = tx.txid();
+				if !txids.insert(txid) {
+					return Err(Error::DuplicateTxid(txid));
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Verify the input scripts of all txs in the block spending our outputs,
+	/// instead of trusting the block source.
+	#[cfg(feature = "bitcoinconsensus")]
+	fn verify_block_scripts(&self, txs: &[Transaction]) -> Result<()> {
+		use bitcoin::consensus::encode::serialize;
+		use bitcoinconsensus;
+
+		// Our outputs created in the block, these can be spent later in the block.
+		let mut block_outputs = HashMap::new();
+		for tx in txs {
+			let txid = tx.txid();
+			let mut serialized = None;
+			for (idx, input) in tx.input.iter().enumerate() {
+				let outpoint = &input.previous_output;
+				let prev = match self.owned_utxos.get(outpoint) {
+					Some(utxo) => self.utxo_txout(utxo),
+					None => block_outputs.get(outpoint).cloned(),
+				};
+				let prev = match prev {
+					Some(prev) => prev,
+					None => continue,
+				};
+				let serialized = serialized.get_or_insert_with(|| serialize(tx));
+				let script = prev.script_pubkey.as_bytes();
+				if bitcoinconsensus::verify(script, prev.value, serialized, idx).is_err() {
+					return Err(Error::ScriptVerification(txid));
+				}
+			}
+
+			for (vout, output) in tx.output.iter().enumerate() {
+				if self.is_mine_script(&output.script_pubkey) {
+					let outpoint = OutPoint {
+						txid: txid,
+						vout: vout as u32,
+					};
+					block_outputs.insert(outpoint, output);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// The maximum height of a block with the given number of confirmations.
+	fn max_height_for_confirmations(&self, minimum_confirmations: Option<u32>) -> u32 {
+		let current_height = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		match minimum_confirmations {
+			None => current_height,
+			Some(minconf) => current_height.checked_sub(minconf).unwrap_or(0) + 1,
+		}
+	}
+
+	/// The balance on watch-only scripts, which is not spendable by the wallet.
+	pub fn get_watched_balance(&self, minimum_confirmations: Option<u32>) -> Amount {
+		let max_height = self.max_height_for_confirmations(minimum_confirmations);
+		let watched = self.watched_utxos.values().filter(|u| u.height <= max_height);
+		Amount::from_sat(watched.map(|u| u.txout.value).sum())
+	}
+
+	pub fn get_balance(&self, minimum_confirmations: Option<u32>) -> Amount {
+		let max_height = self.max_height_for_confirmations(minimum_confirmations);
+		let confirmed = self.owned_utxos.values().filter(|u| u.height <= max_height);
+		//TODO(stevenroose) unconfirmed
+		Amount::from_sat(confirmed.map(|u| u.value.as_sat()).sum())
+	}
+
+	/// The value of all UTXOs that are not yet spent by a committed tx and
+	/// of which the timelock is satisfied.
+	pub fn get_spendable_balance(&self) -> Amount {
+		let tip = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		let spendable = self.owned_utxos.values().filter(|u| u.is_available() && u.is_mature(tip));
+		Amount::from_sat(spendable.map(|u| u.value.as_sat()).sum())
+	}
+
+	/// The value of all UTXOs of which the timelock is not yet satisfied,
+	/// including immature coinbase outputs.
+	pub fn get_timelocked_balance(&self) -> Amount {
+		let tip = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		let locked = self.owned_utxos.values().filter(|u| !u.is_mature(tip));
+		Amount::from_sat(locked.map(|u| u.value.as_sat()).sum())
+	}
+
+	/// Emit an event for every configured threshold the spendable balance
+	/// crossed since the last check.
+	fn check_balance_thresholds(&mut self) {
+		let balance = self.get_spendable_balance().as_sat();
+		// Without a known previous balance, no threshold was crossed.
+		let previous = self.last_spendable_balance.unwrap_or(balance);
+		for &threshold in self.config.balance_thresholds.iter() {
+			if previous >= threshold && balance < threshold {
+				self.events.push(WalletEvent::BalanceBelowThreshold {
+					threshold: Amount::from_sat(threshold),
+					balance: Amount::from_sat(balance),
+				});
+			} else if previous < threshold && balance >= threshold {
+				self.events.push(WalletEvent::BalanceAboveThreshold {
+					threshold: Amount::from_sat(threshold),
+					balance: Amount::from_sat(balance),
+				});
+			}
+		}
+		self.last_spendable_balance = Some(balance);
+	}
+
+	/// Returns an iterator over the [Utxo]s owned by the wallet.
+	/// The order is arbitrary, use [list_utxos] for a stable order.
+	pub fn get_utxos(&self) -> collections::hash_map::Values<OutPoint, Utxo> {
+		self.owned_utxos.values()
+	}
+
+	/// List the [Utxo]s owned by the wallet, ordered by outpoint.
+	pub fn list_utxos(&self) -> Vec<&Utxo> {
+		sorted_by_outpoint(&self.owned_utxos).into_iter().map(|(_, u)| u).collect()
+	}
+
+	/// List the UTXOs matching the filter with their info, ordered by outpoint.
+	pub fn get_utxos_filtered(&self, filter: &UtxoFilter) -> Result<Vec<UtxoInfo>> {
+		let tip = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		let mut ret = Vec::new();
+		for utxo in self.list_utxos() {
+			let confirmations = (tip + 1).saturating_sub(utxo.height);
+			let spendable = self.is_utxo_spendable(utxo);
+			let script_pubkey = self.utxo_script_pubkey(utxo);
+			let address_type = self.utxo_address_type(utxo);
+			if filter.min_confirmations.map(|m| confirmations < m).unwrap_or(false)
+				|| filter.min_value.map(|m| utxo.value < m).unwrap_or(false)
+				|| filter.max_value.map(|m| utxo.value > m).unwrap_or(false)
+				|| (filter.spendable_only && !spendable)
+				|| filter.address_type.map(|t| address_type != Some(t)).unwrap_or(false)
+			{
+				continue;
+			}
+
+			let address = script_pubkey.and_then(|s| Address::from_script(s, self.config.network));
+			let derivation = if utxo.external {
+				None
+			} else {
+				let (_, _, path) =
+					self.derive_key(utxo.generation, utxo.keychain, utxo.child_number)?;
+				Some((utxo.keychain, path))
+			};
+			let label = self
+				.labels
+				.get(&LabelRef::Output(utxo.outpoint))
+				.or_else(|| address.as_ref().and_then(|a| self.labels.get(&LabelRef::address(a))))
+				.map(|l| l.label.clone());
+			ret.push(UtxoInfo {
+				outpoint: utxo.outpoint,
+				value: utxo.value,
+				height: utxo.height,
+				confirmations: confirmations,
+				address: address,
+				address_type: address_type,
+				derivation: derivation,
+				spendable: spendable,
+				locked: utxo.locked,
+				label: label,
+			});
+		}
+		Ok(ret)
+	}
+
+	/// Export the UTXOs matching the filter in the JSON format of the
+	/// `listunspent` RPC of Bitcoin Core, for tools that expect it.
+	pub fn export_listunspent(&self, filter: &UtxoFilter) -> Result<String> {
+		use bitcoin_hashes::hex::ToHex;
+
+		let mut entries = Vec::new();
+		for info in self.get_utxos_filtered(filter)? {
+			let utxo = &self.owned_utxos[&info.outpoint];
+			let script_pubkey = self.utxo_script_pubkey(utxo).ok_or(Error::MissingUtxoData)?;
+			entries.push(ListUnspentEntry {
+				txid: info.outpoint.txid,
+				vout: info.outpoint.vout,
+				address: info.address.map(|a| a.to_string()),
+				label: info.label,
+				script_pubkey: script_pubkey.as_bytes().to_hex(),
+				amount: info.value.as_sat() as f64 / 100_000_000.0,
+				confirmations: info.confirmations,
+				spendable: info.spendable,
+				// The wallet knows how to spend all its UTXOs.
+				solvable: true,
+				// All UTXOs are confirmed.
+				safe: true,
+			});
+		}
+		Ok(serde_json::to_string(&entries).expect("serializing can't fail"))
+	}
+
+	/// Lock the UTXO so that it's not selected automatically when creating txs.
+	/// It can still be spent by passing it explicitly.
+	pub fn lock_utxo(&mut self, outpoint: &OutPoint) -> Result<()> {
+		self.set_utxo_locked(outpoint, true)
+	}
+
+	/// Undo [lock_utxo].
+	pub fn unlock_utxo(&mut self, outpoint: &OutPoint) -> Result<()> {
+		self.set_utxo_locked(outpoint, false)
+	}
+
+	fn set_utxo_locked(&mut self, outpoint: &OutPoint, locked: bool) -> Result<()> {
+		self.version += 1;
+		match self.owned_utxos.get_mut(outpoint) {
+			Some(utxo) => {
+				utxo.locked = locked;
+				Ok(())
+			}
+			None => Err(Error::UtxoNotInWallet),
+		}
+	}
+
+	/// List the outpoints of all locked UTXOs, ordered by outpoint.
+	pub fn list_locked_utxos(&self) -> Vec<OutPoint> {
+		self.list_utxos().into_iter().filter(|u| u.locked).map(|u| u.outpoint).collect()
+	}
+
+	/// Take all events that happened since the last time events were taken.
+	pub fn take_events(&mut self) -> Vec<WalletEvent> {
+		self.version += 1;
+		::std::mem::replace(&mut self.events, Vec::new())
+	}
+
+	/// Put back events that were taken but could not be handled, so that they
+	/// are returned first by the next [take_events].
+	pub fn restore_events(&mut self, mut events: Vec<WalletEvent>) {
+		self.version += 1;
+		events.extend(self.events.drain(..));
+		self.events = events;
+	}
+
+	/// The labels attached to addresses, outputs and txs.
+	pub fn labels(&self) -> &Labels {
+		&self.labels
+	}
+
+	pub fn labels_mut(&mut self) -> &mut Labels {
+		self.version += 1;
+		&mut self.labels
+	}
+
+	/// Get a tx from either the history or the pending txs, with some details.
+	pub fn get_transaction(&self, txid: sha256d::Hash) -> Option<TxDetails> {
+		let entry = self.get_history_entry(txid);
+		let pending_tx = self.pending_txs.iter().find(|t| t.txid() == txid);
+		let tx = match (entry, pending_tx) {
+			(Some(entry), _) => &entry.tx,
+			(None, Some(tx)) => tx,
+			(None, None) => return None,
+		};
+
+		let tip = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		Some(TxDetails {
+			tx: tx,
+			entry: entry,
+			confirmations: entry.map(|e| (tip + 1).saturating_sub(e.height)).unwrap_or(0),
+			pending: pending_tx.is_some(),
+			our_outputs: tx
+				.output
+				.iter()
+				.enumerate()
+				.filter(|(_, o)| self.is_mine_script(&o.script_pubkey))
+				.map(|(i, _)| i as u32)
+				.collect(),
+		})
+	}
+
+	/// List the entries in the wallet's transaction history that match the filter,
+	/// in the order the transactions were processed.
+	pub fn list_transactions(&self, filter: &TxFilter) -> Vec<&TxEntry> {
+		// The history is ordered by height, so we can jump to the start
+		// of the height range and stop at the end of it.
+		let max_height = filter.max_height.unwrap_or(u32::max_value());
+		self.tx_history
+			.iter_from_height(filter.min_height.unwrap_or(0))
+			.take_while(|e| e.height <= max_height)
+			.filter(|e| filter.matches(e))
+			.skip(filter.offset)
+			.take(filter.limit.unwrap_or(usize::max_value()))
+			.collect()
+	}
+
+	/// Statistics of the wallet's UTXOs, addresses and history.
+	pub fn stats(&self) -> WalletStats {
+		let mut values: Vec<u64> = self.owned_utxos.values().map(|u| u.value.as_sat()).collect();
+		values.sort();
+		let total: u64 = values.iter().sum();
+		let median = match values.len() {
+			0 => 0,
+			n if n % 2 == 0 => (values[n / 2 - 1] + values[n / 2]) / 2,
+			n => values[n / 2],
+		};
+
+		let mut keychains = vec![Keychain::External];
+		if self.change_derivation_path.is_some() {
+			keychains.push(Keychain::Internal);
+		}
+		keychains.extend(self.branches.keys().map(|b| Keychain::Branch(*b)));
+
+		WalletStats {
+			utxo_count: values.len(),
+			total_value: Amount::from_sat(total),
+			average_value: Amount::from_sat(total / cmp::max(values.len() as u64, 1)),
+			median_value: Amount::from_sat(median),
+			derived_addresses: keychains.into_iter().map(|k| self.nb_sourced(k)).sum(),
+			used_addresses: self.script_usage.len(),
+			history_len: self.tx_history.len(),
+			last_activity_height: self.tx_history.iter().last().map(|e| e.height),
+		}
+	}
+
+	/// The balance after every block with txs of the wallet, computed from the
+	/// history. Funds that were not received through the history, like imported
+	/// UTXOs, are not included, so the balance can be negative.
+	pub fn balance_history(&self) -> Vec<BalancePoint> {
+		let mut points: Vec<BalancePoint> = Vec::new();
+		let mut balance = 0;
+		for entry in self.tx_history.iter() {
+			balance += entry.net_amount();
+			match points.last_mut() {
+				Some(point) if point.height == entry.height => {
+					point.delta += entry.net_amount();
+					point.balance = balance;
+				}
+				_ => points.push(BalancePoint {
+					height: entry.height,
+					delta: entry.net_amount(),
+					balance: balance,
+				}),
+			}
+		}
+		points
+	}
+
+	/// Check the wallet's state for inconsistencies: the UTXOs against their
+	/// outputs, the keys of the UTXOs and the script index against the keys
+	/// and the txs UTXOs are used in against the pending txs.
+	///
+	/// Use this before trusting state that was stored for a long time, and
+	/// [repair] to fix what can be fixed.
+	pub fn check_integrity(&self) -> Result<IntegrityReport> {
+		let mut report = IntegrityReport::default();
+		for (outpoint, utxo) in sorted_by_outpoint(&self.owned_utxos) {
+			report.utxos_checked += 1;
+			for txid in &utxo.used_in_tx {
+				if self.get_pending_transaction(*txid).is_none() {
+					report.issues.push(IntegrityIssue::StaleUtxoUsage(*outpoint, *txid));
+				}
+			}
+
+			let txout = match self.utxo_txout(utxo) {
+				Some(txout) => txout,
+				None => {
+					report.issues.push(IntegrityIssue::MissingUtxoData(*outpoint));
+					continue;
+				}
+			};
+			if utxo.value.as_sat() != txout.value {
+				report.issues.push(IntegrityIssue::UtxoValueMismatch(*outpoint));
+			}
+			if !utxo.external {
+				let matches = if self.has_generation(utxo.generation) {
+					let (pubkey, _, _) =
+						self.derive_key(utxo.generation, utxo.keychain, utxo.child_number)?;
+					AddressType::all_types()
+						.iter()
+						.any(|t| self.script_pubkey_for(&pubkey, *t) == txout.script_pubkey)
+				} else {
+					false
+				};
+				if !matches {
+					report.issues.push(IntegrityIssue::UtxoKeyMismatch(*outpoint));
+				}
+			}
+		}
+
+		for (hash, info) in &self.script_index {
+			report.scripts_checked += 1;
+			if self.indexed_script(info)?.map(|s| script_hash(&s)) != Some(*hash) {
+				report.issues.push(IntegrityIssue::ScriptIndexMismatch(*hash));
+			}
+		}
+		Ok(report)
+	}
+
+	/// Fix the issues found by [check_integrity] that can be fixed without
+	/// guessing: stale usages are removed, values are taken from the outputs,
+	/// index entries are moved to the hash of their script and UTXO keys are
+	/// taken from the index. Returns the report of the remaining issues.
+	pub fn repair(&mut self) -> Result<IntegrityReport> {
+		let report = self.check_integrity()?;
+		if report.is_ok() {
+			return Ok(report);
+		}
+		self.version += 1;
+
+		// First the index, it is used to fix the UTXO keys.
+		for issue in &report.issues {
+			if let IntegrityIssue::ScriptIndexMismatch(hash) = *issue {
+				let info = self.script_index.remove(&hash).expect("checked");
+				if let Some(script) = self.indexed_script(&info)? {
+					self.script_index.entry(script_hash(&script)).or_insert(info);
+				}
+			}
+		}
+		for issue in &report.issues {
+			match *issue {
+				IntegrityIssue::StaleUtxoUsage(outpoint, txid) => {
+					self.owned_utxos.get_mut(&outpoint).expect("checked").used_in_tx.remove(&txid);
+				}
+				IntegrityIssue::UtxoValueMismatch(outpoint) => {
+					let utxo = &self.owned_utxos[&outpoint];
+					let value = self.utxo_txout(utxo).expect("checked").value;
+					self.owned_utxos.get_mut(&outpoint).expect("checked").value =
+						Amount::from_sat(value);
+				}
+				IntegrityIssue::UtxoKeyMismatch(outpoint) => {
+					let utxo = &self.owned_utxos[&outpoint];
+					let script = self.utxo_script_pubkey(utxo).expect("checked");
+					if let Some(info) = self.script_index.get(&script_hash(script)).cloned() {
+						let utxo = self.owned_utxos.get_mut(&outpoint).expect("checked");
+						utxo.keychain = info.keychain;
+						utxo.child_number = info.child;
+						utxo.generation = info.generation;
+					}
+				}
+				IntegrityIssue::MissingUtxoData(_) | IntegrityIssue::ScriptIndexMismatch(_) => {}
+			}
+		}
+		self.check_integrity()
+	}
+
+	/// Export the changes after the block at the height, so that another
+	/// instance of the same wallet can catch up with [merge] without
+	/// processing the blocks. Pending txs, labels and the other metadata of
+	/// the wallet are not exported.
+	pub fn export_delta(&self, since_height: u32) -> WalletDelta {
+		let blocks = self.block_hashes.iter_from_height(since_height).map(|(h, hash)| KnownBlock {
+			height: h,
+			hash: hash,
+		});
+
+		let mut keychains = vec![Keychain::External];
+		if self.change_derivation_path.is_some() {
+			keychains.push(Keychain::Internal);
+		}
+		keychains.extend(self.branches.keys().map(|b| Keychain::Branch(*b)));
+		let mut last_sourced = Vec::new();
+		for keychain in keychains {
+			let nb_sourced = self.nb_sourced(keychain);
+			if nb_sourced > 0 {
+				let last = bip32::ChildNumber::from_normal_idx(nb_sourced - 1).expect("sourced");
+				last_sourced.push((keychain, last));
+			}
+		}
+
+		let mut new_utxos = Vec::new();
+		for (_, utxo) in sorted_by_outpoint(&self.owned_utxos) {
+			if utxo.height > since_height {
+				let mut utxo = utxo.clone();
+				// Our pending txs are not exported.
+				utxo.used_in_tx.clear();
+				new_utxos.push(utxo);
+			}
+		}
+
+		let history: Vec<TxEntry> =
+			self.tx_history.iter_from_height(since_height + 1).cloned().collect();
+		let spent_outpoints = history
+			.iter()
+			.flat_map(|e| e.tx.input.iter().map(|i| i.previous_output))
+			.collect();
+		WalletDelta {
+			xpub_fingerprint: self.extended_pubkey.fingerprint(),
+			generation: self.generation,
+			since_height: since_height,
+			blocks: blocks.collect(),
+			last_sourced: last_sourced,
+			new_utxos: new_utxos,
+			spent_outpoints: spent_outpoints,
+			history: history,
+		}
+	}
+
+	/// Merge the changes of another instance of the same wallet, exported with
+	/// [export_delta]. Merging is idempotent: merging a delta again, or one
+	/// that is already covered by the wallet's state, changes nothing.
+	///
+	/// Fails with [Error::InvalidWalletDelta] when the delta is of other keys,
+	/// of a conflicting chain or starts after the wallet's last block.
+	pub fn merge(&mut self, delta: &WalletDelta) -> Result<()> {
+		let height = match self.last_known_block {
+			Some(ref block) => block.height,
+			None => return Err(Error::WalletNotFullyInitialized),
+		};
+		let other_keys = delta.xpub_fingerprint != self.extended_pubkey.fingerprint();
+		if other_keys || delta.generation != self.generation {
+			return Err(Error::InvalidWalletDelta("other keys"));
+		}
+		if delta.since_height > height {
+			return Err(Error::InvalidWalletDelta("delta starts after the last block"));
+		}
+		for block in &delta.blocks {
+			if self.block_hashes.get(block.height).map(|h| *h != block.hash).unwrap_or(false) {
+				return Err(Error::InvalidWalletDelta("conflicting chain"));
+			}
+		}
+		self.version += 1;
+
+		for &(keychain, last) in &delta.last_sourced {
+			if let Keychain::Branch(index) = keychain {
+				if !self.branches.contains_key(&index) {
+					self.create_branch(index, None)?;
+				}
+			}
+			let nb_sourced = self.nb_sourced(keychain);
+			if nb_sourced > u32::from(last) {
+				continue;
+			}
+			for idx in nb_sourced..=u32::from(last) {
+				self.index_script_pubkeys(keychain, bip32::ChildNumber::from_normal_idx(idx)?);
+			}
+			self.set_last_sourced_child(keychain, Some(last));
+		}
+
+		for entry in &delta.history {
+			let txid = entry.txid();
+			if self.tx_history.insert(entry.clone()) {
+				self.pending_txs.retain(|tx| tx.txid() != txid);
+				self.pending_heights.remove(&txid);
+				self.pending_times.remove(&txid);
+				self.broadcasts.remove(&txid);
+				self.unconfirmed_change.retain(|o, _| o.txid != txid);
+			}
+		}
+
+		let spent_outpoints: HashSet<OutPoint> = delta.spent_outpoints.iter().cloned().collect();
+		for outpoint in &spent_outpoints {
+			if let Some(utxo) = self.owned_utxos.remove(outpoint) {
+				if let Some(script) = self.utxo_script_pubkey(&utxo).cloned() {
+					if let Some(usage) = self.script_usage.get_mut(&script) {
+						*usage = usage.saturating_sub(1);
+					}
+				}
+			}
+			self.watched_utxos.remove(outpoint);
+		}
+		for utxo in &delta.new_utxos {
+			let outpoint = utxo.outpoint;
+			if self.owned_utxos.contains_key(&outpoint) || spent_outpoints.contains(&outpoint) {
+				continue;
+			}
+			// We might have spent it in a block the delta doesn't have.
+			let spent = self.tx_history.iter_from_height(utxo.height).any(|e| {
+				e.tx.input.iter().any(|i| i.previous_output == outpoint)
+			});
+			if !spent {
+				if let Some(script) = self.utxo_script_pubkey(utxo).cloned() {
+					*self.script_usage.entry(script).or_insert(0) += 1;
+				}
+				self.owned_utxos.insert(outpoint, utxo.clone());
+			}
+		}
+
+		for block in &delta.blocks {
+			self.block_hashes.insert(block.height, block.hash);
+			if block.height > height {
+				self.last_known_block = Some(block.clone());
+			}
+		}
+		Ok(())
+	}
+
+	/// Commit to the tx by considering the UTXOs it spends as used in the tx.
+	/// The tx will also be kept as pending.
+	/// No check is done to prevent adding the same tx twice.
+	pub fn commit_transaction(&mut self, tx: Transaction) {
+		self.version += 1;
+		let txid = tx.txid();
+		for input in &tx.input {
+			let prev = &input.previous_output;
+			if let Some(utxo) = self.owned_utxos.get_mut(prev) {
+				utxo.used_in_tx.insert(txid);
+			} else if let Some(utxo) = self.unconfirmed_change.get_mut(prev) {
+				utxo.used_in_tx.insert(txid);
+			}
+		}
+		for (idx, output) in tx.output.iter().enumerate() {
+			if let Some(&info) = self.script_index.get(&script_hash(&output.script_pubkey)) {
+				let outpoint = OutPoint {
+					txid: txid,
+					vout: idx as u32,
+				};
+				self.unconfirmed_change.insert(
+					outpoint,
+					Utxo {
+						outpoint: outpoint,
+						value: Amount::from_sat(output.value),
+						height: 0,
+						keychain: info.keychain,
+						child_number: info.child,
+						generation: info.generation,
+						used_in_tx: BTreeSet::new(),
+						locked: false,
+						prevout: Some(output.clone()),
+						prev_tx: full_prev_tx(output, &tx),
+						external: false,
+						input_kind: None,
+						silent_payment_tweak: None,
+						timelock: None,
+						coinbase: false,
+					},
+				);
+			}
+		}
+		let height = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		self.pending_heights.insert(txid, height);
+		// Without a clock, the tx only expires after a number of blocks.
+		if let Some(now) = self.now() {
+			self.pending_times.insert(txid, now);
+		}
+		self.pending_txs.push(tx);
+		self.check_balance_thresholds();
+	}
+
+	/// Drop a transaction that is considered pending by the wallet.
+	/// This also frees the UTXOs the transaction was spending to being used
+	/// again in new txs. Pending txs spending its change are dropped too.
+	pub fn drop_pending_transaction(&mut self, txid: sha256d::Hash) -> bool {
+		self.version += 1;
+		for (_, utxo) in self.owned_utxos.iter_mut() {
+			utxo.used_in_tx.remove(&txid);
+		}
+		for (_, utxo) in self.unconfirmed_change.iter_mut() {
+			utxo.used_in_tx.remove(&txid);
+		}
+		self.unconfirmed_change.retain(|o, _| o.txid != txid);
+		let children: Vec<sha256d::Hash> = self
+			.pending_txs
+			.iter()
+			.filter(|tx| tx.input.iter().any(|i| i.previous_output.txid == txid))
+			.map(|tx| tx.txid())
+			.collect();
+		for child in children {
+			self.drop_pending_transaction(child);
+		}
+
+		self.pending_heights.remove(&txid);
+		self.pending_times.remove(&txid);
+		self.broadcasts.remove(&txid);
+		let len_before = self.pending_txs.len();
+		self.pending_txs.retain(|tx| tx.txid() != txid);
+		self.pending_txs.len() < len_before
+	}
+
+	/// Find the linkage cluster of each of our scripts that ever received funds,
+	/// based on the txs in the history. Returns the cluster index per script.
+	fn script_clusters(&self) -> HashMap<&Script, usize> {
+		// A simple union-find over script indices.
+		fn find(parents: &mut [usize], mut i: usize) -> usize {
+			while parents[i] != i {
+				parents[i] = parents[parents[i]];
+				i = parents[i];
+			}
+			i
+		}
+
+		let mut indices: HashMap<&Script, usize> = HashMap::new();
+		let mut parents: Vec<usize> = Vec::new();
+		for entry in self.tx_history.iter() {
+			// Our scripts spent by this tx and our outputs of it, all linked.
+			let mut linked: Vec<&Script> = entry
+				.tx
+				.input
+				.iter()
+				.filter_map(|i| self.get_history_txout(&i.previous_output))
+				.map(|o| &o.script_pubkey)
+				.filter(|s| self.is_mine_script(*s))
+				.collect();
+			if linked.is_empty() {
+				// Only receiving doesn't link anything.
+				continue;
+			}
+			linked.extend(
+				entry
+					.tx
+					.output
+					.iter()
+					.map(|o| &o.script_pubkey)
+					.filter(|s| self.is_mine_script(*s)),
+			);
+
+			let mut root = None;
+			for script in linked {
+				let idx = *indices.entry(script).or_insert_with(|| {
+					parents.push(parents.len());
+					parents.len() - 1
+				});
+				let idx_root = find(&mut parents, idx);
+				match root {
+					None => root = Some(idx_root),
+					Some(r) => parents[idx_root] = r,
+				}
+			}
+		}
+
+		let mut ret = HashMap::new();
+		for (script, idx) in indices {
+			ret.insert(script, find(&mut parents, idx));
+		}
+		ret
+	}
+
+	/// Set the accounting tag of the history entry of the tx, overriding the
+	/// default tag. See [accounting::default_tag].
+	pub fn set_accounting_tag(&mut self, txid: sha256d::Hash, tag: AccountingTag) -> Result<()> {
+		if self.get_history_entry(txid).is_none() {
+			return Err(Error::TxNotInHistory);
+		}
+		self.version += 1;
+		self.accounting_tags.insert(txid, tag);
+		Ok(())
+	}
+
+	/// Remove the explicit accounting tag of the entry, reverting to the default.
+	pub fn clear_accounting_tag(&mut self, txid: sha256d::Hash) -> bool {
+		self.version += 1;
+		self.accounting_tags.remove(&txid).is_some()
+	}
+
+	/// The accounting tag of the history entry of the tx.
+	pub fn accounting_tag(&self, txid: sha256d::Hash) -> Option<AccountingTag> {
+		let entry = self.get_history_entry(txid)?;
+		Some(self.entry_accounting_tag(entry))
+	}
+
+	fn entry_accounting_tag(&self, entry: &TxEntry) -> AccountingTag {
+		match self.accounting_tags.get(&entry.txid()) {
+			Some(tag) => tag.clone(),
+			None => accounting::default_tag(entry, |s| self.is_mine_script(s)),
+		}
+	}
+
+	/// Group the wallet's history into accounting lots.
+	/// See the [accounting] module.
+	pub fn accounting_lots(&self) -> Vec<AccountingLot> {
+		accounting::lots(
+			self.tx_history.iter(),
+			|s| self.is_mine_script(s),
+			|e| self.entry_accounting_tag(e),
+		)
+	}
+
+	/// Export the accounting lots as JSON, for use in accounting tools.
+	pub fn export_accounting_lots(&self) -> String {
+		serde_json::to_string(&self.accounting_lots()).expect("serializing can't fail")
+	}
+
+	/// Write the history entries matching the filter of the options as CSV,
+	/// one row per entry with the columns in [accounting::CSV_COLUMNS]. The
+	/// amount is the absolute net amount, the fee is only set for outgoing
+	/// txs of which it is known. Returns the number of written entries.
+	pub fn export_history_csv<W: io::Write>(
+		&self,
+		writer: &mut W,
+		options: &CsvOptions,
+	) -> Result<usize> {
+		let delimiter = options.delimiter.to_string();
+		let mut rows = Vec::new();
+		if options.header {
+			rows.push(accounting::CSV_COLUMNS.join(&delimiter));
+		}
+		let entries = self.list_transactions(&options.filter);
+		for entry in &entries {
+			let txid = entry.txid();
+			let direction = entry.direction();
+			let fee = match (direction, entry.fee) {
+				(TxDirection::Outgoing, Some(fee)) => Some(fee),
+				_ => None,
+			};
+			let fee = fee.map(|f| accounting::csv_amount(f.as_sat(), options.btc_amounts));
+			let fee = fee.unwrap_or_default();
+			let label = self.labels.get(&LabelRef::Tx(txid)).map(|l| &l.label[..]).unwrap_or("");
+			let fields = [
+				txid.to_string(),
+				entry.height.to_string(),
+				entry.timestamp.to_string(),
+				match direction {
+					TxDirection::Incoming => "incoming".to_owned(),
+					TxDirection::Outgoing => "outgoing".to_owned(),
+				},
+				accounting::csv_amount(entry.net_amount().abs() as u64, options.btc_amounts),
+				fee,
+				accounting::csv_field(label, options.delimiter),
+			];
+			rows.push(fields.join(&delimiter));
+		}
+		for row in rows {
+			writeln!(writer, "{}", row).map_err(|e| Error::Io(e.to_string()))?;
+		}
+		Ok(entries.len())
+	}
+
+	/// Group the wallet's UTXOs into clusters of UTXOs that are linked by
+	/// past txs of the wallet. UTXOs of scripts that were never spent from
+	/// form a cluster per script.
+	pub fn coin_clusters(&self) -> Vec<CoinCluster> {
+		let script_clusters = self.script_clusters();
+		let mut clusters: Vec<CoinCluster> = Vec::new();
+		// Cluster keys are either a cluster index or an unlinked script.
+		let mut cluster_idx: HashMap<Result<usize, &Script>, usize> = HashMap::new();
+		let mut utxos: Vec<&Utxo> = self.owned_utxos.values().collect();
+		utxos.sort_by_key(|u| (u.height, u.outpoint.txid, u.outpoint.vout));
+		for utxo in utxos {
+			let script = match self.utxo_script_pubkey(utxo) {
+				Some(script) => script,
+				None => continue,
+			};
+			let key = script_clusters.get(script).cloned().ok_or(script);
+			let idx = *cluster_idx.entry(key).or_insert_with(|| {
+				clusters.push(CoinCluster {
+					utxos: Vec::new(),
+					value: Amount::from_sat(0),
+				});
+				clusters.len() - 1
+			});
+			clusters[idx].utxos.push(utxo.outpoint);
+			clusters[idx].value += utxo.value;
+		}
+		clusters
+	}
+
+	/// The clusters of [coin_clusters] of which the tx spends UTXOs. When it's
+	/// more than one, signing the tx links these clusters together.
+	pub fn clusters_merged_by(&self, tx: &Transaction) -> Vec<CoinCluster> {
+		self.coin_clusters()
+			.into_iter()
+			.filter(|c| c.utxos.iter().any(|o| tx.input.iter().any(|i| i.previous_output == *o)))
+			.collect()
+	}
+
+	/// Drop the pending tx like [drop_pending_transaction] and also release
+	/// the addresses of its change outputs, so that they are handed out again.
+	/// This is only possible for change addresses that were the last ones handed
+	/// out from their keychain.
+	pub fn abandon_transaction(&mut self, txid: sha256d::Hash) -> bool {
+		let tx = match self.get_pending_transaction(txid) {
+			Some(tx) => tx.clone(),
+			None => return false,
+		};
+		self.drop_pending_transaction(txid);
+
+		let keychain = self.change_keychain();
+		let mut change_children: Vec<bip32::ChildNumber> = tx
+			.output
+			.iter()
+			.filter_map(|o| self.script_index.get(&script_hash(&o.script_pubkey)))
+			.filter(|i| i.keychain == keychain && i.generation == self.generation)
+			.map(|i| i.child)
+			.collect();
+		change_children.sort_by_key(|c| cmp::Reverse(u32::from(*c)));
+		for child in change_children {
+			if *self.last_sourced_child_mut(keychain) != Some(child)
+				|| self.is_child_used(keychain, child)
+			{
+				break;
+			}
+			let previous = match u32::from(child) {
+				0 => None,
+				i => Some(bip32::ChildNumber::from_normal_idx(i - 1).unwrap()),
+			};
+			self.set_last_sourced_child(keychain, previous);
+		}
+		true
+	}
+
+	/// Drop the pending txs that didn't confirm in time according to the config.
+	/// This is done automatically for every processed block.
+	pub fn expire_pending_transactions(&mut self) -> Vec<sha256d::Hash> {
+		let policy = self.config.pending_expiry;
+		let height = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		let now = self.now();
+		let expired: Vec<sha256d::Hash> = self
+			.pending_txs
+			.iter()
+			.map(|tx| tx.txid())
+			.filter(|txid| {
+				let blocks = match (policy.max_blocks, self.pending_heights.get(txid)) {
+					(Some(max), Some(&since)) => height.saturating_sub(since) > max,
+					_ => false,
+				};
+				let time = match (policy.max_seconds, self.pending_times.get(txid), now) {
+					(Some(max), Some(&since), Some(now)) => now.saturating_sub(since) > max,
+					_ => false,
+				};
+				blocks || time
+			})
+			.collect();
+
+		for txid in &expired {
+			self.drop_pending_transaction(*txid);
+			self.events.push(WalletEvent::PendingTxExpired {
+				txid: *txid,
+			});
+		}
+		expired
+	}
+
+	/// Shrink the wallet's state by dropping data of txs confirmed below the
+	/// height that is no longer needed. Returns the number of pruned txs.
+	///
+	/// - The inputs of txs of which no outputs are unspent UTXOs of the wallet
+	///   and that are only spent by txs below the height are dropped. Their
+	///   entries stay in the history with their amounts, fee and outputs, see
+	///   [TxEntry::is_pruned].
+	/// - Conflicted txs of which the conflicting tx was confirmed are dropped.
+	///
+	/// Pruned txs can't be disconnected from the chain anymore and their
+	/// inputs are not considered in [coin_clusters] and [accounting_lots].
+	/// The height should be far below the tip, so that the blocks won't be
+	/// reorganized.
+	pub fn compact(&mut self, keep_since_height: u32) -> usize {
+		self.version += 1;
+		// The txs spending outputs of txs that are kept. Their outputs are
+		// needed to restore the UTXOs when the spending tx is disconnected.
+		let mut spent_from_kept = HashSet::new();
+		for entry in self.tx_history.iter_from_height(keep_since_height) {
+			spent_from_kept.extend(entry.tx.input.iter().map(|i| i.previous_output.txid));
+		}
+
+		let mut prunable = Vec::new();
+		for entry in self.tx_history.iter() {
+			if entry.height >= keep_since_height {
+				break;
+			}
+			if entry.is_pruned() {
+				continue;
+			}
+			let txid = entry.txid();
+			let unspent = (0..entry.tx.output.len() as u32).any(|vout| {
+				let outpoint = OutPoint {
+					txid: txid,
+					vout: vout,
+				};
+				self.owned_utxos.contains_key(&outpoint)
+					|| self.watched_utxos.contains_key(&outpoint)
+			});
+			if !unspent && !spent_from_kept.contains(&txid) {
+				prunable.push(txid);
+			}
+		}
+		for txid in &prunable {
+			let entry = self.tx_history.get_mut(txid).expect("txid from history");
+			entry.pruned_txid = Some(*txid);
+			entry.pruned_coinbase = entry.tx.is_coin_base();
+			entry.tx.input = Vec::new();
+		}
+
+		self.prune_conflicted_transactions(keep_since_height);
+		prunable.len()
+	}
+
+	/// Get the pending tx with the given txid.
+	pub fn get_pending_transaction(&self, txid: sha256d::Hash) -> Option<&Transaction> {
+		self.pending_txs.iter().find(|tx| tx.txid() == txid)
+	}
+
+	/// Get the status of the pending tx with the given txid.
+	pub fn pending_status(&self, txid: sha256d::Hash) -> Option<PendingStatus> {
+		let tx = self.get_pending_transaction(txid)?;
+
+		let mut inputs_held = Vec::new();
+		let mut total_in = Some(0);
+		for input in &tx.input {
+			match self.owned_utxos.get(&input.previous_output) {
+				Some(utxo) => {
+					if utxo.used_in_tx.contains(&txid) {
+						inputs_held.push(utxo.outpoint);
+					}
+					total_in = total_in.map(|t| t + utxo.value.as_sat());
+				}
+				None => total_in = None,
+			}
+		}
+		let total_out: u64 = tx.output.iter().map(|o| o.value).sum();
+		let fee = total_in.and_then(|t| t.checked_sub(total_out));
+		let input_kinds: Vec<InputKind> = tx
+			.input
+			.iter()
+			.map(|i| match self.owned_utxos.get(&i.previous_output) {
+				Some(utxo) => self.utxo_input_kind(utxo).unwrap_or(InputKind::P2wpkh),
+				// Foreign inputs of unknown kind, like P2SH, are estimated as P2WPKH.
+				None => self
+					.get_history_txout(&i.previous_output)
+					.and_then(|o| InputKind::from_script(&o.script_pubkey))
+					.unwrap_or(InputKind::P2wpkh),
+			})
+			.collect();
+		let weight = fee::estimate_weight_for_inputs(&input_kinds, &tx.output);
+		let vsize = fee::weight_to_vsize(weight);
+
+		let conflicts = self
+			.pending_txs
+			.iter()
+			.filter(|other| other.txid() != txid)
+			.filter(|other| {
+				other.input.iter().any(|i| {
+					tx.input.iter().any(|our| our.previous_output == i.previous_output)
+				})
+			})
+			.map(|other| other.txid())
+			.collect();
+
+		let current_height = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		let committed_height = self.pending_heights.get(&txid).cloned().unwrap_or(current_height);
+		Some(PendingStatus {
+			inputs_held: inputs_held,
+			age: current_height.saturating_sub(committed_height),
+			fee: fee.map(Amount::from_sat),
+			fee_rate: fee.map(|f| f as f64 / vsize as f64),
+			replaceable: tx.input.iter().any(|i| i.sequence < 0xFFFFFFFE),
+			conflicts: conflicts,
+			broadcasts: self.broadcasts.get(&txid).cloned().unwrap_or_default(),
+		})
+	}
+
+	/// Record a broadcast of the pending tx to the backend and whether the
+	/// backend acknowledged it, so that a tx that was never sent can be told
+	/// apart from one that was sent but doesn't propagate.
+	pub fn record_broadcast(
+		&mut self,
+		txid: sha256d::Hash,
+		backend: String,
+		acknowledged: bool,
+		message: Option<String>,
+	) -> Result<()> {
+		if self.get_pending_transaction(txid).is_none() {
+			return Err(Error::TxNotPending);
+		}
+		let time = self.now().ok_or(Error::NoClock)?;
+		self.version += 1;
+		self.broadcasts.entry(txid).or_insert_with(Vec::new).push(BroadcastRecord {
+			backend: backend,
+			time: time,
+			acknowledged: acknowledged,
+			message: message,
+		});
+		Ok(())
+	}
+
+	/// Broadcast the signed tx and commit it once the broadcast succeeded.
+	/// Returns the txid.
+	///
+	/// When the tx was already committed, f.e. when rebroadcasting it, the
+	/// broadcast is recorded, also when it fails. The tx is then kept, it might
+	/// be in the mempool already. Use [drop_pending_transaction] to spend its
+	/// inputs again.
+	pub fn commit_on_broadcast<B: Broadcaster>(
+		&mut self,
+		tx: Transaction,
+		broadcaster: &B,
+	) -> Result<sha256d::Hash> {
+		let txid = tx.txid();
+		let committed = self.get_pending_transaction(txid).is_some();
+		let result = broadcaster.broadcast(&tx).and_then(|acked| {
+			if acked != txid {
+				return Err(Error::Backend(format!("backend broadcast tx {}", acked)));
+			}
+			Ok(txid)
+		});
+		match result {
+			Ok(_) => {
+				if !committed {
+					self.commit_transaction(tx);
+				}
+				self.record_broadcast(txid, broadcaster.name(), true, None)?;
+			}
+			Err(ref e) if committed => {
+				self.record_broadcast(txid, broadcaster.name(), false, Some(e.to_string()))?;
+			}
+			Err(_) => {}
+		}
+		result
+	}
+
+	/// Sign the PSBT of a created tx, finalize it and broadcast it with
+	/// [commit_on_broadcast]. The tx can be created with or without
+	/// [TxOptions::commit].
+	///
+	/// When signing or the broadcast fails, a tx that was never broadcast is
+	/// dropped again, so that its inputs can be spent by other txs. A tx that
+	/// was broadcast before is kept and the failed broadcast is recorded.
+	pub fn sign_and_broadcast<B: Broadcaster>(
+		&mut self,
+		mut psbt: psbt::PartiallySignedTransaction,
+		signer: &mut HotSigner,
+		broadcaster: &B,
+	) -> Result<sha256d::Hash> {
+		let txid = psbt.global.unsigned_tx.txid();
+		let broadcast_before = self.broadcasts.contains_key(&txid);
+		let signed = signer.sign_psbt(&mut psbt).and_then(|_| signer::finalize_psbt(&psbt));
+		let result = match signed {
+			Ok(tx) => self.commit_on_broadcast(tx, broadcaster),
+			Err(e) => Err(e),
+		};
+		if result.is_err() && !broadcast_before {
+			self.drop_pending_transaction(txid);
+		}
+		result
+	}
+
+	/// The last recorded broadcast of the pending tx.
+	pub fn last_broadcast(&self, txid: sha256d::Hash) -> Option<&BroadcastRecord> {
+		self.broadcasts.get(&txid).and_then(|b| b.last())
+	}
+
+	/// Guard against paying absurd fees, like when mixing up units.
+	fn check_fee_limits(&self, fee: u64, weight: u64) -> Result<()> {
+		if let Some(max_fee) = self.config.max_absolute_fee {
+			if fee > max_fee {
+				return Err(Error::FeeTooHigh(fee));
+			}
+		}
+		if let Some(max_rate) = self.config.max_fee_rate {
+			let vsize = fee::weight_to_vsize(weight);
+			if fee > max_rate * vsize {
+				return Err(Error::FeeRateTooHigh(fee / cmp::max(vsize, 1)));
+			}
+		}
+		Ok(())
+	}
+
+	/// The indices of the UTXOs that are spent along with the one at [idx].
+	/// With [WalletConfig::avoid_partial_spends], these are all UTXOs paying
+	/// the same script.
+	fn spend_group(&self, utxos: &[&Utxo], idx: usize) -> Vec<usize> {
+		let script = match self.utxo_script_pubkey(utxos[idx]) {
+			Some(script) if self.config.avoid_partial_spends => script,
+			_ => return vec![idx],
+		};
+		(0..utxos.len())
+			.filter(|i| self.utxo_script_pubkey(utxos[*i]) == Some(script))
+			.collect()
+	}
+
+	/// The output of the UTXO, if known.
+	fn utxo_txout<'a>(&'a self, utxo: &'a Utxo) -> Option<&'a TxOut> {
+		utxo.prevout.as_ref().or_else(|| self.get_history_txout(&utxo.outpoint))
+	}
+
+	/// The script the UTXO pays to, if the output is known.
+	fn utxo_script_pubkey<'a>(&'a self, utxo: &'a Utxo) -> Option<&'a Script> {
+		self.utxo_txout(utxo).map(|o| &o.script_pubkey)
+	}
+
+	/// The PSBT input data needed to sign an input spending the UTXO.
+	fn psbt_input(&self, utxo: &Utxo) -> Result<psbt::Input> {
+		let prev = self.utxo_txout(utxo).ok_or(Error::MissingUtxoData)?;
+		let mut psbt_input = psbt::Input::default();
+		if utxo.external {
+			// We don't know the key, so we can't provide more info.
+			psbt_input.witness_utxo = Some(prev.clone());
+			if let Some(tweak) = utxo.silent_payment_tweak {
+				psbt_input.unknown.insert(silent_payments::psbt_tweak_key(), tweak.to_vec());
+			}
+			return Ok(psbt_input);
+		}
+
+		let (pubkey, master_fp, path) =
+			self.derive_key(utxo.generation, utxo.keychain, utxo.child_number)?;
+		psbt_input.hd_keypaths.insert(pubkey, (master_fp, path));
+		let prev_tx = utxo.prev_tx.as_ref().or_else(|| {
+			self.get_history_entry(utxo.outpoint.txid).filter(|e| !e.is_pruned()).map(|e| &e.tx)
+		});
+		match (self.utxo_address_type(utxo), prev_tx) {
+			(Some(AddressType::P2pkh), Some(tx)) => {
+				psbt_input.non_witness_utxo = Some(tx.clone());
+			}
+			(Some(AddressType::P2pkh), None) => return Err(Error::MissingUtxoData),
+			(Some(AddressType::P2shP2wpkh), _) => {
+				let redeem_addr = Address::p2wpkh(&pubkey, self.config.network);
+				psbt_input.redeem_script = Some(redeem_addr.script_pubkey());
+				psbt_input.witness_utxo = Some(prev.clone());
+			}
+			_ => psbt_input.witness_utxo = Some(prev.clone()),
+		}
+		Ok(psbt_input)
+	}
+
+	/// - At most one change output is created per given change child.
+	/// - This method does not commit the tx inputs.
+	fn create_transaction_with_change(
+		&mut self,
+		mut outputs: Vec<TxOut>,
+		use_inputs: Vec<OutPoint>,
+		change_children: &[bip32::ChildNumber],
+		fee: Fee,
+		options: &TxOptions,
+	) -> Result<CreatedTx> {
+		let mut rng = self.rng()?;
+
+		let keep_order = options.keep_order();
+		for (outpoint, sighash_type) in &options.sighash_types {
+			let idx = match use_inputs.iter().position(|o| o == outpoint) {
+				Some(idx) => idx,
+				None => return Err(Error::InvalidTxOptions("sighash type for an input not given")),
+			};
+			if sighash_type.as_u32() & 0x1f == 0x03 && idx >= outputs.len() {
+				return Err(Error::InvalidTxOptions("SIGHASH_SINGLE input without output"));
+			}
+		}
+		if options.no_change && options.drain_to.is_some() {
+			return Err(Error::InvalidTxOptions("drain script given without change"));
+		}
+		for (outpoint, sequence) in &options.sequences {
+			if !use_inputs.contains(outpoint) {
+				return Err(Error::InvalidTxOptions("sequence for an input not given"));
+			}
+			// Sequences without the disable flag are relative locktimes.
+			if sequence & (1 << 31) == 0 && options.version < 2 {
+				return Err(Error::InvalidTxOptions("relative locktime needs tx version 2"));
+			}
+		}
+
+		// Check all given inputs.
+		let mut total_in = 0;
+		let mut in_weight = 0;
+		let mut in_utxos = HashMap::new();
+		let mut foreign_kinds = HashMap::new();
+		let tip = self.last_known_block.as_ref().map(|b| b.height).unwrap_or(0);
+		for outpoint in &use_inputs {
+			if let Some(utxo) = self.get_utxo(outpoint) {
+				if !utxo.is_mature(tip) {
+					return Err(Error::UtxoTimelocked);
+				}
+				if in_utxos.insert(outpoint, utxo).is_some() {
+					return Err(Error::DuplicateUtxo);
+				}
+				total_in += utxo.value.as_sat();
+				in_weight += self.utxo_input_kind(utxo)?.weight();
+			} else if let Some(foreign) = options.foreign_input(outpoint) {
+				let (value, kind) = foreign?;
+				if foreign_kinds.insert(outpoint, kind).is_some() {
+					return Err(Error::DuplicateUtxo);
+				}
+				total_in += value;
+				in_weight += kind.weight();
+			} else {
+				return Err(Error::UtxoNotInWallet);
+			}
+		}
+		let (foreign_total, foreign_weight) = (total_in, in_weight);
+
+		// Count the total output value and check standardness.
+		let mut total_out = 0;
+		for output in &outputs {
+			if output.value < self.config.min_value_for_script(&output.script_pubkey) {
+				return Err(Error::DustOutput);
+			}
+			total_out += output.value;
+		}
+
+		// Determine the change scripts up front, their size influences the fee.
+		let change_policy = options.change_type.unwrap_or(self.config.change_type);
+		let change_type = match options.change_address_type {
+			Some(address_type) => address_type,
+			None => self.change_address_type(change_policy, &outputs),
+		};
+		let change_keychain = self.change_keychain();
+		let change_scripts: Vec<Script> = match options.drain_to {
+			Some(ref script) => vec![script.clone()],
+			None => change_children
+				.iter()
+				.map(|c| self.get_address(change_keychain, *c, change_type).script_pubkey())
+				.collect(),
+		};
+		let change_weight = if options.no_change {
+			0
+		} else {
+			change_scripts.first().map(|s| fee::txout_weight(s)).unwrap_or(0)
+		};
+		let outputs_weight: u64 = outputs.iter().map(|o| fee::txout_weight(&o.script_pubkey)).sum();
+		// The fee to pay for a tx with inputs of the given weight and a change output.
+		let required_fee = |in_weight: u64| {
+			let weight = fee::TX_BASE_WEIGHT + in_weight + outputs_weight + change_weight;
+			(fee.for_weight(weight), weight)
+		};
+		let max_weight = self.config.max_tx_weight.unwrap_or(u64::max_value());
+		let fits_weight = |in_weight: u64| required_fee(in_weight).1 <= max_weight;
+		let min_change = match change_scripts.first() {
+			Some(script) => self.config.min_value_for_script(script),
+			None => u64::max_value(),
+		};
+
+		// Add random extra inputs from our own UTXOs until sufficient.
+		if total_out + required_fee(in_weight).0 > total_in {
+			// To do this more efficiently, we keep a vector of the
+			// remaining UTXOs in the wallet.
+			let mut remaining_utxos: Vec<&Utxo> = self
+				.selectable_utxos()
+				.into_iter()
+				.filter(|u| !in_utxos.contains_key(&u.outpoint))
+				.collect();
+
+			// With a long-term feerate, take the candidate input set with the
+			// least waste. Without a fitting candidate, random selection below
+			// takes care of the errors.
+			let long_term_rate = self.config.long_term_fee_rate;
+			if let (Some(long_term_rate), Fee::Rate(rate)) = (long_term_rate, fee) {
+				let value = |i: &usize| remaining_utxos[*i].value;
+				let rate = rate.as_sat_per_vb();
+				let mut orders: Vec<Vec<usize>> = vec![(0..remaining_utxos.len()).collect(); 3];
+				rng.shuffle(&mut orders[0]);
+				orders[1].sort_by_key(|i| cmp::Reverse(value(i)));
+				orders[2].sort_by_key(|i| value(i));
+
+				let mut best: Option<(i64, Vec<usize>)> = None;
+				for order in orders {
+					let mut selected = Vec::new();
+					let (mut sel_in, mut sel_weight) = (total_in, in_weight);
+					for idx in order {
+						if total_out + required_fee(sel_weight).0 <= sel_in {
+							break;
+						}
+						for i in self.spend_group(&remaining_utxos, idx) {
+							if !selected.contains(&i) {
+								let utxo = remaining_utxos[i];
+								sel_in += utxo.value.as_sat();
+								sel_weight += self.utxo_input_weight(utxo);
+								selected.push(i);
+							}
+						}
+					}
+					let sel_fee = required_fee(sel_weight).0;
+					if total_out + sel_fee > sel_in || !fits_weight(sel_weight) {
+						continue;
+					}
+					let excess = sel_in - total_out - sel_fee;
+					let change_cost = if excess >= min_change {
+						Some(fee.for_weight(change_weight))
+					} else {
+						None
+					};
+					let waste = selection_waste(
+						rate,
+						long_term_rate,
+						sel_weight - in_weight,
+						change_cost,
+						excess,
+					);
+					if best.as_ref().map(|b| waste < b.0).unwrap_or(true) {
+						best = Some((waste, selected));
+					}
+				}
+
+				if let Some((_, mut selected)) = best {
+					// Remove from the back so that the other indices stay valid.
+					selected.sort();
+					for idx in selected.into_iter().rev() {
+						let utxo = remaining_utxos.remove(idx);
+						total_in += utxo.value.as_sat();
+						in_weight += self.utxo_input_weight(utxo);
+						in_utxos.insert(&utxo.outpoint, utxo);
+					}
+				}
+			}
+
+			while total_out + required_fee(in_weight).0 > total_in {
+				if remaining_utxos.is_empty() {
+					return Err(Error::InsufficientFunds);
+				}
+
+				let rand_idx = rng.gen_range(0, remaining_utxos.len());
+				let group_idxs = self.spend_group(&remaining_utxos, rand_idx);
+				let group_weight: u64 = group_idxs
+					.iter()
+					.map(|i| self.utxo_input_weight(remaining_utxos[*i]))
+					.sum();
+				if !fits_weight(in_weight + group_weight) {
+					// Random selection hits the maximum weight, so select the
+					// largest coins instead to minimize the number of inputs.
+					in_utxos.retain(|o, _| use_inputs.contains(*o));
+					total_in =
+						foreign_total + in_utxos.values().map(|u| u.value.as_sat()).sum::<u64>();
+					in_weight = foreign_weight
+						+ in_utxos.values().map(|u| self.utxo_input_weight(u)).sum::<u64>();
+					let mut largest: Vec<&Utxo> = self
+						.selectable_utxos()
+						.into_iter()
+						.filter(|u| !in_utxos.contains_key(&u.outpoint))
+						.collect();
+					largest.sort_by_key(|u| {
+						(cmp::Reverse(u.value), u.outpoint.txid, u.outpoint.vout)
+					});
+					for idx in 0..largest.len() {
+						if in_utxos.contains_key(&largest[idx].outpoint) {
+							continue;
+						}
+						let group: Vec<&Utxo> =
+							self.spend_group(&largest, idx).iter().map(|i| largest[*i]).collect();
+						let group_weight: u64 =
+							group.iter().map(|u| self.utxo_input_weight(u)).sum();
+						if total_out + required_fee(in_weight).0 <= total_in
+							|| !fits_weight(in_weight + group_weight)
+						{
+							break;
+						}
+						for utxo in group {
+							total_in += utxo.value.as_sat();
+							in_weight += self.utxo_input_weight(utxo);
+							in_utxos.insert(&utxo.outpoint, utxo);
+						}
+					}
+
+					if total_out + required_fee(in_weight).0 > total_in {
+						if fits_weight(in_weight + fee::P2WPKH_INPUT_WEIGHT) {
+							return Err(Error::InsufficientFunds);
+						}
+						let fits = total_in.saturating_sub(required_fee(in_weight).0);
+						return Err(Error::TxTooLarge(fits));
+					}
+					break;
+				}
+
+				// Remove from the back so that the other indices stay valid.
+				for idx in group_idxs.into_iter().rev() {
+					let utxo = remaining_utxos.remove(idx);
+					total_in += utxo.value.as_sat();
+					in_weight += self.utxo_input_weight(utxo);
+					in_utxos.insert(&utxo.outpoint, utxo);
+				}
+			}
+		}
+
+		if !fits_weight(in_weight) {
+			let fits = total_in.saturating_sub(required_fee(in_weight).0);
+			return Err(Error::TxTooLarge(cmp::min(fits, total_out)));
+		}
+
+		// Add change.
+		let (fee_amount, weight) = required_fee(in_weight);
+		let change_amount = total_in - total_out - fee_amount;
+		let mut output_children: Vec<Option<bip32::ChildNumber>> = vec![None; outputs.len()];
+		let mut change_value = 0;
+		// If the change is too small, it goes to the fee.
+		if change_amount > 0 && change_amount >= min_change {
+			if options.no_change {
+				return Err(Error::ChangeNotAllowed(change_amount));
+			}
+			let change_values = match self.config.change_split {
+				// Drained funds are not split or rounded.
+				_ if options.drain_to.is_some() => vec![change_amount],
+				Some(ref policy) if change_children.len() > 1 => {
+					// Every extra change output should not lower the feerate.
+					let extra_cost = fee.marginal(weight, change_weight);
+					let min_value = cmp::max(policy.min_output_value, min_change);
+					split_change(
+						&mut rng,
+						min_value,
+						policy.max_outputs,
+						change_amount,
+						&outputs,
+						extra_cost,
+						policy.round_decoys,
+					)
+				}
+				_ => match self.config.change_rounding {
+					Some(ref policy) => {
+						let max_extra = cmp::min(policy.max_extra_fee, change_amount - min_change);
+						vec![round_change(&mut rng, change_amount, max_extra, &outputs)]
+					}
+					None => vec![change_amount],
+				},
+			};
+
+			// Extra outputs are paid for from the change.
+			change_value = change_values.iter().sum();
+			for (i, value) in change_values.into_iter().enumerate() {
+				let idx = if keep_order {
+					outputs.len()
+				} else {
+					rng.gen_range(0, outputs.len() + 1)
+				};
+				outputs.insert(
+					idx,
+					TxOut {
+						value: value,
+						script_pubkey: change_scripts[i].clone(),
+					},
+				);
+				let child = match options.drain_to {
+					Some(_) => None,
+					None => Some(change_children[i]),
+				};
+				output_children.insert(idx, child);
+			}
+		} else if options.drain_to.is_some() {
+			return Err(Error::DustOutput);
+		}
+		let change = if change_value > 0 && options.drain_to.is_none() {
+			let change_idxs: Vec<usize> = output_children
+				.iter()
+				.enumerate()
+				.filter(|(_, c)| c.is_some())
+				.map(|(i, _)| i)
+				.collect();
+			Some(ChangeInfo {
+				children: change_idxs.iter().map(|i| output_children[*i].unwrap()).collect(),
+				output_indices: change_idxs,
+				value: Amount::from_sat(change_value),
+				keychain: change_keychain,
+			})
+		} else {
+			None
+		};
+
+		// Spending timelocked UTXOs needs the tx to satisfy the timelocks.
+		let mut timelocked_options = None;
+		for utxo in in_utxos.values().filter(|u| u.timelock.is_some()) {
+			let opts = timelocked_options.get_or_insert_with(|| options.clone());
+			match utxo.timelock {
+				Some(Timelock::Height(height)) => {
+					if opts.lock_time >= 500_000_000 {
+						return Err(Error::InvalidTxOptions("timestamp locktime with CLTV input"));
+					}
+					opts.lock_time = cmp::max(opts.lock_time, height);
+				}
+				Some(Timelock::Blocks(blocks)) => {
+					opts.version = cmp::max(opts.version, 2);
+					opts.sequences.entry(utxo.outpoint).or_insert(u32::from(blocks));
+				}
+				None => {}
+			}
+		}
+		let options = timelocked_options.as_ref().unwrap_or(options);
+
+		// Shuffle inputs and prepare PSBT data.
+		let mut prevouts: Vec<&OutPoint> =
+			in_utxos.keys().chain(foreign_kinds.keys()).map(|o| *o).collect();
+		prevouts.sort_by_key(|o| (o.txid, o.vout));
+		rng.shuffle(&mut prevouts);
+		if keep_order {
+			let extra = prevouts.into_iter().filter(|o| !use_inputs.contains(*o));
+			prevouts = use_inputs.iter().chain(extra).collect();
+		}
+		let mut inputs = vec![];
+		let mut psbt_inputs = vec![];
+		for prevout in &prevouts {
+			inputs.push(TxIn {
+				previous_output: *prevout.clone(),
+				script_sig: Script::new(),
+				sequence: options.sequence(prevout),
+				witness: vec![],
+			});
+			let mut psbt_input = match in_utxos.get(prevout) {
+				Some(utxo) => self.psbt_input(utxo)?,
+				None => options.foreign_inputs[*prevout].clone(),
+			};
+			if let Some(sighash_type) = options.sighash_types.get(*prevout) {
+				psbt_input.sighash_type = Some(*sighash_type);
+			}
+			psbt_inputs.push(psbt_input);
+		}
+
+		// PSBT outputs for change.
+		let mut psbt_outputs: Vec<psbt::Output> = vec![Default::default(); outputs.len()];
+		for (idx, child) in output_children.iter().enumerate() {
+			if let Some(child) = child {
+				let (pubkey, master_fp, path) =
+					self.derive_key(self.generation, change_keychain, *child)?;
+				psbt_outputs[idx].hd_keypaths.insert(pubkey, (master_fp, path));
+			}
+		}
+
+		// Create the unsigned tx.
+		let tx = Transaction {
+			version: options.version,
+			lock_time: options.lock_time,
+			input: inputs,
+			output: outputs,
+		};
+		let input_kinds: Vec<InputKind> = prevouts
+			.iter()
+			.map(|o| match in_utxos.get(*o) {
+				Some(utxo) => self.utxo_input_kind(utxo),
+				None => Ok(foreign_kinds[*o]),
+			})
+			.collect::<Result<_>>()?;
+		let weight = fee::estimate_weight_for_inputs(&input_kinds, &tx.output);
+		let fee_amount = total_in - tx.output.iter().map(|o| o.value).sum::<u64>();
+		self.check_fee_limits(fee_amount, weight)?;
+
+		Ok(CreatedTx {
+			fee: Amount::from_sat(fee_amount),
+			fee_rate: fee_amount as f64 / fee::weight_to_vsize(weight) as f64,
+			selected_utxos: prevouts.iter().map(|o| **o).collect(),
+			change: change,
+			weight: weight,
+			vsize: fee::weight_to_vsize(weight),
+			psbt: psbt::PartiallySignedTransaction {
+				global: psbt::Global::from_unsigned_tx(tx).expect("only when non-empty sigs"),
+				inputs: psbt_inputs,
+				outputs: psbt_outputs,
+			},
+		})
+	}
+
+	/// Create a tx with the outputs, spending at least the given inputs.
+	/// For more options, use [build_tx].
+	///
+	/// Possible errors:
+	/// - [Error::Bip32]
+	/// - [Error::DuplicateUtxo]
+	/// - [Error::FeeRateTooHigh]
+	/// - [Error::FeeTooHigh]
+	/// - [Error::InsufficientFunds]
+	/// - [Error::MissingUtxoData]
+	/// - [Error::UtxoNotInWallet]
+	pub fn create_transaction(
+		&mut self,
+		outputs: Vec<TxOut>,
+		use_inputs: Vec<OutPoint>,
+		fee: Amount,
+	) -> Result<CreatedTx> {
+		let options = TxOptions::default();
+		self.create_and_commit_transaction(outputs, use_inputs, Fee::Absolute(fee), &options)
+	}
+
+	/// Like [create_transaction], but pays a fee at the given feerate.
+	pub fn create_transaction_with_feerate(
+		&mut self,
+		outputs: Vec<TxOut>,
+		use_inputs: Vec<OutPoint>,
+		fee_rate: FeeRate,
+	) -> Result<CreatedTx> {
+		let options = TxOptions::default();
+		self.create_and_commit_transaction(outputs, use_inputs, Fee::Rate(fee_rate), &options)
+	}
+
+	/// Start building a tx. This is the most flexible way to create txs.
+	pub fn build_tx(&mut self) -> TxBuilder {
+		TxBuilder::new(self)
+	}
+
+	/// Create a tx that spends up to [max_inputs] of the smallest UTXOs to a
+	/// single change output of the given address type, preferably at a low
+	/// feerate. Of equal values, the oldest UTXOs are spent first. UTXOs that
+	/// are worth less than the fee to spend them are skipped.
+	///
+	/// The paid fee is reported in the created tx.
+	/// Fails with [Error::InsufficientFunds] when there are less than two UTXOs
+	/// to consolidate.
+	pub fn create_consolidation(
+		&mut self,
+		max_inputs: usize,
+		fee_rate: FeeRate,
+		address_type: AddressType,
+	) -> Result<CreatedTx> {
+		let fee = Fee::Rate(fee_rate);
+		let mut candidates: Vec<&Utxo> = self
+			.owned_utxos
+			.values()
+			.filter(|u| self.is_utxo_selectable(u))
+			.filter(|u| u.value.as_sat() > fee.for_weight(self.utxo_input_weight(u)))
+			.collect();
+		candidates.sort_by_key(|u| (u.value, u.height, u.outpoint.txid, u.outpoint.vout));
+		let inputs: Vec<OutPoint> =
+			candidates.iter().take(max_inputs).map(|u| u.outpoint).collect();
+		if inputs.len() < 2 {
+			return Err(Error::InsufficientFunds);
+		}
+
+		let mut options = TxOptions::default();
+		options.change_address_type = Some(address_type);
+		let created = self.create_and_commit_transaction(Vec::new(), inputs, fee, &options)?;
+		if created.change.is_none() {
+			// Everything went to the fee.
+			self.drop_pending_transaction(created.txid());
+			return Err(Error::InsufficientFunds);
+		}
+		Ok(created)
+	}
+
+	/// Like [create_transaction], but with the given [TxOptions].
+	///
+	/// Possible errors, in addition to those of [create_transaction]:
+	/// - [Error::InvalidTxOptions]
+	pub fn create_transaction_with_options(
+		&mut self,
+		outputs: Vec<TxOut>,
+		use_inputs: Vec<OutPoint>,
+		fee: Fee,
+		options: &TxOptions,
+	) -> Result<CreatedTx> {
+		self.create_and_commit_transaction(outputs, use_inputs, fee, options)
+	}
+
+	/// Fund the PSBT with inputs and change of the wallet, like the
+	/// `walletcreatefundedpsbt` RPC of Bitcoin Core.
+	///
+	/// The outputs and inputs of the PSBT stay in order at the start of the tx
+	/// and keep their PSBT data. Inputs that are not of the wallet must have
+	/// their spent output set. The tx version, locktime and sequences are kept
+	/// and added inputs signal replaceability if any given input does.
+	/// On success, the PSBT is replaced with the funded one.
+	///
+	/// Possible errors, in addition to those of [create_transaction]:
+	/// - [Error::InvalidTxOptions]
+	/// - [Error::UnsupportedAddressType]
+	pub fn fund_psbt(
+		&mut self,
+		psbt: &mut psbt::PartiallySignedTransaction,
+		fee_rate: FeeRate,
+	) -> Result<CreatedTx> {
+		let template = &psbt.global.unsigned_tx;
+		let mut options = TxOptions::default();
+		options.version = template.version;
+		options.lock_time = template.lock_time;
+		options.keep_order = true;
+		let mut use_inputs = Vec::with_capacity(template.input.len());
+		for (input, psbt_input) in template.input.iter().zip(psbt.inputs.iter()) {
+			let outpoint = input.previous_output;
+			use_inputs.push(outpoint);
+			options.sequences.insert(outpoint, input.sequence);
+			options.rbf |= input.sequence < 0xFFFFFFFE;
+			if let Some(sighash_type) = psbt_input.sighash_type {
+				options.sighash_types.insert(outpoint, sighash_type);
+			}
+			if !self.owned_utxos.contains_key(&outpoint) {
+				options.foreign_inputs.insert(outpoint, psbt_input.clone());
+			}
+		}
+
+		let outputs = template.output.clone();
+		let mut created =
+			self.create_and_commit_transaction(outputs, use_inputs, Fee::Rate(fee_rate), &options)?;
+		for (idx, output) in psbt.outputs.iter().enumerate() {
+			created.psbt.outputs[idx] = output.clone();
+		}
+		created.psbt.global.unknown = psbt.global.unknown.clone();
+		*psbt = created.psbt.clone();
+		Ok(created)
+	}
+
+	/// Create a tx paying the BIP-21 URI at the given feerate. The URI's label,
+	/// if any, is set on the address and the tx.
+	///
+	/// Fails if the URI has no amount or an address for another network.
+	pub fn create_payment(&mut self, uri: &Uri, fee_rate: FeeRate) -> Result<CreatedTx> {
+		if uri.address.network != self.config.network {
+			return Err(Error::WrongNetwork);
+		}
+		let amount = match uri.amount {
+			Some(amount) => amount,
+			None => return Err(Error::InvalidUri("no amount")),
+		};
+		let payment = TxOut {
+			value: amount.as_sat(),
+			script_pubkey: uri.address.script_pubkey(),
+		};
+		let created = self.create_transaction_with_feerate(vec![payment], vec![], fee_rate)?;
+
+		if let Some(ref label) = uri.label {
+			let txid = created.txid();
+			self.version += 1;
+			self.labels.set(LabelRef::address(&uri.address), Label::new(label.clone()));
+			self.labels.set(LabelRef::Tx(txid), Label::new(label.clone()));
+		}
+		Ok(created)
+	}
+
+	/// Create a tx paying all recipients at the given feerate. Addresses for
+	/// another network are rejected. Addresses that occur more than once are
+	/// handled according to the config.
+	///
+	/// The order of the outputs is randomized.
+	pub fn create_batch_payment(
+		&mut self,
+		recipients: &[(Address, Amount)],
+		fee_rate: FeeRate,
+	) -> Result<CreatedTx> {
+		let mut outputs: Vec<TxOut> = Vec::with_capacity(recipients.len());
+		for (address, amount) in recipients {
+			if address.network != self.config.network {
+				return Err(Error::WrongNetwork);
+			}
+			let script_pubkey = address.script_pubkey();
+			match outputs.iter_mut().find(|o| o.script_pubkey == script_pubkey) {
+				Some(output) => match self.config.duplicate_recipients {
+					DuplicateRecipientPolicy::Reject => return Err(Error::DuplicateRecipient),
+					DuplicateRecipientPolicy::Merge => output.value += amount.as_sat(),
+				},
+				None => outputs.push(TxOut {
+					value: amount.as_sat(),
+					script_pubkey: script_pubkey,
+				}),
+			}
+		}
+		// Don't leak the order in which the recipients were given.
+		self.rng()?.shuffle(&mut outputs);
+		self.create_transaction_with_feerate(outputs, vec![], fee_rate)
+	}
+
+	/// Pay all recipients in a single tx at the given feerate. This is
+	/// [create_batch_payment] with some early checks. The amounts must be
+	/// covered by the UTXOs coin selection can pick, see [is_utxo_selectable],
+	/// so not by locked or insufficiently confirmed ones.
+	pub fn send_many<I>(&mut self, recipients: I, fee_rate: FeeRate) -> Result<CreatedTx>
+	where
+		I: IntoIterator<Item = (Address, Amount)>,
+	{
+		let recipients: Vec<(Address, Amount)> = recipients.into_iter().collect();
+		if recipients.is_empty() {
+			return Err(Error::NoRecipients);
+		}
+		let total = recipients.iter().try_fold(Amount::from_sat(0), |acc, (_, amount)| {
+			acc.checked_add(*amount)
+		});
+		let selectable: u64 = self.selectable_utxos().iter().map(|u| u.value.as_sat()).sum();
+		match total {
+			Some(total) if total.as_sat() <= selectable => {}
+			_ => return Err(Error::InsufficientFunds),
+		}
+		self.create_batch_payment(&recipients, fee_rate)
+	}
+
+	/// Create a set of txs that together make all the given payouts, each of them
+	/// within the given limits. The txs are committed and tracked as a [PayoutRun].
+	/// If any of the txs can't be created, none of them are.
+	///
+	/// Returns the id of the payout run and the created txs, with the PSBTs to
+	/// sign.
+	pub fn create_payout_run(
+		&mut self,
+		payouts: Vec<TxOut>,
+		fee_rate: FeeRate,
+		limits: &PayoutLimits,
+	) -> Result<(usize, Vec<CreatedTx>)> {
+		// Wallets from before the counter was stored only have the runs.
+		let first_unused = self.payout_runs.iter().map(|r| r.id + 1).max().unwrap_or(0);
+		let mut run = PayoutRun {
+			id: cmp::max(self.next_payout_run_id, first_unused),
+			txids: Vec::new(),
+			payouts: Vec::new(),
+		};
+		let mut created_txs = Vec::new();
+		let change_keychain = self.change_keychain();
+		let change_before = *self.last_sourced_child_mut(change_keychain);
+
+		let mut next = 0;
+		while next < payouts.len() {
+			// Leave room for a change output.
+			let mut size = cmp::min(payouts.len() - next, cmp::max(limits.max_outputs, 2) - 1);
+			loop {
+				let last_change = *self.last_sourced_child_mut(change_keychain);
+				let outputs = payouts[next..next + size].to_vec();
+				let res = self.create_transaction_with_feerate(outputs, vec![], fee_rate);
+				let created = match res {
+					Ok(created) => created,
+					// Too big for the wallet's maximum weight, retry with fewer payouts.
+					Err(Error::TxTooLarge(_)) if size > 1 => {
+						self.release_children(change_keychain, last_change);
+						size /= 2;
+						continue;
+					}
+					Err(e) => {
+						for txid in &run.txids {
+							self.drop_pending_transaction(*txid);
+						}
+						self.release_children(change_keychain, change_before);
+						return Err(e);
+					}
+				};
+
+				let txid = created.txid();
+				if limits.allows(&created.psbt.global.unsigned_tx) {
+					run.txids.push(txid);
+					run.payouts.push((next..next + size).collect());
+					created_txs.push(created);
+					next += size;
+					break;
+				}
+
+				// Too big, retry with fewer payouts.
+				self.drop_pending_transaction(txid);
+				self.release_children(change_keychain, last_change);
+				if size == 1 {
+					for txid in &run.txids {
+						self.drop_pending_transaction(*txid);
+					}
+					self.release_children(change_keychain, change_before);
+					return Err(Error::TxLimitsExceeded);
+				}
+				size /= 2;
+			}
+		}
+
+		let id = run.id;
+		self.next_payout_run_id = id + 1;
+		self.payout_runs.push(run);
+		Ok((id, created_txs))
+	}
+
+	/// Create a proof of reserves for all UTXOs of the wallet, committing to the
+	/// message. See [proof_of_reserves] for the format. The returned PSBT should
+	/// be signed like any other tx, except for its first input.
+	pub fn create_reserve_proof(&self, message: &str) -> Result<psbt::PartiallySignedTransaction> {
+		if self.owned_utxos.is_empty() {
+			return Err(Error::InsufficientFunds);
+		}
+
+		let mut inputs = vec![proof_of_reserves::challenge_txin(message)];
+		let mut psbt_inputs = vec![psbt::Input::default()];
+		let mut total = 0;
+		for utxo in self.owned_utxos.values() {
+			inputs.push(TxIn {
+				previous_output: utxo.outpoint,
+				script_sig: Script::new(),
+				sequence: 0xFFFFFFFF,
+				witness: vec![],
+			});
+			psbt_inputs.push(self.psbt_input(utxo)?);
+			total += utxo.value.as_sat();
+		}
+		let tx = Transaction {
+			version: 1,
+			lock_time: 0,
+			input: inputs,
+			output: vec![TxOut {
+				value: total,
+				script_pubkey: proof_of_reserves::unspendable_script(),
+			}],
+		};
+
+		Ok(psbt::PartiallySignedTransaction {
+			global: psbt::Global::from_unsigned_tx(tx).expect("only when non-empty sigs"),
+			inputs: psbt_inputs,
+			outputs: vec![Default::default()],
+		})
+	}
+
+	/// Create an export of the UTXOs of the wallet for auditors, to be signed
+	/// by the key of each of the UTXOs. See [audit] for how to sign it.
+	///
+	/// Imported UTXOs with keys that are not part of the wallet are not listed,
+	/// neither are P2PKH UTXOs, of which the keys can't sign BIP-322 messages.
+	pub fn create_audit_export(&self) -> Result<AuditExport> {
+		let mut entries = Vec::new();
+		for utxo in self.owned_utxos.values().filter(|u| !u.external) {
+			if self.utxo_txout(utxo).is_none() {
+				return Err(Error::MissingUtxoData);
+			}
+			let address_type = match self.utxo_address_type(utxo) {
+				Some(AddressType::P2pkh) | None => continue,
+				Some(t) => t,
+			};
+			let (pubkey, _, path) =
+				self.derive_key(utxo.generation, utxo.keychain, utxo.child_number)?;
+			entries.push(AuditEntry {
+				outpoint: utxo.outpoint,
+				value: utxo.value.as_sat(),
+				path: path,
+				pubkey: pubkey,
+				address_type: address_type,
+				signature: None,
+			});
+		}
+		if entries.is_empty() {
+			return Err(Error::InsufficientFunds);
+		}
+		entries.sort_by_key(|e| (e.outpoint.txid, e.outpoint.vout));
+		Ok(AuditExport {
+			network: self.config.network,
+			entries: entries,
+		})
+	}
+
+	/// Get the payout run with the given id.
+	pub fn get_payout_run(&self, id: usize) -> Option<&PayoutRun> {
+		self.payout_runs.iter().find(|r| r.id == id)
+	}
+
+	/// Drop all the pending txs of the payout run and forget about the run.
+	pub fn drop_payout_run(&mut self, id: usize) -> bool {
+		let run = match self.payout_runs.iter().position(|r| r.id == id) {
+			Some(idx) => self.payout_runs.remove(idx),
+			None => return false,
+		};
+		for txid in run.txids {
+			self.drop_pending_transaction(txid);
+		}
+		true
+	}
+
+	fn create_and_commit_transaction(
+		&mut self,
+		outputs: Vec<TxOut>,
+		use_inputs: Vec<OutPoint>,
+		fee: Fee,
+		options: &TxOptions,
+	) -> Result<CreatedTx> {
+		let nb_change = match self.config.change_split {
+			Some(ref policy) => policy.max_outputs.max(1),
+			None => 1,
+		};
+		let keychain = self.change_keychain();
+		let last_before = *self.last_sourced_child_mut(keychain);
+		let change_children: Vec<_> =
+			(0..nb_change).map(|_| self.next_unused_child(keychain)).collect();
+		let created = match self.create_transaction_with_change(
+			outputs,
+			use_inputs,
+			&change_children,
+			fee,
+			options,
+		) {
+			Ok(res) => res,
+			Err(e) => {
+				self.set_last_sourced_child(keychain, last_before);
+				return Err(e);
+			}
+		};
+		// Change children are used in order, so release the ones after the last used one.
+		let nb_used = created.change.as_ref().map(|c| c.children.len()).unwrap_or(0);
+		let last_used = change_children[0..nb_used].last().cloned();
+		self.set_last_sourced_child(keychain, last_used.or(last_before));
+		for child in &change_children[0..nb_used] {
+			self.index_script_pubkeys(keychain, *child);
+		}
+		if options.commit {
+			self.commit_transaction(created.psbt.global.unsigned_tx.clone());
+		}
+		Ok(created)
+	}
+}
+
+/// The key of the script in the script index.
+fn script_hash(script: &Script) -> sha256::Hash {
+	sha256::Hash::hash(script.as_bytes())
+}
+
+/// The default of amounts missing in stored wallets.
+fn zero_amount() -> Amount {
+	Amount::from_sat(0)
+}
+
+/// Serialize the map with its entries ordered by key.
+fn serialize_sorted_map<K, V, S>(
+	map: &HashMap<K, V>,
+	serializer: S,
+) -> ::std::result::Result<S::Ok, S::Error>
+where
+	K: Serialize + Ord + StdHash,
+	V: Serialize,
+	S: Serializer,
+{
+	serializer.collect_map(map.iter().collect::<BTreeMap<&K, &V>>())
+}
+
+/// Serialize the set with its items ordered.
+fn serialize_sorted_set<T, S>(
+	set: &HashSet<T>,
+	serializer: S,
+) -> ::std::result::Result<S::Ok, S::Error>
+where
+	T: Serialize + Ord + StdHash,
+	S: Serializer,
+{
+	serializer.collect_seq(set.iter().collect::<BTreeSet<&T>>())
+}
+
+/// Serialize the map with its entries ordered by outpoint.
+fn serialize_by_outpoint<V, S>(
+	map: &HashMap<OutPoint, V>,
+	serializer: S,
+) -> ::std::result::Result<S::Ok, S::Error>
+where
+	V: Serialize,
+	S: Serializer,
+{
+	serializer.collect_map(sorted_by_outpoint(map))
+}
+
+/// The tx to store on a UTXO of the output, for P2PKH outputs.
+fn full_prev_tx(txout: &TxOut, tx: &Transaction) -> Option<Transaction> {
+	if txout.script_pubkey.is_p2pkh() {
+		Some(tx.clone())
+	} else {
+		None
+	}
+}
+
+/// The entries of the map ordered by outpoint.
+fn sorted_by_outpoint<V>(map: &HashMap<OutPoint, V>) -> Vec<(&OutPoint, &V)> {
+	let mut entries: Vec<(&OutPoint, &V)> = map.iter().collect();
+	entries.sort_by_key(|(o, _)| (o.txid, o.vout));
+	entries
+}
+
+/// Deserialize the script index, also from wallets that were serialized when
+/// the index was keyed by the scripts themselves.
+fn deserialize_script_index<'de, D>(
+	deserializer: D,
+) -> ::std::result::Result<HashMap<sha256::Hash, ScriptInfo>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	use bitcoin_hashes::hex::FromHex;
+	use serde::de::Error;
+
+	let index = HashMap::<String, ScriptInfo>::deserialize(deserializer)?;
+	let mut ret = HashMap::with_capacity(index.len());
+	for (key, info) in index {
+		// None of our scripts is 32 bytes long, so it must be a hash.
+		let hash = match key.len() {
+			64 => sha256::Hash::from_hex(&key).map_err(D::Error::custom)?,
+			_ => script_hash(&Script::from(Vec::<u8>::from_hex(&key).map_err(D::Error::custom)?)),
+		};
+		ret.insert(hash, info);
+	}
+	Ok(ret)
+}
+
+/// Deserialize the used features, failing for features unknown to this version.
+fn deserialize_features<'de, D>(
+	deserializer: D,
+) -> ::std::result::Result<BTreeSet<WalletFeature>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	use serde::de::value::{Error as ValueError, StrDeserializer};
+	use serde::de::{Error, IntoDeserializer};
+
+	let mut ret = BTreeSet::new();
+	for name in Vec::<String>::deserialize(deserializer)? {
+		let name_de: StrDeserializer<ValueError> = name.as_str().into_deserializer();
+		match WalletFeature::deserialize(name_de) {
+			Ok(feature) => ret.insert(feature),
+			Err(_) => {
+				let msg = format!("wallet uses feature {} which is not supported", name);
+				return Err(D::Error::custom(msg));
+			}
+		};
+	}
+	Ok(ret)
+}
+
+/// The child of the base path under which the branches are derived. It's
+/// never used for a receive address.
+fn branches_root_child() -> bip32::ChildNumber {
+	bip32::ChildNumber::from_normal_idx((1 << 31) - 1).unwrap()
+}
+
+/// The child number of the branch with the given index.
+fn branch_child(index: u32) -> bip32::ChildNumber {
+	bip32::ChildNumber::from_normal_idx(index).expect("branch indices are checked")
+}
+
+/// The derivation path of the keychain, given the paths of the keys.
+fn keychain_path(
+	base_path: &bip32::DerivationPath,
+	change_path: Option<&bip32::DerivationPath>,
+	keychain: Keychain,
+) -> bip32::DerivationPath {
+	match (keychain, change_path) {
+		(Keychain::Internal, Some(path)) => path.clone(),
+		(Keychain::Branch(b), _) => base_path.child(branches_root_child()).child(branch_child(b)),
+		_ => base_path.clone(),
+	}
+}
+
+/// The current UNIX time in seconds.
+#[cfg(not(target_arch = "wasm32"))]
+fn unix_time() -> Option<u64> {
+	Some(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0))
+}
+
+/// There's no system time on wasm, a clock must be set with [Wallet::set_clock].
+#[cfg(target_arch = "wasm32")]
+fn unix_time() -> Option<u64> {
+	None
+}
+
+fn rng_seed_from_bytes(bytes: &[u8]) -> Vec<usize> {
+	bytes.iter().map(|b| *b as usize).collect()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn os_rng() -> Result<StdRng> {
+	StdRng::new().map_err(|_| Error::NoEntropySource)
+}
+
+/// There's no OS randomness on wasm, a source must be set with
+/// [Wallet::set_entropy_source].
+#[cfg(target_arch = "wasm32")]
+fn os_rng() -> Result<StdRng> {
+	Err(Error::NoEntropySource)
+}
+
+/// Split the change amount into at most [max_outputs] values of at least [min_value]
+/// that resemble the payment values, or round decoy values without payments.
+/// Every extra output costs [extra_cost], which is deducted from the change.
+fn split_change<R: Rng>(
+	rng: &mut R,
+	min_value: u64,
+	max_outputs: usize,
+	change_amount: u64,
+	payments: &[TxOut],
+	extra_cost: u64,
+	round_decoys: bool,
+) -> Vec<u64> {
+	let mut values = Vec::with_capacity(max_outputs);
+	if payments.is_empty() && !round_decoys {
+		values.push(change_amount);
+		return values;
+	}
+
+	let mut remaining = change_amount;
+	while values.len() + 1 < max_outputs {
+		let value = if payments.is_empty() {
+			decoy_value(rng, remaining)
+		} else {
+			// Pick a value between half and the full value of a random payment.
+			let reference = payments[rng.gen_range(0, payments.len())].value;
+			rng.gen_range(reference / 2, reference + 1)
+		};
+		// Make sure both this and the remaining output are worth creating.
+		if value < min_value || remaining < value + extra_cost + min_value {
+			break;
+		}
+		values.push(value);
+		remaining -= value + extra_cost;
+	}
+	values.push(remaining);
+	values
+}
+
+/// A random value of a quarter to half of [max], rounded down to a random
+/// number of trailing zeros like payments often are. At least two
+/// significant digits are kept.
+fn decoy_value<R: Rng>(rng: &mut R, max: u64) -> u64 {
+	let value = rng.gen_range(max / 4, max / 2 + 1);
+	let mut max_zeros = 0;
+	while max_zeros < 18 && 10u64.pow(max_zeros + 2) <= value {
+		max_zeros += 1;
+	}
+	let zeros = rng.gen_range(max_zeros / 2, max_zeros + 1);
+	value - value % 10u64.pow(zeros)
+}
+
+/// The waste of an input set, as defined by Bitcoin Core: the extra cost of
+/// spending the inputs now at [fee_rate] instead of later at [long_term_rate],
+/// plus either the cost of creating and later spending the change or the
+/// excess that goes to the fee when there's no change.
+fn selection_waste(
+	fee_rate: u64,
+	long_term_rate: u64,
+	inputs_weight: u64,
+	change_cost: Option<u64>,
+	excess: u64,
+) -> i64 {
+	let inputs_vsize = fee::weight_to_vsize(inputs_weight) as i64;
+	let timing = (fee_rate as i64 - long_term_rate as i64) * inputs_vsize;
+	match change_cost {
+		Some(cost) => {
+			let spend_change = long_term_rate * fee::weight_to_vsize(fee::P2WPKH_INPUT_WEIGHT);
+			timing + (cost + spend_change) as i64
+		}
+		None => timing + excess as i64,
+	}
+}
+
+/// The number of trailing decimal zeros of the value.
+fn trailing_zeros(mut value: u64) -> u32 {
+	let mut zeros = 0;
+	while value != 0 && value % 10 == 0 {
+		value /= 10;
+		zeros += 1;
+	}
+	zeros
+}
+
+/// Round the change amount down by at most [max_extra] to a random number of
+/// trailing zeros. Preferably, the change gets at least as many trailing zeros
+/// as the least round payment.
+fn round_change<R: Rng>(
+	rng: &mut R,
+	change_amount: u64,
+	max_extra: u64,
+	payments: &[TxOut],
+) -> u64 {
+	// The most zeros we can get within the maximum extra fee.
+	let mut max_zeros = 0;
+	while max_zeros < 19 && change_amount % 10u64.pow(max_zeros + 1) <= max_extra {
+		max_zeros += 1;
+	}
+	if max_zeros == 0 {
+		return change_amount;
+	}
+
+	let payment_zeros = payments.iter().map(|o| trailing_zeros(o.value)).min().unwrap_or(0);
+	let zeros = rng.gen_range(cmp::min(payment_zeros, max_zeros), max_zeros + 1);
+	change_amount - change_amount % 10u64.pow(zeros)
+}
+
+impl fmt::Debug for Wallet {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use bitcoin_hashes::hex::ToHex;
+
+		write!(f, "--- Wallet ---\n")?;
+		write!(f, "features: {:?}\n", self.features)?;
+		write!(f, "config: {:?}\n", self.config)?;
+		write!(f, "extended_pubkey: {}\n", self.extended_pubkey)?;
+		write!(f, "master_fp: {}\n", self.master_fp[..].to_hex())?;
+		if let Some(ref path) = self.xpub_origin_path {
+			write!(f, "xpub_origin_path: {}\n", path)?;
+		}
+		write!(f, "base_derivation_path: {}\n", self.base_derivation_path)?;
+		write!(f, "last_sourced_child: {:?}\n", self.last_sourced_child)?;
+		if let Some(ref path) = self.change_derivation_path {
+			write!(f, "change_derivation_path: {}\n", path)?;
+			write!(f, "last_sourced_change_child: {:?}\n", self.last_sourced_change_child)?;
+		}
+		write!(f, "generation: {}\n", self.generation)?;
+		write!(f, "retired_keys (len: {}):\n", self.retired_keys.len())?;
+		for keys in self.retired_keys.iter() {
+			write!(f, "- {:?}\n", keys)?;
+		}
+		write!(f, "owned_utxos (len: {}):\n", self.owned_utxos.len())?;
+		for utxo in self.list_utxos() {
+			write!(f, "- {:?}\n", utxo)?;
+		}
+		write!(f, "watched_scripts (len: {}):\n", self.watched_scripts.len())?;
+		for script in self.watched_scripts.iter().collect::<BTreeSet<_>>() {
+			write!(f, "- {}\n", script.to_hex())?;
+		}
+		write!(f, "watched_utxos (len: {}):\n", self.watched_utxos.len())?;
+		for utxo in self.list_watched_utxos() {
+			write!(f, "- {:?}\n", utxo)?;
+		}
+		write!(f, "script_index (len: {}):\n", self.script_index.len())?;
+		for (hash, info) in self.script_index.iter().collect::<BTreeMap<_, _>>() {
+			write!(f, "- {}: {:?}\n", hash, info)?;
+		}
+		write!(f, "last_known_block: {:?}\n", self.last_known_block)?;
+		write!(f, "block_hashes (len: {})\n", self.block_hashes.len())?;
+		write!(f, "birthday: {:?}\n", self.birthday)?;
+		write!(f, "pending_txs (len: {}):\n", self.pending_txs.len())?;
+		for tx in self.pending_txs.iter() {
+			write!(f, "- {:?}\n", tx)?;
+		}
+		write!(f, "tx_history (len: {}):\n", self.tx_history.len())?;
+		for entry in self.tx_history.iter() {
+			write!(f, "- {:?}\n", entry)?;
+		}
+		write!(f, "payout_runs (len: {}):\n", self.payout_runs.len())?;
+		for run in self.payout_runs.iter() {
+			write!(f, "- {:?}\n", run)?;
+		}
+		write!(f, "expected_payments (len: {}):\n", self.expected_payments.len())?;
+		for payment in self.expected_payments.iter() {
+			write!(f, "- {:?}\n", payment)?;
+		}
+		write!(f, "height_triggers (len: {}):\n", self.height_triggers.len())?;
+		for trigger in self.height_triggers.iter() {
+			write!(f, "- {:?}\n", trigger)?;
+		}
+		write!(f, "labels (len: {})\n", self.labels.len())?;
+		write!(f, "accounting_tags (len: {})\n", self.accounting_tags.len())?;
+		write!(f, "branches (len: {}):\n", self.branches.len())?;
+		for branch in self.branches.values() {
+			write!(f, "- {:?}\n", branch)?;
+		}
+		write!(f, "events (len: {})\n", self.events.len())?;
+		write!(f, "last_spendable_balance: {:?}\n", self.last_spendable_balance)?;
+		// The seed can be derived from the master seed, so it is not printed.
+		write!(f, "rng_seed set: {}\n", self.rng_seed.is_some())?;
+		write!(f, "entropy_source set: {}\n", self.entropy_source.is_some())?;
+		write!(f, "clock set: {}\n", self.clock.is_some())?;
+		write!(f, "--------------")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::util::hash::bitcoin_merkle_root;
+	use hex;
+
+	const SEED: [u8; 32] = [42; 32];
+
+	/// A wallet that processed the regtest genesis block.
+	fn test_wallet() -> Wallet {
+		let mut wallet = Wallet::new_for_testing(&SEED, Network::Regtest);
+		wallet.set_last_block(genesis_block(Network::Regtest).bitcoin_hash(), 0);
+		wallet
+	}
+
+	fn test_signer() -> HotSigner {
+		let xpriv = bip32::ExtendedPrivKey::new_master(Network::Regtest, &SEED).unwrap();
+		HotSigner::new(xpriv, 100)
+	}
+
+	/// A script of another wallet.
+	fn foreign_script() -> Script {
+		let mut other = Wallet::new_for_testing(&[1; 32], Network::Regtest);
+		other.new_receive_address().unwrap().script_pubkey()
+	}
+
+	/// The next block after the last block of the wallet, with a valid
+	/// merkle root and proof of work.
+	fn next_block(wallet: &Wallet, txs: Vec<Transaction>) -> Block {
+		let last = wallet.last_block().unwrap();
+		let mut header = BlockHeader {
+			prev_blockhash: last.hash,
+			merkle_root: bitcoin_merkle_root(txs.iter().map(|t| t.txid()).collect()),
+			time: 1_500_000_000 + last.height * 600,
+			..genesis_block(Network::Regtest).header
+		};
+		while header.validate_pow(&header.target()).is_err() {
+			header.nonce += 1;
+		}
+		Block {
+			header: header,
+			txdata: txs,
+		}
+	}
+
+	/// A tx of another wallet paying the values to new receive addresses.
+	fn funding_tx(wallet: &mut Wallet, values: &[u64]) -> Transaction {
+		let output: Vec<TxOut> = values
+			.iter()
+			.map(|v| TxOut {
+				value: *v,
+				script_pubkey: wallet.new_receive_address().unwrap().script_pubkey(),
+			})
+			.collect();
+		Transaction {
+			version: 1,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: OutPoint {
+					txid: sha256d::Hash::hash(output[0].script_pubkey.as_bytes()),
+					vout: 0,
+				},
+				script_sig: Script::new(),
+				sequence: 0xFFFFFFFF,
+				witness: vec![],
+			}],
+			output: output,
+		}
+	}
+
+	/// Sign the created tx and return the final tx.
+	fn sign(created: &CreatedTx) -> Transaction {
+		let mut psbt = created.psbt.clone();
+		test_signer().sign_psbt(&mut psbt).unwrap();
+		signer::finalize_psbt(&psbt).unwrap()
+	}
+
+	/// Confirm a funding tx paying the values to the wallet in the next block.
+	fn fund(wallet: &mut Wallet, values: &[u64]) -> Transaction {
+		let tx = funding_tx(wallet, values);
+		let block = next_block(wallet, vec![tx.clone()]);
+		wallet.process_block(&block).unwrap();
+		tx
+	}
+
+	struct TestBroadcaster {
+		accept: bool,
+	}
+
+	impl Broadcaster for TestBroadcaster {
+		fn name(&self) -> String {
+			"test".to_owned()
+		}
+
+		fn broadcast(&self, tx: &Transaction) -> Result<sha256d::Hash> {
+			if self.accept {
+				Ok(tx.txid())
+			} else {
+				Err(Error::Backend("rejected".to_owned()))
+			}
+		}
+	}
+
+	fn payment(value: u64) -> Vec<TxOut> {
+		vec![TxOut {
+			value: value,
+			script_pubkey: foreign_script(),
+		}]
+	}
+
+	#[test]
+	fn deserialize_script_keyed_index() {
+		let mut wallet = test_wallet();
+		let addresses: Vec<Address> =
+			(0..3).map(|_| wallet.new_receive_address().unwrap()).collect();
+
+		// Serialize the wallet like before the index was keyed by script hash.
+		let mut json = serde_json::to_value(&wallet).unwrap();
+		let index = json["script_index"].as_object().unwrap().clone();
+		let mut old_index = serde_json::Map::new();
+		for script in wallet.relevant_scripts().unwrap() {
+			let hash = script_hash(&script).to_string();
+			old_index.insert(hex::encode(script.as_bytes()), index[&hash].clone());
+		}
+		assert_eq!(old_index.len(), index.len());
+		json["script_index"] = serde_json::Value::Object(old_index);
+
+		let old: Wallet = serde_json::from_value(json).unwrap();
+		let new: Wallet = serde_json::from_str(&serde_json::to_string(&wallet).unwrap()).unwrap();
+		for address in &addresses {
+			assert!(old.is_mine_address(address));
+			assert!(new.is_mine_address(address));
+			let script = address.script_pubkey();
+			assert_eq!(old.get_script_info(&script), wallet.get_script_info(&script));
+		}
+		assert_eq!(old.script_index, new.script_index);
+	}
+
+	#[test]
+	fn sign_and_broadcast_failure_uncommits() {
+		let mut wallet = test_wallet();
+		fund(&mut wallet, &[100_000]);
+		let fee_rate = FeeRate::from_sat_per_vb(1);
+		let rejecting = TestBroadcaster {
+			accept: false,
+		};
+
+		// Created txs are committed by default.
+		let created =
+			wallet.create_transaction_with_feerate(payment(50_000), vec![], fee_rate).unwrap();
+		let txid = created.txid();
+		assert!(wallet.get_pending_transaction(txid).is_some());
+		assert_eq!(wallet.get_spendable_balance(), Amount::from_sat(0));
+
+		let result = wallet.sign_and_broadcast(created.psbt, &mut test_signer(), &rejecting);
+		assert_eq!(result, Err(Error::Backend("rejected".to_owned())));
+		assert!(wallet.get_pending_transaction(txid).is_none());
+		assert!(wallet.last_broadcast(txid).is_none());
+		assert_eq!(wallet.get_spendable_balance(), Amount::from_sat(100_000));
+
+		// The coins can be spent again.
+		let created =
+			wallet.create_transaction_with_feerate(payment(60_000), vec![], fee_rate).unwrap();
+		let accepting = TestBroadcaster {
+			accept: true,
+		};
+		let txid = wallet.sign_and_broadcast(created.psbt, &mut test_signer(), &accepting).unwrap();
+		assert!(wallet.last_broadcast(txid).unwrap().acknowledged);
+
+		// A failed rebroadcast keeps the tx.
+		let tx = wallet.get_pending_transaction(txid).unwrap().clone();
+		assert!(wallet.commit_on_broadcast(tx, &rejecting).is_err());
+		assert!(wallet.get_pending_transaction(txid).is_some());
+		assert!(!wallet.last_broadcast(txid).unwrap().acknowledged);
+	}
+
+	#[test]
+	fn merge_confirmed_pending_tx() {
+		let mut wallet = test_wallet();
+		fund(&mut wallet, &[100_000]);
+		let fee_rate = FeeRate::from_sat_per_vb(1);
+		let created =
+			wallet.create_transaction_with_feerate(payment(50_000), vec![], fee_rate).unwrap();
+		let tx = sign(&created);
+		let txid = tx.txid();
+		wallet.record_broadcast(txid, "test".to_owned(), true, None).unwrap();
+		assert!(wallet.pending_times.contains_key(&txid));
+
+		// Another instance of the wallet sees the tx confirmed.
+		let mut other = wallet.clone();
+		let block = next_block(&other, vec![tx]);
+		other.process_block(&block).unwrap();
+		let delta = other.export_delta(1);
+
+		wallet.merge(&delta).unwrap();
+		assert!(wallet.get_pending_transaction(txid).is_none());
+		assert!(!wallet.pending_heights.contains_key(&txid));
+		assert!(!wallet.pending_times.contains_key(&txid));
+		assert!(wallet.last_broadcast(txid).is_none());
+		assert!(wallet.get_transaction(txid).unwrap().entry.is_some());
+		assert_eq!(wallet.last_block().unwrap().hash, block.bitcoin_hash());
+		let change = created.change.unwrap().value;
+		assert_eq!(wallet.get_balance(None), change);
+		assert_eq!(wallet.get_spendable_balance(), change);
+
+		// Merging again changes nothing.
+		wallet.merge(&delta).unwrap();
+		assert_eq!(wallet.get_balance(None), other.get_balance(None));
+		assert_eq!(wallet.list_transactions(&TxFilter::default()).len(), 2);
+	}
+
+	#[test]
+	fn address_type_of_p2sh() {
+		let mut wallet = test_wallet();
+		let mut tx = funding_tx(&mut wallet, &[10_000, 20_000]);
+		let child = wallet.get_script_info(&tx.output[1].script_pubkey).unwrap().child;
+		let ours = wallet.get_address(Keychain::External, child, AddressType::P2shP2wpkh);
+		tx.output[1].script_pubkey = ours.script_pubkey();
+		let block = next_block(&wallet, vec![tx]);
+		wallet.process_block(&block).unwrap();
+
+		assert_eq!(AddressType::from_script(&ours.script_pubkey()), None);
+		assert_eq!(wallet.address_type_of(&ours.script_pubkey()), Some(AddressType::P2shP2wpkh));
+		assert_eq!(wallet.address_type_of(&foreign_script()), Some(AddressType::P2wpkh));
+		let other = Wallet::new_for_testing(&[1; 32], Network::Regtest);
+		let theirs = other.get_address(Keychain::External, child, AddressType::P2shP2wpkh);
+		assert_eq!(wallet.address_type_of(&theirs.script_pubkey()), None);
+
+		let filter = UtxoFilter {
+			address_type: Some(AddressType::P2shP2wpkh),
+			..Default::default()
+		};
+		let utxos = wallet.get_utxos_filtered(&filter).unwrap();
+		assert_eq!(utxos.len(), 1);
+		assert_eq!(utxos[0].value, Amount::from_sat(20_000));
+		assert_eq!(utxos[0].address_type, Some(AddressType::P2shP2wpkh));
+	}
+
+	#[test]
+	fn change_matches_payment_type() {
+		let mut wallet = test_wallet();
+		fund(&mut wallet, &[100_000]);
+		let other = Wallet::new_for_testing(&[1; 32], Network::Regtest);
+		let child = bip32::ChildNumber::from_normal_idx(0).unwrap();
+		let change_type = |wallet: &mut Wallet, address: Address| {
+			let created = wallet
+				.build_tx()
+				.add_recipient(&address, Amount::from_sat(10_000))
+				.fee_rate(FeeRate::from_sat_per_vb(1))
+				.change_policy(ChangeTypePolicy::MatchPayments)
+				.without_commit()
+				.finish()
+				.unwrap();
+			let change = &created.psbt.global.unsigned_tx.output[created.change_index().unwrap()];
+			wallet.address_type_of(&change.script_pubkey).unwrap()
+		};
+
+		let p2pkh = other.get_address(Keychain::External, child, AddressType::P2pkh);
+		assert_eq!(change_type(&mut wallet, p2pkh), AddressType::P2pkh);
+		// Foreign P2SH scripts can wrap anything, so they don't match.
+		let p2sh = other.get_address(Keychain::External, child, AddressType::P2shP2wpkh);
+		assert_eq!(change_type(&mut wallet, p2sh), AddressType::P2wpkh);
+		let ours = wallet.get_address(Keychain::External, child, AddressType::P2shP2wpkh);
+		assert_eq!(change_type(&mut wallet, ours), AddressType::P2shP2wpkh);
+	}
+
+	#[test]
+	fn process_transaction_with_header_proof() {
+		let mut wallet = test_wallet();
+		let mut config = wallet.config().clone();
+		config.reorg_safety_depth = 2;
+		wallet.set_config(config).unwrap();
+		let mut headers = HeaderChain::new(Network::Regtest);
+
+		// The wallet misses the tx of the first block, as a light client would.
+		let tx = funding_tx(&mut wallet, &[50_000]);
+		let block = next_block(&wallet, vec![tx.clone()]);
+		let proof = MerkleBlock::from_block(&block, &vec![tx.txid()].into_iter().collect());
+		headers.add_header(block.header).unwrap();
+		wallet.process_block(&Block { header: block.header, txdata: vec![] }).unwrap();
+		for _ in 0..4 {
+			let block = next_block(&wallet, vec![]);
+			headers.add_header(block.header).unwrap();
+			wallet.process_block(&block).unwrap();
+		}
+
+		// The hash of the first block is pruned by now.
+		assert_eq!(wallet.process_transaction_with_proof(&tx, &proof), Err(Error::UnknownBlock));
+		assert_eq!(wallet.get_balance(None), Amount::from_sat(0));
+
+		let other = funding_tx(&mut wallet, &[10_000]);
+		assert_eq!(
+			wallet.process_transaction_with_header_proof(&other, &proof, &headers),
+			Err(Error::InvalidMerkleProof)
+		);
+		let unknown = HeaderChain::new(Network::Regtest);
+		assert_eq!(
+			wallet.process_transaction_with_header_proof(&tx, &proof, &unknown),
+			Err(Error::UnknownBlock)
+		);
+
+		wallet.process_transaction_with_header_proof(&tx, &proof, &headers).unwrap();
+		assert_eq!(wallet.get_balance(None), Amount::from_sat(50_000));
+		assert_eq!(wallet.get_history_entry(tx.txid()).unwrap().height, 1);
+	}
+
+	#[test]
+	fn gap_limit() {
+		let mut wallet = test_wallet();
+		let mut config = wallet.config().clone();
+		config.signet = true;
+		let err = Error::InvalidConfig("the chain can't be changed");
+		assert_eq!(wallet.set_config(config), Err(err));
+
+		// Another instance of the wallet hands out addresses.
+		let mut other = test_wallet();
+		let addresses: Vec<Address> =
+			(0..10).map(|_| other.new_receive_address().unwrap()).collect();
+		let mut config = wallet.config().clone();
+		config.gap_limit = 5;
+		wallet.set_config(config).unwrap();
+
+		let payment = |address: &Address| Transaction {
+			version: 1,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: OutPoint {
+					txid: sha256d::Hash::hash(address.to_string().as_bytes()),
+					vout: 0,
+				},
+				script_sig: Script::new(),
+				sequence: 0xFFFFFFFF,
+				witness: vec![],
+			}],
+			output: vec![TxOut {
+				value: 10_000,
+				script_pubkey: address.script_pubkey(),
+			}],
+		};
+		// The payment to the last address is beyond the gap, also after the
+		// first payment extended the lookahead.
+		let txs = vec![payment(&addresses[3]), payment(&addresses[9])];
+		let block = next_block(&wallet, txs);
+		wallet.process_block(&block).unwrap();
+		assert_eq!(wallet.get_balance(None), Amount::from_sat(10_000));
+		assert_eq!(wallet.new_receive_address().unwrap(), addresses[4]);
+	}
+
+	#[test]
+	fn create_payment() {
+		let mut wallet = test_wallet();
+		fund(&mut wallet, &[1_000_000]);
+		let fee_rate = FeeRate::from_sat_per_vb(1);
+
+		let mainnet = "bitcoin:bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4?amount=0.001";
+		let uri = Uri::parse(mainnet).unwrap();
+		assert_eq!(wallet.create_payment(&uri, fee_rate).map(|_| ()), Err(Error::WrongNetwork));
+
+		let address = Address::from_script(&foreign_script(), Network::Regtest).unwrap();
+		let mut uri = Uri::new(address.clone());
+		assert_eq!(
+			wallet.create_payment(&uri, fee_rate).map(|_| ()),
+			Err(Error::InvalidUri("no amount"))
+		);
+
+		uri.amount = Some(Amount::from_sat(100_000));
+		let mut unlabeled = wallet.clone();
+		let version = unlabeled.version;
+		unlabeled.create_payment(&uri, fee_rate).unwrap();
+		let unlabeled_versions = unlabeled.version - version;
+
+		uri.label = Some("rent".to_owned());
+		let created = wallet.create_payment(&uri, fee_rate).unwrap();
+		assert_eq!(wallet.version - version, unlabeled_versions + 1);
+		let payment = created.psbt.global.unsigned_tx.output.iter().find(|o| {
+			o.script_pubkey == address.script_pubkey()
+		});
+		assert_eq!(payment.unwrap().value, 100_000);
+		let label = Label::new("rent".to_owned());
+		assert_eq!(wallet.labels().get(&LabelRef::address(&address)), Some(&label));
+		assert_eq!(wallet.labels().get(&LabelRef::Tx(created.txid())), Some(&label));
+	}
+
+	#[test]
+	fn send_many_checks_selectable_balance() {
+		let mut wallet = test_wallet();
+		let tx = fund(&mut wallet, &[100_000, 50_000]);
+		let fee_rate = FeeRate::from_sat_per_vb(1);
+		let address = Address::from_script(&foreign_script(), Network::Regtest).unwrap();
+		let send = |wallet: &mut Wallet, sat| {
+			let recipients = vec![(address.clone(), Amount::from_sat(sat))];
+			wallet.send_many(recipients, fee_rate).map(|_| ())
+		};
+
+		let locked = tx.output.iter().position(|o| o.value == 100_000).unwrap();
+		wallet.lock_utxo(&OutPoint { txid: tx.txid(), vout: locked as u32 }).unwrap();
+		assert_eq!(wallet.get_spendable_balance(), Amount::from_sat(150_000));
+		assert_eq!(send(&mut wallet, 60_000), Err(Error::InsufficientFunds));
+
+		let mut config = wallet.config().clone();
+		config.min_spend_confirmations = 2;
+		wallet.set_config(config).unwrap();
+		assert_eq!(send(&mut wallet, 40_000), Err(Error::InsufficientFunds));
+
+		let block = next_block(&wallet, vec![]);
+		wallet.process_block(&block).unwrap();
+		assert_eq!(send(&mut wallet, 40_000), Ok(()));
+	}
+
+	#[test]
+	fn list_transactions() {
+		let mut wallet = test_wallet();
+		let funding = fund(&mut wallet, &[100_000]);
+
+		// A second payment to the same address.
+		let mut reuse = funding_tx(&mut wallet, &[50_000]);
+		reuse.output[0].script_pubkey = funding.output[0].script_pubkey.clone();
+		let block = next_block(&wallet, vec![reuse]);
+		wallet.process_block(&block).unwrap();
+
+		let fee_rate = FeeRate::from_sat_per_vb(1);
+		let created =
+			wallet.create_transaction_with_feerate(payment(30_000), vec![], fee_rate).unwrap();
+		let block = next_block(&wallet, vec![sign(&created)]);
+		wallet.process_block(&block).unwrap();
+
+		let all = wallet.list_transactions(&TxFilter::default());
+		assert_eq!(all.iter().map(|e| e.height).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+		// The funding txs spend unknown inputs.
+		assert_eq!(all[0].received, Amount::from_sat(100_000));
+		assert_eq!(all[0].sent, Amount::from_sat(0));
+		assert_eq!(all[0].fee, None);
+		assert_eq!(all[0].direction(), TxDirection::Incoming);
+		assert!(!all[0].reused_address);
+		assert_eq!(all[1].net_amount(), 50_000);
+		assert!(all[1].reused_address);
+
+		// The payment spends our own coins and pays change back to us.
+		let outgoing = all[2];
+		assert_eq!(outgoing.txid(), created.txid());
+		assert_eq!(outgoing.fee, Some(created.fee));
+		assert_eq!(outgoing.net_amount(), -(30_000 + created.fee.as_sat() as i64));
+		assert!(outgoing.is_outgoing());
+		assert!(!outgoing.reused_address);
+
+		let list = |filter: TxFilter| -> Vec<u32> {
+			wallet.list_transactions(&filter).iter().map(|e| e.height).collect()
+		};
+		assert_eq!(
+			list(TxFilter {
+				min_height: Some(2),
+				..Default::default()
+			}),
+			vec![2, 3]
+		);
+		assert_eq!(
+			list(TxFilter {
+				max_height: Some(2),
+				..Default::default()
+			}),
+			vec![1, 2]
+		);
+		assert_eq!(
+			list(TxFilter {
+				min_height: Some(2),
+				max_height: Some(2),
+				..Default::default()
+			}),
+			vec![2]
+		);
+		assert_eq!(
+			list(TxFilter {
+				direction: Some(TxDirection::Outgoing),
+				..Default::default()
+			}),
+			vec![3]
+		);
+		assert_eq!(
+			list(TxFilter {
+				direction: Some(TxDirection::Incoming),
+				..Default::default()
+			}),
+			vec![1, 2]
+		);
+		// The absolute net amount counts for outgoing txs.
+		assert_eq!(
+			list(TxFilter {
+				min_amount: Some(Amount::from_sat(30_000)),
+				..Default::default()
+			}),
+			vec![1, 2, 3]
+		);
+		assert_eq!(
+			list(TxFilter {
+				min_amount: Some(Amount::from_sat(50_001)),
+				..Default::default()
+			}),
+			vec![1]
+		);
+		assert_eq!(
+			list(TxFilter {
+				offset: 1,
+				limit: Some(1),
+				..Default::default()
+			}),
+			vec![2]
+		);
+		assert_eq!(
+			list(TxFilter {
+				direction: Some(TxDirection::Incoming),
+				offset: 1,
+				..Default::default()
+			}),
+			vec![2]
+		);
+		assert!(list(TxFilter {
+			offset: 3,
+			..Default::default()
+		})
+		.is_empty());
+	}
+
+	#[test]
+	fn waste_metric() {
+		// A P2WPKH input of 68 vbytes.
+		let input = fee::P2WPKH_INPUT_WEIGHT;
+		assert_eq!(fee::weight_to_vsize(input), 68);
+
+		// Without change, the excess is wasted.
+		assert_eq!(selection_waste(10, 5, input, None, 1_000), 5 * 68 + 1_000);
+		// With change, creating and later spending it at the long-term rate is.
+		assert_eq!(selection_waste(10, 5, input, Some(310), 1_000), 5 * 68 + 310 + 5 * 68);
+		// Spending inputs below the long-term rate saves fees.
+		assert_eq!(selection_waste(2, 5, input, None, 0), -3 * 68);
+		assert_eq!(selection_waste(2, 5, 2 * input, None, 0), -3 * 136);
+		assert_eq!(selection_waste(5, 5, 2 * input, None, 0), 0);
+	}
+
+	#[test]
+	fn coin_selection_minimizes_waste() {
+		let select = |fee_rate: u64, long_term_rate: u64| -> (usize, bool) {
+			let mut wallet = test_wallet();
+			let mut config = wallet.config().clone();
+			config.long_term_fee_rate = Some(long_term_rate);
+			wallet.set_config(config).unwrap();
+			let funding = fund(&mut wallet, &[10_000, 10_000, 10_000, 10_000, 100_000]);
+			let large = OutPoint {
+				txid: funding.txid(),
+				vout: 4,
+			};
+
+			let fee_rate = FeeRate::from_sat_per_vb(fee_rate);
+			let created =
+				wallet.create_transaction_with_feerate(payment(35_000), vec![], fee_rate).unwrap();
+			(created.selected_utxos.len(), created.selected_utxos.contains(&large))
+		};
+
+		// Above the long-term rate, as few inputs as possible.
+		assert_eq!(select(20, 1), (1, true));
+		// Below it, the small coins are consolidated.
+		assert_eq!(select(1, 20).0, 4);
+	}
+
+	#[test]
+	fn payout_run() {
+		let mut wallet = test_wallet();
+		fund(&mut wallet, &[100_000, 100_000, 100_000]);
+		let fee_rate = FeeRate::from_sat_per_vb(1);
+		let payouts: Vec<TxOut> = (0..5).map(|_| payment(20_000).remove(0)).collect();
+		let limits = PayoutLimits {
+			max_inputs: 1,
+			max_outputs: 3,
+			max_weight: u64::max_value(),
+		};
+
+		let (id, created) = wallet.create_payout_run(payouts.clone(), fee_rate, &limits).unwrap();
+		let run = wallet.get_payout_run(id).unwrap().clone();
+		assert_eq!(run.payouts, vec![vec![0, 1], vec![2, 3], vec![4]]);
+		assert_eq!(run.txids, created.iter().map(|c| c.txid()).collect::<Vec<_>>());
+
+		// Every tx is within the limits and the txs don't share inputs.
+		let mut spent = HashSet::new();
+		for (created, payouts) in created.iter().zip(run.payouts.iter()) {
+			let tx = &created.psbt.global.unsigned_tx;
+			assert!(tx.input.len() <= limits.max_inputs);
+			assert!(tx.output.len() <= limits.max_outputs);
+			assert_eq!(tx.output.len(), payouts.len() + 1);
+			for input in &tx.input {
+				assert!(spent.insert(input.previous_output));
+			}
+			assert!(wallet.get_pending_transaction(created.txid()).is_some());
+		}
+		assert_eq!(wallet.get_spendable_balance(), Amount::from_sat(0));
+
+		// Dropping the run drops all its txs.
+		assert!(wallet.drop_payout_run(id));
+		assert!(wallet.get_payout_run(id).is_none());
+		for txid in &run.txids {
+			assert!(wallet.get_pending_transaction(*txid).is_none());
+		}
+		assert_eq!(wallet.get_spendable_balance(), Amount::from_sat(300_000));
+
+		// When a single payout doesn't fit, no txs are created.
+		let tiny = PayoutLimits {
+			max_weight: 1,
+			..limits.clone()
+		};
+		let res = wallet.create_payout_run(payouts.clone(), fee_rate, &tiny);
+		assert_eq!(res.map(|r| r.0), Err(Error::TxLimitsExceeded));
+		assert_eq!(wallet.get_spendable_balance(), Amount::from_sat(300_000));
+
+		// Ids of dropped runs are not reused.
+		let (next_id, _) = wallet.create_payout_run(payouts, fee_rate, &limits).unwrap();
+		assert!(next_id > id);
+	}
+
+	#[test]
+	fn disconnect_block() {
+		let mut wallet = test_wallet();
+		let funding = fund(&mut wallet, &[100_000]);
+		let fee_rate = FeeRate::from_sat_per_vb(1);
+		let created =
+			wallet.create_transaction_with_feerate(payment(30_000), vec![], fee_rate).unwrap();
+		let txid = created.txid();
+		let spend = next_block(&wallet, vec![sign(&created)]);
+		wallet.process_block(&spend).unwrap();
+		let change = Amount::from_sat(70_000) - created.fee;
+		assert_eq!(wallet.get_balance(None), change);
+		assert!(!wallet.get_transaction(txid).unwrap().pending);
+
+		// The spent coin is back and the tx spending it is pending again.
+		wallet.disconnect_block().unwrap();
+		assert_eq!(wallet.last_block().unwrap().height, 1);
+		assert!(!wallet.is_block_processed(&spend.bitcoin_hash()));
+		assert_eq!(wallet.get_balance(None), Amount::from_sat(100_000));
+		assert_eq!(wallet.get_spendable_balance(), Amount::from_sat(0));
+		let details = wallet.get_transaction(txid).unwrap();
+		assert!(details.pending);
+		assert!(details.entry.is_none());
+		assert_eq!(details.confirmations, 0);
+		assert_eq!(wallet.list_transactions(&TxFilter::default()).len(), 1);
+
+		// Confirming it again merges the pending tx.
+		wallet.process_block(&spend).unwrap();
+		assert_eq!(wallet.get_balance(None), change);
+		let details = wallet.get_transaction(txid).unwrap();
+		assert!(!details.pending);
+		assert_eq!(details.confirmations, 1);
+
+		wallet.rollback_to(0).unwrap();
+		assert_eq!(wallet.get_balance(None), Amount::from_sat(0));
+		assert!(wallet.get_transaction(funding.txid()).is_none());
+		assert!(wallet.list_transactions(&TxFilter::default()).is_empty());
+		// The block before the genesis block is not known.
+		assert_eq!(wallet.disconnect_block(), Err(Error::UnknownBlock));
+	}
+
+	#[test]
+	fn avoid_partial_spends() {
+		let select = |avoid: bool| -> (bool, bool) {
+			let mut wallet = test_wallet();
+			let mut config = wallet.config().clone();
+			config.avoid_partial_spends = avoid;
+			wallet.set_config(config).unwrap();
+			// Two coins on a reused address and a small one.
+			let mut tx = funding_tx(&mut wallet, &[50_000, 50_000, 5_000]);
+			tx.output[1].script_pubkey = tx.output[0].script_pubkey.clone();
+			let block = next_block(&wallet, vec![tx.clone()]);
+			wallet.process_block(&block).unwrap();
+
+			let fee_rate = FeeRate::from_sat_per_vb(1);
+			let created =
+				wallet.create_transaction_with_feerate(payment(30_000), vec![], fee_rate).unwrap();
+			let spends = |vout: u32| {
+				created.selected_utxos.contains(&OutPoint {
+					txid: tx.txid(),
+					vout: vout,
+				})
+			};
+			(spends(0), spends(1))
+		};
+
+		assert_eq!(select(true), (true, true));
+		assert_ne!(select(false), (true, true));
+	}
+
+	#[test]
+	fn compact_round_trip() {
+		let mut wallet = test_wallet();
+		let funding = fund(&mut wallet, &[100_000]);
+		let fee_rate = FeeRate::from_sat_per_vb(1);
+		let created =
+			wallet.create_transaction_with_feerate(payment(30_000), vec![], fee_rate).unwrap();
+		let block = next_block(&wallet, vec![sign(&created)]);
+		wallet.process_block(&block).unwrap();
+		let balance = wallet.get_balance(None);
+
+		// Only the fully spent funding tx is pruned, the payment has change.
+		assert_eq!(wallet.compact(3), 1);
+		assert_eq!(wallet.compact(3), 0);
+
+		let json = serde_json::to_string(&wallet).unwrap();
+		let mut wallet: Wallet = serde_json::from_str(&json).unwrap();
+		assert_eq!(wallet.get_balance(None), balance);
+		assert_eq!(wallet.get_spendable_balance(), balance);
+
+		{
+			let history = wallet.list_transactions(&TxFilter::default());
+			assert_eq!(history.len(), 2);
+			assert!(history[0].is_pruned());
+			assert!(history[0].tx.input.is_empty());
+			assert_eq!(history[0].txid(), funding.txid());
+			assert_eq!(history[0].received, Amount::from_sat(100_000));
+			assert!(!history[1].is_pruned());
+			assert_eq!(history[1].fee, Some(created.fee));
+		}
+
+		// The change can still be spent.
+		let spend =
+			wallet.create_transaction_with_feerate(payment(20_000), vec![], fee_rate).unwrap();
+		let change = OutPoint {
+			txid: created.txid(),
+			vout: created.change_index().unwrap() as u32,
+		};
+		assert_eq!(spend.selected_utxos, vec![change]);
+		sign(&spend);
+	}
+
+	#[test]
+	fn compact_keeps_txs_spent_above_height() {
+		let mut wallet = test_wallet();
+		let coinbase = Transaction {
+			version: 1,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: OutPoint::null(),
+				script_sig: Script::from(vec![0x51]),
+				sequence: 0xFFFFFFFF,
+				witness: vec![],
+			}],
+			output: vec![TxOut {
+				value: 100_000,
+				script_pubkey: wallet.new_receive_address().unwrap().script_pubkey(),
+			}],
+		};
+		let spender = Transaction {
+			version: 1,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: OutPoint {
+					txid: coinbase.txid(),
+					vout: 0,
+				},
+				script_sig: Script::new(),
+				sequence: 0xFFFFFFFF,
+				witness: vec![],
+			}],
+			output: payment(99_000),
+		};
+		let mut blocks = Vec::new();
+		for tx in vec![coinbase.clone(), spender.clone()] {
+			let block = next_block(&wallet, vec![tx]);
+			wallet.process_block(&block).unwrap();
+			blocks.push(block);
+		}
+		fund(&mut wallet, &[50_000]);
+
+		// The coinbase tx is spent in a block that is kept.
+		assert_eq!(wallet.compact(2), 0);
+		assert!(!wallet.get_history_entry(coinbase.txid()).unwrap().is_pruned());
+
+		// Disconnecting the spender restores the immature coinbase output.
+		wallet.rollback_to(1).unwrap();
+		assert_eq!(wallet.get_balance(None), Amount::from_sat(100_000));
+		assert_eq!(wallet.get_timelocked_balance(), Amount::from_sat(100_000));
+		assert_eq!(wallet.get_spendable_balance(), Amount::from_sat(0));
+
+		wallet.process_block(&blocks[1]).unwrap();
+		fund(&mut wallet, &[50_000]);
+		assert_eq!(wallet.compact(3), 2);
+		let entry = wallet.get_history_entry(coinbase.txid()).unwrap();
+		assert!(entry.is_pruned());
+		assert!(entry.is_coinbase());
+		assert_eq!(entry.txid(), coinbase.txid());
+		assert!(wallet.get_history_entry(spender.txid()).unwrap().is_pruned());
+		assert_eq!(wallet.get_balance(None), Amount::from_sat(50_000));
 	}
 }
@@ -16,11 +16,13 @@ extern crate bitcoin_wallet;
 use std::{env, io};
 
 use bitcoin::util::bip32;
-use bitcoin::{Block, Network, TxOut};
+use bitcoin::TxOut;
 use bitcoin::consensus::encode::serialize;
 use bitcoincore_rpc::RpcApi;
 
-use bitcoin_wallet::config::WalletConfig;
+use bitcoin_wallet::amount::Amount;
+use bitcoin_wallet::config::{Chain, WalletConfig};
+use bitcoin_wallet::rpc::sync_with_bitcoind;
 use bitcoin_wallet::wallet::Wallet;
 
 lazy_static! {
@@ -37,14 +39,23 @@ fn init_bitcoind() -> bitcoincore_rpc::Client {
 	bitcoincore_rpc::Client::new(bitcoind_host, bitcoind_auth).expect("RPC error")
 }
 
-fn init_wallet() -> (bip32::ExtendedPrivKey, Wallet) {
-	let config = WalletConfig {
-		network: Network::Regtest,
-	};
+/// A bitcoind on a custom signet with the trivial challenge, so that it can
+/// generate blocks: `bitcoind -signet -signetchallenge=51`.
+/// The signet test is skipped when it is not configured.
+fn init_signet_bitcoind() -> Option<bitcoincore_rpc::Client> {
+	let bitcoind_host = env::var("BITCOIND_SIGNET_HOST").ok()?;
+	let bitcoind_cookie =
+		env::var("BITCOIND_SIGNET_COOKIE").expect("BITCOIND_SIGNET_COOKIE missing");
+	let bitcoind_auth = bitcoincore_rpc::Auth::CookieFile(bitcoind_cookie.into());
+	Some(bitcoincore_rpc::Client::new(bitcoind_host, bitcoind_auth).expect("RPC error"))
+}
+
+fn init_wallet(chain: Chain) -> (bip32::ExtendedPrivKey, Wallet) {
+	let config = WalletConfig::for_chain(chain);
 
 	let seed =
 		hex::decode("d7e6ab0cb485ab6e73975626d2d8e7a92d8643b873feef202306ee1bd4121683").unwrap();
-	let xpriv = bip32::ExtendedPrivKey::new_master(Network::Regtest, &seed).unwrap();
+	let xpriv = bip32::ExtendedPrivKey::new_master(chain.network(), &seed).unwrap();
 	let xpub = bip32::ExtendedPubKey::from_private(
 		&SECP,
 		&xpriv.derive_priv(&SECP, &XPUB_PATH.parse::<bip32::DerivationPath>().unwrap()).unwrap(),
@@ -54,40 +65,43 @@ fn init_wallet() -> (bip32::ExtendedPrivKey, Wallet) {
 	(xpriv, wallet)
 }
 
-fn generate(bitcoind: &bitcoincore_rpc::Client) -> Block {
+fn generate(bitcoind: &bitcoincore_rpc::Client) {
 	let generate_addr = bitcoind.get_new_address(None, None).expect("RPR");
 	let block_hashes = bitcoind.generate_to_address(1, &generate_addr).expect("RPC");
 	assert_eq!(block_hashes.len(), 1);
-	bitcoind.get_block(&block_hashes[0]).expect("RPC")
 }
 
 #[test]
 fn main() {
 	let bitcoind = init_bitcoind();
-	let (xpriv, mut wallet) = init_wallet();
+	let (xpriv, mut wallet) = init_wallet(Chain::Regtest);
 
 	println!("initial wallet");
 	println!("{:?}", wallet);
 
 	// add the tip as first block
-	let blockchain_info = bitcoind.get_blockchain_info().expect("RPC");
-	wallet.set_last_block(blockchain_info.bestblockhash, blockchain_info.blocks as u32);
+	sync_with_bitcoind(&mut wallet, &bitcoind).expect("sync");
 
 	println!("wallet ready");
 	println!("{:?}", wallet);
 
 	// receive some txs
 	for _ in 0..5 {
-		let addr = wallet.new_receive_address();
+		let addr = wallet.new_receive_address().unwrap();
 		bitcoind.send_to_address(&addr, 1.0, None, None, None, None, None, None).expect("RPC");
-		let block = generate(&bitcoind);
-		wallet.process_block(&block).expect("process block");
+		generate(&bitcoind);
+		sync_with_bitcoind(&mut wallet, &bitcoind).expect("sync");
 	}
 
 	println!("wallet finaly");
 	println!("{:?}", wallet);
 	println!("balance: {}", wallet.get_balance(None));
-	assert_eq!(wallet.get_balance(None), 500000000);
+	assert_eq!(wallet.get_balance(None), Amount::from_sat(500000000));
+	assert_eq!(wallet.list_transactions(&Default::default()).len(), 5);
+	for entry in wallet.list_transactions(&Default::default()) {
+		assert_eq!(entry.received, Amount::from_sat(100000000));
+		assert_eq!(entry.net_amount(), 100000000);
+	}
 
 	// make tx
 	let delivery_addr = bitcoind.get_new_address(None, None).expect("RPR");
@@ -95,7 +109,10 @@ fn main() {
 		value: 250000000,
 		script_pubkey: delivery_addr.script_pubkey(),
 	};
-	let psbt = wallet.create_transaction(vec![output], vec![], 0).expect("create_transaction");
+	let psbt = wallet
+		.create_transaction(vec![output], vec![], Amount::from_sat(0))
+		.expect("create_transaction")
+		.psbt;
 	let b64 = base64::encode(&serialize(&psbt));
 
 	println!("psbt: {}", b64);
@@ -103,3 +120,38 @@ fn main() {
 
 
 }
+
+#[test]
+fn signet() {
+	let bitcoind = match init_signet_bitcoind() {
+		Some(bitcoind) => bitcoind,
+		None => return println!("BITCOIND_SIGNET_HOST missing, skipping"),
+	};
+	let (_, mut wallet) = init_wallet(Chain::Signet);
+	assert_eq!(wallet.config().chain(), Chain::Signet);
+	assert_eq!(wallet.config().min_output_value, 0);
+	sync_with_bitcoind(&mut wallet, &bitcoind).expect("sync");
+
+	// signet uses the address format of testnet
+	let addr = wallet.new_receive_address().unwrap();
+	assert!(addr.to_string().starts_with("tb1"));
+
+	// mine a block to the wallet, its coinbase output needs 100 blocks to mature
+	let block_hashes = bitcoind.generate_to_address(1, &addr).expect("RPC");
+	assert_eq!(block_hashes.len(), 1);
+	sync_with_bitcoind(&mut wallet, &bitcoind).expect("sync");
+	let subsidy = wallet.get_balance(None);
+	assert!(subsidy > Amount::from_sat(0));
+	assert_eq!(wallet.get_spendable_balance(), Amount::from_sat(0));
+	assert_eq!(wallet.get_timelocked_balance(), subsidy);
+
+	let generate_addr = bitcoind.get_new_address(None, None).expect("RPC");
+	bitcoind.generate_to_address(98, &generate_addr).expect("RPC");
+	sync_with_bitcoind(&mut wallet, &bitcoind).expect("sync");
+	assert_eq!(wallet.get_spendable_balance(), Amount::from_sat(0));
+
+	generate(&bitcoind);
+	sync_with_bitcoind(&mut wallet, &bitcoind).expect("sync");
+	assert_eq!(wallet.get_spendable_balance(), subsidy);
+	assert_eq!(wallet.get_timelocked_balance(), Amount::from_sat(0));
+}